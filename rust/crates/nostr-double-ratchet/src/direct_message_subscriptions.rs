@@ -1,6 +1,6 @@
 use crate::{
     utils::pubkey_from_hex, APP_KEYS_EVENT_KIND, INVITE_EVENT_KIND, INVITE_LIST_LABEL,
-    INVITE_RESPONSE_KIND, MESSAGE_EVENT_KIND,
+    INVITE_RESPONSE_KIND, MESSAGE_EVENT_KIND, ROSTER_D_TAG, ROSTER_EVENT_KIND,
 };
 use nostr::{Alphabet, Filter, Kind, PublicKey, SingleLetterTag};
 use serde_json::Value;
@@ -256,6 +256,25 @@ pub fn build_app_keys_backfill_filter(
         .limit(limit)
 }
 
+pub fn build_roster_backfill_filter(
+    authors: impl IntoIterator<Item = PublicKey>,
+    limit: usize,
+) -> Filter {
+    let mut unique_authors = Vec::new();
+    let mut seen_authors = HashSet::new();
+    for author in authors {
+        if seen_authors.insert(author) {
+            unique_authors.push(author);
+        }
+    }
+
+    Filter::new()
+        .kind(Kind::from(ROSTER_EVENT_KIND as u16))
+        .authors(unique_authors)
+        .custom_tag(SingleLetterTag::lowercase(Alphabet::D), ROSTER_D_TAG)
+        .limit(limit)
+}
+
 pub fn build_invite_backfill_filter(
     authors: impl IntoIterator<Item = PublicKey>,
     limit: usize,
@@ -14,12 +14,38 @@ pub enum DomainError {
     #[error("too many skipped messages")]
     TooManySkippedMessages,
 
+    #[error("duplicate message")]
+    DuplicateMessage,
+
+    /// The message is older than anything the receiver still remembers, so
+    /// it cannot tell a replay from a late delivery.
+    #[error("message outside retention window")]
+    MessageOutsideRetention,
+
+    #[error("peer does not support control messages")]
+    ControlNotSupported,
+
     #[error("invite already used")]
     InviteAlreadyUsed,
 
     #[error("invite exhausted")]
     InviteExhausted,
 
+    #[error("invite expired")]
+    InviteExpired,
+
+    #[error("invite revoked")]
+    InviteRevoked,
+
+    #[error("stale invite response")]
+    StaleInviteResponse,
+
+    #[error("insufficient proof of work")]
+    InsufficientProofOfWork,
+
+    #[error("proof of work difficulty too high")]
+    ProofOfWorkTooHigh,
+
     #[error("invalid group operation: {0}")]
     InvalidGroupOperation(String),
 
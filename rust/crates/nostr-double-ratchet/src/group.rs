@@ -1,4 +1,6 @@
-use crate::{Delivery, DevicePubkey, InviteResponseEnvelope, OwnerPubkey, RelayGap, UnixSeconds};
+use crate::{
+    Delivery, DevicePubkey, InviteResponseEnvelope, OwnerPubkey, RelayGap, SecretBytes, UnixSeconds,
+};
 use serde::{Deserialize, Deserializer, Serialize};
 
 pub type SenderEventPubkey = DevicePubkey;
@@ -239,7 +241,7 @@ pub struct GroupSenderKeyRecordSnapshot {
     pub sender_device: DevicePubkey,
     pub sender_event_pubkey: SenderEventPubkey,
     #[serde(default, with = "serde_option_bytes_array")]
-    pub sender_event_secret_key: Option<[u8; 32]>,
+    pub sender_event_secret_key: Option<SecretBytes>,
     pub latest_key_id: Option<u32>,
     pub states: Vec<crate::SenderKeyState>,
     #[serde(default)]
@@ -269,7 +271,7 @@ pub struct SenderKeyDistribution {
     pub key_id: u32,
     pub sender_event_pubkey: SenderEventPubkey,
     #[serde(with = "serde_bytes_array")]
-    pub chain_key: [u8; 32],
+    pub chain_key: SecretBytes,
     pub iteration: u32,
     pub created_at: UnixSeconds,
 }
@@ -360,7 +362,7 @@ pub struct GroupSenderKeyMessageEnvelope {
     pub group_id: String,
     pub sender_event_pubkey: SenderEventPubkey,
     #[serde(with = "serde_bytes_array")]
-    pub signer_secret_key: [u8; 32],
+    pub signer_secret_key: SecretBytes,
     pub key_id: u32,
     pub message_number: u32,
     #[serde(default, skip_serializing_if = "Option::is_none")]
@@ -514,40 +516,43 @@ mod tests {
 }
 
 mod serde_bytes_array {
+    use crate::SecretBytes;
     use serde::{Deserialize, Deserializer, Serializer};
 
-    pub fn serialize<S>(bytes: &[u8; 32], serializer: S) -> Result<S::Ok, S::Error>
+    pub fn serialize<S>(bytes: &SecretBytes, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        serializer.serialize_str(&hex::encode(bytes))
+        serializer.serialize_str(&hex::encode(bytes.expose_secret()))
     }
 
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<[u8; 32], D::Error>
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<SecretBytes, D::Error>
     where
         D: Deserializer<'de>,
     {
         let value = String::deserialize(deserializer)?;
         let bytes = hex::decode(value).map_err(serde::de::Error::custom)?;
         <[u8; 32]>::try_from(bytes.as_slice())
+            .map(SecretBytes::new)
             .map_err(|_| serde::de::Error::custom("expected 32-byte hex"))
     }
 }
 
 mod serde_option_bytes_array {
+    use crate::SecretBytes;
     use serde::{Deserialize, Deserializer, Serializer};
 
-    pub fn serialize<S>(bytes: &Option<[u8; 32]>, serializer: S) -> Result<S::Ok, S::Error>
+    pub fn serialize<S>(bytes: &Option<SecretBytes>, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
         match bytes {
-            Some(bytes) => serializer.serialize_str(&hex::encode(bytes)),
+            Some(bytes) => serializer.serialize_str(&hex::encode(bytes.expose_secret())),
             None => serializer.serialize_none(),
         }
     }
 
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<[u8; 32]>, D::Error>
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<SecretBytes>, D::Error>
     where
         D: Deserializer<'de>,
     {
@@ -556,6 +561,7 @@ mod serde_option_bytes_array {
             .map(|value| {
                 let bytes = hex::decode(value).map_err(serde::de::Error::custom)?;
                 <[u8; 32]>::try_from(bytes.as_slice())
+                    .map(SecretBytes::new)
                     .map_err(|_| serde::de::Error::custom("expected 32-byte hex"))
             })
             .transpose()
@@ -6,8 +6,8 @@ use crate::{
     GroupSenderKeyMessage, GroupSenderKeyMessageEnvelope, GroupSenderKeyPlaintext,
     GroupSenderKeyPlaintextDecodeContext, GroupSenderKeyRecordSnapshot,
     GroupSenderKeyRepairRequestEvent, GroupSenderKeyRepairSnapshot, GroupSnapshot, OwnerPubkey,
-    ProtocolContext, Result, SenderEventPubkey, SenderKeyDistribution, SenderKeyMessageContent,
-    SenderKeyRepairRequest, SenderKeyState, SessionManager, UnixSeconds,
+    ProtocolContext, Result, SecretBytes, SenderEventPubkey, SenderKeyDistribution,
+    SenderKeyMessageContent, SenderKeyRepairRequest, SenderKeyState, SessionManager, UnixSeconds,
 };
 use rand::{CryptoRng, RngCore};
 use std::collections::{BTreeMap, BTreeSet};
@@ -49,7 +49,7 @@ struct SenderKeyRecord {
     sender_owner: OwnerPubkey,
     sender_device: DevicePubkey,
     sender_event_pubkey: SenderEventPubkey,
-    sender_event_secret_key: Option<[u8; 32]>,
+    sender_event_secret_key: Option<SecretBytes>,
     latest_key_id: Option<u32>,
     states: BTreeMap<u32, SenderKeyState>,
     distribution_history: BTreeMap<u32, SenderKeyDistribution>,
@@ -1312,6 +1312,7 @@ where
         state.apply_encrypt(plan);
         let signer_secret_key = sender_record
             .sender_event_secret_key
+            .clone()
             .ok_or_else(|| group_error("missing local sender-event secret key"))?;
 
         let sender_key_message = GroupSenderKeyMessageEnvelope {
@@ -1412,7 +1413,7 @@ where
                 sender_owner: self.local_owner_pubkey,
                 sender_device: local_device,
                 sender_event_pubkey,
-                sender_event_secret_key: Some(sender_event_secret_key),
+                sender_event_secret_key: Some(SecretBytes::new(sender_event_secret_key)),
                 latest_key_id: None,
                 states: BTreeMap::new(),
                 distribution_history: BTreeMap::new(),
@@ -1442,7 +1443,7 @@ where
                 group_id: record.group_id.clone(),
                 key_id,
                 sender_event_pubkey: sender_record.sender_event_pubkey,
-                chain_key,
+                chain_key: SecretBytes::new(chain_key),
                 iteration: 0,
                 created_at: ctx.now,
             });
@@ -1460,7 +1461,7 @@ where
             group_id: record.group_id.clone(),
             key_id,
             sender_event_pubkey: sender_record.sender_event_pubkey,
-            chain_key: state.chain_key(),
+            chain_key: SecretBytes::new(state.chain_key()),
             iteration: state.iteration(),
             created_at: ctx.now,
         });
@@ -1611,7 +1612,7 @@ where
         record.states.entry(distribution.key_id).or_insert_with(|| {
             SenderKeyState::new(
                 distribution.key_id,
-                distribution.chain_key,
+                *distribution.chain_key.expose_secret(),
                 distribution.iteration,
             )
         });
@@ -2135,7 +2136,7 @@ impl SenderKeyRecord {
             sender_owner: self.sender_owner,
             sender_device: self.sender_device,
             sender_event_pubkey: self.sender_event_pubkey,
-            sender_event_secret_key: self.sender_event_secret_key,
+            sender_event_secret_key: self.sender_event_secret_key.clone(),
             latest_key_id: self.latest_key_id,
             states: self.states.values().cloned().collect(),
             distribution_history: self.distribution_history.values().cloned().collect(),
@@ -1,7 +1,7 @@
 use crate::{
     DevicePubkey, Error, GroupPairwiseCommand, GroupPayloadCodec, GroupPayloadEncodeContext,
     GroupProtocol, GroupSenderKeyPlaintext, GroupSenderKeyPlaintextDecodeContext, GroupSnapshot,
-    OwnerPubkey, Result, SenderKeyDistribution, SenderKeyRepairRequest, UnixSeconds,
+    OwnerPubkey, Result, SecretBytes, SenderKeyDistribution, SenderKeyRepairRequest, UnixSeconds,
 };
 use nostr::{
     Alphabet, Event, EventBuilder, EventId, Filter, Kind, PublicKey, SingleLetterTag, Tag, Tags,
@@ -494,7 +494,7 @@ fn encode_sender_key_distribution(
     let content = serde_json::to_string(&SenderKeyDistributionContent {
         group_id: distribution.group_id.clone(),
         key_id: distribution.key_id,
-        chain_key: hex::encode(distribution.chain_key),
+        chain_key: hex::encode(distribution.chain_key.expose_secret()),
         iteration: distribution.iteration,
         created_at: distribution.created_at,
         sender_event_pubkey: distribution.sender_event_pubkey.to_string(),
@@ -556,7 +556,7 @@ fn decode_sender_key_distribution(payload: &[u8]) -> Result<Option<GroupPairwise
             group_id: content.group_id,
             key_id: content.key_id,
             sender_event_pubkey,
-            chain_key,
+            chain_key: SecretBytes::new(chain_key),
             iteration: content.iteration,
             created_at: content.created_at,
         },
@@ -1476,7 +1476,7 @@ mod tests {
             group_id: "group-1".to_string(),
             key_id: 7,
             sender_event_pubkey: device(3),
-            chain_key: [4; 32],
+            chain_key: SecretBytes::new([4; 32]),
             iteration: 9,
             created_at: UnixSeconds(11),
         };
@@ -1527,7 +1527,7 @@ mod tests {
             group_id: "group-1".to_string(),
             key_id: 7,
             sender_event_pubkey: device(3),
-            chain_key: [4; 32],
+            chain_key: SecretBytes::new([4; 32]),
             iteration: 9,
             created_at: UnixSeconds(11),
         };
@@ -1552,7 +1552,7 @@ mod tests {
             group_id: "group-1".to_string(),
             key_id: 7,
             sender_event_pubkey: device(3),
-            chain_key: [4; 32],
+            chain_key: SecretBytes::new([4; 32]),
             iteration: 9,
             created_at: UnixSeconds(11),
         };
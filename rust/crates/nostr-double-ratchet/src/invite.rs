@@ -1,31 +1,55 @@
 use crate::{
     owner_pubkey_from_device_pubkey, random_secret_key_bytes, secret_key_from_bytes, DevicePubkey,
-    DeviceRoster, DomainError, OwnerPubkey, ProtocolContext, Result, Session, UnixSeconds,
+    DeviceRoster, DomainError, OwnerPubkey, ProtocolContext, Result, SecretBytes, Session,
+    UnixSeconds,
 };
 use base64::Engine;
+use nostr::nips::nip13::get_leading_zero_bits;
 use nostr::nips::nip44::{self, Version};
 use nostr::PublicKey;
 use rand::rngs::OsRng;
 use rand::{CryptoRng, RngCore};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Responses to an invite without `expires_at` are refused once they are
+/// older than this.
+pub const INVITE_REPLAY_WINDOW_SECS: u64 = 30 * 24 * 60 * 60;
+/// Cap on response digests kept per invite for replay protection.
+pub const MAX_INVITE_REPLAY_ENTRIES: usize = 512;
+/// Responses dated further than this past the local clock are refused.
+pub const MAX_INVITE_RESPONSE_CLOCK_SKEW_SECS: u64 = 15 * 60;
+/// Highest NIP-13 difficulty an invite may demand from responders.
+pub const MAX_INVITE_POW_DIFFICULTY: u8 = 24;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Invite {
     pub inviter_device_pubkey: DevicePubkey,
     pub inviter_ephemeral_public_key: DevicePubkey,
     #[serde(with = "serde_bytes_array")]
-    pub shared_secret: [u8; 32],
+    pub shared_secret: SecretBytes,
     #[serde(
         default,
         skip_serializing_if = "Option::is_none",
         with = "serde_option_bytes_array"
     )]
-    pub inviter_ephemeral_private_key: Option<[u8; 32]>,
+    pub inviter_ephemeral_private_key: Option<SecretBytes>,
     pub max_uses: Option<usize>,
+    /// Devices that used the invite. Only tracked when `max_uses` is set.
     pub used_by: Vec<DevicePubkey>,
-    #[serde(default, skip_serializing_if = "Vec::is_empty")]
-    pub used_response_contents: Vec<String>,
+    #[serde(default, skip_serializing_if = "InviteReplayCache::is_empty")]
+    pub used_responses: InviteReplayCache,
     pub created_at: UnixSeconds,
+    /// Responses arriving after this time are rejected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<UnixSeconds>,
+    /// Set once the inviter withdrew the invite. A revoked invite can no
+    /// longer be accepted and carries no usable shared secret.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revoked_at: Option<UnixSeconds>,
+    /// NIP-13 difficulty responses must meet before they are decrypted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pow_difficulty: Option<u8>,
     pub inviter_owner_pubkey: Option<OwnerPubkey>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub purpose: Option<String>,
@@ -49,10 +73,83 @@ pub struct InviteResponse {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct InviteResponseEnvelope {
     pub sender: DevicePubkey,
-    pub signer_secret_key: [u8; 32],
+    pub signer_secret_key: SecretBytes,
     pub recipient: DevicePubkey,
     pub created_at: UnixSeconds,
     pub content: String,
+    pub proof_of_work: Option<ProofOfWork>,
+    /// NIP-01 id of the response event, known once the response was mined
+    /// or parsed from a verified event. Proof of work is checked against it.
+    pub event_id: Option<[u8; 32]>,
+}
+
+/// NIP-13 `nonce` tag of an invite response event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProofOfWork {
+    pub nonce: u64,
+    pub difficulty: u8,
+}
+
+impl InviteResponseEnvelope {
+    /// Whether the NIP-13 proof of work on the response event reaches
+    /// `difficulty`. Only compares the event id, so it runs before any
+    /// decryption.
+    pub fn meets_difficulty(&self, difficulty: u8) -> bool {
+        if difficulty == 0 {
+            return true;
+        }
+        self.proof_of_work
+            .is_some_and(|pow| pow.difficulty >= difficulty)
+            && self
+                .event_id
+                .is_some_and(|event_id| get_leading_zero_bits(event_id) >= difficulty)
+    }
+
+    fn mine(&mut self, difficulty: u8, start: u64) {
+        let event_id = ResponseEventId::new(self, difficulty);
+        let mut nonce = start;
+        let mut hash = event_id.hash(nonce);
+        while get_leading_zero_bits(hash) < difficulty {
+            nonce = nonce.wrapping_add(1);
+            hash = event_id.hash(nonce);
+        }
+        self.proof_of_work = Some(ProofOfWork { nonce, difficulty });
+        self.event_id = Some(hash);
+    }
+}
+
+/// NIP-01 id of the response event as [`crate::invite_response_event`]
+/// lays it out, split around the nonce value so mining does not
+/// re-serialize the content for every attempt.
+struct ResponseEventId {
+    prefix: Sha256,
+    suffix: String,
+}
+
+impl ResponseEventId {
+    fn new(envelope: &InviteResponseEnvelope, difficulty: u8) -> Self {
+        let prefix = format!(
+            "[0,\"{}\",{},{},[[\"p\",\"{}\"],[\"nonce\",\"",
+            envelope.sender,
+            envelope.created_at.get(),
+            crate::INVITE_RESPONSE_KIND,
+            envelope.recipient,
+        );
+        let content = serde_json::Value::String(envelope.content.clone()).to_string();
+        Self {
+            prefix: Sha256::new().chain_update(prefix),
+            suffix: format!("\",\"{difficulty}\"]],{content}]"),
+        }
+    }
+
+    fn hash(&self, nonce: u64) -> [u8; 32] {
+        self.prefix
+            .clone()
+            .chain_update(nonce.to_string())
+            .chain_update(&self.suffix)
+            .finalize()
+            .into()
+    }
 }
 
 impl InviteResponse {
@@ -132,12 +229,15 @@ impl Invite {
         Ok(Self {
             inviter_device_pubkey,
             inviter_ephemeral_public_key,
-            shared_secret,
-            inviter_ephemeral_private_key: Some(inviter_ephemeral_private_key),
+            shared_secret: SecretBytes::new(shared_secret),
+            inviter_ephemeral_private_key: Some(SecretBytes::new(inviter_ephemeral_private_key)),
             max_uses,
             used_by: Vec::new(),
-            used_response_contents: Vec::new(),
+            used_responses: InviteReplayCache::default(),
             created_at: ctx.now,
+            expires_at: None,
+            revoked_at: None,
+            pow_difficulty: None,
             inviter_owner_pubkey,
             purpose: None,
             inviter: inviter_device_pubkey.to_nostr()?,
@@ -148,12 +248,30 @@ impl Invite {
         })
     }
 
+    pub fn is_expired(&self, now: UnixSeconds) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
+
+    pub fn is_revoked(&self) -> bool {
+        self.revoked_at.is_some()
+    }
+
+    /// Withdraw the invite, wiping its shared secret and ephemeral private
+    /// key.
+    pub fn revoke(&mut self, revoked_at: UnixSeconds) {
+        self.revoked_at.get_or_insert(revoked_at);
+        self.shared_secret = SecretBytes::default();
+        self.inviter_ephemeral_private_key = None;
+    }
+
     pub fn serialize(&self) -> Result<String> {
         Ok(serde_json::to_string(self)?)
     }
 
+    /// Parse an invite written by [`Invite::serialize`] or a stored
+    /// snapshot envelope, migrating older formats.
     pub fn deserialize(json: &str) -> Result<Self> {
-        Ok(serde_json::from_str(json)?)
+        Ok(crate::decode_snapshot::<Self>(json)?.snapshot)
     }
 
     pub fn accept(
@@ -225,7 +343,13 @@ impl Invite {
     where
         R: RngCore + CryptoRng,
     {
-        self.ensure_accept_allowed(invitee_public_key)?;
+        self.ensure_accept_allowed(invitee_public_key, ctx.now)?;
+        if self
+            .pow_difficulty
+            .is_some_and(|difficulty| difficulty > MAX_INVITE_POW_DIFFICULTY)
+        {
+            return Err(DomainError::ProofOfWorkTooHigh.into());
+        }
 
         let invitee_session_key = random_secret_key_bytes(ctx.rng)?;
         let invitee_session_public_key =
@@ -235,7 +359,7 @@ impl Invite {
             ctx,
             self.inviter_ephemeral_public_key,
             invitee_session_key,
-            self.shared_secret,
+            *self.shared_secret.expose_secret(),
         )?;
 
         let payload = InviteResponsePayload {
@@ -252,7 +376,7 @@ impl Invite {
             Version::V2,
         )?;
 
-        let conversation_key = nip44::v2::ConversationKey::new(self.shared_secret);
+        let conversation_key = nip44::v2::ConversationKey::new(*self.shared_secret.expose_secret());
         let encrypted_bytes =
             nip44::v2::encrypt_to_bytes(&conversation_key, dh_encrypted.as_bytes())?;
         let inner_event = InviteResponseInnerEvent {
@@ -277,16 +401,20 @@ impl Invite {
         };
         let created_at = UnixSeconds(ctx.now.get().saturating_sub(jitter));
 
-        Ok((
-            session,
-            InviteResponseEnvelope {
-                sender: random_sender_pubkey,
-                signer_secret_key: random_sender_secret,
-                recipient: self.inviter_ephemeral_public_key,
-                created_at,
-                content: envelope_content,
-            },
-        ))
+        let mut envelope = InviteResponseEnvelope {
+            sender: random_sender_pubkey,
+            signer_secret_key: SecretBytes::new(random_sender_secret),
+            recipient: self.inviter_ephemeral_public_key,
+            created_at,
+            content: envelope_content,
+            proof_of_work: None,
+            event_id: None,
+        };
+        if let Some(difficulty) = self.pow_difficulty.filter(|difficulty| *difficulty > 0) {
+            envelope.mine(difficulty, ctx.rng.next_u64());
+        }
+
+        Ok((session, envelope))
     }
 
     pub fn process_response<R>(
@@ -298,8 +426,17 @@ impl Invite {
     where
         R: RngCore + CryptoRng,
     {
+        if self.is_revoked() {
+            return Err(DomainError::InviteRevoked.into());
+        }
+        if self.is_expired(ctx.now) {
+            return Err(DomainError::InviteExpired.into());
+        }
+        self.check_response_pow(envelope)?;
         let inviter_ephemeral_private_key = self
             .inviter_ephemeral_private_key
+            .as_ref()
+            .map(|key| *key.expose_secret())
             .ok_or_else(|| crate::Error::Parse("ephemeral key not available".to_string()))?;
 
         let inviter_ephemeral_sk = secret_key_from_bytes(&inviter_ephemeral_private_key)?;
@@ -313,7 +450,7 @@ impl Invite {
         let ciphertext_bytes = base64::engine::general_purpose::STANDARD
             .decode(inner_event.content.as_bytes())
             .map_err(|e| crate::Error::Decryption(e.to_string()))?;
-        let conversation_key = nip44::v2::ConversationKey::new(self.shared_secret);
+        let conversation_key = nip44::v2::ConversationKey::new(*self.shared_secret.expose_secret());
         let dh_encrypted_ciphertext = String::from_utf8(nip44::v2::decrypt_to_bytes(
             &conversation_key,
             &ciphertext_bytes,
@@ -328,18 +465,28 @@ impl Invite {
         )?;
 
         let payload: InviteResponsePayload = serde_json::from_str(&dh_decrypted)?;
-        if self.used_response_contents.contains(&envelope.content) {
+        if self.used_responses.contains(&envelope.content) {
             return Err(DomainError::InviteAlreadyUsed.into());
         }
-        self.ensure_accept_allowed(inner_event.pubkey)?;
+        if !self
+            .used_responses
+            .admits(inner_event.created_at, ctx.now, self.expires_at)
+        {
+            return Err(DomainError::StaleInviteResponse.into());
+        }
+        self.ensure_accept_allowed(inner_event.pubkey, ctx.now)?;
         let session = Session::new_responder(
             ctx,
             payload.session_key,
             inviter_ephemeral_private_key,
-            self.shared_secret,
+            *self.shared_secret.expose_secret(),
         )?;
         self.record_use(inner_event.pubkey);
-        self.record_response_content(envelope.content.clone());
+        self.used_responses
+            .insert(&envelope.content, inner_event.created_at, ctx.now);
+        if self.expires_at.is_none() {
+            self.used_responses.expire(ctx.now);
+        }
 
         Ok(InviteResponse {
             session,
@@ -353,7 +500,25 @@ impl Invite {
         })
     }
 
-    fn ensure_accept_allowed(&self, invitee_public_key: DevicePubkey) -> Result<()> {
+    pub fn check_response_pow(&self, envelope: &InviteResponseEnvelope) -> Result<()> {
+        if envelope.meets_difficulty(self.pow_difficulty.unwrap_or(0)) {
+            Ok(())
+        } else {
+            Err(DomainError::InsufficientProofOfWork.into())
+        }
+    }
+
+    fn ensure_accept_allowed(
+        &self,
+        invitee_public_key: DevicePubkey,
+        now: UnixSeconds,
+    ) -> Result<()> {
+        if self.is_revoked() {
+            return Err(DomainError::InviteRevoked.into());
+        }
+        if self.is_expired(now) {
+            return Err(DomainError::InviteExpired.into());
+        }
         if self.used_by.contains(&invitee_public_key) {
             return Ok(());
         }
@@ -367,20 +532,155 @@ impl Invite {
     }
 
     fn record_use(&mut self, invitee_public_key: DevicePubkey) {
-        if self.used_by.contains(&invitee_public_key) {
+        if self.max_uses.is_none() || self.used_by.contains(&invitee_public_key) {
             return;
         }
         self.used_by.push(invitee_public_key);
         self.used_by.sort();
     }
+}
+
+/// Digests of processed invite responses. Holds at most
+/// [`MAX_INVITE_REPLAY_ENTRIES`], evicting the earliest received first;
+/// whenever an entry is dropped, responses created at or before it are
+/// refused, so a replay is never accepted twice.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InviteReplayCache {
+    entries: Vec<UsedInviteResponse>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    floor: Option<UnixSeconds>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+struct UsedInviteResponse {
+    /// Local time the response was processed. `0` for entries migrated from
+    /// plain response contents; the next insert stamps them.
+    received_at: UnixSeconds,
+    /// `0` for entries migrated from plain response contents, whose age is
+    /// unknown.
+    created_at: UnixSeconds,
+    digest: String,
+}
+
+impl InviteReplayCache {
+    /// Cache for responses recorded before their age was tracked.
+    pub(crate) fn from_contents(contents: Vec<String>) -> Self {
+        let mut entries: Vec<UsedInviteResponse> = contents
+            .iter()
+            .map(|content| UsedInviteResponse {
+                received_at: UnixSeconds(0),
+                created_at: UnixSeconds(0),
+                digest: response_digest(content),
+            })
+            .collect();
+        entries.sort();
+        entries.dedup();
+        Self {
+            entries,
+            floor: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty() && self.floor.is_none()
+    }
+
+    pub fn contains(&self, response_content: &str) -> bool {
+        let digest = response_digest(response_content);
+        self.entries.iter().any(|entry| entry.digest == digest)
+    }
+
+    /// Whether a response created at `created_at` is recent enough to be
+    /// told apart from a replay, and not dated ahead of the local clock.
+    fn admits(
+        &self,
+        created_at: UnixSeconds,
+        now: UnixSeconds,
+        expires_at: Option<UnixSeconds>,
+    ) -> bool {
+        if created_at.get()
+            > now
+                .get()
+                .saturating_add(MAX_INVITE_RESPONSE_CLOCK_SKEW_SECS)
+        {
+            return false;
+        }
+        if self.floor.is_some_and(|floor| created_at <= floor) {
+            return false;
+        }
+        expires_at.is_some()
+            || created_at.get().saturating_add(INVITE_REPLAY_WINDOW_SECS) >= now.get()
+    }
+
+    fn insert(&mut self, response_content: &str, created_at: UnixSeconds, now: UnixSeconds) {
+        if self
+            .entries
+            .first()
+            .is_some_and(|entry| entry.received_at.get() == 0)
+        {
+            for entry in &mut self.entries {
+                if entry.received_at.get() == 0 {
+                    entry.received_at = now;
+                }
+            }
+            self.entries.sort();
+        }
+        let entry = UsedInviteResponse {
+            received_at: now,
+            created_at,
+            digest: response_digest(response_content),
+        };
+        if let Err(index) = self.entries.binary_search(&entry) {
+            self.entries.insert(index, entry);
+        }
+        while self.entries.len() > MAX_INVITE_REPLAY_ENTRIES {
+            let evicted = self.entries.remove(0);
+            self.raise_floor(evicted.latest_created_at());
+        }
+    }
 
-    fn record_response_content(&mut self, content: String) {
-        if self.used_response_contents.contains(&content) {
+    /// Drop entries that [`admits`](Self::admits) would refuse anyway.
+    fn expire(&mut self, now: UnixSeconds) {
+        let Some(cutoff) = now.get().checked_sub(INVITE_REPLAY_WINDOW_SECS + 1) else {
             return;
+        };
+        let cutoff = UnixSeconds(cutoff);
+        let before = self.entries.len();
+        self.entries
+            .retain(|entry| entry.received_at.get() == 0 || entry.latest_created_at() > cutoff);
+        if self.entries.len() != before {
+            self.raise_floor(cutoff);
         }
-        self.used_response_contents.push(content);
-        self.used_response_contents.sort();
     }
+
+    fn raise_floor(&mut self, created_at: UnixSeconds) {
+        self.floor = Some(self.floor.map_or(created_at, |floor| floor.max(created_at)));
+    }
+}
+
+impl UsedInviteResponse {
+    /// Latest creation time a replay of this response can claim. Admission
+    /// keeps it within the clock skew of the receive time; entries of
+    /// unknown age are bounded by the receive time alone.
+    fn latest_created_at(&self) -> UnixSeconds {
+        let bound = self
+            .received_at
+            .get()
+            .saturating_add(MAX_INVITE_RESPONSE_CLOCK_SKEW_SECS);
+        if self.created_at.get() == 0 {
+            UnixSeconds(bound)
+        } else {
+            UnixSeconds(self.created_at.get().min(bound))
+        }
+    }
+}
+
+fn response_digest(response_content: &str) -> String {
+    hex::encode(Sha256::digest(response_content.as_bytes()))
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -410,45 +710,49 @@ fn now_seconds() -> UnixSeconds {
 }
 
 mod serde_bytes_array {
+    use crate::SecretBytes;
     use serde::{Deserialize, Deserializer, Serializer};
 
-    pub fn serialize<S>(bytes: &[u8; 32], serializer: S) -> Result<S::Ok, S::Error>
+    pub fn serialize<S>(bytes: &SecretBytes, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        serializer.serialize_str(&hex::encode(bytes))
+        serializer.serialize_str(&hex::encode(bytes.expose_secret()))
     }
 
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<[u8; 32], D::Error>
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<SecretBytes, D::Error>
     where
         D: Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        super::decode_hex_32(&s).map_err(serde::de::Error::custom)
+        super::decode_hex_32(&s)
+            .map(SecretBytes::new)
+            .map_err(serde::de::Error::custom)
     }
 }
 
 mod serde_option_bytes_array {
+    use crate::SecretBytes;
     use serde::{Deserialize, Deserializer, Serializer};
 
-    pub fn serialize<S>(bytes: &Option<[u8; 32]>, serializer: S) -> Result<S::Ok, S::Error>
+    pub fn serialize<S>(bytes: &Option<SecretBytes>, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
         match bytes {
-            Some(b) => serializer.serialize_str(&hex::encode(b)),
+            Some(b) => serializer.serialize_str(&hex::encode(b.expose_secret())),
             None => serializer.serialize_none(),
         }
     }
 
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<[u8; 32]>, D::Error>
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<SecretBytes>, D::Error>
     where
         D: Deserializer<'de>,
     {
         let opt: Option<String> = Option::deserialize(deserializer)?;
         match opt {
             Some(s) => super::decode_hex_32(&s)
-                .map(Some)
+                .map(|bytes| Some(SecretBytes::new(bytes)))
                 .map_err(serde::de::Error::custom),
             None => Ok(None),
         }
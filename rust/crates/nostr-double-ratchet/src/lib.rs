@@ -11,13 +11,23 @@ pub mod message_builders;
 pub mod message_origin;
 pub mod multi_device;
 pub mod one_to_many;
+pub mod padding;
+pub mod pending_outbound;
+pub mod protocol_subscriptions;
 pub mod protocol_types;
+pub mod publish_batch;
 pub mod roster;
 pub mod roster_editor;
+pub mod runtime;
+pub mod safety_number;
+pub mod sealed_snapshot;
+pub mod secret;
 pub mod sender_key;
 pub mod session;
 pub mod session_manager;
 pub mod shared_channel;
+pub mod snapshot_migration;
+pub mod storage;
 pub mod utils;
 pub mod wire;
 
@@ -36,9 +46,9 @@ pub use direct_message_subscriptions::{
     app_keys_subscription_authors, build_app_keys_backfill_filter,
     build_direct_message_backfill_filter, build_invite_backfill_filter,
     build_invite_response_backfill_filter, build_protocol_discovery_filters,
-    build_runtime_backfill_filters, direct_message_subscription_authors,
-    invite_response_subscription_recipients, DirectMessageSubscriptionTracker,
-    RuntimeSubscriptionRegistration, RuntimeSubscriptionTracker,
+    build_roster_backfill_filter, build_runtime_backfill_filters,
+    direct_message_subscription_authors, invite_response_subscription_recipients,
+    DirectMessageSubscriptionTracker, RuntimeSubscriptionRegistration, RuntimeSubscriptionTracker,
 };
 pub use error::{DomainError, Error, Result};
 pub use group::*;
@@ -50,7 +60,11 @@ pub use group_wire::{
     GROUP_ROSTER_FACT_TYPE,
 };
 pub use ids::{DevicePubkey, OwnerPubkey, UnixSeconds};
-pub use invite::{Invite, InviteResponse, InviteResponseEnvelope, OwnerClaimVerifier};
+pub use invite::{
+    Invite, InviteReplayCache, InviteResponse, InviteResponseEnvelope, OwnerClaimVerifier,
+    ProofOfWork, INVITE_REPLAY_WINDOW_SECS, MAX_INVITE_POW_DIFFICULTY, MAX_INVITE_REPLAY_ENTRIES,
+    MAX_INVITE_RESPONSE_CLOCK_SKEW_SECS,
+};
 pub use message_builders::*;
 pub use message_origin::{classify_message_origin, MessageOrigin};
 pub use multi_device::{
@@ -61,26 +75,68 @@ pub use multi_device::{
     DeviceRegistrationState, InviteOwnerRoutingResolution,
 };
 pub use one_to_many::*;
-pub use protocol_types::{ProtocolContext, MAX_SKIP};
+pub use padding::{
+    pad_payload, unpad_payload, PayloadPadding, BUCKETED_PADDING_SCHEME, PADDING_BUCKETS,
+};
+pub use pending_outbound::{
+    OutboundRetry, PendingOutbound, PendingOutboundEntry, ResolvedOutbound,
+    PENDING_OUTBOUND_MAX_AGE_SECS,
+};
+pub use protocol_subscriptions::{
+    ProtocolSubscriptionDiff, ProtocolSubscriptionPlan, GROUP_MESSAGE_SUBSCRIPTION_ID,
+    INVITE_RESPONSE_SUBSCRIPTION_ID, INVITE_SUBSCRIPTION_ID, MESSAGE_SUBSCRIPTION_ID,
+    ROSTER_SUBSCRIPTION_ID,
+};
+pub use protocol_types::{
+    ProtocolContext, SessionLimits, DEFAULT_SKIPPED_KEY_MAX_AGE_SECS, MAX_SKIP,
+};
+pub use publish_batch::{PreparedPublishBatch, PreparedPublishEvent};
 pub use roster::{AuthorizedDevice, DeviceRoster, RosterSnapshotDecision};
 pub use roster_editor::RosterEditor;
+pub use runtime::{
+    NdrRuntime, RuntimeAction, RuntimeInput, RuntimeSnapshot, TimerId, PUBLISH_RETRY_DELAY_MS,
+    RUNTIME_BACKFILL_LIMIT,
+};
+pub use safety_number::{
+    owner_fingerprint, OwnerFingerprint, SafetyNumber, SAFETY_NUMBER_QR_PREFIX,
+    SAFETY_NUMBER_VERSION,
+};
+pub use sealed_snapshot::{
+    SealedSnapshot, SealedSnapshotHeader, SnapshotKeySource, SnapshotStorageKey,
+    SEALED_SNAPSHOT_VERSION,
+};
+pub use secret::{Secret, SecretBytes};
 pub use sender_key::*;
 pub use session::{
     Header, MessageEnvelope, ReceiveOutcome, ReceivePlan, SendOutcome, SendPlan,
-    SerializableKeyPair, Session, SessionState, SkippedKeysEntry,
+    SerializableKeyPair, Session, SessionControl, SessionState, SkippedKeysEntry,
 };
 pub use session_manager::{
-    Delivery, DeviceRecordSnapshot, PreparedSend, ProcessedInviteResponse, PruneReport,
-    ReceivedMessage, RelayGap, SessionManager, SessionManagerSnapshot, UserRecordSnapshot,
+    BrokenSession, Delivery, DeviceRecordSnapshot, PreparedSend, ProcessedInviteResponse,
+    PruneReport, PrunedSessions, PrunedSkippedKey, ReceivedMessage, RelayGap, RetentionPolicy,
+    SeenMessageIdChanges, SessionManager, SessionManagerChanges, SessionManagerSettings,
+    SessionManagerSnapshot, UserRecordSnapshot, DEFAULT_DECRYPT_FAILURE_THRESHOLD,
+    DEFAULT_INACTIVE_SESSION_MAX_IDLE_SECS, DEFAULT_RETIRED_INVITE_GRACE_SECS,
+    DEFAULT_SEEN_MESSAGE_ID_LIMIT, DEFAULT_STALE_DEVICE_GRACE_SECS,
 };
 pub use shared_channel::SharedChannel;
+pub use snapshot_migration::{
+    decode_snapshot, encode_snapshot, LoadedSnapshot, SnapshotEnvelope, SnapshotKind,
+    SnapshotMigration, VersionedSnapshot, SNAPSHOT_VERSION,
+};
+pub use storage::{
+    FileStorageAdapter, InMemoryStorageAdapter, SessionManagerStore, StorageAdapter,
+    SESSION_MANAGER_STORAGE_PREFIX,
+};
 pub use wire::{
-    group_sender_key_message_event, invite_response_event, invite_unsigned_event, invite_url,
-    message_event, parse_group_sender_key_message_event,
+    compact_invite_url, encode_compact_invite, group_sender_key_message_event,
+    invite_response_event, invite_revocation_unsigned_event, invite_unsigned_event, invite_url,
+    message_event, parse_compact_invite, parse_group_sender_key_message_event,
     parse_group_sender_key_message_event_unchecked, parse_invite_event,
-    parse_invite_response_event, parse_invite_url, parse_message_event, parse_roster_event,
-    roster_unsigned_event, DecodedRosterEvent, GROUP_SENDER_KEY_MESSAGE_KIND, INVITE_EVENT_KIND,
-    INVITE_LIST_LABEL, INVITE_RESPONSE_KIND, MESSAGE_EVENT_KIND, ROSTER_EVENT_KIND,
+    parse_invite_response_event, parse_invite_response_event_with_difficulty, parse_invite_url,
+    parse_message_event, parse_roster_event, roster_unsigned_event, DecodedRosterEvent,
+    COMPACT_INVITE_HRP, GROUP_SENDER_KEY_MESSAGE_KIND, INVITE_EVENT_KIND, INVITE_LIST_LABEL,
+    INVITE_RESPONSE_KIND, MESSAGE_EVENT_KIND, ROSTER_D_TAG, ROSTER_EVENT_KIND,
 };
 
 pub(crate) use ids::owner_pubkey_from_device_pubkey;
@@ -125,10 +181,17 @@ pub fn process_invite_response_event(
     event: &nostr::Event,
     inviter_private_key: [u8; 32],
 ) -> Result<Option<InviteResponse>> {
-    let envelope = parse_invite_response_event(event)
-        .map_err(|error| Error::InvalidEvent(error.to_string()))?;
+    let envelope =
+        parse_invite_response_event_with_difficulty(event, invite.pow_difficulty.unwrap_or(0))
+            .map_err(|error| Error::InvalidEvent(error.to_string()))?;
     let mut rng = rand::rngs::OsRng;
-    let mut ctx = ProtocolContext::new(UnixSeconds(event.created_at.as_secs()), &mut rng);
+    let now = UnixSeconds(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+    );
+    let mut ctx = ProtocolContext::new(now, &mut rng);
     let mut invite = invite.clone();
     invite
         .process_response(&mut ctx, &envelope, inviter_private_key)
@@ -0,0 +1,50 @@
+use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
+
+/// Header value marking a plaintext padded with [`pad_payload`].
+pub const BUCKETED_PADDING_SCHEME: u8 = 1;
+
+/// Padded plaintext sizes, prefix included. The last bucket is the largest
+/// plaintext NIP-44 accepts.
+pub const PADDING_BUCKETS: &[usize] = &[256, 1024, 4096, 16384, 65535];
+
+const LENGTH_PREFIX_LEN: usize = 2;
+
+/// Whether a session pads outgoing payloads to hide their length.
+///
+/// `Bucketed` advertises support in every header but only pads once the
+/// peer has advertised it too, so peers without padding support still
+/// receive plain payloads.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadPadding {
+    #[default]
+    Disabled,
+    Bucketed,
+}
+
+/// Prefix `payload` with its big-endian `u16` length and zero-fill up to the
+/// next bucket. Returns `None` when the payload is too large to pad.
+pub fn pad_payload(payload: &[u8]) -> Option<Vec<u8>> {
+    let length = u16::try_from(payload.len()).ok()?;
+    let padded_len = PADDING_BUCKETS
+        .iter()
+        .copied()
+        .find(|bucket| *bucket >= payload.len() + LENGTH_PREFIX_LEN)?;
+
+    let mut padded = Vec::with_capacity(padded_len);
+    padded.extend_from_slice(&length.to_be_bytes());
+    padded.extend_from_slice(payload);
+    padded.resize(padded_len, 0);
+    Some(padded)
+}
+
+pub fn unpad_payload(padded: &[u8]) -> Result<Vec<u8>> {
+    let invalid = || Error::Decryption("invalid payload padding".to_string());
+    let prefix = padded.get(..LENGTH_PREFIX_LEN).ok_or_else(invalid)?;
+    let length = u16::from_be_bytes([prefix[0], prefix[1]]) as usize;
+    padded
+        .get(LENGTH_PREFIX_LEN..LENGTH_PREFIX_LEN + length)
+        .map(<[u8]>::to_vec)
+        .ok_or_else(invalid)
+}
@@ -0,0 +1,307 @@
+use crate::{
+    DeviceRoster, Invite, OwnerPubkey, PreparedSend, ProtocolContext, RelayGap, Result,
+    RosterSnapshotDecision, SessionManager, UnixSeconds,
+};
+use rand::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Queued payloads older than this are dropped instead of retried.
+pub const PENDING_OUTBOUND_MAX_AGE_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// A payload that could not be delivered to every target yet.
+///
+/// `relay_gaps` lists what is still missing; the entry is dropped once every
+/// gap has been resolved by a later roster, invite, or invite response, or
+/// once it is older than [`PENDING_OUTBOUND_MAX_AGE_SECS`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingOutboundEntry {
+    pub id: String,
+    pub recipient_owner: OwnerPubkey,
+    pub payload: Vec<u8>,
+    pub relay_gaps: Vec<RelayGap>,
+    pub queued_at: UnixSeconds,
+}
+
+/// Deliveries prepared for a queued entry after one of its gaps closed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedOutbound {
+    pub id: String,
+    pub recipient_owner: OwnerPubkey,
+    pub prepared: Vec<PreparedSend>,
+    /// Why retrying a gap failed. That gap and the ones not tried yet stay
+    /// queued; `prepared` already advanced ratchet state and must still be
+    /// published.
+    pub failure: Option<String>,
+}
+
+/// Result of retrying queued entries.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OutboundRetry {
+    pub resolved: Vec<ResolvedOutbound>,
+    /// Entries dropped without delivery because they were queued more than
+    /// [`PENDING_OUTBOUND_MAX_AGE_SECS`] ago.
+    pub expired: Vec<PendingOutboundEntry>,
+}
+
+/// Queue of outbound payloads keyed by recipient owner, waiting on
+/// [`RelayGap`]s reported by `SessionManager::prepare_*`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PendingOutbound {
+    entries: BTreeMap<OwnerPubkey, Vec<PendingOutboundEntry>>,
+}
+
+impl PendingOutbound {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_entries(entries: impl IntoIterator<Item = PendingOutboundEntry>) -> Self {
+        let mut queue = Self::new();
+        for entry in entries {
+            queue.insert(entry);
+        }
+        queue
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &PendingOutboundEntry> {
+        self.entries.values().flatten()
+    }
+
+    pub fn entries_for(&self, recipient_owner: OwnerPubkey) -> &[PendingOutboundEntry] {
+        self.entries
+            .get(&recipient_owner)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.values().map(Vec::len).sum()
+    }
+
+    /// Queue the unresolved part of `prepared`. Returns `false` when the send
+    /// had no relay gaps and nothing was queued.
+    pub fn enqueue(
+        &mut self,
+        id: impl Into<String>,
+        prepared: &PreparedSend,
+        queued_at: UnixSeconds,
+    ) -> bool {
+        if prepared.relay_gaps.is_empty() {
+            return false;
+        }
+        self.insert(PendingOutboundEntry {
+            id: id.into(),
+            recipient_owner: prepared.recipient_owner,
+            payload: prepared.payload.clone(),
+            relay_gaps: prepared.relay_gaps.clone(),
+            queued_at,
+        });
+        true
+    }
+
+    pub fn remove(&mut self, id: &str) -> Vec<PendingOutboundEntry> {
+        let mut removed = Vec::new();
+        self.entries.retain(|_, entries| {
+            entries.retain(|entry| {
+                if entry.id == id {
+                    removed.push(entry.clone());
+                    false
+                } else {
+                    true
+                }
+            });
+            !entries.is_empty()
+        });
+        removed
+    }
+
+    /// Drop entries queued more than [`PENDING_OUTBOUND_MAX_AGE_SECS`] before
+    /// `now` and return them.
+    pub fn prune_expired(&mut self, now: UnixSeconds) -> Vec<PendingOutboundEntry> {
+        let mut expired = Vec::new();
+        self.entries.retain(|_, entries| {
+            entries.retain(|entry| {
+                let fresh = now.get().saturating_sub(entry.queued_at.get())
+                    <= PENDING_OUTBOUND_MAX_AGE_SECS;
+                if !fresh {
+                    expired.push(entry.clone());
+                }
+                fresh
+            });
+            !entries.is_empty()
+        });
+        expired
+    }
+
+    /// Apply `roster` and retry every queued gap for `owner_pubkey`.
+    pub fn observe_peer_roster<R>(
+        &mut self,
+        manager: &mut SessionManager,
+        ctx: &mut ProtocolContext<'_, R>,
+        owner_pubkey: OwnerPubkey,
+        roster: DeviceRoster,
+    ) -> (RosterSnapshotDecision, OutboundRetry)
+    where
+        R: RngCore + CryptoRng,
+    {
+        let decision = manager.observe_peer_roster(owner_pubkey, roster);
+        let retry = self.retry_owner(manager, ctx, owner_pubkey);
+        (decision, retry)
+    }
+
+    /// Record `invite` and retry queued `MissingDeviceInvite` gaps for its device.
+    pub fn observe_device_invite<R>(
+        &mut self,
+        manager: &mut SessionManager,
+        ctx: &mut ProtocolContext<'_, R>,
+        owner_pubkey: OwnerPubkey,
+        invite: Invite,
+    ) -> Result<OutboundRetry>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let device_pubkey = invite.inviter_device_pubkey;
+        manager.observe_device_invite(owner_pubkey, invite)?;
+        Ok(self.retry_matching(manager, ctx, |gap| {
+            *gap == RelayGap::MissingDeviceInvite {
+                owner_pubkey,
+                device_pubkey,
+            }
+        }))
+    }
+
+    /// Retry every queued gap that belongs to `owner_pubkey`, e.g. after an
+    /// invite response created a session for one of its devices.
+    pub fn retry_owner<R>(
+        &mut self,
+        manager: &mut SessionManager,
+        ctx: &mut ProtocolContext<'_, R>,
+        owner_pubkey: OwnerPubkey,
+    ) -> OutboundRetry
+    where
+        R: RngCore + CryptoRng,
+    {
+        self.retry_matching(manager, ctx, |gap| gap_owner(gap) == owner_pubkey)
+    }
+
+    fn retry_matching<R>(
+        &mut self,
+        manager: &mut SessionManager,
+        ctx: &mut ProtocolContext<'_, R>,
+        matches: impl Fn(&RelayGap) -> bool,
+    ) -> OutboundRetry
+    where
+        R: RngCore + CryptoRng,
+    {
+        let expired = self.prune_expired(ctx.now);
+        let mut resolved = Vec::new();
+        for entries in self.entries.values_mut() {
+            for entry in entries.iter_mut() {
+                if !entry.relay_gaps.iter().any(&matches) {
+                    continue;
+                }
+
+                let mut remaining_gaps = Vec::new();
+                let mut prepared = Vec::new();
+                let mut failure = None;
+                for gap in std::mem::take(&mut entry.relay_gaps) {
+                    if failure.is_some() || !matches(&gap) {
+                        remaining_gaps.push(gap);
+                        continue;
+                    }
+                    if !gap_still_relevant(manager, &gap) {
+                        continue;
+                    }
+                    match retry_gap(manager, ctx, &gap, entry.payload.clone()) {
+                        Ok(retry) => {
+                            remaining_gaps.extend(retry.relay_gaps.iter().cloned());
+                            if !retry.deliveries.is_empty() {
+                                prepared.push(retry);
+                            }
+                        }
+                        Err(error) => {
+                            failure = Some(error.to_string());
+                            remaining_gaps.push(gap);
+                        }
+                    }
+                }
+                remaining_gaps.sort();
+                remaining_gaps.dedup();
+                entry.relay_gaps = remaining_gaps;
+
+                if !prepared.is_empty() || failure.is_some() {
+                    resolved.push(ResolvedOutbound {
+                        id: entry.id.clone(),
+                        recipient_owner: entry.recipient_owner,
+                        prepared,
+                        failure,
+                    });
+                }
+            }
+            entries.retain(|entry| !entry.relay_gaps.is_empty());
+        }
+        self.entries.retain(|_, entries| !entries.is_empty());
+        OutboundRetry { resolved, expired }
+    }
+
+    fn insert(&mut self, entry: PendingOutboundEntry) {
+        self.entries
+            .entry(entry.recipient_owner)
+            .or_default()
+            .push(entry);
+    }
+}
+
+fn gap_owner(gap: &RelayGap) -> OwnerPubkey {
+    match gap {
+        RelayGap::MissingRoster { owner_pubkey }
+        | RelayGap::MissingDeviceInvite { owner_pubkey, .. } => *owner_pubkey,
+    }
+}
+
+fn gap_still_relevant(manager: &SessionManager, gap: &RelayGap) -> bool {
+    match gap {
+        RelayGap::MissingRoster { .. } => true,
+        RelayGap::MissingDeviceInvite {
+            owner_pubkey,
+            device_pubkey,
+        } => manager
+            .roster(*owner_pubkey)
+            .is_none_or(|roster| roster.get_device(device_pubkey).is_some()),
+    }
+}
+
+fn retry_gap<R>(
+    manager: &mut SessionManager,
+    ctx: &mut ProtocolContext<'_, R>,
+    gap: &RelayGap,
+    payload: Vec<u8>,
+) -> Result<PreparedSend>
+where
+    R: RngCore + CryptoRng,
+{
+    let local_owner_pubkey = manager.local_owner_pubkey();
+    match gap {
+        RelayGap::MissingRoster { owner_pubkey } if *owner_pubkey == local_owner_pubkey => {
+            manager.prepare_local_sibling_send(ctx, payload)
+        }
+        RelayGap::MissingRoster { owner_pubkey } => {
+            manager.prepare_remote_send(ctx, *owner_pubkey, payload)
+        }
+        RelayGap::MissingDeviceInvite {
+            owner_pubkey,
+            device_pubkey,
+        } if *owner_pubkey == local_owner_pubkey => {
+            manager.prepare_local_sibling_send_to_devices(ctx, [*device_pubkey], payload)
+        }
+        RelayGap::MissingDeviceInvite {
+            owner_pubkey,
+            device_pubkey,
+        } => manager.prepare_remote_send_to_devices(ctx, *owner_pubkey, [*device_pubkey], payload),
+    }
+}
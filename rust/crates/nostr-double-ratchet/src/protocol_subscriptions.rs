@@ -0,0 +1,247 @@
+use crate::{
+    build_direct_message_backfill_filter, build_invite_backfill_filter,
+    build_invite_response_backfill_filter, build_roster_backfill_filter, DevicePubkey,
+    GroupManager, GroupPayloadCodec, OwnerPubkey, RelayGap, SessionManager,
+    GROUP_SENDER_KEY_MESSAGE_KIND, INVITE_EVENT_KIND, INVITE_LIST_LABEL, INVITE_RESPONSE_KIND,
+    MESSAGE_EVENT_KIND, ROSTER_D_TAG, ROSTER_EVENT_KIND,
+};
+use nostr::{Alphabet, Filter, Kind, PublicKey, SingleLetterTag};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+pub const ROSTER_SUBSCRIPTION_ID: &str = "ndr-runtime-rosters";
+pub const INVITE_SUBSCRIPTION_ID: &str = "ndr-runtime-invites";
+pub const INVITE_RESPONSE_SUBSCRIPTION_ID: &str = "ndr-runtime-invite-responses";
+pub const MESSAGE_SUBSCRIPTION_ID: &str = "ndr-runtime-messages";
+pub const GROUP_MESSAGE_SUBSCRIPTION_ID: &str = "ndr-runtime-group-messages";
+
+/// Relay subscriptions a runtime should hold for the current protocol state.
+///
+/// Each author set maps to one filter with a stable subscription id, so a
+/// runtime can keep relay subscriptions in sync by applying
+/// [`ProtocolSubscriptionPlan::diff`] against the plan it last applied.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtocolSubscriptionPlan {
+    pub roster_authors: BTreeSet<OwnerPubkey>,
+    pub invite_authors: BTreeSet<DevicePubkey>,
+    pub invite_response_recipients: BTreeSet<DevicePubkey>,
+    pub message_authors: BTreeSet<DevicePubkey>,
+    pub group_sender_event_authors: BTreeSet<DevicePubkey>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProtocolSubscriptionDiff {
+    /// Subscriptions to open, or to replace when the subid is already open.
+    pub added: Vec<(String, Filter)>,
+    pub removed: Vec<String>,
+    /// Bounded replay filters covering only the newly added authors and
+    /// recipients.
+    pub backfill: Vec<Filter>,
+}
+
+impl ProtocolSubscriptionPlan {
+    pub fn from_session_manager(manager: &SessionManager) -> Self {
+        let local_owner_pubkey = manager.local_owner_pubkey();
+        let local_device_pubkey = manager.local_device_pubkey();
+
+        let mut plan = Self::default();
+        plan.roster_authors.insert(local_owner_pubkey);
+        for owner_pubkey in manager.known_owner_pubkeys() {
+            plan.roster_authors.insert(owner_pubkey);
+            if let Some(roster) = manager.roster(owner_pubkey) {
+                plan.invite_authors.extend(
+                    roster
+                        .devices()
+                        .iter()
+                        .map(|device| device.device_pubkey)
+                        .filter(|device_pubkey| *device_pubkey != local_device_pubkey),
+                );
+            }
+        }
+        plan.invite_response_recipients.extend(
+            manager
+                .local_invite()
+                .into_iter()
+                .chain(manager.retired_local_invites())
+                .map(|invite| invite.inviter_ephemeral_public_key),
+        );
+        plan.message_authors
+            .extend(manager.message_author_pubkeys());
+        plan
+    }
+
+    pub fn with_group_manager<C>(mut self, groups: &GroupManager<C>) -> Self
+    where
+        C: GroupPayloadCodec,
+    {
+        self.group_sender_event_authors
+            .extend(groups.known_sender_event_pubkeys());
+        self
+    }
+
+    /// Also watch the rosters and invites that queued sends are waiting on.
+    pub fn with_relay_gaps<'a>(
+        mut self,
+        relay_gaps: impl IntoIterator<Item = &'a RelayGap>,
+    ) -> Self {
+        for gap in relay_gaps {
+            match gap {
+                RelayGap::MissingRoster { owner_pubkey } => {
+                    self.roster_authors.insert(*owner_pubkey);
+                }
+                RelayGap::MissingDeviceInvite {
+                    owner_pubkey,
+                    device_pubkey,
+                } => {
+                    self.roster_authors.insert(*owner_pubkey);
+                    self.invite_authors.insert(*device_pubkey);
+                }
+            }
+        }
+        self
+    }
+
+    pub fn filters(&self) -> BTreeMap<String, Filter> {
+        let mut filters = BTreeMap::new();
+        if !self.roster_authors.is_empty() {
+            filters.insert(
+                ROSTER_SUBSCRIPTION_ID.to_string(),
+                Filter::new()
+                    .kind(Kind::from(ROSTER_EVENT_KIND as u16))
+                    .authors(nostr_pubkeys(
+                        self.roster_authors.iter().map(|owner| owner.to_bytes()),
+                    ))
+                    .custom_tag(SingleLetterTag::lowercase(Alphabet::D), ROSTER_D_TAG),
+            );
+        }
+        if !self.invite_authors.is_empty() {
+            filters.insert(
+                INVITE_SUBSCRIPTION_ID.to_string(),
+                Filter::new()
+                    .kind(Kind::from(INVITE_EVENT_KIND as u16))
+                    .authors(device_pubkeys(&self.invite_authors))
+                    .custom_tag(SingleLetterTag::lowercase(Alphabet::L), INVITE_LIST_LABEL),
+            );
+        }
+        if !self.invite_response_recipients.is_empty() {
+            filters.insert(
+                INVITE_RESPONSE_SUBSCRIPTION_ID.to_string(),
+                Filter::new()
+                    .kind(Kind::from(INVITE_RESPONSE_KIND as u16))
+                    .custom_tags(
+                        SingleLetterTag::lowercase(Alphabet::P),
+                        self.invite_response_recipients
+                            .iter()
+                            .map(|recipient| recipient.to_hex()),
+                    ),
+            );
+        }
+        if !self.message_authors.is_empty() {
+            filters.insert(
+                MESSAGE_SUBSCRIPTION_ID.to_string(),
+                Filter::new()
+                    .kind(Kind::from(MESSAGE_EVENT_KIND as u16))
+                    .authors(device_pubkeys(&self.message_authors)),
+            );
+        }
+        if !self.group_sender_event_authors.is_empty() {
+            filters.insert(
+                GROUP_MESSAGE_SUBSCRIPTION_ID.to_string(),
+                Filter::new()
+                    .kind(Kind::from(GROUP_SENDER_KEY_MESSAGE_KIND as u16))
+                    .authors(device_pubkeys(&self.group_sender_event_authors)),
+            );
+        }
+        filters
+    }
+
+    /// Changes needed to move relay subscriptions from `previous` to `self`.
+    pub fn diff(&self, previous: &Self, backfill_limit: usize) -> ProtocolSubscriptionDiff {
+        let current_filters = self.filters();
+        let previous_filters = previous.filters();
+
+        let added = current_filters
+            .iter()
+            .filter(|(subid, filter)| previous_filters.get(*subid) != Some(*filter))
+            .map(|(subid, filter)| (subid.clone(), filter.clone()))
+            .collect();
+        let removed = previous_filters
+            .keys()
+            .filter(|subid| !current_filters.contains_key(*subid))
+            .cloned()
+            .collect();
+
+        let mut backfill = Vec::new();
+        let added_roster_authors = nostr_pubkeys(
+            self.roster_authors
+                .difference(&previous.roster_authors)
+                .map(|owner| owner.to_bytes()),
+        );
+        if !added_roster_authors.is_empty() {
+            backfill.push(build_roster_backfill_filter(
+                added_roster_authors,
+                backfill_limit,
+            ));
+        }
+        let added_invite_authors =
+            added_device_pubkeys(&self.invite_authors, &previous.invite_authors);
+        if !added_invite_authors.is_empty() {
+            backfill.push(build_invite_backfill_filter(
+                added_invite_authors,
+                backfill_limit,
+            ));
+        }
+        let added_recipients = added_device_pubkeys(
+            &self.invite_response_recipients,
+            &previous.invite_response_recipients,
+        );
+        if !added_recipients.is_empty() {
+            backfill.push(build_invite_response_backfill_filter(
+                added_recipients,
+                backfill_limit,
+            ));
+        }
+        let mut added_message_authors =
+            added_device_pubkeys(&self.message_authors, &previous.message_authors);
+        added_message_authors.extend(added_device_pubkeys(
+            &self.group_sender_event_authors,
+            &previous.group_sender_event_authors,
+        ));
+        if !added_message_authors.is_empty() {
+            backfill.push(build_direct_message_backfill_filter(
+                added_message_authors,
+                backfill_limit,
+            ));
+        }
+
+        ProtocolSubscriptionDiff {
+            added,
+            removed,
+            backfill,
+        }
+    }
+}
+
+impl ProtocolSubscriptionDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.backfill.is_empty()
+    }
+}
+
+fn device_pubkeys(devices: &BTreeSet<DevicePubkey>) -> Vec<PublicKey> {
+    nostr_pubkeys(devices.iter().map(|device| device.to_bytes()))
+}
+
+fn added_device_pubkeys(
+    current: &BTreeSet<DevicePubkey>,
+    previous: &BTreeSet<DevicePubkey>,
+) -> Vec<PublicKey> {
+    nostr_pubkeys(current.difference(previous).map(|device| device.to_bytes()))
+}
+
+fn nostr_pubkeys(bytes: impl IntoIterator<Item = [u8; 32]>) -> Vec<PublicKey> {
+    bytes
+        .into_iter()
+        .filter_map(|bytes| PublicKey::from_slice(&bytes).ok())
+        .collect()
+}
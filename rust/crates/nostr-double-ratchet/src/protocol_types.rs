@@ -1,7 +1,38 @@
 use crate::UnixSeconds;
 use rand::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
 
 pub const MAX_SKIP: usize = 1000;
+pub const DEFAULT_SKIPPED_KEY_MAX_AGE_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// Bounds on the work and memory a single session spends on skipped
+/// messages. The defaults match the historical `MAX_SKIP` behaviour.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionLimits {
+    /// Largest gap in one receiving chain that is bridged by deriving
+    /// skipped keys; a bigger jump fails with `TooManySkippedMessages`.
+    pub max_skip_per_chain: usize,
+    /// Skipped keys kept per sender chain; the oldest are evicted first.
+    pub max_stored_skipped_keys: usize,
+    /// Sender chains with stored skipped keys. When exceeded, the chain
+    /// whose keys were derived least recently is dropped.
+    pub max_skipped_senders: Option<usize>,
+    /// Skipped keys kept per device across all of its sessions, enforced by
+    /// `SessionManager::prune_stale`. Standalone sessions ignore it.
+    #[serde(default)]
+    pub max_skipped_keys_per_device: Option<usize>,
+}
+
+impl Default for SessionLimits {
+    fn default() -> Self {
+        Self {
+            max_skip_per_chain: MAX_SKIP,
+            max_stored_skipped_keys: MAX_SKIP,
+            max_skipped_senders: None,
+            max_skipped_keys_per_device: None,
+        }
+    }
+}
 
 pub struct ProtocolContext<'a, R>
 where
@@ -0,0 +1,102 @@
+use crate::{invite_response_event, message_event, PreparedSend, Result, UnixSeconds};
+use nostr::{Event, EventId};
+use serde::{Deserialize, Serialize};
+
+/// Signed relay events produced from one or more [`PreparedSend`]s.
+///
+/// `prepare_send` advances ratchet state, so a failed publish must be retried
+/// from this batch rather than by preparing the payload again. The batch is
+/// serializable so it can be persisted next to the `SessionManagerSnapshot`
+/// that produced it and resumed after restart.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreparedPublishBatch {
+    pub correlation_id: String,
+    pub created_at: UnixSeconds,
+    pub events: Vec<PreparedPublishEvent>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreparedPublishEvent {
+    pub event: Event,
+    #[serde(default)]
+    pub acknowledged: bool,
+}
+
+impl PreparedPublishBatch {
+    /// Sign every invite response and delivery in `prepared`.
+    ///
+    /// Invite responses are ordered before message events so a relay that
+    /// accepts events in order never sees a 1060 its recipient cannot yet
+    /// attribute.
+    pub fn from_prepared_sends<'a>(
+        correlation_id: impl Into<String>,
+        created_at: UnixSeconds,
+        prepared: impl IntoIterator<Item = &'a PreparedSend>,
+    ) -> Result<Self> {
+        let mut events = Vec::new();
+        for prepared in prepared {
+            for response in &prepared.invite_responses {
+                events.push(PreparedPublishEvent::new(invite_response_event(response)?));
+            }
+            for delivery in &prepared.deliveries {
+                events.push(PreparedPublishEvent::new(message_event(
+                    &delivery.envelope,
+                )?));
+            }
+        }
+
+        Ok(Self {
+            correlation_id: correlation_id.into(),
+            created_at,
+            events,
+        })
+    }
+
+    /// Add events prepared later for the same correlation id, e.g. after a
+    /// queued relay gap resolved.
+    pub fn append(&mut self, other: PreparedPublishBatch) {
+        self.events.extend(other.events);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.events.iter().all(|event| event.acknowledged)
+    }
+
+    pub fn pending_events(&self) -> impl Iterator<Item = &Event> {
+        self.events
+            .iter()
+            .filter(|event| !event.acknowledged)
+            .map(|event| &event.event)
+    }
+
+    pub fn acknowledge(&mut self, event_id: &EventId) -> bool {
+        let Some(entry) = self
+            .events
+            .iter_mut()
+            .find(|entry| entry.event.id == *event_id && !entry.acknowledged)
+        else {
+            return false;
+        };
+        entry.acknowledged = true;
+        true
+    }
+
+    pub fn acknowledge_all(&mut self) {
+        for entry in &mut self.events {
+            entry.acknowledged = true;
+        }
+    }
+}
+
+impl PreparedPublishEvent {
+    fn new(event: Event) -> Self {
+        Self {
+            event,
+            acknowledged: false,
+        }
+    }
+}
@@ -0,0 +1,616 @@
+use crate::wire::invite_response_recipient;
+use crate::{
+    invite_unsigned_event, parse_invite_event, parse_invite_response_event_with_difficulty,
+    parse_message_event, parse_roster_event, secret_key_from_bytes, DevicePubkey, OutboundRetry,
+    OwnerPubkey, PendingOutbound, PendingOutboundEntry, PreparedPublishBatch, PreparedSend,
+    ProtocolContext, ProtocolSubscriptionPlan, ReceivedMessage, Result, SessionControl,
+    SessionManager, SessionManagerSnapshot, UnixSeconds, INVITE_EVENT_KIND, INVITE_RESPONSE_KIND,
+    MESSAGE_EVENT_KIND, ROSTER_D_TAG, ROSTER_EVENT_KIND,
+};
+use nostr::{Event, EventId, Filter, Keys, Kind};
+use rand::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub const PUBLISH_RETRY_DELAY_MS: u64 = 5_000;
+pub const RUNTIME_BACKFILL_LIMIT: usize = 100;
+
+const INVITE_D_TAG_PREFIX: &str = "double-ratchet/invites/";
+const KEY_UPDATE_CORRELATION_PREFIX: &str = "key-update:";
+
+#[derive(Debug, Clone)]
+pub enum RuntimeInput {
+    Startup,
+    SendRumor {
+        recipient_owner: OwnerPubkey,
+        remote_payload: Vec<u8>,
+        local_sibling_payload: Option<Vec<u8>>,
+        correlation_id: String,
+    },
+    RelayEvent(Event),
+    PublishFinished {
+        correlation_id: String,
+        success: bool,
+    },
+    EventPublished {
+        correlation_id: String,
+        event_id: EventId,
+    },
+    TimerFired(TimerId),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuntimeAction {
+    Publish(Event),
+    Subscribe {
+        subid: String,
+        filter: Filter,
+    },
+    Unsubscribe(String),
+    Fetch(Vec<Filter>),
+    DeliverDecrypted {
+        sender_owner: OwnerPubkey,
+        sender_device: Option<DevicePubkey>,
+        payload: Vec<u8>,
+        outer_event_id: Option<String>,
+    },
+    /// The peer device reset its session with us. Local sessions with it are
+    /// closed and the next send re-handshakes.
+    SessionReset {
+        sender_owner: OwnerPubkey,
+        sender_device: DevicePubkey,
+    },
+    /// Repeated messages from the device failed to decrypt, so its sessions
+    /// were closed. The next send re-handshakes.
+    SessionBroken {
+        sender_owner: OwnerPubkey,
+        sender_device: DevicePubkey,
+    },
+    /// The device roster of an owner the user verified changed, so its
+    /// safety number no longer matches.
+    VerifiedRosterChanged {
+        owner: OwnerPubkey,
+    },
+    /// Part of a send, or the key update answering a refresh request, failed
+    /// after ratchet state had already advanced. What was prepared is still
+    /// published and persisted. Recipients whose queued retry failed stay
+    /// queued; the rest of a direct send is dropped. Also reported for
+    /// queued deliveries that expired before their gaps closed.
+    SendFailed {
+        correlation_id: String,
+        reason: String,
+    },
+    ScheduleTimer {
+        id: TimerId,
+        after_ms: u64,
+    },
+    Persist(Box<RuntimeSnapshot>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum TimerId {
+    PublishRetry { correlation_id: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuntimeSnapshot {
+    pub session_manager: SessionManagerSnapshot,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub publish_batches: Vec<PreparedPublishBatch>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pending_outbound: Vec<PendingOutboundEntry>,
+}
+
+/// Synchronous runtime around [`SessionManager`].
+///
+/// Hosts feed [`RuntimeInput`]s and execute the returned [`RuntimeAction`]s
+/// (relay publishes, subscriptions, timers, persistence) outside the state
+/// machine. All randomness and time come from the supplied `ProtocolContext`.
+#[derive(Debug, Clone)]
+pub struct NdrRuntime {
+    core: SessionManager,
+    local_device_keys: Keys,
+    publish_batches: BTreeMap<String, PreparedPublishBatch>,
+    pending_outbound: PendingOutbound,
+    subscription_plan: ProtocolSubscriptionPlan,
+}
+
+impl NdrRuntime {
+    pub fn new(local_owner_pubkey: OwnerPubkey, local_device_secret_key: [u8; 32]) -> Result<Self> {
+        Self::from_core(
+            SessionManager::new(local_owner_pubkey, local_device_secret_key),
+            local_device_secret_key,
+        )
+    }
+
+    pub fn from_snapshot(
+        snapshot: RuntimeSnapshot,
+        local_device_secret_key: [u8; 32],
+    ) -> Result<Self> {
+        let core =
+            SessionManager::from_snapshot(snapshot.session_manager, local_device_secret_key)?;
+        let mut runtime = Self::from_core(core, local_device_secret_key)?;
+        runtime.publish_batches = snapshot
+            .publish_batches
+            .into_iter()
+            .filter(|batch| !batch.is_complete())
+            .map(|batch| (batch.correlation_id.clone(), batch))
+            .collect();
+        runtime.pending_outbound = PendingOutbound::from_entries(snapshot.pending_outbound);
+        Ok(runtime)
+    }
+
+    fn from_core(core: SessionManager, local_device_secret_key: [u8; 32]) -> Result<Self> {
+        Ok(Self {
+            core,
+            local_device_keys: Keys::new(secret_key_from_bytes(&local_device_secret_key)?),
+            publish_batches: BTreeMap::new(),
+            pending_outbound: PendingOutbound::new(),
+            subscription_plan: ProtocolSubscriptionPlan::default(),
+        })
+    }
+
+    pub fn snapshot(&self) -> RuntimeSnapshot {
+        RuntimeSnapshot {
+            session_manager: self.core.snapshot(),
+            publish_batches: self.publish_batches.values().cloned().collect(),
+            pending_outbound: self.pending_outbound.entries().cloned().collect(),
+        }
+    }
+
+    pub fn publish_batches(&self) -> impl Iterator<Item = &PreparedPublishBatch> {
+        self.publish_batches.values()
+    }
+
+    pub fn pending_outbound(&self) -> &PendingOutbound {
+        &self.pending_outbound
+    }
+
+    pub fn session_manager(&self) -> &SessionManager {
+        &self.core
+    }
+
+    pub fn session_manager_mut(&mut self) -> &mut SessionManager {
+        &mut self.core
+    }
+
+    pub fn handle<R>(
+        &mut self,
+        ctx: &mut ProtocolContext<'_, R>,
+        input: RuntimeInput,
+    ) -> Result<Vec<RuntimeAction>>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let mut actions = Vec::new();
+        match input {
+            RuntimeInput::Startup => self.handle_startup(ctx, &mut actions)?,
+            RuntimeInput::SendRumor {
+                recipient_owner,
+                remote_payload,
+                local_sibling_payload,
+                correlation_id,
+            } => self.handle_send(
+                ctx,
+                recipient_owner,
+                remote_payload,
+                local_sibling_payload,
+                correlation_id,
+                &mut actions,
+            )?,
+            RuntimeInput::RelayEvent(event) => {
+                self.handle_relay_event(ctx, &event, &mut actions)?
+            }
+            RuntimeInput::PublishFinished {
+                correlation_id,
+                success,
+            } => self.handle_publish_finished(correlation_id, success, &mut actions),
+            RuntimeInput::EventPublished {
+                correlation_id,
+                event_id,
+            } => self.handle_event_published(correlation_id, event_id, &mut actions),
+            RuntimeInput::TimerFired(id) => self.handle_timer(id, &mut actions),
+        }
+        Ok(actions)
+    }
+
+    fn handle_startup<R>(
+        &mut self,
+        ctx: &mut ProtocolContext<'_, R>,
+        actions: &mut Vec<RuntimeAction>,
+    ) -> Result<()>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let invite = self.core.ensure_local_invite(ctx)?.clone();
+        let invite_event =
+            invite_unsigned_event(&invite)?.sign_with_keys(&self.local_device_keys)?;
+        actions.push(RuntimeAction::Publish(invite_event));
+
+        self.subscription_plan = ProtocolSubscriptionPlan::default();
+        self.sync_subscriptions(actions);
+
+        for batch in self.publish_batches.values() {
+            actions.extend(batch.pending_events().cloned().map(RuntimeAction::Publish));
+        }
+        actions.push(RuntimeAction::Persist(Box::new(self.snapshot())));
+        Ok(())
+    }
+
+    fn handle_send<R>(
+        &mut self,
+        ctx: &mut ProtocolContext<'_, R>,
+        recipient_owner: OwnerPubkey,
+        remote_payload: Vec<u8>,
+        local_sibling_payload: Option<Vec<u8>>,
+        correlation_id: String,
+        actions: &mut Vec<RuntimeAction>,
+    ) -> Result<()>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let mut prepared = Vec::new();
+        let mut outcome = match local_sibling_payload {
+            Some(local_sibling_payload) => self
+                .core
+                .prepare_remote_send(ctx, recipient_owner, remote_payload)
+                .and_then(|remote| {
+                    prepared.push(remote);
+                    self.core
+                        .prepare_local_sibling_send(ctx, local_sibling_payload)
+                })
+                .map(|local_sibling| prepared.push(local_sibling)),
+            None => self
+                .core
+                .prepare_send(ctx, recipient_owner, remote_payload)
+                .map(|remote| prepared.push(remote)),
+        };
+        if prepared.is_empty() {
+            return outcome;
+        }
+
+        // Whatever was prepared already advanced ratchet state, so it is
+        // published and persisted even if a later step failed.
+        for prepared in &prepared {
+            self.pending_outbound
+                .enqueue(correlation_id.clone(), prepared, ctx.now);
+            let published = self.publish_prepared(
+                correlation_id.clone(),
+                ctx.now,
+                std::slice::from_ref(prepared),
+                actions,
+            );
+            outcome = outcome.and(published);
+        }
+        self.sync_subscriptions(actions);
+        actions.push(RuntimeAction::Persist(Box::new(self.snapshot())));
+        if let Err(error) = outcome {
+            actions.push(RuntimeAction::SendFailed {
+                correlation_id,
+                reason: error.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    fn handle_relay_event<R>(
+        &mut self,
+        ctx: &mut ProtocolContext<'_, R>,
+        event: &Event,
+        actions: &mut Vec<RuntimeAction>,
+    ) -> Result<()>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let changed = if event.kind == Kind::from(MESSAGE_EVENT_KIND as u16) {
+            self.receive_message_event(ctx, event, actions)
+        } else if event.kind == Kind::from(INVITE_RESPONSE_KIND as u16) {
+            self.observe_invite_response_event(ctx, event, actions)?
+        } else if event.kind == Kind::from(ROSTER_EVENT_KIND as u16)
+            && d_tag(event).as_deref() == Some(ROSTER_D_TAG)
+        {
+            self.observe_roster_event(ctx, event, actions)?
+        } else if event.kind == Kind::from(INVITE_EVENT_KIND as u16)
+            && d_tag(event).is_some_and(|value| value.starts_with(INVITE_D_TAG_PREFIX))
+        {
+            self.observe_invite_event(ctx, event, actions)?
+        } else {
+            false
+        };
+
+        if changed {
+            self.sync_subscriptions(actions);
+            actions.push(RuntimeAction::Persist(Box::new(self.snapshot())));
+        }
+        Ok(())
+    }
+
+    fn receive_message_event<R>(
+        &mut self,
+        ctx: &mut ProtocolContext<'_, R>,
+        event: &Event,
+        actions: &mut Vec<RuntimeAction>,
+    ) -> bool
+    where
+        R: RngCore + CryptoRng,
+    {
+        let Ok(envelope) = parse_message_event(event) else {
+            return false;
+        };
+        let received = self.core.receive_any(ctx, &envelope);
+        let broken_sessions = self.core.take_broken_sessions();
+        let closed_broken_sessions = !broken_sessions.is_empty();
+        actions.extend(
+            broken_sessions
+                .into_iter()
+                .map(|broken| RuntimeAction::SessionBroken {
+                    sender_owner: broken.owner_pubkey,
+                    sender_device: broken.device_pubkey,
+                }),
+        );
+        let Ok(Some(received)) = received else {
+            return closed_broken_sessions;
+        };
+        if let Some(control) = received.control {
+            match control {
+                SessionControl::RefreshRequest => {
+                    self.answer_refresh_request(ctx, &received, event, actions)
+                }
+                SessionControl::Reset => actions.push(RuntimeAction::SessionReset {
+                    sender_owner: received.owner_pubkey,
+                    sender_device: received.device_pubkey,
+                }),
+                SessionControl::KeyUpdate => {}
+            }
+            return true;
+        }
+        actions.push(RuntimeAction::DeliverDecrypted {
+            sender_owner: received.owner_pubkey,
+            sender_device: Some(received.device_pubkey),
+            payload: received.payload,
+            outer_event_id: Some(event.id.to_hex()),
+        });
+        true
+    }
+
+    fn answer_refresh_request<R>(
+        &mut self,
+        ctx: &mut ProtocolContext<'_, R>,
+        received: &ReceivedMessage,
+        event: &Event,
+        actions: &mut Vec<RuntimeAction>,
+    ) where
+        R: RngCore + CryptoRng,
+    {
+        let correlation_id = format!("{KEY_UPDATE_CORRELATION_PREFIX}{}", event.id.to_hex());
+        let outcome = self
+            .core
+            .prepare_key_update(ctx, received.owner_pubkey, received.device_pubkey)
+            .and_then(|prepared| {
+                self.publish_prepared(correlation_id.clone(), ctx.now, &[prepared], actions)
+            });
+        if let Err(error) = outcome {
+            actions.push(RuntimeAction::SendFailed {
+                correlation_id,
+                reason: error.to_string(),
+            });
+        }
+    }
+
+    fn observe_invite_response_event<R>(
+        &mut self,
+        ctx: &mut ProtocolContext<'_, R>,
+        event: &Event,
+        actions: &mut Vec<RuntimeAction>,
+    ) -> Result<bool>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let Ok(recipient) = invite_response_recipient(event) else {
+            return Ok(false);
+        };
+        let Some(required_difficulty) = self
+            .core
+            .local_invite()
+            .into_iter()
+            .chain(self.core.retired_local_invites())
+            .find(|invite| invite.inviter_ephemeral_public_key == recipient)
+            .map(|invite| invite.pow_difficulty.unwrap_or(0))
+        else {
+            return Ok(false);
+        };
+        let Ok(envelope) = parse_invite_response_event_with_difficulty(event, required_difficulty)
+        else {
+            return Ok(false);
+        };
+        let Ok(Some(processed)) = self.core.observe_invite_response(ctx, &envelope) else {
+            return Ok(false);
+        };
+        let retry = self
+            .pending_outbound
+            .retry_owner(&mut self.core, ctx, processed.owner_pubkey);
+        self.publish_resolved(ctx.now, retry, actions)?;
+        Ok(true)
+    }
+
+    fn observe_roster_event<R>(
+        &mut self,
+        ctx: &mut ProtocolContext<'_, R>,
+        event: &Event,
+        actions: &mut Vec<RuntimeAction>,
+    ) -> Result<bool>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let Ok(decoded) = parse_roster_event(event) else {
+            return Ok(false);
+        };
+        let (_, retry) = self.pending_outbound.observe_peer_roster(
+            &mut self.core,
+            ctx,
+            decoded.owner_pubkey,
+            decoded.roster,
+        );
+        actions.extend(
+            self.core
+                .take_verified_roster_changes()
+                .into_iter()
+                .map(|owner| RuntimeAction::VerifiedRosterChanged { owner }),
+        );
+        self.publish_resolved(ctx.now, retry, actions)?;
+        Ok(true)
+    }
+
+    fn observe_invite_event<R>(
+        &mut self,
+        ctx: &mut ProtocolContext<'_, R>,
+        event: &Event,
+        actions: &mut Vec<RuntimeAction>,
+    ) -> Result<bool>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let Ok(invite) = parse_invite_event(event) else {
+            return Ok(false);
+        };
+        if invite.inviter_device_pubkey == self.core.local_device_pubkey() {
+            return Ok(false);
+        }
+        let owner_pubkey = invite.inviter_owner_pubkey.unwrap_or_else(|| {
+            crate::owner_pubkey_from_device_pubkey(invite.inviter_device_pubkey)
+        });
+        let Ok(retry) =
+            self.pending_outbound
+                .observe_device_invite(&mut self.core, ctx, owner_pubkey, invite)
+        else {
+            return Ok(false);
+        };
+        self.publish_resolved(ctx.now, retry, actions)?;
+        Ok(true)
+    }
+
+    fn publish_resolved(
+        &mut self,
+        now: UnixSeconds,
+        retry: OutboundRetry,
+        actions: &mut Vec<RuntimeAction>,
+    ) -> Result<()> {
+        let mut outcome = Ok(());
+        for resolved in retry.resolved {
+            let published =
+                self.publish_prepared(resolved.id.clone(), now, &resolved.prepared, actions);
+            if let Some(reason) = resolved.failure {
+                actions.push(RuntimeAction::SendFailed {
+                    correlation_id: resolved.id,
+                    reason,
+                });
+            }
+            outcome = outcome.and(published);
+        }
+        actions.extend(
+            retry
+                .expired
+                .into_iter()
+                .map(|entry| RuntimeAction::SendFailed {
+                    correlation_id: entry.id,
+                    reason: "pending delivery expired".to_string(),
+                }),
+        );
+        outcome
+    }
+
+    fn publish_prepared(
+        &mut self,
+        correlation_id: String,
+        now: UnixSeconds,
+        prepared: &[PreparedSend],
+        actions: &mut Vec<RuntimeAction>,
+    ) -> Result<()> {
+        let batch = PreparedPublishBatch::from_prepared_sends(correlation_id, now, prepared)?;
+        if batch.is_empty() {
+            return Ok(());
+        }
+        actions.extend(batch.pending_events().cloned().map(RuntimeAction::Publish));
+        match self.publish_batches.get_mut(&batch.correlation_id) {
+            Some(existing) => existing.append(batch),
+            None => {
+                self.publish_batches
+                    .insert(batch.correlation_id.clone(), batch);
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_publish_finished(
+        &mut self,
+        correlation_id: String,
+        success: bool,
+        actions: &mut Vec<RuntimeAction>,
+    ) {
+        if success {
+            if self.publish_batches.remove(&correlation_id).is_some() {
+                actions.push(RuntimeAction::Persist(Box::new(self.snapshot())));
+            }
+        } else if self.publish_batches.contains_key(&correlation_id) {
+            actions.push(RuntimeAction::ScheduleTimer {
+                id: TimerId::PublishRetry { correlation_id },
+                after_ms: PUBLISH_RETRY_DELAY_MS,
+            });
+        }
+    }
+
+    fn handle_event_published(
+        &mut self,
+        correlation_id: String,
+        event_id: EventId,
+        actions: &mut Vec<RuntimeAction>,
+    ) {
+        let Some(batch) = self.publish_batches.get_mut(&correlation_id) else {
+            return;
+        };
+        if !batch.acknowledge(&event_id) {
+            return;
+        }
+        if batch.is_complete() {
+            self.publish_batches.remove(&correlation_id);
+        }
+        actions.push(RuntimeAction::Persist(Box::new(self.snapshot())));
+    }
+
+    fn handle_timer(&mut self, id: TimerId, actions: &mut Vec<RuntimeAction>) {
+        match id {
+            TimerId::PublishRetry { correlation_id } => {
+                if let Some(batch) = self.publish_batches.get(&correlation_id) {
+                    actions.extend(batch.pending_events().cloned().map(RuntimeAction::Publish));
+                }
+            }
+        }
+    }
+
+    fn sync_subscriptions(&mut self, actions: &mut Vec<RuntimeAction>) {
+        let plan = ProtocolSubscriptionPlan::from_session_manager(&self.core).with_relay_gaps(
+            self.pending_outbound
+                .entries()
+                .flat_map(|entry| entry.relay_gaps.iter()),
+        );
+        let diff = plan.diff(&self.subscription_plan, RUNTIME_BACKFILL_LIMIT);
+        actions.extend(diff.removed.into_iter().map(RuntimeAction::Unsubscribe));
+        actions.extend(
+            diff.added
+                .into_iter()
+                .map(|(subid, filter)| RuntimeAction::Subscribe { subid, filter }),
+        );
+        if !diff.backfill.is_empty() {
+            actions.push(RuntimeAction::Fetch(diff.backfill));
+        }
+        self.subscription_plan = plan;
+    }
+}
+
+fn d_tag(event: &Event) -> Option<String> {
+    event
+        .tags
+        .iter()
+        .find(|tag| tag.as_slice().first().map(|value| value.as_str()) == Some("d"))
+        .and_then(|tag| tag.as_slice().get(1).cloned())
+}
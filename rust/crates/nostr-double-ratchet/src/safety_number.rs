@@ -0,0 +1,125 @@
+use crate::{DeviceRoster, OwnerPubkey};
+use sha2::{Digest, Sha512};
+
+/// Version byte mixed into every fingerprint and the QR payload.
+pub const SAFETY_NUMBER_VERSION: u8 = 1;
+/// Prefix of [`SafetyNumber::qr_payload`].
+pub const SAFETY_NUMBER_QR_PREFIX: &str = "ndr-safety-number";
+
+const FINGERPRINT_ITERATIONS: usize = 5200;
+const FINGERPRINT_LEN: usize = 32;
+const DIGIT_CHUNKS: usize = 6;
+
+/// Fingerprint of one owner key together with its authorized devices.
+pub type OwnerFingerprint = [u8; FINGERPRINT_LEN];
+
+/// Number two users compare out of band to confirm they see the same owner
+/// keys and device rosters. Both sides derive the same value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SafetyNumber {
+    fingerprints: [OwnerFingerprint; 2],
+}
+
+impl SafetyNumber {
+    pub fn new(
+        local_owner: OwnerPubkey,
+        local_roster: &DeviceRoster,
+        remote_owner: OwnerPubkey,
+        remote_roster: &DeviceRoster,
+    ) -> Self {
+        Self::from_fingerprints(
+            local_owner,
+            owner_fingerprint(local_owner, local_roster),
+            remote_owner,
+            owner_fingerprint(remote_owner, remote_roster),
+        )
+    }
+
+    /// Builds the number from fingerprints computed earlier with
+    /// [`owner_fingerprint`], skipping the iterated hash.
+    pub fn from_fingerprints(
+        local_owner: OwnerPubkey,
+        local: OwnerFingerprint,
+        remote_owner: OwnerPubkey,
+        remote: OwnerFingerprint,
+    ) -> Self {
+        let fingerprints = if local_owner <= remote_owner {
+            [local, remote]
+        } else {
+            [remote, local]
+        };
+        Self { fingerprints }
+    }
+
+    /// Sixty decimal digits, thirty per owner.
+    pub fn digits(&self) -> String {
+        self.fingerprints
+            .iter()
+            .flat_map(|fingerprint| fingerprint.chunks(5).take(DIGIT_CHUNKS))
+            .map(|chunk| {
+                let value = chunk
+                    .iter()
+                    .fold(0u64, |acc, byte| (acc << 8) | u64::from(*byte));
+                format!("{:05}", value % 100_000)
+            })
+            .collect()
+    }
+
+    /// [`digits`](Self::digits) in groups of five, for display.
+    pub fn display(&self) -> String {
+        let digits = self.digits();
+        digits
+            .as_bytes()
+            .chunks(5)
+            .map(|group| std::str::from_utf8(group).expect("digits are ascii"))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Payload to render as a QR code. Scanning the peer's code and
+    /// comparing it with [`matches_qr_payload`](Self::matches_qr_payload)
+    /// replaces reading the digits aloud.
+    pub fn qr_payload(&self) -> String {
+        format!(
+            "{SAFETY_NUMBER_QR_PREFIX}:{SAFETY_NUMBER_VERSION}:{}{}",
+            hex::encode(self.fingerprints[0]),
+            hex::encode(self.fingerprints[1])
+        )
+    }
+
+    pub fn matches_qr_payload(&self, scanned: &str) -> bool {
+        scanned.trim() == self.qr_payload()
+    }
+}
+
+/// Iterated hash over the owner key and its sorted authorized device keys.
+pub fn owner_fingerprint(owner_pubkey: OwnerPubkey, roster: &DeviceRoster) -> OwnerFingerprint {
+    let mut devices: Vec<[u8; 32]> = roster
+        .devices()
+        .iter()
+        .map(|device| device.device_pubkey.to_bytes())
+        .collect();
+    devices.sort();
+    devices.dedup();
+
+    let mut material = Vec::with_capacity(32 * (devices.len() + 1));
+    material.extend_from_slice(&owner_pubkey.to_bytes());
+    for device in &devices {
+        material.extend_from_slice(device);
+    }
+
+    let mut hash = Sha512::new()
+        .chain_update([SAFETY_NUMBER_VERSION])
+        .chain_update(&material)
+        .finalize();
+    for _ in 0..FINGERPRINT_ITERATIONS {
+        hash = Sha512::new()
+            .chain_update(hash)
+            .chain_update(&material)
+            .finalize();
+    }
+
+    let mut fingerprint = [0u8; FINGERPRINT_LEN];
+    fingerprint.copy_from_slice(&hash[..FINGERPRINT_LEN]);
+    fingerprint
+}
@@ -0,0 +1,169 @@
+use crate::{
+    decode_snapshot, encode_snapshot, kdf, DevicePubkey, Error, LoadedSnapshot, Result,
+    SecretBytes, SnapshotKind, VersionedSnapshot,
+};
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rand::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+pub const SEALED_SNAPSHOT_VERSION: u32 = 1;
+
+const DEVICE_SECRET_STORAGE_KEY_SALT: &[u8] = b"ndr-snapshot-storage-v1";
+const NONCE_LEN: usize = 24;
+
+/// Symmetric key used to seal snapshots at rest.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SnapshotStorageKey {
+    bytes: SecretBytes,
+    source: SnapshotKeySource,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SnapshotKeySource {
+    AppProvided,
+    DeviceSecret,
+}
+
+/// Metadata readable without the key. It is bound to the ciphertext as
+/// associated data, so tampering with it makes [`SealedSnapshot::open`] fail.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SealedSnapshotHeader {
+    pub version: u32,
+    pub kind: SnapshotKind,
+    pub local_device_pubkey: DevicePubkey,
+    pub key_source: SnapshotKeySource,
+}
+
+/// A versioned snapshot encrypted with XChaCha20-Poly1305.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SealedSnapshot {
+    pub header: SealedSnapshotHeader,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+impl SnapshotStorageKey {
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self {
+            bytes: SecretBytes::new(bytes),
+            source: SnapshotKeySource::AppProvided,
+        }
+    }
+
+    /// Derive a storage key from the local device secret, for apps that have
+    /// no platform keystore to hold a separate key.
+    pub fn from_device_secret(device_secret_key: &[u8; 32]) -> Self {
+        Self {
+            bytes: SecretBytes::new(kdf(device_secret_key, DEVICE_SECRET_STORAGE_KEY_SALT, 1)[0]),
+            source: SnapshotKeySource::DeviceSecret,
+        }
+    }
+
+    pub fn source(&self) -> SnapshotKeySource {
+        self.source
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(self.bytes.expose_secret().into())
+    }
+}
+
+impl std::fmt::Debug for SnapshotStorageKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SnapshotStorageKey")
+            .field("bytes", &self.bytes)
+            .field("source", &self.source)
+            .finish()
+    }
+}
+
+impl SealedSnapshot {
+    pub fn seal<T, R>(
+        snapshot: &T,
+        local_device_pubkey: DevicePubkey,
+        key: &SnapshotStorageKey,
+        rng: &mut R,
+    ) -> Result<Self>
+    where
+        T: VersionedSnapshot,
+        R: RngCore + CryptoRng,
+    {
+        let header = SealedSnapshotHeader {
+            version: SEALED_SNAPSHOT_VERSION,
+            kind: T::KIND,
+            local_device_pubkey,
+            key_source: key.source,
+        };
+        let aad = serde_json::to_vec(&header)?;
+        let plaintext = encode_snapshot(snapshot)?;
+
+        let mut nonce = [0u8; NONCE_LEN];
+        rng.fill_bytes(&mut nonce);
+        let ciphertext = key
+            .cipher()
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext.as_bytes(),
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| Error::Encryption("failed to seal snapshot".to_string()))?;
+
+        let engine = base64::engine::general_purpose::STANDARD;
+        Ok(Self {
+            header,
+            nonce: engine.encode(nonce),
+            ciphertext: engine.encode(ciphertext),
+        })
+    }
+
+    pub fn open<T>(&self, key: &SnapshotStorageKey) -> Result<LoadedSnapshot<T>>
+    where
+        T: VersionedSnapshot,
+    {
+        if self.header.version > SEALED_SNAPSHOT_VERSION {
+            return Err(Error::Serialization(format!(
+                "sealed snapshot version {} is newer than supported version {SEALED_SNAPSHOT_VERSION}",
+                self.header.version
+            )));
+        }
+        if self.header.kind != T::KIND {
+            return Err(Error::Serialization(format!(
+                "expected sealed {:?} snapshot, found {:?}",
+                T::KIND,
+                self.header.kind
+            )));
+        }
+
+        let engine = base64::engine::general_purpose::STANDARD;
+        let nonce = engine
+            .decode(&self.nonce)
+            .map_err(|e| Error::Decryption(e.to_string()))?;
+        if nonce.len() != NONCE_LEN {
+            return Err(Error::Decryption(
+                "invalid sealed snapshot nonce".to_string(),
+            ));
+        }
+        let ciphertext = engine
+            .decode(&self.ciphertext)
+            .map_err(|e| Error::Decryption(e.to_string()))?;
+        let aad = serde_json::to_vec(&self.header)?;
+        let plaintext = key
+            .cipher()
+            .decrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| Error::Decryption("failed to open sealed snapshot".to_string()))?;
+        let plaintext =
+            String::from_utf8(plaintext).map_err(|e| Error::Decryption(e.to_string()))?;
+        decode_snapshot(&plaintext)
+    }
+}
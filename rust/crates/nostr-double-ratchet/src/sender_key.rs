@@ -1,4 +1,4 @@
-use crate::{DomainError, Result};
+use crate::{DomainError, Result, SecretBytes};
 use base64::Engine;
 use nostr::nips::nip44;
 use serde::{Deserialize, Serialize};
@@ -13,10 +13,10 @@ const SENDER_KEY_KDF_SALT: &[u8] = b"ndr-sender-key-v1";
 pub struct SenderKeyState {
     pub key_id: u32,
     #[serde(with = "serde_bytes_array")]
-    chain_key: [u8; 32],
+    chain_key: SecretBytes,
     iteration: u32,
     #[serde(default, with = "serde_btreemap_u32_bytes")]
-    skipped_message_keys: BTreeMap<u32, [u8; 32]>,
+    skipped_message_keys: BTreeMap<u32, SecretBytes>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -66,7 +66,7 @@ impl SenderKeyState {
     pub fn new(key_id: u32, chain_key: [u8; 32], iteration: u32) -> Self {
         Self {
             key_id,
-            chain_key,
+            chain_key: SecretBytes::new(chain_key),
             iteration,
             skipped_message_keys: BTreeMap::new(),
         }
@@ -77,7 +77,7 @@ impl SenderKeyState {
     }
 
     pub fn chain_key(&self) -> [u8; 32] {
-        self.chain_key
+        *self.chain_key.expose_secret()
     }
 
     pub fn iteration(&self) -> u32 {
@@ -91,8 +91,9 @@ impl SenderKeyState {
     pub fn plan_encrypt(&self, plaintext: &[u8]) -> Result<SenderKeyEncryptPlan> {
         let mut next_state = self.clone();
         let message_number = next_state.iteration;
-        let (next_chain_key, message_key) = derive_message_key(&next_state.chain_key);
-        next_state.chain_key = next_chain_key;
+        let (next_chain_key, message_key) =
+            derive_message_key(next_state.chain_key.expose_secret());
+        next_state.chain_key = SecretBytes::new(next_chain_key);
         next_state.iteration = next_state
             .iteration
             .checked_add(1)
@@ -139,7 +140,8 @@ impl SenderKeyState {
 
     pub fn plan_decrypt_blind(&self, ciphertext: &[u8]) -> Result<SenderKeyBlindDecryptPlan> {
         for (message_number, message_key) in &self.skipped_message_keys {
-            if let Ok(plaintext) = decrypt_with_message_key(message_key, ciphertext) {
+            if let Ok(plaintext) = decrypt_with_message_key(message_key.expose_secret(), ciphertext)
+            {
                 let mut next_state = self.clone();
                 next_state.skipped_message_keys.remove(message_number);
                 return Ok(SenderKeyBlindDecryptPlan {
@@ -155,8 +157,9 @@ impl SenderKeyState {
         let max_message_number = self.iteration.saturating_add(SENDER_KEY_MAX_SKIP as u32);
         while next_state.iteration <= max_message_number {
             let message_number = next_state.iteration;
-            let (next_chain_key, message_key) = derive_message_key(&next_state.chain_key);
-            next_state.chain_key = next_chain_key;
+            let (next_chain_key, message_key) =
+                derive_message_key(next_state.chain_key.expose_secret());
+            next_state.chain_key = SecretBytes::new(next_chain_key);
             next_state.iteration = next_state.iteration.checked_add(1).ok_or_else(|| {
                 crate::Error::Decryption("sender-key iteration overflow".to_string())
             })?;
@@ -173,7 +176,7 @@ impl SenderKeyState {
 
             next_state
                 .skipped_message_keys
-                .insert(message_number, message_key);
+                .insert(message_number, SecretBytes::new(message_key));
         }
 
         Err(crate::Error::Decryption(
@@ -238,7 +241,7 @@ impl SenderKeyState {
                 .ok_or_else(|| {
                     crate::Error::Decryption("duplicate or missing sender-key message".to_string())
                 })?;
-            return decrypt_with_message_key(&message_key, ciphertext);
+            return decrypt_with_message_key(message_key.expose_secret(), ciphertext);
         }
 
         let delta = (message_number - self.iteration) as usize;
@@ -247,17 +250,17 @@ impl SenderKeyState {
         }
 
         while self.iteration < message_number {
-            let (next_chain_key, message_key) = derive_message_key(&self.chain_key);
-            self.chain_key = next_chain_key;
+            let (next_chain_key, message_key) = derive_message_key(self.chain_key.expose_secret());
+            self.chain_key = SecretBytes::new(next_chain_key);
             self.skipped_message_keys
-                .insert(self.iteration, message_key);
+                .insert(self.iteration, SecretBytes::new(message_key));
             self.iteration = self.iteration.checked_add(1).ok_or_else(|| {
                 crate::Error::Decryption("sender-key iteration overflow".to_string())
             })?;
         }
 
-        let (next_chain_key, message_key) = derive_message_key(&self.chain_key);
-        self.chain_key = next_chain_key;
+        let (next_chain_key, message_key) = derive_message_key(self.chain_key.expose_secret());
+        self.chain_key = SecretBytes::new(next_chain_key);
         self.iteration = self
             .iteration
             .checked_add(1)
@@ -278,7 +281,7 @@ fn decrypt_with_message_key(message_key: &[u8; 32], ciphertext: &[u8]) -> Result
     nip44::v2::decrypt_to_bytes(&conversation_key, ciphertext).map_err(Into::into)
 }
 
-fn prune_skipped(map: &mut BTreeMap<u32, [u8; 32]>) {
+fn prune_skipped(map: &mut BTreeMap<u32, SecretBytes>) {
     while map.len() > SENDER_KEY_MAX_STORED_SKIPPED_KEYS {
         let Some(first) = map.keys().next().copied() else {
             break;
@@ -288,42 +291,45 @@ fn prune_skipped(map: &mut BTreeMap<u32, [u8; 32]>) {
 }
 
 mod serde_bytes_array {
+    use crate::SecretBytes;
     use serde::{Deserialize, Deserializer, Serializer};
 
-    pub fn serialize<S>(bytes: &[u8; 32], serializer: S) -> Result<S::Ok, S::Error>
+    pub fn serialize<S>(bytes: &SecretBytes, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        serializer.serialize_str(&hex::encode(bytes))
+        serializer.serialize_str(&hex::encode(bytes.expose_secret()))
     }
 
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<[u8; 32], D::Error>
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<SecretBytes, D::Error>
     where
         D: Deserializer<'de>,
     {
         let value = String::deserialize(deserializer)?;
         let bytes = hex::decode(value).map_err(serde::de::Error::custom)?;
         <[u8; 32]>::try_from(bytes.as_slice())
+            .map(SecretBytes::new)
             .map_err(|_| serde::de::Error::custom("expected 32-byte hex"))
     }
 }
 
 mod serde_btreemap_u32_bytes {
+    use crate::SecretBytes;
     use serde::{Deserialize, Deserializer, Serialize, Serializer};
     use std::collections::BTreeMap;
 
-    pub fn serialize<S>(map: &BTreeMap<u32, [u8; 32]>, serializer: S) -> Result<S::Ok, S::Error>
+    pub fn serialize<S>(map: &BTreeMap<u32, SecretBytes>, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
         let values: BTreeMap<String, String> = map
             .iter()
-            .map(|(key, value)| (key.to_string(), hex::encode(value)))
+            .map(|(key, value)| (key.to_string(), hex::encode(value.expose_secret())))
             .collect();
         values.serialize(serializer)
     }
 
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<BTreeMap<u32, [u8; 32]>, D::Error>
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<BTreeMap<u32, SecretBytes>, D::Error>
     where
         D: Deserializer<'de>,
     {
@@ -334,7 +340,7 @@ mod serde_btreemap_u32_bytes {
             let bytes = hex::decode(value).map_err(serde::de::Error::custom)?;
             let value = <[u8; 32]>::try_from(bytes.as_slice())
                 .map_err(|_| serde::de::Error::custom("expected 32-byte hex"))?;
-            out.insert(key, value);
+            out.insert(key, SecretBytes::new(value));
         }
         Ok(out)
     }
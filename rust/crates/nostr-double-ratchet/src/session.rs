@@ -1,14 +1,18 @@
 use crate::{
-    device_pubkey_from_secret_bytes, kdf, random_secret_key_bytes, secret_key_from_bytes,
-    DevicePubkey, DomainError, ProtocolContext, Result, UnixSeconds, MAX_SKIP,
+    device_pubkey_from_secret_bytes, kdf, pad_payload, random_secret_key_bytes,
+    secret_key_from_bytes, unpad_payload, DevicePubkey, DomainError, PayloadPadding,
+    ProtocolContext, Result, SecretBytes, SessionLimits, UnixSeconds, BUCKETED_PADDING_SCHEME,
 };
 use base64::Engine;
-use nostr::nips::nip44::{self, Version};
+use nostr::nips::nip44;
 use nostr::PublicKey;
 use rand::rngs::OsRng;
 use rand::{CryptoRng, RngCore};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
@@ -16,25 +20,90 @@ pub struct Header {
     pub number: u32,
     pub previous_chain_length: u32,
     pub next_public_key: DevicePubkey,
+    /// Padding scheme applied to this message's plaintext, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub padding: Option<u8>,
+    /// Set when the sender can strip padding, allowing its peer to pad.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub accepts_padding: bool,
+    /// Marks a protocol control message whose payload is not for the app.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub control: Option<SessionControl>,
+    /// Set when the sender understands control messages, allowing its peer
+    /// to send them. Older peers would show a control body as chat.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub accepts_control: bool,
 }
 
+/// Session-level control messages carried in the encrypted header.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+pub enum SessionControl {
+    /// Ask the peer to reply so that both sides perform a DH ratchet step.
+    RefreshRequest,
+    /// Reply to a refresh request. Receiving it moves the requester onto a
+    /// fresh sending chain.
+    KeyUpdate,
+    /// The sender discarded this session. The receiver closes it and
+    /// re-handshakes through the peer's invite on its next send.
+    Reset,
+}
+
+/// Placeholder body for control messages; NIP-44 cannot encrypt an empty
+/// plaintext. Receivers discard it.
+const CONTROL_BODY: &[u8] = &[0];
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct SerializableKeyPair {
     pub public_key: DevicePubkey,
     #[serde(with = "serde_bytes_array")]
-    pub private_key: [u8; 32],
+    pub private_key: SecretBytes,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
 pub struct SkippedKeysEntry {
     #[serde(with = "serde_btreemap_u32_bytes")]
-    pub message_keys: BTreeMap<u32, [u8; 32]>,
+    pub message_keys: BTreeMap<u32, SecretBytes>,
+    /// When each key in `message_keys` was derived. Keys stored before this
+    /// was tracked are stamped the first time the session receives.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub derived_at: BTreeMap<u32, UnixSeconds>,
+}
+
+impl SkippedKeysEntry {
+    pub fn insert(&mut self, message_number: u32, message_key: SecretBytes, now: UnixSeconds) {
+        self.message_keys.insert(message_number, message_key);
+        self.derived_at.insert(message_number, now);
+    }
+
+    pub fn remove(&mut self, message_number: u32) -> Option<SecretBytes> {
+        self.derived_at.remove(&message_number);
+        self.message_keys.remove(&message_number)
+    }
+
+    /// Drop keys derived more than `max_age_secs` before `now`, returning
+    /// their message numbers.
+    pub fn expire(&mut self, now: UnixSeconds, max_age_secs: u64) -> Vec<u32> {
+        for message_number in self.message_keys.keys() {
+            self.derived_at.entry(*message_number).or_insert(now);
+        }
+        let expired = self
+            .derived_at
+            .iter()
+            .filter(|(_, derived_at)| now.get().saturating_sub(derived_at.get()) > max_age_secs)
+            .map(|(message_number, _)| *message_number)
+            .collect::<Vec<_>>();
+        for message_number in &expired {
+            self.remove(*message_number);
+        }
+        expired
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct SessionState {
     #[serde(with = "serde_bytes_array")]
-    pub root_key: [u8; 32],
+    pub root_key: SecretBytes,
     pub their_current_nostr_public_key: Option<DevicePubkey>,
     pub their_next_nostr_public_key: Option<DevicePubkey>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
@@ -42,13 +111,23 @@ pub struct SessionState {
     pub our_current_nostr_key: Option<SerializableKeyPair>,
     pub our_next_nostr_key: SerializableKeyPair,
     #[serde(default, with = "serde_option_bytes_array")]
-    pub receiving_chain_key: Option<[u8; 32]>,
+    pub receiving_chain_key: Option<SecretBytes>,
     #[serde(default, with = "serde_option_bytes_array")]
-    pub sending_chain_key: Option<[u8; 32]>,
+    pub sending_chain_key: Option<SecretBytes>,
     pub sending_chain_message_number: u32,
     pub receiving_chain_message_number: u32,
     pub previous_sending_chain_message_count: u32,
     pub skipped_keys: BTreeMap<DevicePubkey, SkippedKeysEntry>,
+    /// Whether the peer's last message advertised padding support.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub peer_accepts_padding: bool,
+    /// Whether the peer's last message advertised control message support.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub peer_accepts_control: bool,
+    /// Set once the session was reset. A closed session still decrypts
+    /// in-flight messages but never sends again.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub closed: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -56,12 +135,24 @@ pub struct MessageEnvelope {
     pub sender: DevicePubkey,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub recipient: Option<DevicePubkey>,
-    pub signer_secret_key: [u8; 32],
+    pub signer_secret_key: SecretBytes,
     pub created_at: UnixSeconds,
     pub encrypted_header: String,
     pub ciphertext: String,
 }
 
+impl MessageEnvelope {
+    /// Stable identifier for this envelope, independent of the outer event
+    /// signature, used to recognise relay redeliveries.
+    pub fn message_id(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.sender.to_bytes());
+        hasher.update(self.encrypted_header.as_bytes());
+        hasher.update(self.ciphertext.as_bytes());
+        hex::encode(hasher.finalize())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SendPlan {
     pub next_state: SessionState,
@@ -80,18 +171,93 @@ pub struct ReceivePlan {
     pub next_state: SessionState,
     pub payload: Vec<u8>,
     pub sender: DevicePubkey,
+    pub control: Option<SessionControl>,
 }
 
 #[derive(Debug, Clone)]
 pub struct ReceiveOutcome {
     pub payload: Vec<u8>,
     pub sender: DevicePubkey,
+    pub control: Option<SessionControl>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Session {
     pub state: SessionState,
     pub name: String,
+    skipped_key_max_age_secs: Option<u64>,
+    limits: SessionLimits,
+    padding: PayloadPadding,
+    conversation_keys: ConversationKeyCache,
+}
+
+/// NIP-44 conversation keys keyed by (our pubkey, their pubkey), so header
+/// encryption and ratchet steps skip the ECDH for pairs already seen. Never
+/// serialized; entries for keypairs the state no longer holds are dropped.
+#[derive(Default)]
+struct ConversationKeyCache {
+    keys: Mutex<BTreeMap<(DevicePubkey, DevicePubkey), SecretBytes>>,
+    derivations: AtomicU64,
+}
+
+impl ConversationKeyCache {
+    fn get(
+        &self,
+        ours: &SerializableKeyPair,
+        theirs: DevicePubkey,
+    ) -> Result<nip44::v2::ConversationKey> {
+        let mut keys = self.keys.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(key) = keys.get(&(ours.public_key, theirs)) {
+            return Ok(nip44::v2::ConversationKey::new(*key.expose_secret()));
+        }
+        let conversation_key = nip44::v2::ConversationKey::derive(
+            &secret_key_from_bytes(ours.private_key.expose_secret())?,
+            &theirs.to_nostr()?,
+        )?;
+        self.derivations.fetch_add(1, Ordering::Relaxed);
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(conversation_key.as_bytes());
+        keys.insert((ours.public_key, theirs), SecretBytes::new(bytes));
+        Ok(conversation_key)
+    }
+
+    fn retain_for(&self, state: &SessionState) {
+        let live: Vec<DevicePubkey> = [
+            state.our_previous_nostr_key.as_ref(),
+            state.our_current_nostr_key.as_ref(),
+            Some(&state.our_next_nostr_key),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|pair| pair.public_key)
+        .collect();
+        self.keys
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|(ours, _), _| live.contains(ours));
+    }
+}
+
+impl Clone for ConversationKeyCache {
+    fn clone(&self) -> Self {
+        Self {
+            keys: Mutex::new(
+                self.keys
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .clone(),
+            ),
+            derivations: AtomicU64::new(self.derivations.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl std::fmt::Debug for ConversationKeyCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConversationKeyCache")
+            .field("derivations", &self.derivations.load(Ordering::Relaxed))
+            .finish_non_exhaustive()
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -103,14 +269,57 @@ enum HeaderDecryptionTarget {
 
 impl Session {
     pub fn from_state(state: SessionState) -> Self {
+        Self::new(state, String::new())
+    }
+
+    pub fn new(state: SessionState, name: String) -> Self {
         Self {
             state,
-            name: String::new(),
+            name,
+            skipped_key_max_age_secs: None,
+            limits: SessionLimits::default(),
+            padding: PayloadPadding::default(),
+            conversation_keys: ConversationKeyCache::default(),
         }
     }
 
-    pub fn new(state: SessionState, name: String) -> Self {
-        Self { state, name }
+    /// Number of ECDH conversation key derivations this session performed.
+    /// Cached keys are reused, so this grows with ratchet steps rather than
+    /// with messages.
+    pub fn conversation_key_derivations(&self) -> u64 {
+        self.conversation_keys.derivations.load(Ordering::Relaxed)
+    }
+
+    pub fn payload_padding(&self) -> PayloadPadding {
+        self.padding
+    }
+
+    pub fn set_payload_padding(&mut self, padding: PayloadPadding) {
+        self.padding = padding;
+    }
+
+    pub fn limits(&self) -> SessionLimits {
+        self.limits
+    }
+
+    /// Tighter limits take effect on the next receive; keys already stored
+    /// beyond them are evicted then.
+    pub fn set_limits(&mut self, limits: SessionLimits) {
+        self.limits = limits;
+    }
+
+    pub fn skipped_key_max_age_secs(&self) -> Option<u64> {
+        self.skipped_key_max_age_secs
+    }
+
+    /// Skipped message keys older than this are discarded on the next
+    /// receive, so messages lost for longer can no longer be decrypted.
+    /// `None`, the default, keeps them until the [`SessionLimits`] count
+    /// limits evict them. `SessionManager` sessions use
+    /// `DEFAULT_SKIPPED_KEY_MAX_AGE_SECS` unless its retention policy says
+    /// otherwise.
+    pub fn set_skipped_key_max_age_secs(&mut self, max_age_secs: Option<u64>) {
+        self.skipped_key_max_age_secs = max_age_secs;
     }
 
     pub fn init(
@@ -209,89 +418,128 @@ impl Session {
                 )?;
                 let kdf_outputs = kdf(&shared_secret, conversation_key.as_bytes(), 2);
                 (
-                    kdf_outputs[0],
-                    Some(kdf_outputs[1]),
+                    SecretBytes::new(kdf_outputs[0]),
+                    Some(SecretBytes::new(kdf_outputs[1])),
                     Some(SerializableKeyPair {
                         public_key: our_current_pubkey,
-                        private_key: our_ephemeral_private_key,
+                        private_key: SecretBytes::new(our_ephemeral_private_key),
                     }),
                     SerializableKeyPair {
                         public_key: DevicePubkey::from_nostr(our_next_keys.public_key()),
-                        private_key: our_next_private_key,
+                        private_key: SecretBytes::new(our_next_private_key),
                     },
                 )
             } else {
                 (
-                    shared_secret,
+                    SecretBytes::new(shared_secret),
                     None,
                     None,
                     SerializableKeyPair {
                         public_key: DevicePubkey::from_nostr(our_keys.public_key()),
-                        private_key: our_ephemeral_private_key,
+                        private_key: SecretBytes::new(our_ephemeral_private_key),
                     },
                 )
             };
 
-        Ok(Self {
-            state: SessionState {
-                root_key,
-                their_current_nostr_public_key: None,
-                their_next_nostr_public_key: Some(their_ephemeral_public_key),
-                our_previous_nostr_key: None,
-                our_current_nostr_key,
-                our_next_nostr_key,
-                receiving_chain_key: None,
-                sending_chain_key,
-                sending_chain_message_number: 0,
-                receiving_chain_message_number: 0,
-                previous_sending_chain_message_count: 0,
-                skipped_keys: BTreeMap::new(),
-            },
-            name: String::new(),
-        })
+        Ok(Self::from_state(SessionState {
+            root_key,
+            their_current_nostr_public_key: None,
+            their_next_nostr_public_key: Some(their_ephemeral_public_key),
+            our_previous_nostr_key: None,
+            our_current_nostr_key,
+            our_next_nostr_key,
+            receiving_chain_key: None,
+            sending_chain_key,
+            sending_chain_message_number: 0,
+            receiving_chain_message_number: 0,
+            previous_sending_chain_message_count: 0,
+            skipped_keys: BTreeMap::new(),
+            peer_accepts_padding: false,
+            peer_accepts_control: false,
+            closed: false,
+        }))
     }
 
     pub fn can_send(&self) -> bool {
-        self.state.their_next_nostr_public_key.is_some()
+        !self.state.closed
+            && self.state.their_next_nostr_public_key.is_some()
             && self.state.our_current_nostr_key.is_some()
     }
 
+    pub fn is_closed(&self) -> bool {
+        self.state.closed
+    }
+
     pub fn matches_sender(&self, sender: DevicePubkey) -> bool {
-        self.state.their_current_nostr_public_key == Some(sender)
-            || self.state.their_next_nostr_public_key == Some(sender)
-            || self.state.skipped_keys.contains_key(&sender)
+        state_matches_sender(&self.state, sender)
     }
 
     pub fn plan_send(&self, payload: &[u8], now: UnixSeconds) -> Result<SendPlan> {
+        self.plan_send_inner(payload, now, None)
+    }
+
+    /// Whether the peer advertised support for control messages. Planning
+    /// one for a peer that did not fails with `ControlNotSupported`.
+    pub fn peer_accepts_control(&self) -> bool {
+        self.state.peer_accepts_control
+    }
+
+    /// Plan a control message asking the peer to reply with a key update,
+    /// so a sender that has stopped receiving still gets post-compromise
+    /// recovery. The peer must have advertised control support in an
+    /// earlier message.
+    pub fn plan_refresh(&self, now: UnixSeconds) -> Result<SendPlan> {
+        self.plan_send_inner(CONTROL_BODY, now, Some(SessionControl::RefreshRequest))
+    }
+
+    pub fn plan_key_update(&self, now: UnixSeconds) -> Result<SendPlan> {
+        self.plan_send_inner(CONTROL_BODY, now, Some(SessionControl::KeyUpdate))
+    }
+
+    /// Plan a control message telling the peer to discard this session.
+    /// The caller should [`close`](Self::close) the session once it is sent.
+    pub fn plan_reset(&self, now: UnixSeconds) -> Result<SendPlan> {
+        self.plan_send_inner(CONTROL_BODY, now, Some(SessionControl::Reset))
+    }
+
+    fn plan_send_inner(
+        &self,
+        payload: &[u8],
+        now: UnixSeconds,
+        control: Option<SessionControl>,
+    ) -> Result<SendPlan> {
         if !self.can_send() {
             return Err(DomainError::CannotSendYet.into());
         }
+        if control.is_some() && !self.state.peer_accepts_control {
+            return Err(DomainError::ControlNotSupported.into());
+        }
 
         let mut next_state = self.state.clone();
-        let (header, ciphertext) = ratchet_encrypt(&mut next_state, payload)?;
+        let (mut header, ciphertext) = ratchet_encrypt(&mut next_state, payload, self.padding)?;
+        header.control = control;
         let our_current = self
             .state
             .our_current_nostr_key
             .as_ref()
             .ok_or(DomainError::SessionNotReady)?;
-        let our_secret = secret_key_from_bytes(&our_current.private_key)?;
         let their_next = self
             .state
             .their_next_nostr_public_key
             .ok_or(DomainError::SessionNotReady)?;
-        let encrypted_header = nip44::encrypt(
-            &our_secret,
-            &their_next.to_nostr()?,
-            &serde_json::to_string(&header)?,
-            Version::V2,
-        )?;
+        let conversation_key = self.conversation_keys.get(our_current, their_next)?;
+        let encrypted_header =
+            base64::engine::general_purpose::STANDARD.encode(nip44::v2::encrypt_to_bytes(
+                &conversation_key,
+                serde_json::to_string(&header)?.as_bytes(),
+            )?);
 
         Ok(SendPlan {
             next_state,
             envelope: MessageEnvelope {
                 sender: our_current.public_key,
                 recipient: None,
-                signer_secret_key: our_current.private_key,
+                signer_secret_key: our_current.private_key.clone(),
                 created_at: now,
                 encrypted_header,
                 ciphertext,
@@ -302,6 +550,7 @@ impl Session {
 
     pub fn apply_send(&mut self, plan: SendPlan) -> SendOutcome {
         self.state = plan.next_state;
+        self.conversation_keys.retain_for(&self.state);
         SendOutcome {
             envelope: plan.envelope,
             payload: plan.payload,
@@ -321,11 +570,178 @@ impl Session {
         }
 
         let mut next_state = self.state.clone();
+        prune_skipped_keys(
+            &mut next_state,
+            ctx.now,
+            self.skipped_key_max_age_secs,
+            &self.limits,
+        );
+        let (header, decryption_target) = decrypt_header(
+            &next_state,
+            &self.conversation_keys,
+            &envelope.encrypted_header,
+            envelope.sender,
+        )?;
+        if already_consumed(&next_state, envelope.sender, &header, decryption_target) {
+            return Err(DomainError::DuplicateMessage.into());
+        }
+        let payload =
+            self.receive_with_header(&mut next_state, ctx, envelope, &header, decryption_target)?;
+
+        Ok(ReceivePlan {
+            next_state,
+            payload,
+            sender: envelope.sender,
+            control: header.control,
+        })
+    }
+
+    /// Receive many envelopes from this session's peer in one pass.
+    ///
+    /// Envelopes are grouped by sender chain, oldest chain first, and sorted
+    /// by header number where the header can be read up front. Messages on
+    /// the current receiving chain or with a stored skipped key update the
+    /// session state in place; only messages that skip ahead or start a new
+    /// chain work on a copy. Each message is still applied all-or-nothing.
+    /// Results are returned in input order.
+    pub fn receive_batch<R>(
+        &mut self,
+        ctx: &mut ProtocolContext<'_, R>,
+        envelopes: &[MessageEnvelope],
+    ) -> Vec<Result<ReceiveOutcome>>
+    where
+        R: RngCore + CryptoRng,
+    {
+        prune_skipped_keys(
+            &mut self.state,
+            ctx.now,
+            self.skipped_key_max_age_secs,
+            &self.limits,
+        );
+
+        let peeked: Vec<Option<PeekedHeader>> = envelopes
+            .iter()
+            .map(|envelope| peek_header(&self.state, &self.conversation_keys, envelope))
+            .collect();
+        let mut chain_order: BTreeMap<DevicePubkey, (u8, usize)> = BTreeMap::new();
+        for (index, envelope) in envelopes.iter().enumerate() {
+            let rank = match peeked[index].as_ref().map(|peeked| peeked.target) {
+                Some(HeaderDecryptionTarget::Previous) => 0,
+                Some(HeaderDecryptionTarget::Current) => 1,
+                Some(HeaderDecryptionTarget::Next) => 2,
+                None => 3,
+            };
+            let order = chain_order.entry(envelope.sender).or_insert((rank, index));
+            order.0 = order.0.min(rank);
+        }
+        let mut order: Vec<usize> = (0..envelopes.len()).collect();
+        order.sort_by_key(|index| {
+            let number = peeked[*index]
+                .as_ref()
+                .map_or(u32::MAX, |peeked| peeked.header.number);
+            (chain_order[&envelopes[*index].sender], number)
+        });
+
+        let mut results: Vec<Option<Result<ReceiveOutcome>>> =
+            envelopes.iter().map(|_| None).collect();
+        let mut peeked: Vec<Option<PeekedHeader>> = peeked;
+        for index in order {
+            results[index] =
+                Some(self.receive_in_place(ctx, &envelopes[index], peeked[index].take()));
+        }
+        self.conversation_keys.retain_for(&self.state);
+        results
+            .into_iter()
+            .map(|result| result.expect("every envelope has a result"))
+            .collect()
+    }
+
+    fn receive_in_place<R>(
+        &mut self,
+        ctx: &mut ProtocolContext<'_, R>,
+        envelope: &MessageEnvelope,
+        peeked: Option<PeekedHeader>,
+    ) -> Result<ReceiveOutcome>
+    where
+        R: RngCore + CryptoRng,
+    {
+        if !self.matches_sender(envelope.sender) {
+            return Err(DomainError::UnexpectedSender.into());
+        }
+        let (header, target) = match peeked
+            .and_then(|peeked| Some((target_for_key(&self.state, peeked.key)?, peeked.header)))
+        {
+            Some((target, header)) => (header, target),
+            None => decrypt_header(
+                &self.state,
+                &self.conversation_keys,
+                &envelope.encrypted_header,
+                envelope.sender,
+            )?,
+        };
+        if already_consumed(&self.state, envelope.sender, &header, target) {
+            return Err(DomainError::DuplicateMessage.into());
+        }
+        let outcome = |payload| ReceiveOutcome {
+            payload,
+            sender: envelope.sender,
+            control: header.control,
+        };
+
+        if target != HeaderDecryptionTarget::Next {
+            let skipped_key = self
+                .state
+                .skipped_keys
+                .get(&envelope.sender)
+                .and_then(|entry| entry.message_keys.get(&header.number))
+                .cloned();
+            if let Some(message_key) = skipped_key {
+                let plaintext =
+                    decrypt_with_message_key(message_key.expose_secret(), &envelope.ciphertext)?;
+                let payload = decode_payload(&header, plaintext)?;
+                if let Some(entry) = self.state.skipped_keys.get_mut(&envelope.sender) {
+                    entry.remove(header.number);
+                    if entry.message_keys.is_empty() {
+                        self.state.skipped_keys.remove(&envelope.sender);
+                    }
+                }
+                record_received_header(&mut self.state, &header, false);
+                return Ok(outcome(payload));
+            }
+        }
+
+        let in_order = target == HeaderDecryptionTarget::Current
+            && header.number == self.state.receiving_chain_message_number;
+        if let Some(chain_key) = self.state.receiving_chain_key.as_ref().filter(|_| in_order) {
+            let kdf_outputs = kdf(chain_key.expose_secret(), &[1u8], 2);
+            let plaintext = decrypt_with_message_key(&kdf_outputs[1], &envelope.ciphertext)?;
+            let payload = decode_payload(&header, plaintext)?;
+            self.state.receiving_chain_key = Some(SecretBytes::new(kdf_outputs[0]));
+            self.state.receiving_chain_message_number += 1;
+            record_received_header(&mut self.state, &header, true);
+            return Ok(outcome(payload));
+        }
+
+        let mut next_state = self.state.clone();
+        let payload = self.receive_with_header(&mut next_state, ctx, envelope, &header, target)?;
+        self.state = next_state;
+        Ok(outcome(payload))
+    }
+
+    fn receive_with_header<R>(
+        &self,
+        next_state: &mut SessionState,
+        ctx: &mut ProtocolContext<'_, R>,
+        envelope: &MessageEnvelope,
+        header: &Header,
+        decryption_target: HeaderDecryptionTarget,
+    ) -> Result<Vec<u8>>
+    where
+        R: RngCore + CryptoRng,
+    {
         let previous_chain_sender = next_state
             .their_current_nostr_public_key
             .or(next_state.their_next_nostr_public_key);
-        let (header, decryption_target) =
-            decrypt_header(&next_state, &envelope.encrypted_header, envelope.sender)?;
         let should_ratchet = decryption_target == HeaderDecryptionTarget::Next;
 
         let expected_next = next_state.their_next_nostr_public_key;
@@ -338,53 +754,194 @@ impl Session {
             if next_state.receiving_chain_key.is_some() {
                 let skipped_sender = previous_chain_sender.ok_or(DomainError::SessionNotReady)?;
                 skip_message_keys(
-                    &mut next_state,
+                    next_state,
                     header.previous_chain_length,
                     skipped_sender,
+                    ctx.now,
+                    &self.limits,
                 )?;
             }
-            ratchet_step(&mut next_state, ctx.rng)?;
+            ratchet_step(next_state, &self.conversation_keys, ctx.rng)?;
         }
 
-        let payload = ratchet_decrypt(
-            &mut next_state,
-            &header,
+        let chain_position = next_state.receiving_chain_message_number;
+        let plaintext = ratchet_decrypt(
+            next_state,
+            header,
             &envelope.ciphertext,
             envelope.sender,
+            ctx.now,
+            &self.limits,
         )?;
-
-        Ok(ReceivePlan {
-            next_state,
-            payload,
-            sender: envelope.sender,
-        })
+        let payload = decode_payload(header, plaintext)?;
+        let advances_chain =
+            should_ratchet || next_state.receiving_chain_message_number != chain_position;
+        record_received_header(next_state, header, advances_chain);
+        Ok(payload)
     }
 
     pub fn apply_receive(&mut self, plan: ReceivePlan) -> ReceiveOutcome {
         self.state = plan.next_state;
+        self.conversation_keys.retain_for(&self.state);
         ReceiveOutcome {
             payload: plan.payload,
             sender: plan.sender,
+            control: plan.control,
         }
     }
 
-    pub fn close(&self) {}
+    pub fn close(&mut self) {
+        self.state.closed = true;
+    }
+}
+
+fn prune_skipped_keys(
+    state: &mut SessionState,
+    now: UnixSeconds,
+    max_age_secs: Option<u64>,
+    limits: &SessionLimits,
+) {
+    if let Some(max_age_secs) = max_age_secs {
+        expire_skipped_message_keys(state, now, max_age_secs);
+    }
+    enforce_skipped_key_limits(state, limits, None);
 }
 
-fn ratchet_encrypt(state: &mut SessionState, plaintext: &[u8]) -> Result<(Header, String)> {
+fn state_matches_sender(state: &SessionState, sender: DevicePubkey) -> bool {
+    state.their_current_nostr_public_key == Some(sender)
+        || state.their_next_nostr_public_key == Some(sender)
+        || state.skipped_keys.contains_key(&sender)
+}
+
+/// A header decrypted ahead of a batch, with the public half of the key that
+/// opened it so it can be matched against the state at processing time.
+struct PeekedHeader {
+    header: Header,
+    target: HeaderDecryptionTarget,
+    key: DevicePubkey,
+}
+
+fn peek_header(
+    state: &SessionState,
+    conversation_keys: &ConversationKeyCache,
+    envelope: &MessageEnvelope,
+) -> Option<PeekedHeader> {
+    if !state_matches_sender(state, envelope.sender) {
+        return None;
+    }
+    let (header, target) = decrypt_header(
+        state,
+        conversation_keys,
+        &envelope.encrypted_header,
+        envelope.sender,
+    )
+    .ok()?;
+    let key = match target {
+        HeaderDecryptionTarget::Current => state.our_current_nostr_key.as_ref()?.public_key,
+        HeaderDecryptionTarget::Next => state.our_next_nostr_key.public_key,
+        HeaderDecryptionTarget::Previous => state.our_previous_nostr_key.as_ref()?.public_key,
+    };
+    Some(PeekedHeader {
+        header,
+        target,
+        key,
+    })
+}
+
+fn target_for_key(state: &SessionState, key: DevicePubkey) -> Option<HeaderDecryptionTarget> {
+    let is_key =
+        |pair: Option<&SerializableKeyPair>| pair.is_some_and(|pair| pair.public_key == key);
+    if is_key(state.our_current_nostr_key.as_ref()) {
+        Some(HeaderDecryptionTarget::Current)
+    } else if is_key(Some(&state.our_next_nostr_key)) {
+        Some(HeaderDecryptionTarget::Next)
+    } else if is_key(state.our_previous_nostr_key.as_ref()) {
+        Some(HeaderDecryptionTarget::Previous)
+    } else {
+        None
+    }
+}
+
+fn decode_payload(header: &Header, plaintext: Vec<u8>) -> Result<Vec<u8>> {
+    match header.padding {
+        _ if header.control.is_some() => Ok(Vec::new()),
+        None => Ok(plaintext),
+        Some(BUCKETED_PADDING_SCHEME) => unpad_payload(&plaintext),
+        Some(scheme) => Err(crate::Error::Decryption(format!(
+            "unsupported padding scheme {scheme}"
+        ))),
+    }
+}
+
+/// A message is a replay when its chain was already read past its number and
+/// no skipped key is left for it.
+fn already_consumed(
+    state: &SessionState,
+    sender: DevicePubkey,
+    header: &Header,
+    target: HeaderDecryptionTarget,
+) -> bool {
+    if target == HeaderDecryptionTarget::Next {
+        return false;
+    }
+    let skipped = state.skipped_keys.get(&sender);
+    if skipped.is_some_and(|entry| entry.message_keys.contains_key(&header.number)) {
+        return false;
+    }
+    if state.their_current_nostr_public_key == Some(sender) {
+        return state.receiving_chain_key.is_some()
+            && header.number < state.receiving_chain_message_number;
+    }
+    state.their_next_nostr_public_key != Some(sender) && skipped.is_some()
+}
+
+/// Peer capabilities are taken only from messages that advance the
+/// receiving chain, so a late message decrypted with a skipped key cannot
+/// turn them off.
+fn record_received_header(state: &mut SessionState, header: &Header, advances_chain: bool) {
+    if advances_chain {
+        state.peer_accepts_padding = header.accepts_padding;
+        state.peer_accepts_control = header.accepts_control;
+    }
+    state.closed |= header.control == Some(SessionControl::Reset);
+}
+
+fn decrypt_with_message_key(message_key: &[u8; 32], ciphertext: &str) -> Result<Vec<u8>> {
+    let conversation_key = nip44::v2::ConversationKey::new(*message_key);
+    let ciphertext_bytes = base64::engine::general_purpose::STANDARD
+        .decode(ciphertext)
+        .map_err(|e| crate::Error::Decryption(e.to_string()))?;
+    nip44::v2::decrypt_to_bytes(&conversation_key, &ciphertext_bytes).map_err(Into::into)
+}
+
+fn ratchet_encrypt(
+    state: &mut SessionState,
+    plaintext: &[u8],
+    padding: PayloadPadding,
+) -> Result<(Header, String)> {
     let sending_chain_key = state
         .sending_chain_key
+        .as_ref()
         .ok_or(DomainError::SessionNotReady)?;
 
-    let kdf_outputs = kdf(&sending_chain_key, &[1u8], 2);
-    state.sending_chain_key = Some(kdf_outputs[0]);
+    let kdf_outputs = kdf(sending_chain_key.expose_secret(), &[1u8], 2);
+    state.sending_chain_key = Some(SecretBytes::new(kdf_outputs[0]));
     let message_key = kdf_outputs[1];
 
+    let accepts_padding = padding != PayloadPadding::Disabled;
+    let padded = (accepts_padding && state.peer_accepts_padding)
+        .then(|| pad_payload(plaintext))
+        .flatten();
     let header = Header {
         number: state.sending_chain_message_number,
         next_public_key: state.our_next_nostr_key.public_key,
         previous_chain_length: state.previous_sending_chain_message_count,
+        padding: padded.is_some().then_some(BUCKETED_PADDING_SCHEME),
+        accepts_padding,
+        control: None,
+        accepts_control: true,
     };
+    let plaintext = padded.as_deref().unwrap_or(plaintext);
 
     state.sending_chain_message_number += 1;
 
@@ -399,6 +956,8 @@ fn ratchet_decrypt(
     header: &Header,
     ciphertext: &str,
     sender: DevicePubkey,
+    now: UnixSeconds,
+    limits: &SessionLimits,
 ) -> Result<Vec<u8>> {
     if let Some(plaintext) = try_skipped_message_keys(state, header, ciphertext, sender)? {
         return Ok(plaintext);
@@ -408,26 +967,25 @@ fn ratchet_decrypt(
         return Err(DomainError::SessionNotReady.into());
     }
 
-    skip_message_keys(state, header.number, sender)?;
+    skip_message_keys(state, header.number, sender, now, limits)?;
 
     let receiving_chain_key = state
         .receiving_chain_key
+        .as_ref()
         .ok_or(DomainError::SessionNotReady)?;
 
-    let kdf_outputs = kdf(&receiving_chain_key, &[1u8], 2);
-    state.receiving_chain_key = Some(kdf_outputs[0]);
-    let message_key = kdf_outputs[1];
+    let kdf_outputs = kdf(receiving_chain_key.expose_secret(), &[1u8], 2);
+    state.receiving_chain_key = Some(SecretBytes::new(kdf_outputs[0]));
     state.receiving_chain_message_number += 1;
 
-    let conversation_key = nip44::v2::ConversationKey::new(message_key);
-    let ciphertext_bytes = base64::engine::general_purpose::STANDARD
-        .decode(ciphertext)
-        .map_err(|e| crate::Error::Decryption(e.to_string()))?;
-
-    nip44::v2::decrypt_to_bytes(&conversation_key, &ciphertext_bytes).map_err(Into::into)
+    decrypt_with_message_key(&kdf_outputs[1], ciphertext)
 }
 
-fn ratchet_step<R>(state: &mut SessionState, rng: &mut R) -> Result<()>
+fn ratchet_step<R>(
+    state: &mut SessionState,
+    conversation_keys: &ConversationKeyCache,
+    rng: &mut R,
+) -> Result<()>
 where
     R: RngCore + CryptoRng,
 {
@@ -435,39 +993,45 @@ where
     state.sending_chain_message_number = 0;
     state.receiving_chain_message_number = 0;
 
-    let our_next_sk = secret_key_from_bytes(&state.our_next_nostr_key.private_key)?;
     let their_next_pk = state
         .their_next_nostr_public_key
         .ok_or(DomainError::SessionNotReady)?;
 
-    let conversation_key1 =
-        nip44::v2::ConversationKey::derive(&our_next_sk, &their_next_pk.to_nostr()?)?;
-    let kdf_outputs = kdf(&state.root_key, conversation_key1.as_bytes(), 2);
-    state.receiving_chain_key = Some(kdf_outputs[1]);
+    let conversation_key1 = conversation_keys.get(&state.our_next_nostr_key, their_next_pk)?;
+    let kdf_outputs = kdf(
+        state.root_key.expose_secret(),
+        conversation_key1.as_bytes(),
+        2,
+    );
+    state.receiving_chain_key = Some(SecretBytes::new(kdf_outputs[1]));
     state.our_previous_nostr_key = state.our_current_nostr_key.clone();
     state.our_current_nostr_key = Some(state.our_next_nostr_key.clone());
 
     let our_next_private_key = random_secret_key_bytes(rng)?;
     state.our_next_nostr_key = SerializableKeyPair {
         public_key: device_pubkey_from_secret_bytes(&our_next_private_key)?,
-        private_key: our_next_private_key,
+        private_key: SecretBytes::new(our_next_private_key),
     };
 
-    let our_next_sk2 = secret_key_from_bytes(&our_next_private_key)?;
-    let conversation_key2 =
-        nip44::v2::ConversationKey::derive(&our_next_sk2, &their_next_pk.to_nostr()?)?;
+    let conversation_key2 = conversation_keys.get(&state.our_next_nostr_key, their_next_pk)?;
     let kdf_outputs2 = kdf(&kdf_outputs[0], conversation_key2.as_bytes(), 2);
-    state.root_key = kdf_outputs2[0];
-    state.sending_chain_key = Some(kdf_outputs2[1]);
+    state.root_key = SecretBytes::new(kdf_outputs2[0]);
+    state.sending_chain_key = Some(SecretBytes::new(kdf_outputs2[1]));
     Ok(())
 }
 
-fn skip_message_keys(state: &mut SessionState, until: u32, sender: DevicePubkey) -> Result<()> {
+fn skip_message_keys(
+    state: &mut SessionState,
+    until: u32,
+    sender: DevicePubkey,
+    now: UnixSeconds,
+    limits: &SessionLimits,
+) -> Result<()> {
     if until <= state.receiving_chain_message_number {
         return Ok(());
     }
 
-    if (until - state.receiving_chain_message_number) as usize > MAX_SKIP {
+    if (until - state.receiving_chain_message_number) as usize > limits.max_skip_per_chain {
         return Err(DomainError::TooManySkippedMessages.into());
     }
 
@@ -476,16 +1040,19 @@ fn skip_message_keys(state: &mut SessionState, until: u32, sender: DevicePubkey)
     while state.receiving_chain_message_number < until {
         let receiving_chain_key = state
             .receiving_chain_key
+            .as_ref()
             .ok_or(DomainError::SessionNotReady)?;
-        let kdf_outputs = kdf(&receiving_chain_key, &[1u8], 2);
-        state.receiving_chain_key = Some(kdf_outputs[0]);
-        entry
-            .message_keys
-            .insert(state.receiving_chain_message_number, kdf_outputs[1]);
+        let kdf_outputs = kdf(receiving_chain_key.expose_secret(), &[1u8], 2);
+        state.receiving_chain_key = Some(SecretBytes::new(kdf_outputs[0]));
+        entry.insert(
+            state.receiving_chain_message_number,
+            SecretBytes::new(kdf_outputs[1]),
+            now,
+        );
         state.receiving_chain_message_number += 1;
     }
 
-    prune_skipped_message_keys(&mut entry.message_keys);
+    enforce_skipped_key_limits(state, limits, Some(sender));
     Ok(())
 }
 
@@ -496,12 +1063,8 @@ fn try_skipped_message_keys(
     sender: DevicePubkey,
 ) -> Result<Option<Vec<u8>>> {
     if let Some(entry) = state.skipped_keys.get_mut(&sender) {
-        if let Some(message_key) = entry.message_keys.remove(&header.number) {
-            let conversation_key = nip44::v2::ConversationKey::new(message_key);
-            let ciphertext_bytes = base64::engine::general_purpose::STANDARD
-                .decode(ciphertext)
-                .map_err(|e| crate::Error::Decryption(e.to_string()))?;
-            let plaintext = nip44::v2::decrypt_to_bytes(&conversation_key, &ciphertext_bytes)?;
+        if let Some(message_key) = entry.remove(header.number) {
+            let plaintext = decrypt_with_message_key(message_key.expose_secret(), ciphertext)?;
             if entry.message_keys.is_empty() {
                 state.skipped_keys.remove(&sender);
             }
@@ -514,83 +1077,131 @@ fn try_skipped_message_keys(
 
 fn decrypt_header(
     state: &SessionState,
+    conversation_keys: &ConversationKeyCache,
     encrypted_header: &str,
     sender: DevicePubkey,
 ) -> Result<(Header, HeaderDecryptionTarget)> {
-    if let Some(current) = &state.our_current_nostr_key {
-        let current_sk = secret_key_from_bytes(&current.private_key)?;
-        if let Ok(decrypted) = nip44::decrypt(&current_sk, &sender.to_nostr()?, encrypted_header) {
-            let header: Header = serde_json::from_str(&decrypted)?;
-            return Ok((header, HeaderDecryptionTarget::Current));
-        }
-    }
-
-    let next_sk = secret_key_from_bytes(&state.our_next_nostr_key.private_key)?;
-    if let Ok(decrypted) = nip44::decrypt(&next_sk, &sender.to_nostr()?, encrypted_header) {
+    let Ok(payload) = base64::engine::general_purpose::STANDARD.decode(encrypted_header) else {
+        return Err(crate::Error::FailedToDecryptHeader);
+    };
+    let candidates = [
+        (
+            state.our_current_nostr_key.as_ref(),
+            HeaderDecryptionTarget::Current,
+        ),
+        (
+            Some(&state.our_next_nostr_key),
+            HeaderDecryptionTarget::Next,
+        ),
+        (
+            state.our_previous_nostr_key.as_ref(),
+            HeaderDecryptionTarget::Previous,
+        ),
+    ];
+    for (ours, target) in candidates {
+        let Some(ours) = ours else {
+            continue;
+        };
+        let conversation_key = conversation_keys.get(ours, sender)?;
+        let Ok(decrypted) = nip44::v2::decrypt_to_bytes(&conversation_key, &payload) else {
+            continue;
+        };
+        let Ok(decrypted) = String::from_utf8(decrypted) else {
+            continue;
+        };
         let header: Header = serde_json::from_str(&decrypted)?;
-        return Ok((header, HeaderDecryptionTarget::Next));
-    }
-
-    if let Some(previous) = &state.our_previous_nostr_key {
-        let previous_sk = secret_key_from_bytes(&previous.private_key)?;
-        if let Ok(decrypted) = nip44::decrypt(&previous_sk, &sender.to_nostr()?, encrypted_header) {
-            let header: Header = serde_json::from_str(&decrypted)?;
-            return Ok((header, HeaderDecryptionTarget::Previous));
-        }
+        return Ok((header, target));
     }
 
-    Err(crate::Error::Parse("invalid header".to_string()))
+    Err(crate::Error::FailedToDecryptHeader)
 }
 
-fn prune_skipped_message_keys(map: &mut BTreeMap<u32, [u8; 32]>) {
-    while map.len() > MAX_SKIP {
-        let Some(first) = map.keys().next().copied() else {
+/// Trim every sender chain to `max_stored_skipped_keys`, oldest message
+/// numbers first, then drop whole chains, least recently derived first,
+/// until at most `max_skipped_senders` remain. `keep` is never dropped.
+fn enforce_skipped_key_limits(
+    state: &mut SessionState,
+    limits: &SessionLimits,
+    keep: Option<DevicePubkey>,
+) {
+    state.skipped_keys.retain(|_, entry| {
+        while entry.message_keys.len() > limits.max_stored_skipped_keys {
+            let Some(first) = entry.message_keys.keys().next().copied() else {
+                break;
+            };
+            entry.remove(first);
+        }
+        !entry.message_keys.is_empty()
+    });
+
+    let Some(max_senders) = limits.max_skipped_senders else {
+        return;
+    };
+    while state.skipped_keys.len() > max_senders {
+        let oldest = state
+            .skipped_keys
+            .iter()
+            .filter(|(sender, _)| Some(**sender) != keep)
+            .min_by_key(|(sender, entry)| (entry.derived_at.values().max().copied(), **sender))
+            .map(|(sender, _)| *sender);
+        let Some(oldest) = oldest else {
             break;
         };
-        map.remove(&first);
+        state.skipped_keys.remove(&oldest);
     }
 }
 
+fn expire_skipped_message_keys(state: &mut SessionState, now: UnixSeconds, max_age_secs: u64) {
+    state.skipped_keys.retain(|_, entry| {
+        entry.expire(now, max_age_secs);
+        !entry.message_keys.is_empty()
+    });
+}
+
 mod serde_bytes_array {
+    use crate::SecretBytes;
     use serde::{Deserialize, Deserializer, Serializer};
 
-    pub fn serialize<S>(bytes: &[u8; 32], serializer: S) -> Result<S::Ok, S::Error>
+    pub fn serialize<S>(bytes: &SecretBytes, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        serializer.serialize_str(&hex::encode(bytes))
+        serializer.serialize_str(&hex::encode(bytes.expose_secret()))
     }
 
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<[u8; 32], D::Error>
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<SecretBytes, D::Error>
     where
         D: Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        super::decode_hex_32(&s).map_err(serde::de::Error::custom)
+        super::decode_hex_32(&s)
+            .map(SecretBytes::new)
+            .map_err(serde::de::Error::custom)
     }
 }
 
 mod serde_option_bytes_array {
+    use crate::SecretBytes;
     use serde::{Deserialize, Deserializer, Serializer};
 
-    pub fn serialize<S>(bytes: &Option<[u8; 32]>, serializer: S) -> Result<S::Ok, S::Error>
+    pub fn serialize<S>(bytes: &Option<SecretBytes>, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
         match bytes {
-            Some(b) => serializer.serialize_str(&hex::encode(b)),
+            Some(b) => serializer.serialize_str(&hex::encode(b.expose_secret())),
             None => serializer.serialize_none(),
         }
     }
 
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<[u8; 32]>, D::Error>
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<SecretBytes>, D::Error>
     where
         D: Deserializer<'de>,
     {
         let opt: Option<String> = Option::deserialize(deserializer)?;
         match opt {
             Some(s) => super::decode_hex_32(&s)
-                .map(Some)
+                .map(|bytes| Some(SecretBytes::new(bytes)))
                 .map_err(serde::de::Error::custom),
             None => Ok(None),
         }
@@ -598,21 +1209,22 @@ mod serde_option_bytes_array {
 }
 
 mod serde_btreemap_u32_bytes {
+    use crate::SecretBytes;
     use serde::{Deserialize, Deserializer, Serialize, Serializer};
     use std::collections::BTreeMap;
 
-    pub fn serialize<S>(map: &BTreeMap<u32, [u8; 32]>, serializer: S) -> Result<S::Ok, S::Error>
+    pub fn serialize<S>(map: &BTreeMap<u32, SecretBytes>, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
         let string_map: BTreeMap<String, String> = map
             .iter()
-            .map(|(k, v)| (k.to_string(), hex::encode(v)))
+            .map(|(k, v)| (k.to_string(), hex::encode(v.expose_secret())))
             .collect();
         string_map.serialize(serializer)
     }
 
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<BTreeMap<u32, [u8; 32]>, D::Error>
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<BTreeMap<u32, SecretBytes>, D::Error>
     where
         D: Deserializer<'de>,
     {
@@ -622,7 +1234,9 @@ mod serde_btreemap_u32_bytes {
             let idx: u32 = k.parse().map_err(serde::de::Error::custom)?;
             out.insert(
                 idx,
-                super::decode_hex_32(&v).map_err(serde::de::Error::custom)?,
+                super::decode_hex_32(&v)
+                    .map(SecretBytes::new)
+                    .map_err(serde::de::Error::custom)?,
             );
         }
         Ok(out)
@@ -651,6 +1265,10 @@ mod tests {
             number: 3,
             previous_chain_length: 2,
             next_public_key: DevicePubkey::from_bytes([9u8; 32]),
+            padding: None,
+            accepts_padding: false,
+            control: None,
+            accepts_control: false,
         };
 
         let json = serde_json::to_value(&header).unwrap();
@@ -791,7 +1409,7 @@ mod tests {
                 &MessageEnvelope {
                     sender: device_pubkey_from_secret_bytes(&bob_secret).unwrap(),
                     recipient: None,
-                    signer_secret_key: bob_secret,
+                    signer_secret_key: SecretBytes::new(bob_secret),
                     created_at: UnixSeconds(1),
                     encrypted_header: "bad".to_string(),
                     ciphertext: "bad".to_string(),
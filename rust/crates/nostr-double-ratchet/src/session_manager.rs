@@ -1,20 +1,181 @@
 use crate::{
     AuthorizedDevice, DevicePubkey, DeviceRoster, DomainError, Error, Invite, InviteResponse,
-    InviteResponseEnvelope, MessageEnvelope, OwnerPubkey, ProtocolContext, Result,
-    RosterSnapshotDecision, Session, SessionState, UnixSeconds,
+    InviteResponseEnvelope, MessageEnvelope, OwnerFingerprint, OwnerPubkey, PayloadPadding,
+    ProtocolContext, Result, RosterSnapshotDecision, SafetyNumber, SecretBytes, Session,
+    SessionControl, SessionLimits, SessionState, UnixSeconds, DEFAULT_SKIPPED_KEY_MAX_AGE_SECS,
+    MAX_INVITE_POW_DIFFICULTY,
 };
 use rand::{CryptoRng, RngCore};
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::OnceLock;
 
 const MAX_INACTIVE_SESSIONS: usize = 10;
+pub const DEFAULT_STALE_DEVICE_GRACE_SECS: u64 = 7 * 24 * 60 * 60;
+pub const DEFAULT_INACTIVE_SESSION_MAX_IDLE_SECS: u64 = 30 * 24 * 60 * 60;
+pub const DEFAULT_DECRYPT_FAILURE_THRESHOLD: u32 = 3;
+pub const DEFAULT_SEEN_MESSAGE_ID_LIMIT: usize = 1024;
+pub const DEFAULT_RETIRED_INVITE_GRACE_SECS: u64 = 24 * 60 * 60;
+
 #[derive(Debug, Clone)]
 pub struct SessionManager {
     local_owner_pubkey: OwnerPubkey,
     local_device_pubkey: DevicePubkey,
-    local_device_secret_key: [u8; 32],
+    local_device_secret_key: SecretBytes,
     local_invite: Option<Invite>,
+    retired_local_invites: Vec<Invite>,
     users: BTreeMap<OwnerPubkey, UserRecord>,
+    sender_index: BTreeMap<DevicePubkey, BTreeSet<TargetDevice>>,
+    indexed_senders: BTreeMap<TargetDevice, BTreeSet<DevicePubkey>>,
+    retention_policy: RetentionPolicy,
+    session_limits: SessionLimits,
+    payload_padding: PayloadPadding,
+    decrypt_failure_threshold: Option<u32>,
+    invite_pow_difficulty: Option<u8>,
+    broken_sessions: Vec<BrokenSession>,
+    verified_roster_changes: Vec<OwnerPubkey>,
+    seen_message_ids: SeenMessageIds,
+    dirty_users: BTreeSet<OwnerPubkey>,
+    local_invite_dirty: bool,
+    settings_dirty: bool,
+}
+
+/// Limits enforced by [`SessionManager::prune_stale`]. The default keeps
+/// the original behaviour of dropping stale devices immediately and never
+/// expiring idle sessions; [`RetentionPolicy::recommended`] opts in to both.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// How long a device removed from its owner's roster is kept, counted
+    /// from `stale_since`, so late messages from it can still be read.
+    pub stale_device_grace_secs: u64,
+    /// Inactive sessions of a device with no activity for this long are
+    /// dropped. The active session is always kept.
+    pub inactive_session_max_idle_secs: Option<u64>,
+    /// Skipped message keys older than this are discarded when a message
+    /// from the device is received.
+    #[serde(default = "default_skipped_key_max_age_secs")]
+    pub skipped_key_max_age_secs: Option<u64>,
+    /// How many recently received message ids are remembered so relay
+    /// redeliveries are reported as duplicates. Older messages the sessions
+    /// have moved past are reported as outside retention instead. `0`
+    /// disables the check, leaving every such message a duplicate.
+    #[serde(default = "default_seen_message_id_limit")]
+    pub seen_message_id_limit: usize,
+    /// How long a rotated-out local invite keeps accepting responses.
+    #[serde(default = "default_retired_invite_grace_secs")]
+    pub retired_invite_grace_secs: u64,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            stale_device_grace_secs: 0,
+            inactive_session_max_idle_secs: None,
+            skipped_key_max_age_secs: default_skipped_key_max_age_secs(),
+            seen_message_id_limit: DEFAULT_SEEN_MESSAGE_ID_LIMIT,
+            retired_invite_grace_secs: DEFAULT_RETIRED_INVITE_GRACE_SECS,
+        }
+    }
+}
+
+impl RetentionPolicy {
+    /// Keep stale devices for a grace period and expire idle inactive
+    /// sessions, using the `DEFAULT_*` retention constants.
+    pub fn recommended() -> Self {
+        Self {
+            stale_device_grace_secs: DEFAULT_STALE_DEVICE_GRACE_SECS,
+            inactive_session_max_idle_secs: Some(DEFAULT_INACTIVE_SESSION_MAX_IDLE_SECS),
+            ..Self::default()
+        }
+    }
+}
+
+fn default_skipped_key_max_age_secs() -> Option<u64> {
+    Some(DEFAULT_SKIPPED_KEY_MAX_AGE_SECS)
+}
+
+fn default_seen_message_id_limit() -> usize {
+    DEFAULT_SEEN_MESSAGE_ID_LIMIT
+}
+
+fn default_retired_invite_grace_secs() -> u64 {
+    DEFAULT_RETIRED_INVITE_GRACE_SECS
+}
+
+fn default_decrypt_failure_threshold() -> Option<u32> {
+    Some(DEFAULT_DECRYPT_FAILURE_THRESHOLD)
+}
+
+/// Recently received message ids as a ring buffer with a set for lookups.
+/// Ids are numbered consecutively, starting at `first_seq` for the oldest.
+#[derive(Debug, Clone, Default)]
+struct SeenMessageIds {
+    first_seq: u64,
+    order: VecDeque<String>,
+    lookup: BTreeSet<String>,
+    /// Values of `first_seq` and the next sequence number when changes were
+    /// last drained.
+    saved_first_seq: u64,
+    saved_next_seq: u64,
+}
+
+impl SeenMessageIds {
+    fn restore(first_seq: u64, ids: Vec<String>) -> Self {
+        let lookup = ids.iter().cloned().collect();
+        let order: VecDeque<String> = ids.into();
+        let next_seq = first_seq + order.len() as u64;
+        Self {
+            first_seq,
+            order,
+            lookup,
+            saved_first_seq: first_seq,
+            saved_next_seq: next_seq,
+        }
+    }
+
+    fn next_seq(&self) -> u64 {
+        self.first_seq + self.order.len() as u64
+    }
+
+    fn push(&mut self, id: String) {
+        if self.lookup.insert(id.clone()) {
+            self.order.push_back(id);
+        }
+    }
+
+    fn trim(&mut self, limit: usize) {
+        while self.order.len() > limit {
+            if let Some(id) = self.order.pop_front() {
+                self.lookup.remove(&id);
+            }
+            self.first_seq += 1;
+        }
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.first_seq != self.saved_first_seq || self.next_seq() != self.saved_next_seq
+    }
+
+    fn take_changes(&mut self) -> Option<SeenMessageIdChanges> {
+        if !self.is_dirty() {
+            return None;
+        }
+        let appended_from = self.saved_next_seq.max(self.first_seq);
+        let changes = SeenMessageIdChanges {
+            previous_first_seq: self.saved_first_seq,
+            first_seq: self.first_seq,
+            appended_from,
+            appended: self
+                .order
+                .iter()
+                .skip((appended_from - self.first_seq) as usize)
+                .cloned()
+                .collect(),
+        };
+        self.saved_first_seq = self.first_seq;
+        self.saved_next_seq = self.next_seq();
+        Some(changes)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -22,6 +183,8 @@ struct UserRecord {
     owner_pubkey: OwnerPubkey,
     roster: Option<DeviceRoster>,
     devices: BTreeMap<DevicePubkey, DeviceRecord>,
+    verified_fingerprint: Option<String>,
+    roster_fingerprint: OnceLock<OwnerFingerprint>,
 }
 
 #[derive(Debug, Clone)]
@@ -37,6 +200,7 @@ struct DeviceRecord {
     inactive_sessions: Vec<Session>,
     last_activity: Option<UnixSeconds>,
     created_at: UnixSeconds,
+    decrypt_failures: u32,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -44,7 +208,25 @@ pub struct SessionManagerSnapshot {
     pub local_owner_pubkey: OwnerPubkey,
     pub local_device_pubkey: DevicePubkey,
     pub local_invite: Option<Invite>,
+    /// Rotated-out local invites still accepting responses until they expire.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub retired_local_invites: Vec<Invite>,
     pub users: Vec<UserRecordSnapshot>,
+    /// Ids of recently received messages, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub seen_message_ids: Vec<String>,
+    /// Sequence number of the first entry in `seen_message_ids`. Ids are
+    /// numbered consecutively so stores can persist them in chunks.
+    #[serde(default, skip_serializing_if = "is_zero_seq")]
+    pub seen_message_id_offset: u64,
+    #[serde(default)]
+    pub retention_policy: RetentionPolicy,
+    #[serde(default)]
+    pub session_limits: SessionLimits,
+    #[serde(default)]
+    pub payload_padding: PayloadPadding,
+    #[serde(default = "default_decrypt_failure_threshold")]
+    pub decrypt_failure_threshold: Option<u32>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -52,6 +234,11 @@ pub struct UserRecordSnapshot {
     pub owner_pubkey: OwnerPubkey,
     pub roster: Option<DeviceRoster>,
     pub devices: Vec<DeviceRecordSnapshot>,
+    /// [`SafetyNumber::qr_payload`] the user verified out of band. It covers
+    /// both rosters, so verification lapses once either side's roster
+    /// changes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verified_fingerprint: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -69,6 +256,63 @@ pub struct DeviceRecordSnapshot {
     pub inactive_sessions: Vec<SessionState>,
     pub last_activity: Option<UnixSeconds>,
     pub created_at: UnixSeconds,
+    /// Consecutive messages from this device whose header could not be
+    /// decrypted.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub decrypt_failures: u32,
+}
+
+/// State changed since the last [`SessionManager::take_changes`], for hosts
+/// that persist user records individually instead of whole snapshots.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionManagerChanges {
+    /// The local invite, when it was created or updated.
+    pub local_invite: Option<Invite>,
+    /// Set when the local invite was revoked and must be deleted.
+    pub local_invite_removed: bool,
+    /// The full retired local invite list, when the local invite changed.
+    pub retired_local_invites: Option<Vec<Invite>>,
+    pub upserted_users: Vec<UserRecordSnapshot>,
+    pub removed_users: Vec<OwnerPubkey>,
+    /// Seen message ids added or evicted, when either happened.
+    pub seen_message_ids: Option<SeenMessageIdChanges>,
+    /// Manager-wide settings, when any of them was changed.
+    pub settings: Option<SessionManagerSettings>,
+}
+
+/// Settings a [`SessionManager`] persists alongside its user records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionManagerSettings {
+    pub retention_policy: RetentionPolicy,
+    pub session_limits: SessionLimits,
+    pub payload_padding: PayloadPadding,
+    pub decrypt_failure_threshold: Option<u32>,
+}
+
+impl Default for SessionManagerSettings {
+    fn default() -> Self {
+        Self {
+            retention_policy: RetentionPolicy::default(),
+            session_limits: SessionLimits::default(),
+            payload_padding: PayloadPadding::default(),
+            decrypt_failure_threshold: default_decrypt_failure_threshold(),
+        }
+    }
+}
+
+/// Seen message ids added or evicted since the previous
+/// [`SessionManager::take_changes`]. Ids are numbered consecutively, so a
+/// store can append them without rewriting the whole list.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SeenMessageIdChanges {
+    /// Sequence number of the oldest id at the previous drain.
+    pub previous_first_seq: u64,
+    /// Sequence number of the oldest id still remembered; earlier ones were
+    /// evicted.
+    pub first_seq: u64,
+    /// Sequence number of the first entry in `appended`.
+    pub appended_from: u64,
+    pub appended: Vec<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -99,6 +343,18 @@ pub struct ReceivedMessage {
     pub owner_pubkey: OwnerPubkey,
     pub device_pubkey: DevicePubkey,
     pub payload: Vec<u8>,
+    /// Set for protocol control messages. Their payload is empty and must
+    /// not be shown to the user.
+    pub control: Option<SessionControl>,
+}
+
+/// A device whose sessions were closed after repeated decrypt failures.
+/// The next send to it re-handshakes through its public invite.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrokenSession {
+    pub owner_pubkey: OwnerPubkey,
+    pub device_pubkey: DevicePubkey,
+    pub decrypt_failures: u32,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
@@ -116,6 +372,25 @@ pub enum RelayGap {
 pub struct PruneReport {
     pub removed_devices: Vec<(OwnerPubkey, DevicePubkey)>,
     pub removed_users: Vec<OwnerPubkey>,
+    #[serde(default)]
+    pub removed_sessions: Vec<PrunedSessions>,
+    #[serde(default)]
+    pub removed_skipped_keys: Vec<PrunedSkippedKey>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PrunedSessions {
+    pub owner_pubkey: OwnerPubkey,
+    pub device_pubkey: DevicePubkey,
+    pub inactive_sessions: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PrunedSkippedKey {
+    pub owner_pubkey: OwnerPubkey,
+    pub device_pubkey: DevicePubkey,
+    pub sender: DevicePubkey,
+    pub message_number: u32,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -124,6 +399,14 @@ struct TargetDevice {
     device_pubkey: DevicePubkey,
 }
 
+/// Manager-wide settings pushed onto each session before it is used.
+#[derive(Debug, Clone, Copy)]
+struct SessionSettings {
+    skipped_key_max_age_secs: Option<u64>,
+    limits: SessionLimits,
+    padding: PayloadPadding,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum SendSessionSource {
     Active,
@@ -138,9 +421,23 @@ impl SessionManager {
         Self {
             local_owner_pubkey,
             local_device_pubkey,
-            local_device_secret_key,
+            local_device_secret_key: SecretBytes::new(local_device_secret_key),
             local_invite: None,
+            retired_local_invites: Vec::new(),
             users: BTreeMap::new(),
+            sender_index: BTreeMap::new(),
+            indexed_senders: BTreeMap::new(),
+            retention_policy: RetentionPolicy::default(),
+            session_limits: SessionLimits::default(),
+            payload_padding: PayloadPadding::default(),
+            decrypt_failure_threshold: Some(DEFAULT_DECRYPT_FAILURE_THRESHOLD),
+            invite_pow_difficulty: None,
+            broken_sessions: Vec::new(),
+            verified_roster_changes: Vec::new(),
+            seen_message_ids: SeenMessageIds::default(),
+            dirty_users: BTreeSet::new(),
+            local_invite_dirty: false,
+            settings_dirty: false,
         }
     }
 
@@ -157,20 +454,42 @@ impl SessionManager {
             .into());
         }
 
-        let users = snapshot
+        let users: BTreeMap<OwnerPubkey, UserRecord> = snapshot
             .users
             .into_iter()
             .map(UserRecord::from_snapshot)
             .map(|record| (record.owner_pubkey, record))
             .collect();
-
-        Ok(Self {
+        let invite_pow_difficulty = snapshot
+            .local_invite
+            .as_ref()
+            .and_then(|invite| invite.pow_difficulty);
+        let mut manager = Self {
             local_owner_pubkey: snapshot.local_owner_pubkey,
             local_device_pubkey: snapshot.local_device_pubkey,
-            local_device_secret_key,
+            local_device_secret_key: SecretBytes::new(local_device_secret_key),
             local_invite: snapshot.local_invite,
+            retired_local_invites: snapshot.retired_local_invites,
             users,
-        })
+            sender_index: BTreeMap::new(),
+            indexed_senders: BTreeMap::new(),
+            retention_policy: snapshot.retention_policy,
+            session_limits: snapshot.session_limits,
+            payload_padding: snapshot.payload_padding,
+            decrypt_failure_threshold: snapshot.decrypt_failure_threshold,
+            invite_pow_difficulty,
+            broken_sessions: Vec::new(),
+            verified_roster_changes: Vec::new(),
+            seen_message_ids: SeenMessageIds::restore(
+                snapshot.seen_message_id_offset,
+                snapshot.seen_message_ids,
+            ),
+            dirty_users: BTreeSet::new(),
+            local_invite_dirty: false,
+            settings_dirty: false,
+        };
+        manager.rebuild_sender_index();
+        Ok(manager)
     }
 
     pub fn snapshot(&self) -> SessionManagerSnapshot {
@@ -178,36 +497,306 @@ impl SessionManager {
             local_owner_pubkey: self.local_owner_pubkey,
             local_device_pubkey: self.local_device_pubkey,
             local_invite: self.local_invite.clone(),
+            retired_local_invites: self.retired_local_invites.clone(),
             users: self.users.values().map(UserRecord::snapshot).collect(),
+            seen_message_ids: self.seen_message_ids.order.iter().cloned().collect(),
+            seen_message_id_offset: self.seen_message_ids.first_seq,
+            retention_policy: self.retention_policy,
+            session_limits: self.session_limits,
+            payload_padding: self.payload_padding,
+            decrypt_failure_threshold: self.decrypt_failure_threshold,
+        }
+    }
+
+    pub fn has_pending_changes(&self) -> bool {
+        self.local_invite_dirty
+            || self.seen_message_ids.is_dirty()
+            || self.settings_dirty
+            || !self.dirty_users.is_empty()
+    }
+
+    /// Drain the set of records touched since the previous call. A record may
+    /// be reported even if an operation left it unchanged.
+    pub fn take_changes(&mut self) -> SessionManagerChanges {
+        let local_invite_dirty = std::mem::take(&mut self.local_invite_dirty);
+        let local_invite = local_invite_dirty
+            .then(|| self.local_invite.clone())
+            .flatten();
+        let local_invite_removed = local_invite_dirty && self.local_invite.is_none();
+        let retired_local_invites = local_invite_dirty.then(|| self.retired_local_invites.clone());
+        let seen_message_ids = self.seen_message_ids.take_changes();
+        let settings = std::mem::take(&mut self.settings_dirty).then_some(SessionManagerSettings {
+            retention_policy: self.retention_policy,
+            session_limits: self.session_limits,
+            payload_padding: self.payload_padding,
+            decrypt_failure_threshold: self.decrypt_failure_threshold,
+        });
+        let mut upserted_users = Vec::new();
+        let mut removed_users = Vec::new();
+        for owner_pubkey in std::mem::take(&mut self.dirty_users) {
+            match self.users.get(&owner_pubkey) {
+                Some(user) => upserted_users.push(user.snapshot()),
+                None => removed_users.push(owner_pubkey),
+            }
+        }
+
+        SessionManagerChanges {
+            local_invite,
+            local_invite_removed,
+            retired_local_invites,
+            upserted_users,
+            removed_users,
+            seen_message_ids,
+            settings,
         }
     }
 
+    pub fn local_owner_pubkey(&self) -> OwnerPubkey {
+        self.local_owner_pubkey
+    }
+
     pub fn local_device_pubkey(&self) -> DevicePubkey {
         self.local_device_pubkey
     }
 
+    pub fn retention_policy(&self) -> RetentionPolicy {
+        self.retention_policy
+    }
+
+    pub fn set_retention_policy(&mut self, policy: RetentionPolicy) {
+        self.retention_policy = policy;
+        self.settings_dirty = true;
+        self.trim_seen_message_ids();
+    }
+
+    pub fn session_limits(&self) -> SessionLimits {
+        self.session_limits
+    }
+
+    /// Limits applied to every session when it next receives.
+    pub fn set_session_limits(&mut self, limits: SessionLimits) {
+        self.session_limits = limits;
+        self.settings_dirty = true;
+    }
+
+    pub fn payload_padding(&self) -> PayloadPadding {
+        self.payload_padding
+    }
+
+    /// Padding mode applied to every session when it next sends or receives.
+    pub fn set_payload_padding(&mut self, padding: PayloadPadding) {
+        self.payload_padding = padding;
+        self.settings_dirty = true;
+    }
+
+    pub fn decrypt_failure_threshold(&self) -> Option<u32> {
+        self.decrypt_failure_threshold
+    }
+
+    /// Close a device's sessions once this many consecutive messages from it
+    /// have headers that no session can decrypt. Messages that skip too far
+    /// ahead are not counted: an honest peer produces them after a long
+    /// offline period. `None` never closes them.
+    pub fn set_decrypt_failure_threshold(&mut self, threshold: Option<u32>) {
+        self.decrypt_failure_threshold = threshold;
+        self.settings_dirty = true;
+    }
+
+    pub fn invite_pow_difficulty(&self) -> Option<u8> {
+        self.invite_pow_difficulty
+    }
+
+    /// NIP-13 difficulty demanded from responders to local invites created
+    /// from now on. The current invite keeps its difficulty until rotated.
+    pub fn set_invite_pow_difficulty(&mut self, difficulty: Option<u8>) -> Result<()> {
+        if difficulty.is_some_and(|difficulty| difficulty > MAX_INVITE_POW_DIFFICULTY) {
+            return Err(DomainError::InvalidState(format!(
+                "invite proof of work difficulty above {MAX_INVITE_POW_DIFFICULTY}"
+            ))
+            .into());
+        }
+        self.invite_pow_difficulty = difficulty;
+        Ok(())
+    }
+
+    /// Devices whose sessions were closed as broken since the last call.
+    pub fn take_broken_sessions(&mut self) -> Vec<BrokenSession> {
+        std::mem::take(&mut self.broken_sessions)
+    }
+
+    /// Safety number for the local owner and `owner_pubkey`, once both
+    /// device rosters are known.
+    pub fn safety_number(&self, owner_pubkey: OwnerPubkey) -> Option<SafetyNumber> {
+        let local = self
+            .users
+            .get(&self.local_owner_pubkey)?
+            .roster_fingerprint()?;
+        let remote = self.users.get(&owner_pubkey)?.roster_fingerprint()?;
+        Some(SafetyNumber::from_fingerprints(
+            self.local_owner_pubkey,
+            local,
+            owner_pubkey,
+            remote,
+        ))
+    }
+
+    /// Record that the user compared safety numbers with `owner_pubkey`.
+    /// Pins the current safety number, which covers both rosters.
+    pub fn mark_owner_verified(&mut self, owner_pubkey: OwnerPubkey) -> Result<()> {
+        let pin = self
+            .safety_number(owner_pubkey)
+            .map(|number| number.qr_payload())
+            .ok_or_else(|| DomainError::InvalidState("owner has no known roster".to_string()))?;
+        self.user_record_mut(owner_pubkey).verified_fingerprint = Some(pin);
+        Ok(())
+    }
+
+    pub fn clear_owner_verification(&mut self, owner_pubkey: OwnerPubkey) {
+        if self
+            .users
+            .get(&owner_pubkey)
+            .is_some_and(|user| user.verified_fingerprint.is_some())
+        {
+            self.user_record_mut(owner_pubkey).verified_fingerprint = None;
+        }
+    }
+
+    /// Whether the owner was verified and neither roster has changed since.
+    pub fn is_owner_verified(&self, owner_pubkey: OwnerPubkey) -> bool {
+        self.users
+            .get(&owner_pubkey)
+            .and_then(|user| user.verified_fingerprint.as_deref())
+            .is_some_and(|pin| {
+                self.safety_number(owner_pubkey)
+                    .is_some_and(|number| number.qr_payload() == pin)
+            })
+    }
+
+    /// Verified owners whose safety number changed since the last call,
+    /// because either their roster or the local one changed. They stay
+    /// unverified until [`mark_owner_verified`](Self::mark_owner_verified) is
+    /// called again.
+    pub fn take_verified_roster_changes(&mut self) -> Vec<OwnerPubkey> {
+        std::mem::take(&mut self.verified_roster_changes)
+    }
+
+    pub fn local_invite(&self) -> Option<&Invite> {
+        self.local_invite.as_ref()
+    }
+
+    pub fn known_owner_pubkeys(&self) -> Vec<OwnerPubkey> {
+        self.users.keys().copied().collect()
+    }
+
+    pub fn roster(&self, owner_pubkey: OwnerPubkey) -> Option<&DeviceRoster> {
+        self.users
+            .get(&owner_pubkey)
+            .and_then(|user| user.roster.as_ref())
+    }
+
     pub fn replace_local_invite(&mut self, invite: Invite) {
         self.local_invite = Some(invite);
+        self.local_invite_dirty = true;
     }
 
+    /// Create the local invite if there is none. An expired invite is
+    /// rotated, and the replacement keeps its lifetime.
     pub fn ensure_local_invite<R>(&mut self, ctx: &mut ProtocolContext<'_, R>) -> Result<&Invite>
     where
         R: RngCore + CryptoRng,
     {
-        if self.local_invite.is_none() {
-            let invite = Invite::create_new_with_context(
-                ctx,
-                self.local_device_pubkey,
-                Some(self.local_owner_pubkey),
-                None,
-            )?;
-            self.observe_public_invite(self.local_owner_pubkey, invite.clone())?;
-            self.local_invite = Some(invite);
+        match self.local_invite.as_ref() {
+            None => {
+                let mut invite = Invite::create_new_with_context(
+                    ctx,
+                    self.local_device_pubkey,
+                    Some(self.local_owner_pubkey),
+                    None,
+                )?;
+                invite.pow_difficulty = self.invite_pow_difficulty;
+                self.observe_public_invite(self.local_owner_pubkey, invite.clone())?;
+                self.local_invite = Some(invite);
+                self.local_invite_dirty = true;
+            }
+            Some(invite) if invite.is_expired(ctx.now) => {
+                let expires_at = invite.expires_at.map(|expires_at| {
+                    let ttl = expires_at.get().saturating_sub(invite.created_at.get());
+                    UnixSeconds(ctx.now.get().saturating_add(ttl))
+                });
+                self.rotate_local_invite(ctx, expires_at)?;
+            }
+            Some(_) => {}
+        }
+
+        Ok(self.local_invite.as_ref().expect("local invite must exist"))
+    }
+
+    /// Replace the local invite with a fresh one. The previous invite keeps
+    /// accepting responses for
+    /// [`retired_invite_grace_secs`](RetentionPolicy::retired_invite_grace_secs)
+    /// so acceptances already in flight are not lost.
+    pub fn rotate_local_invite<R>(
+        &mut self,
+        ctx: &mut ProtocolContext<'_, R>,
+        expires_at: Option<UnixSeconds>,
+    ) -> Result<&Invite>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let mut invite = Invite::create_new_with_context(
+            ctx,
+            self.local_device_pubkey,
+            Some(self.local_owner_pubkey),
+            None,
+        )?;
+        invite.expires_at = expires_at;
+        invite.pow_difficulty = self.invite_pow_difficulty;
+        self.observe_public_invite(self.local_owner_pubkey, invite.clone())?;
+
+        if let Some(mut previous) = self.local_invite.replace(invite) {
+            let grace_end = UnixSeconds(
+                ctx.now
+                    .get()
+                    .saturating_add(self.retention_policy.retired_invite_grace_secs),
+            );
+            previous.expires_at = Some(
+                previous
+                    .expires_at
+                    .map_or(grace_end, |expires_at| expires_at.min(grace_end)),
+            );
+            if !previous.is_expired(ctx.now) {
+                self.retired_local_invites.push(previous);
+            }
         }
+        self.local_invite_dirty = true;
 
         Ok(self.local_invite.as_ref().expect("local invite must exist"))
     }
 
+    /// Withdraw the local invite. It is returned marked revoked so the host
+    /// can publish a revocation event for it; the next
+    /// [`ensure_local_invite`](Self::ensure_local_invite) creates a new one.
+    /// Retired invites still in their grace window are dropped too, so no
+    /// earlier invite keeps accepting responses.
+    pub fn revoke_local_invite(&mut self, now: UnixSeconds) -> Result<Option<Invite>> {
+        if !self.retired_local_invites.is_empty() {
+            self.retired_local_invites.clear();
+            self.local_invite_dirty = true;
+        }
+        let Some(mut invite) = self.local_invite.take() else {
+            return Ok(None);
+        };
+        invite.revoke(now);
+        self.local_invite_dirty = true;
+        self.observe_public_invite(self.local_owner_pubkey, invite.clone())?;
+        Ok(Some(invite))
+    }
+
+    /// Rotated-out local invites whose grace window has not ended yet.
+    pub fn retired_local_invites(&self) -> &[Invite] {
+        &self.retired_local_invites
+    }
+
     pub fn apply_local_roster(&mut self, roster: DeviceRoster) -> RosterSnapshotDecision {
         self.apply_roster_for_owner(self.local_owner_pubkey, roster)
     }
@@ -240,19 +829,33 @@ impl SessionManager {
     where
         R: RngCore + CryptoRng,
     {
-        let Some(invite) = self.local_invite.clone() else {
+        let retired_index = self
+            .retired_local_invites
+            .iter()
+            .position(|invite| invite.inviter_ephemeral_public_key == envelope.recipient);
+        let Some(invite) = (match retired_index {
+            Some(index) => self.retired_local_invites.get_mut(index),
+            None => self.local_invite.as_mut(),
+        }) else {
             return Ok(None);
         };
 
-        let mut owned_invite = invite;
+        invite.check_response_pow(envelope)?;
+
+        let mut owned_invite = invite.clone();
         let InviteResponse {
             session,
             invitee_device_pubkey,
             invitee_owner_pubkey,
             ..
-        } = owned_invite.process_response(ctx, envelope, self.local_device_secret_key)?;
+        } = owned_invite.process_response(
+            ctx,
+            envelope,
+            *self.local_device_secret_key.expose_secret(),
+        )?;
 
-        self.local_invite = Some(owned_invite);
+        *invite = owned_invite;
+        self.local_invite_dirty = true;
 
         let device_owner_pubkey = crate::owner_pubkey_from_device_pubkey(invitee_device_pubkey);
         let invitee_owner_pubkey = invitee_owner_pubkey.ok_or_else(|| {
@@ -277,7 +880,7 @@ impl SessionManager {
                 .is_none();
         let user = self.user_record_mut(owner_pubkey);
         if should_seed_single_device_roster {
-            user.roster = Some(DeviceRoster::new(
+            user.set_roster(DeviceRoster::new(
                 ctx.now,
                 vec![AuthorizedDevice::new(invitee_device_pubkey, ctx.now)],
             ));
@@ -291,14 +894,21 @@ impl SessionManager {
         }
         record.invite_response_generated = true;
         record.upsert_session(session, ctx.now);
+        let claimed_owner_pubkey = record.claimed_owner_pubkey;
+        self.reindex_device(owner_pubkey, invitee_device_pubkey);
 
         Ok(Some(ProcessedInviteResponse {
             owner_pubkey,
             device_pubkey: invitee_device_pubkey,
-            claimed_owner_pubkey: record.claimed_owner_pubkey,
+            claimed_owner_pubkey,
         }))
     }
 
+    /// Prepare a send to every authorized device of the recipient owner and
+    /// to local sibling devices. Devices without a session are reached
+    /// through their invite; when it demands proof of work, the response is
+    /// mined here, which can take up to 2^[`MAX_INVITE_POW_DIFFICULTY`]
+    /// hashes per device.
     pub fn prepare_send<R>(
         &mut self,
         ctx: &mut ProtocolContext<'_, R>,
@@ -459,105 +1069,534 @@ impl SessionManager {
         })
     }
 
-    fn prepare_local_sibling_send_inner<R>(
+    fn prepare_local_sibling_send_inner<R>(
+        &mut self,
+        ctx: &mut ProtocolContext<'_, R>,
+        payload: Vec<u8>,
+        refresh_one_way_bootstrap: bool,
+    ) -> Result<PreparedSend>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let mut targets = BTreeSet::new();
+        self.collect_local_sibling_targets(&mut targets);
+
+        let mut deliveries = Vec::new();
+        let mut invite_responses = Vec::new();
+        let mut relay_gaps = Vec::new();
+
+        for target in targets {
+            match self.prepare_device_delivery(
+                ctx,
+                target.owner_pubkey,
+                target.device_pubkey,
+                &payload,
+                refresh_one_way_bootstrap,
+            )? {
+                Some((delivery, maybe_response)) => {
+                    deliveries.push(delivery);
+                    if let Some(response) = maybe_response {
+                        invite_responses.push(response);
+                    }
+                }
+                None => {
+                    relay_gaps.push(RelayGap::MissingDeviceInvite {
+                        owner_pubkey: target.owner_pubkey,
+                        device_pubkey: target.device_pubkey,
+                    });
+                }
+            }
+        }
+
+        relay_gaps.sort();
+
+        Ok(PreparedSend {
+            recipient_owner: self.local_owner_pubkey,
+            payload,
+            deliveries,
+            invite_responses,
+            relay_gaps,
+        })
+    }
+
+    pub(crate) fn has_authorized_local_siblings(&self) -> bool {
+        let Some(user) = self.users.get(&self.local_owner_pubkey) else {
+            return false;
+        };
+        if user.roster.is_none() {
+            return false;
+        }
+        user.authorized_non_stale_devices()
+            .into_iter()
+            .any(|device_pubkey| device_pubkey != self.local_device_pubkey)
+    }
+
+    pub fn receive<R>(
+        &mut self,
+        ctx: &mut ProtocolContext<'_, R>,
+        sender_owner: OwnerPubkey,
+        envelope: &MessageEnvelope,
+    ) -> Result<Option<ReceivedMessage>>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let Some(user) = self.users.get(&sender_owner) else {
+            return Ok(None);
+        };
+
+        let device_pubkeys: Vec<DevicePubkey> = user.devices.keys().copied().collect();
+        for device_pubkey in device_pubkeys {
+            if let Some(received) =
+                self.receive_from_device(ctx, sender_owner, device_pubkey, envelope)?
+            {
+                return Ok(Some(received));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Ask every device of `owner_pubkey` we have a sending session with to
+    /// reply with a key update. Devices without a session, or whose session
+    /// has not advertised control message support, are skipped.
+    pub fn prepare_refresh<R>(
+        &mut self,
+        ctx: &mut ProtocolContext<'_, R>,
+        owner_pubkey: OwnerPubkey,
+    ) -> Result<PreparedSend>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let device_pubkeys: Vec<DevicePubkey> = self
+            .users
+            .get(&owner_pubkey)
+            .map(|user| user.devices.keys().copied().collect())
+            .unwrap_or_default();
+        let mut deliveries = Vec::new();
+        for device_pubkey in device_pubkeys {
+            deliveries.extend(self.prepare_control_delivery(
+                ctx,
+                owner_pubkey,
+                device_pubkey,
+                SessionControl::RefreshRequest,
+            )?);
+        }
+
+        Ok(PreparedSend {
+            recipient_owner: owner_pubkey,
+            payload: Vec::new(),
+            deliveries,
+            invite_responses: Vec::new(),
+            relay_gaps: Vec::new(),
+        })
+    }
+
+    /// Answer a [`SessionControl::RefreshRequest`] received from one device.
+    pub fn prepare_key_update<R>(
+        &mut self,
+        ctx: &mut ProtocolContext<'_, R>,
+        owner_pubkey: OwnerPubkey,
+        device_pubkey: DevicePubkey,
+    ) -> Result<PreparedSend>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let deliveries = self
+            .prepare_control_delivery(ctx, owner_pubkey, device_pubkey, SessionControl::KeyUpdate)?
+            .into_iter()
+            .collect();
+        Ok(PreparedSend {
+            recipient_owner: owner_pubkey,
+            payload: Vec::new(),
+            deliveries,
+            invite_responses: Vec::new(),
+            relay_gaps: Vec::new(),
+        })
+    }
+
+    /// Tell one device to discard its session with us, then close every
+    /// local session with it. The next send re-handshakes through the
+    /// device's public invite.
+    ///
+    /// Fails without closing anything when no session can carry the reset:
+    /// `SessionNotReady` if there is no sending session, or
+    /// `ControlNotSupported` if the peer has not advertised control support.
+    pub fn prepare_reset<R>(
+        &mut self,
+        ctx: &mut ProtocolContext<'_, R>,
+        owner_pubkey: OwnerPubkey,
+        device_pubkey: DevicePubkey,
+    ) -> Result<PreparedSend>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let Some(delivery) =
+            self.prepare_control_delivery(ctx, owner_pubkey, device_pubkey, SessionControl::Reset)?
+        else {
+            let has_send_session = self
+                .users
+                .get(&owner_pubkey)
+                .and_then(|user| user.devices.get(&device_pubkey))
+                .is_some_and(|record| record.best_send_session_source().is_some());
+            return Err(if has_send_session {
+                DomainError::ControlNotSupported
+            } else {
+                DomainError::SessionNotReady
+            }
+            .into());
+        };
+        if let Some(record) = self.known_device_record_mut(owner_pubkey, device_pubkey) {
+            record.close_sessions();
+            self.dirty_users.insert(owner_pubkey);
+        }
+        Ok(PreparedSend {
+            recipient_owner: owner_pubkey,
+            payload: Vec::new(),
+            deliveries: vec![delivery],
+            invite_responses: Vec::new(),
+            relay_gaps: Vec::new(),
+        })
+    }
+
+    /// Receive a message without knowing the sender owner up front.
+    ///
+    /// The envelope sender is looked up in the index of expected message
+    /// authors, so only devices with a matching session are tried.
+    pub fn receive_any<R>(
+        &mut self,
+        ctx: &mut ProtocolContext<'_, R>,
+        envelope: &MessageEnvelope,
+    ) -> Result<Option<ReceivedMessage>>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let Some(routes) = self.sender_index.get(&envelope.sender).cloned() else {
+            return Ok(None);
+        };
+
+        let mut last_error = None;
+        for route in routes {
+            match self.receive_from_device(ctx, route.owner_pubkey, route.device_pubkey, envelope) {
+                Ok(Some(received)) => return Ok(Some(received)),
+                Ok(None) => {}
+                Err(error) => last_error = Some(error),
+            }
+        }
+
+        match last_error {
+            Some(error) => Err(error),
+            None => Ok(None),
+        }
+    }
+
+    /// Receive many envelopes at once, e.g. when catching up after being
+    /// offline. Envelopes that match a known session are handed to
+    /// [`Session::receive_batch`] together; the rest go through
+    /// [`receive_any`](Self::receive_any) afterwards. Results are returned in
+    /// input order.
+    pub fn receive_batch<R>(
+        &mut self,
+        ctx: &mut ProtocolContext<'_, R>,
+        envelopes: &[MessageEnvelope],
+    ) -> Vec<Result<Option<ReceivedMessage>>>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let settings = self.session_settings();
+        let mut groups: BTreeMap<(TargetDevice, Option<usize>), Vec<usize>> = BTreeMap::new();
+        let mut leftovers = Vec::new();
+        let mut results: Vec<Option<Result<Option<ReceivedMessage>>>> =
+            envelopes.iter().map(|_| None).collect();
+        for (index, envelope) in envelopes.iter().enumerate() {
+            if self.is_seen_message(envelope) {
+                results[index] = Some(Err(DomainError::DuplicateMessage.into()));
+                continue;
+            }
+            match self.batch_session_slot(envelope.sender) {
+                Some(slot) => groups.entry(slot).or_default().push(index),
+                None => leftovers.push(index),
+            }
+        }
+        let mut targets: BTreeMap<usize, TargetDevice> = BTreeMap::new();
+        let mut promoted: BTreeMap<TargetDevice, BTreeSet<usize>> = BTreeMap::new();
+        for ((target, inactive_index), indexes) in groups {
+            let Some(record) =
+                self.known_device_record_mut(target.owner_pubkey, target.device_pubkey)
+            else {
+                continue;
+            };
+            record.configure_sessions(settings);
+            let session = match inactive_index {
+                None => record.active_session.as_mut(),
+                Some(index) => record.inactive_sessions.get_mut(index),
+            }
+            .expect("batch session slot must exist");
+            let batch: Vec<MessageEnvelope> = indexes
+                .iter()
+                .map(|index| envelopes[*index].clone())
+                .collect();
+
+            let mut received_any = false;
+            let mut reset = false;
+            for (index, outcome) in indexes.into_iter().zip(session.receive_batch(ctx, &batch)) {
+                results[index] = Some(outcome.map(|outcome| {
+                    received_any = true;
+                    reset |= outcome.control == Some(SessionControl::Reset);
+                    Some(ReceivedMessage {
+                        owner_pubkey: target.owner_pubkey,
+                        device_pubkey: target.device_pubkey,
+                        payload: outcome.payload,
+                        control: outcome.control,
+                    })
+                }));
+                targets.insert(index, target.clone());
+            }
+            if received_any {
+                record.last_activity = Some(ctx.now);
+                if let Some(index) = inactive_index {
+                    promoted.entry(target.clone()).or_default().insert(index);
+                }
+            }
+            if reset {
+                record.close_sessions();
+            }
+        }
+
+        for (target, inactive_indexes) in promoted {
+            let Some(record) =
+                self.known_device_record_mut(target.owner_pubkey, target.device_pubkey)
+            else {
+                continue;
+            };
+            let sessions: Vec<Session> = inactive_indexes
+                .into_iter()
+                .rev()
+                .map(|index| record.inactive_sessions.remove(index))
+                .collect();
+            for session in sessions {
+                record.promote_inactive_session(session);
+            }
+        }
+        for target in targets.values().collect::<BTreeSet<_>>() {
+            self.dirty_users.insert(target.owner_pubkey);
+            self.reindex_device(target.owner_pubkey, target.device_pubkey);
+        }
+        for (index, target) in &targets {
+            if let Some(received) = results[*index].take() {
+                let received = self.classify_consumed(&envelopes[*index], received);
+                self.track_receive_result(
+                    target.owner_pubkey,
+                    target.device_pubkey,
+                    envelopes[*index].sender,
+                    &received,
+                );
+                if let Ok(Some(_)) = received {
+                    self.remember_message(&envelopes[*index]);
+                }
+                results[*index] = Some(received);
+            }
+        }
+
+        for index in leftovers {
+            results[index] = Some(self.receive_any(ctx, &envelopes[index]));
+        }
+        results
+            .into_iter()
+            .map(|result| result.unwrap_or(Ok(None)))
+            .collect()
+    }
+
+    /// The single device and session an envelope sender maps to, if any.
+    /// `None` in the second position is the active session.
+    fn batch_session_slot(&self, sender: DevicePubkey) -> Option<(TargetDevice, Option<usize>)> {
+        let routes = self.sender_index.get(&sender)?;
+        let [route] = routes.iter().collect::<Vec<_>>()[..] else {
+            return None;
+        };
+        let record = self
+            .users
+            .get(&route.owner_pubkey)?
+            .devices
+            .get(&route.device_pubkey)?;
+        if record
+            .active_session
+            .as_ref()
+            .is_some_and(|session| session.matches_sender(sender))
+        {
+            return Some((route.clone(), None));
+        }
+        record
+            .inactive_sessions
+            .iter()
+            .position(|session| session.matches_sender(sender))
+            .map(|index| (route.clone(), Some(index)))
+    }
+
+    /// All message author pubkeys a relay subscription should watch: the
+    /// current, next, and skipped-key sender keys of every known session.
+    pub fn message_author_pubkeys(&self) -> Vec<DevicePubkey> {
+        self.sender_index.keys().copied().collect()
+    }
+
+    fn receive_from_device<R>(
         &mut self,
         ctx: &mut ProtocolContext<'_, R>,
-        payload: Vec<u8>,
-        refresh_one_way_bootstrap: bool,
-    ) -> Result<PreparedSend>
+        owner_pubkey: OwnerPubkey,
+        device_pubkey: DevicePubkey,
+        envelope: &MessageEnvelope,
+    ) -> Result<Option<ReceivedMessage>>
     where
         R: RngCore + CryptoRng,
     {
-        let mut targets = BTreeSet::new();
-        self.collect_local_sibling_targets(&mut targets);
+        if self.is_seen_message(envelope) {
+            return Err(DomainError::DuplicateMessage.into());
+        }
+        let received = self.receive_from_device_inner(ctx, owner_pubkey, device_pubkey, envelope);
+        let received = self.classify_consumed(envelope, received);
+        self.track_receive_result(owner_pubkey, device_pubkey, envelope.sender, &received);
+        if let Ok(Some(_)) = received {
+            self.remember_message(envelope);
+        }
+        received
+    }
 
-        let mut deliveries = Vec::new();
-        let mut invite_responses = Vec::new();
-        let mut relay_gaps = Vec::new();
+    fn is_seen_message(&self, envelope: &MessageEnvelope) -> bool {
+        self.retention_policy.seen_message_id_limit > 0
+            && self
+                .seen_message_ids
+                .lookup
+                .contains(&envelope.message_id())
+    }
 
-        for target in targets {
-            match self.prepare_device_delivery(
-                ctx,
-                target.owner_pubkey,
-                target.device_pubkey,
-                &payload,
-                refresh_one_way_bootstrap,
-            )? {
-                Some((delivery, maybe_response)) => {
-                    deliveries.push(delivery);
-                    if let Some(response) = maybe_response {
-                        invite_responses.push(response);
-                    }
-                }
-                None => {
-                    relay_gaps.push(RelayGap::MissingDeviceInvite {
-                        owner_pubkey: target.owner_pubkey,
-                        device_pubkey: target.device_pubkey,
-                    });
-                }
+    /// Sessions report every message number they have moved past as a
+    /// duplicate, including late messages whose skipped key was already
+    /// pruned. Only ids still remembered are known replays; anything older
+    /// is reported as outside retention.
+    fn classify_consumed(
+        &self,
+        envelope: &MessageEnvelope,
+        received: Result<Option<ReceivedMessage>>,
+    ) -> Result<Option<ReceivedMessage>> {
+        match received {
+            Err(Error::Domain(DomainError::DuplicateMessage))
+                if self.retention_policy.seen_message_id_limit > 0
+                    && !self.is_seen_message(envelope) =>
+            {
+                Err(DomainError::MessageOutsideRetention.into())
             }
+            received => received,
         }
+    }
 
-        relay_gaps.sort();
+    fn remember_message(&mut self, envelope: &MessageEnvelope) {
+        if self.retention_policy.seen_message_id_limit == 0 {
+            return;
+        }
+        self.seen_message_ids.push(envelope.message_id());
+        self.trim_seen_message_ids();
+    }
 
-        Ok(PreparedSend {
-            recipient_owner: self.local_owner_pubkey,
-            payload,
-            deliveries,
-            invite_responses,
-            relay_gaps,
-        })
+    fn trim_seen_message_ids(&mut self) {
+        self.seen_message_ids
+            .trim(self.retention_policy.seen_message_id_limit);
     }
 
-    pub(crate) fn has_authorized_local_siblings(&self) -> bool {
-        let Some(user) = self.users.get(&self.local_owner_pubkey) else {
-            return false;
+    fn track_receive_result(
+        &mut self,
+        owner_pubkey: OwnerPubkey,
+        device_pubkey: DevicePubkey,
+        sender: DevicePubkey,
+        received: &Result<Option<ReceivedMessage>>,
+    ) {
+        match received {
+            Err(Error::FailedToDecryptHeader) => {
+                self.record_decrypt_failure(owner_pubkey, device_pubkey, sender);
+            }
+            Ok(Some(_)) => {
+                if let Some(record) = self.known_device_record_mut(owner_pubkey, device_pubkey) {
+                    record.decrypt_failures = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Only failures from a ratchet key the device is expected to send
+    /// with count: anyone can relay old or foreign events signed by keys the
+    /// manager subscribes to, and those must not close a healthy session.
+    fn record_decrypt_failure(
+        &mut self,
+        owner_pubkey: OwnerPubkey,
+        device_pubkey: DevicePubkey,
+        sender: DevicePubkey,
+    ) {
+        let threshold = self.decrypt_failure_threshold;
+        let Some(record) = self
+            .known_device_record_mut(owner_pubkey, device_pubkey)
+            .filter(|record| record.expects_sender(sender))
+        else {
+            return;
         };
-        if user.roster.is_none() {
-            return false;
+        record.decrypt_failures = record.decrypt_failures.saturating_add(1);
+        let decrypt_failures = record.decrypt_failures;
+        let broken = threshold.is_some_and(|threshold| decrypt_failures >= threshold)
+            && record.best_send_session_source().is_some();
+        if broken {
+            record.close_sessions();
+            self.broken_sessions.push(BrokenSession {
+                owner_pubkey,
+                device_pubkey,
+                decrypt_failures,
+            });
         }
-        user.authorized_non_stale_devices()
-            .into_iter()
-            .any(|device_pubkey| device_pubkey != self.local_device_pubkey)
+        self.dirty_users.insert(owner_pubkey);
     }
 
-    pub fn receive<R>(
+    fn known_device_record_mut(
+        &mut self,
+        owner_pubkey: OwnerPubkey,
+        device_pubkey: DevicePubkey,
+    ) -> Option<&mut DeviceRecord> {
+        self.users
+            .get_mut(&owner_pubkey)
+            .and_then(|user| user.devices.get_mut(&device_pubkey))
+    }
+
+    fn receive_from_device_inner<R>(
         &mut self,
         ctx: &mut ProtocolContext<'_, R>,
-        sender_owner: OwnerPubkey,
+        owner_pubkey: OwnerPubkey,
+        device_pubkey: DevicePubkey,
         envelope: &MessageEnvelope,
     ) -> Result<Option<ReceivedMessage>>
     where
         R: RngCore + CryptoRng,
     {
-        let Some(user) = self.users.get_mut(&sender_owner) else {
+        let settings = self.session_settings();
+        let Some(record) = self
+            .users
+            .get_mut(&owner_pubkey)
+            .and_then(|user| user.devices.get_mut(&device_pubkey))
+        else {
             return Ok(None);
         };
+        record.configure_sessions(settings);
 
-        let device_pubkeys: Vec<DevicePubkey> = user.devices.keys().copied().collect();
-        for device_pubkey in device_pubkeys {
-            let record = user
-                .devices
-                .get_mut(&device_pubkey)
-                .expect("device key collected from map");
-
-            if let Some(active_session) = record.active_session.as_ref() {
-                if active_session.matches_sender(envelope.sender) {
-                    let plan = active_session.plan_receive(ctx, envelope)?;
-                    let outcome = record
-                        .active_session
-                        .as_mut()
-                        .expect("active session must still exist")
-                        .apply_receive(plan);
-                    record.last_activity = Some(ctx.now);
-                    return Ok(Some(ReceivedMessage {
-                        owner_pubkey: sender_owner,
-                        device_pubkey,
-                        payload: outcome.payload,
-                    }));
-                }
+        let mut received = None;
+        if let Some(active_session) = record.active_session.as_ref() {
+            if active_session.matches_sender(envelope.sender) {
+                let plan = active_session.plan_receive(ctx, envelope)?;
+                let outcome = record
+                    .active_session
+                    .as_mut()
+                    .expect("active session must still exist")
+                    .apply_receive(plan);
+                record.last_activity = Some(ctx.now);
+                received = Some(outcome);
             }
+        }
 
+        if received.is_none() {
             let mut matched_inactive = None;
             for (index, session) in record.inactive_sessions.iter().enumerate() {
                 if !session.matches_sender(envelope.sender) {
@@ -573,30 +1612,68 @@ impl SessionManager {
                 let outcome = session.apply_receive(plan);
                 record.promote_inactive_session(session);
                 record.last_activity = Some(ctx.now);
-                return Ok(Some(ReceivedMessage {
-                    owner_pubkey: sender_owner,
-                    device_pubkey,
-                    payload: outcome.payload,
-                }));
+                received = Some(outcome);
             }
         }
 
-        Ok(None)
+        let Some(outcome) = received else {
+            return Ok(None);
+        };
+        if outcome.control == Some(SessionControl::Reset) {
+            record.close_sessions();
+        }
+        self.dirty_users.insert(owner_pubkey);
+        self.reindex_device(owner_pubkey, device_pubkey);
+        Ok(Some(ReceivedMessage {
+            owner_pubkey,
+            device_pubkey,
+            payload: outcome.payload,
+            control: outcome.control,
+        }))
     }
 
-    pub fn prune_stale(&mut self, _now: UnixSeconds) -> PruneReport {
+    pub fn prune_stale(&mut self, now: UnixSeconds) -> PruneReport {
+        let policy = self.retention_policy;
+        let max_skipped_keys_per_device = self.session_limits.max_skipped_keys_per_device;
         let mut removed_devices = Vec::new();
         let mut removed_users = Vec::new();
+        let mut removed_sessions = Vec::new();
+        let mut removed_skipped_keys = Vec::new();
 
         self.users.retain(|owner_pubkey, user| {
             user.devices.retain(|device_pubkey, record| {
-                let keep = !record.is_stale;
+                let keep = !record.stale_grace_expired(now, policy.stale_device_grace_secs);
                 if !keep {
                     removed_devices.push((*owner_pubkey, *device_pubkey));
                 }
                 keep
             });
 
+            for (device_pubkey, record) in user.devices.iter_mut() {
+                if let Some(max_idle) = policy.inactive_session_max_idle_secs {
+                    let inactive_sessions = record.expire_idle_inactive_sessions(now, max_idle);
+                    if inactive_sessions > 0 {
+                        removed_sessions.push(PrunedSessions {
+                            owner_pubkey: *owner_pubkey,
+                            device_pubkey: *device_pubkey,
+                            inactive_sessions,
+                        });
+                    }
+                }
+                if let Some(max_skipped_keys) = max_skipped_keys_per_device {
+                    removed_skipped_keys.extend(
+                        record.cap_skipped_keys(max_skipped_keys).into_iter().map(
+                            |(sender, message_number)| PrunedSkippedKey {
+                                owner_pubkey: *owner_pubkey,
+                                device_pubkey: *device_pubkey,
+                                sender,
+                                message_number,
+                            },
+                        ),
+                    );
+                }
+            }
+
             let keep_user = !user.devices.is_empty() || user.roster.is_some();
             if !keep_user {
                 removed_users.push(*owner_pubkey);
@@ -606,16 +1683,42 @@ impl SessionManager {
 
         removed_devices.sort();
         removed_users.sort();
+        removed_sessions.sort();
+        removed_skipped_keys.sort();
+        self.dirty_users.extend(
+            removed_devices
+                .iter()
+                .map(|(owner_pubkey, _)| *owner_pubkey)
+                .chain(removed_users.iter().copied())
+                .chain(removed_sessions.iter().map(|pruned| pruned.owner_pubkey))
+                .chain(
+                    removed_skipped_keys
+                        .iter()
+                        .map(|pruned| pruned.owner_pubkey),
+                ),
+        );
+        self.rebuild_sender_index();
+
+        let retired_local_invites = self.retired_local_invites.len();
+        self.retired_local_invites
+            .retain(|invite| !invite.is_expired(now));
+        if self.retired_local_invites.len() != retired_local_invites {
+            self.local_invite_dirty = true;
+        }
 
         PruneReport {
             removed_devices,
             removed_users,
+            removed_sessions,
+            removed_skipped_keys,
         }
     }
 
     pub fn delete_user(&mut self, owner_pubkey: OwnerPubkey) {
         if owner_pubkey != self.local_owner_pubkey {
             self.users.remove(&owner_pubkey);
+            self.dirty_users.insert(owner_pubkey);
+            self.rebuild_sender_index();
         }
     }
 
@@ -632,6 +1735,7 @@ impl SessionManager {
         record.is_stale = false;
         record.invite_response_generated = true;
         record.upsert_session(Session::from_state(state), now);
+        self.reindex_device(owner_pubkey, device_pubkey);
     }
 
     fn prepare_device_delivery<R>(
@@ -642,19 +1746,43 @@ impl SessionManager {
         payload: &[u8],
         refresh_one_way_bootstrap: bool,
     ) -> Result<Option<(Delivery, Option<InviteResponseEnvelope>)>>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let prepared = self.prepare_device_delivery_inner(
+            ctx,
+            owner_pubkey,
+            device_pubkey,
+            payload,
+            refresh_one_way_bootstrap,
+        );
+        self.reindex_device(owner_pubkey, device_pubkey);
+        prepared
+    }
+
+    fn prepare_device_delivery_inner<R>(
+        &mut self,
+        ctx: &mut ProtocolContext<'_, R>,
+        owner_pubkey: OwnerPubkey,
+        device_pubkey: DevicePubkey,
+        payload: &[u8],
+        refresh_one_way_bootstrap: bool,
+    ) -> Result<Option<(Delivery, Option<InviteResponseEnvelope>)>>
     where
         R: RngCore + CryptoRng,
     {
         let claimed_owner = Some(self.local_owner_pubkey);
         let local_owner_pubkey = self.local_owner_pubkey;
         let local_device_pubkey = self.local_device_pubkey;
-        let local_device_secret_key = self.local_device_secret_key;
+        let local_device_secret_key = self.local_device_secret_key.clone();
+        let settings = self.session_settings();
         let user = self.user_record_mut(owner_pubkey);
         let record = user.device_record_mut(device_pubkey, ctx.now);
 
         if !record.authorized || record.is_stale {
             return Ok(None);
         }
+        record.configure_sessions(settings);
 
         let source = record.best_send_session_source();
         let should_refresh_local_sibling_bootstrap = refresh_one_way_bootstrap
@@ -674,10 +1802,11 @@ impl SessionManager {
             match public_invite.accept_with_owner_context(
                 ctx,
                 local_device_pubkey,
-                local_device_secret_key,
+                *local_device_secret_key.expose_secret(),
                 claimed_owner,
             ) {
                 Ok((mut session, invite_response)) => {
+                    settings.apply(&mut session);
                     let mut envelope = session
                         .apply_send(session.plan_send(payload, ctx.now)?)
                         .envelope;
@@ -695,7 +1824,11 @@ impl SessionManager {
                     )));
                 }
                 Err(Error::Domain(
-                    DomainError::InviteAlreadyUsed | DomainError::InviteExhausted,
+                    DomainError::InviteAlreadyUsed
+                    | DomainError::InviteExhausted
+                    | DomainError::InviteExpired
+                    | DomainError::InviteRevoked
+                    | DomainError::ProofOfWorkTooHigh,
                 )) => {}
                 Err(error) => return Err(error),
             }
@@ -749,15 +1882,20 @@ impl SessionManager {
         let (mut session, invite_response) = match public_invite.accept_with_owner_context(
             ctx,
             local_device_pubkey,
-            local_device_secret_key,
+            *local_device_secret_key.expose_secret(),
             claimed_owner,
         ) {
             Ok(result) => result,
-            Err(Error::Domain(DomainError::InviteAlreadyUsed | DomainError::InviteExhausted)) => {
-                return Ok(None)
-            }
+            Err(Error::Domain(
+                DomainError::InviteAlreadyUsed
+                | DomainError::InviteExhausted
+                | DomainError::InviteExpired
+                | DomainError::InviteRevoked
+                | DomainError::ProofOfWorkTooHigh,
+            )) => return Ok(None),
             Err(error) => return Err(error),
         };
+        settings.apply(&mut session);
         let mut envelope = session
             .apply_send(session.plan_send(payload, ctx.now)?)
             .envelope;
@@ -775,6 +1913,71 @@ impl SessionManager {
         )))
     }
 
+    fn prepare_control_delivery<R>(
+        &mut self,
+        ctx: &mut ProtocolContext<'_, R>,
+        owner_pubkey: OwnerPubkey,
+        device_pubkey: DevicePubkey,
+        control: SessionControl,
+    ) -> Result<Option<Delivery>>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let settings = self.session_settings();
+        let Some(record) = self
+            .users
+            .get_mut(&owner_pubkey)
+            .and_then(|user| user.devices.get_mut(&device_pubkey))
+        else {
+            return Ok(None);
+        };
+        if !record.authorized || record.is_stale {
+            return Ok(None);
+        }
+        record.configure_sessions(settings);
+        let Some(source) = record.best_send_session_source() else {
+            return Ok(None);
+        };
+
+        let session = record
+            .session_for_send_source(&source)
+            .expect("send source must exist");
+        if !session.peer_accepts_control() {
+            return Ok(None);
+        }
+        let plan = match control {
+            SessionControl::RefreshRequest => session.plan_refresh(ctx.now)?,
+            SessionControl::KeyUpdate => session.plan_key_update(ctx.now)?,
+            SessionControl::Reset => session.plan_reset(ctx.now)?,
+        };
+        let mut envelope = match source {
+            SendSessionSource::Active => {
+                record
+                    .active_session
+                    .as_mut()
+                    .expect("active session must exist")
+                    .apply_send(plan)
+                    .envelope
+            }
+            SendSessionSource::Inactive(index) => {
+                let mut session = record.inactive_sessions.remove(index);
+                let outcome = session.apply_send(plan);
+                record.upsert_session(session, ctx.now);
+                outcome.envelope
+            }
+        };
+        envelope.recipient = Some(device_pubkey);
+        record.last_activity = Some(ctx.now);
+        self.dirty_users.insert(owner_pubkey);
+        self.reindex_device(owner_pubkey, device_pubkey);
+
+        Ok(Some(Delivery {
+            owner_pubkey,
+            device_pubkey,
+            envelope,
+        }))
+    }
+
     fn prepare_device_deliveries_for_all_send_sessions<R>(
         &mut self,
         ctx: &mut ProtocolContext<'_, R>,
@@ -785,12 +1988,14 @@ impl SessionManager {
     where
         R: RngCore + CryptoRng,
     {
+        let settings = self.session_settings();
         let user = self.user_record_mut(owner_pubkey);
         let record = user.device_record_mut(device_pubkey, ctx.now);
 
         if !record.authorized || record.is_stale {
             return Ok(Vec::new());
         }
+        record.configure_sessions(settings);
 
         let mut deliveries = Vec::new();
 
@@ -825,6 +2030,7 @@ impl SessionManager {
         if !deliveries.is_empty() {
             record.last_activity = Some(ctx.now);
         }
+        self.reindex_device(owner_pubkey, device_pubkey);
 
         Ok(deliveries)
     }
@@ -1028,6 +2234,18 @@ impl SessionManager {
         incoming_roster: DeviceRoster,
         replace_existing: bool,
     ) -> RosterSnapshotDecision {
+        let verified_before: Vec<OwnerPubkey> = if owner_pubkey == self.local_owner_pubkey {
+            self.users
+                .keys()
+                .copied()
+                .filter(|owner| self.is_owner_verified(*owner))
+                .collect()
+        } else {
+            Some(owner_pubkey)
+                .filter(|owner| self.is_owner_verified(*owner))
+                .into_iter()
+                .collect()
+        };
         let user = self.user_record_mut(owner_pubkey);
         let current_roster = user.roster.as_ref();
         let (decision, next_roster) = if replace_existing {
@@ -1041,7 +2259,7 @@ impl SessionManager {
             .unwrap_or_default();
         let next_authorized = authorized_device_set(&next_roster);
 
-        user.roster = Some(next_roster.clone());
+        user.set_roster(next_roster.clone());
 
         for device in next_roster.devices() {
             let record = user.device_record_mut(device.device_pubkey, device.created_at);
@@ -1059,8 +2277,16 @@ impl SessionManager {
                 record.stale_since = Some(next_roster.created_at);
             }
         }
+        for verified_owner in verified_before {
+            if !self.is_owner_verified(verified_owner)
+                && !self.verified_roster_changes.contains(&verified_owner)
+            {
+                self.verified_roster_changes.push(verified_owner);
+            }
+        }
 
         self.reconcile_verified_claimed_devices(owner_pubkey, &next_roster, next_roster.created_at);
+        self.rebuild_sender_index();
 
         decision
     }
@@ -1115,6 +2341,7 @@ impl SessionManager {
             }
 
             if let Some(user) = self.users.get_mut(&source_owner_pubkey) {
+                self.dirty_users.insert(source_owner_pubkey);
                 let source_roster_is_provisional = user.roster.as_ref().is_some_and(|roster| {
                     roster.devices().iter().all(|device| {
                         matching_devices.contains(&device.device_pubkey)
@@ -1154,7 +2381,69 @@ impl SessionManager {
         }
     }
 
+    fn reindex_device(&mut self, owner_pubkey: OwnerPubkey, device_pubkey: DevicePubkey) {
+        let route = TargetDevice {
+            owner_pubkey,
+            device_pubkey,
+        };
+        let senders = self
+            .users
+            .get(&owner_pubkey)
+            .and_then(|user| user.devices.get(&device_pubkey))
+            .map(DeviceRecord::sender_pubkeys)
+            .unwrap_or_default();
+        let previous = if senders.is_empty() {
+            self.indexed_senders.remove(&route).unwrap_or_default()
+        } else {
+            self.indexed_senders
+                .insert(route.clone(), senders.clone())
+                .unwrap_or_default()
+        };
+
+        for sender in previous.difference(&senders) {
+            if let Some(routes) = self.sender_index.get_mut(sender) {
+                routes.remove(&route);
+                if routes.is_empty() {
+                    self.sender_index.remove(sender);
+                }
+            }
+        }
+        for sender in senders.difference(&previous) {
+            self.sender_index
+                .entry(*sender)
+                .or_default()
+                .insert(route.clone());
+        }
+    }
+
+    fn rebuild_sender_index(&mut self) {
+        self.sender_index.clear();
+        self.indexed_senders.clear();
+        let routes = self
+            .users
+            .values()
+            .flat_map(|user| {
+                user.devices.keys().map(|device_pubkey| TargetDevice {
+                    owner_pubkey: user.owner_pubkey,
+                    device_pubkey: *device_pubkey,
+                })
+            })
+            .collect::<Vec<_>>();
+        for route in routes {
+            self.reindex_device(route.owner_pubkey, route.device_pubkey);
+        }
+    }
+
+    fn session_settings(&self) -> SessionSettings {
+        SessionSettings {
+            skipped_key_max_age_secs: self.retention_policy.skipped_key_max_age_secs,
+            limits: self.session_limits,
+            padding: self.payload_padding,
+        }
+    }
+
     fn user_record_mut(&mut self, owner_pubkey: OwnerPubkey) -> &mut UserRecord {
+        self.dirty_users.insert(owner_pubkey);
         self.users
             .entry(owner_pubkey)
             .or_insert_with(|| UserRecord::new(owner_pubkey))
@@ -1167,6 +2456,8 @@ impl UserRecord {
             owner_pubkey,
             roster: None,
             devices: BTreeMap::new(),
+            verified_fingerprint: None,
+            roster_fingerprint: OnceLock::new(),
         }
     }
 
@@ -1180,6 +2471,8 @@ impl UserRecord {
                 .map(DeviceRecord::from_snapshot)
                 .map(|record| (record.device_pubkey, record))
                 .collect(),
+            verified_fingerprint: snapshot.verified_fingerprint,
+            roster_fingerprint: OnceLock::new(),
         }
     }
 
@@ -1188,7 +2481,28 @@ impl UserRecord {
             owner_pubkey: self.owner_pubkey,
             roster: self.roster.clone(),
             devices: self.devices.values().map(DeviceRecord::snapshot).collect(),
+            verified_fingerprint: self.verified_fingerprint.clone(),
+        }
+    }
+
+    fn set_roster(&mut self, roster: DeviceRoster) {
+        let devices_changed = self
+            .roster
+            .as_ref()
+            .is_none_or(|current| authorized_device_set(current) != authorized_device_set(&roster));
+        if devices_changed {
+            self.roster_fingerprint = OnceLock::new();
         }
+        self.roster = Some(roster);
+    }
+
+    fn roster_fingerprint(&self) -> Option<OwnerFingerprint> {
+        let roster = self.roster.as_ref()?;
+        Some(
+            *self
+                .roster_fingerprint
+                .get_or_init(|| crate::owner_fingerprint(self.owner_pubkey, roster)),
+        )
     }
 
     fn device_record_mut(
@@ -1210,7 +2524,47 @@ impl UserRecord {
     }
 }
 
+impl SessionSettings {
+    fn apply(self, session: &mut Session) {
+        session.set_skipped_key_max_age_secs(self.skipped_key_max_age_secs);
+        session.set_limits(self.limits);
+        session.set_payload_padding(self.padding);
+    }
+}
+
 impl DeviceRecord {
+    fn configure_sessions(&mut self, settings: SessionSettings) {
+        for session in self
+            .active_session
+            .iter_mut()
+            .chain(self.inactive_sessions.iter_mut())
+        {
+            settings.apply(session);
+        }
+    }
+
+    /// Whether `sender` is the current or next ratchet key of one of the
+    /// device's sessions.
+    fn expects_sender(&self, sender: DevicePubkey) -> bool {
+        self.active_session
+            .iter()
+            .chain(self.inactive_sessions.iter())
+            .any(|session| {
+                session.state.their_current_nostr_public_key == Some(sender)
+                    || session.state.their_next_nostr_public_key == Some(sender)
+            })
+    }
+
+    fn close_sessions(&mut self) {
+        for session in self
+            .active_session
+            .iter_mut()
+            .chain(self.inactive_sessions.iter_mut())
+        {
+            session.close();
+        }
+    }
+
     fn new(device_pubkey: DevicePubkey, created_at: UnixSeconds) -> Self {
         Self {
             device_pubkey,
@@ -1224,6 +2578,7 @@ impl DeviceRecord {
             inactive_sessions: Vec::new(),
             last_activity: None,
             created_at,
+            decrypt_failures: 0,
         }
     }
 
@@ -1244,6 +2599,7 @@ impl DeviceRecord {
                 .collect(),
             last_activity: snapshot.last_activity,
             created_at: snapshot.created_at,
+            decrypt_failures: snapshot.decrypt_failures,
         }
     }
 
@@ -1267,6 +2623,7 @@ impl DeviceRecord {
                 .collect(),
             last_activity: self.last_activity,
             created_at: self.created_at,
+            decrypt_failures: self.decrypt_failures,
         }
     }
 
@@ -1358,6 +2715,7 @@ impl DeviceRecord {
             }
         }
         self.invite_response_generated |= other.invite_response_generated;
+        self.decrypt_failures = self.decrypt_failures.max(other.decrypt_failures);
 
         if let Some(session) = other.active_session.take() {
             self.upsert_session(session, now);
@@ -1396,6 +2754,98 @@ impl DeviceRecord {
         }
     }
 
+    fn sender_pubkeys(&self) -> BTreeSet<DevicePubkey> {
+        let mut senders = BTreeSet::new();
+        for session in self
+            .active_session
+            .iter()
+            .chain(self.inactive_sessions.iter())
+        {
+            senders.extend(session.state.their_current_nostr_public_key);
+            senders.extend(session.state.their_next_nostr_public_key);
+            senders.extend(session.state.skipped_keys.keys().copied());
+        }
+        senders
+    }
+
+    fn stale_grace_expired(&self, now: UnixSeconds, grace_secs: u64) -> bool {
+        if !self.is_stale {
+            return false;
+        }
+        self.stale_since
+            .is_none_or(|stale_since| now.get() >= stale_since.get().saturating_add(grace_secs))
+    }
+
+    fn expire_idle_inactive_sessions(&mut self, now: UnixSeconds, max_idle_secs: u64) -> usize {
+        let last_activity = self.last_activity.unwrap_or(self.created_at);
+        if now.get() < last_activity.get().saturating_add(max_idle_secs) {
+            return 0;
+        }
+        let removed = self.inactive_sessions.len();
+        self.inactive_sessions.clear();
+        removed
+    }
+
+    /// Drop the least recently derived skipped message keys beyond
+    /// `max_keys`. Message numbers from different chains are not comparable,
+    /// so age is taken from `derived_at`; on ties inactive sessions go first.
+    fn cap_skipped_keys(&mut self, max_keys: usize) -> Vec<(DevicePubkey, u32)> {
+        let inactive_count = self.inactive_sessions.len();
+        let mut candidates = self
+            .inactive_sessions
+            .iter()
+            .chain(self.active_session.iter())
+            .enumerate()
+            .flat_map(|(slot, session)| {
+                session
+                    .state
+                    .skipped_keys
+                    .iter()
+                    .flat_map(move |(sender, entry)| {
+                        entry.message_keys.keys().map(move |message_number| {
+                            let derived_at = entry
+                                .derived_at
+                                .get(message_number)
+                                .copied()
+                                .unwrap_or(UnixSeconds(0));
+                            (
+                                derived_at,
+                                slot == inactive_count,
+                                *message_number,
+                                *sender,
+                                slot,
+                            )
+                        })
+                    })
+            })
+            .collect::<Vec<_>>();
+        let excess = candidates.len().saturating_sub(max_keys);
+        if excess == 0 {
+            return Vec::new();
+        }
+        candidates.sort();
+
+        let mut removed = Vec::with_capacity(excess);
+        for (_, _, message_number, sender, slot) in candidates.into_iter().take(excess) {
+            let session = if slot == inactive_count {
+                self.active_session.as_mut()
+            } else {
+                self.inactive_sessions.get_mut(slot)
+            };
+            let Some(session) = session else {
+                continue;
+            };
+            if let Some(entry) = session.state.skipped_keys.get_mut(&sender) {
+                entry.remove(message_number);
+                if entry.message_keys.is_empty() {
+                    session.state.skipped_keys.remove(&sender);
+                }
+            }
+            removed.push((sender, message_number));
+        }
+        removed
+    }
+
     fn contains_state(&self, state: &SessionState) -> bool {
         self.active_session
             .as_ref()
@@ -1485,6 +2935,14 @@ fn is_one_way_bootstrap_session(session: &Session) -> bool {
         && session.state.their_current_nostr_public_key.is_none()
 }
 
+fn is_zero(value: &u32) -> bool {
+    *value == 0
+}
+
+fn is_zero_seq(value: &u64) -> bool {
+    *value == 0
+}
+
 fn merge_created_at(current: UnixSeconds, observed: UnixSeconds) -> UnixSeconds {
     match (current.get(), observed.get()) {
         (0, _) => observed,
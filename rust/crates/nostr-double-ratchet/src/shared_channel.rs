@@ -2,14 +2,14 @@ use base64::Engine;
 use nostr::nips::nip44;
 use nostr::{EventBuilder, Keys, PublicKey, Tag};
 
-use crate::{Error, Result, SHARED_CHANNEL_KIND};
+use crate::{Error, Result, SecretBytes, SHARED_CHANNEL_KIND};
 
 /// A shared NIP-44 encrypted channel derived from a secret key.
 /// All participants who know the secret can publish and read events.
 /// Inner content is rumor JSON identifying the real author.
 pub struct SharedChannel {
     public_key: PublicKey,
-    secret_key: [u8; 32],
+    secret_key: SecretBytes,
     conversation_key: nip44::v2::ConversationKey,
 }
 
@@ -25,7 +25,7 @@ impl SharedChannel {
 
         Ok(Self {
             public_key,
-            secret_key: *secret_bytes,
+            secret_key: SecretBytes::new(*secret_bytes),
             conversation_key,
         })
     }
@@ -35,7 +35,7 @@ impl SharedChannel {
         let encrypted = nip44::v2::encrypt_to_bytes(&self.conversation_key, rumor_json.as_bytes())?;
         let encoded = base64::engine::general_purpose::STANDARD.encode(&encrypted);
 
-        let secret_key = nostr::SecretKey::from_slice(&self.secret_key)?;
+        let secret_key = nostr::SecretKey::from_slice(self.secret_key.expose_secret())?;
         let keys = Keys::new(secret_key);
         let public_key_tag = Tag::parse(["p".to_string(), keys.public_key().to_hex()])
             .map_err(|e| Error::InvalidEvent(e.to_string()))?;
@@ -0,0 +1,292 @@
+use crate::{
+    Error, GroupManagerSnapshot, Invite, InviteReplayCache, Result, SenderKeyState,
+    SessionManagerSnapshot, SessionState, UserRecordSnapshot,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Version written for every snapshot kind. Version 0 is the bare serde
+/// struct stored before snapshots were wrapped in an envelope. Fields added
+/// since then default when missing, so only changes in shape need a
+/// migration step.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SnapshotKind {
+    SessionManager,
+    SessionState,
+    GroupManager,
+    SenderKey,
+    Invite,
+    UserRecord,
+    StoreMeta,
+    StoreSettings,
+    StoreRetiredInvites,
+    StoreSeenMessages,
+    StoreSeenMessagesStart,
+}
+
+/// Stored form of a snapshot: the payload plus the schema it was written with.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotEnvelope {
+    pub kind: SnapshotKind,
+    pub version: u32,
+    pub data: Value,
+}
+
+/// One step in a migration chain, rewriting `from_version` JSON into
+/// `from_version + 1`.
+#[derive(Debug, Clone, Copy)]
+pub struct SnapshotMigration {
+    pub from_version: u32,
+    pub name: &'static str,
+    pub migrate: fn(&mut Value) -> Result<()>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadedSnapshot<T> {
+    pub snapshot: T,
+    pub stored_version: u32,
+    /// Names of the migrations that ran, in order. Empty when the stored
+    /// snapshot was already current.
+    pub applied_migrations: Vec<&'static str>,
+}
+
+pub trait VersionedSnapshot: Serialize + DeserializeOwned {
+    const KIND: SnapshotKind;
+    const VERSION: u32 = SNAPSHOT_VERSION;
+
+    fn migrations() -> &'static [SnapshotMigration];
+}
+
+const WRAP_UNVERSIONED: SnapshotMigration = SnapshotMigration {
+    from_version: 0,
+    name: "wrap-unversioned-snapshot",
+    migrate: wrap_unversioned_snapshot,
+};
+
+const LEGACY_MIGRATIONS: &[SnapshotMigration] = &[WRAP_UNVERSIONED];
+
+const SESSION_MANAGER_MIGRATIONS: &[SnapshotMigration] = &[SnapshotMigration {
+    from_version: 0,
+    name: "wrap-unversioned-snapshot",
+    migrate: wrap_unversioned_manager_snapshot,
+}];
+
+const INVITE_MIGRATIONS: &[SnapshotMigration] = &[SnapshotMigration {
+    from_version: 0,
+    name: "wrap-unversioned-snapshot",
+    migrate: wrap_unversioned_invite,
+}];
+
+impl VersionedSnapshot for SessionManagerSnapshot {
+    const KIND: SnapshotKind = SnapshotKind::SessionManager;
+
+    fn migrations() -> &'static [SnapshotMigration] {
+        SESSION_MANAGER_MIGRATIONS
+    }
+}
+
+impl VersionedSnapshot for SessionState {
+    const KIND: SnapshotKind = SnapshotKind::SessionState;
+
+    fn migrations() -> &'static [SnapshotMigration] {
+        LEGACY_MIGRATIONS
+    }
+}
+
+impl VersionedSnapshot for Invite {
+    const KIND: SnapshotKind = SnapshotKind::Invite;
+
+    fn migrations() -> &'static [SnapshotMigration] {
+        INVITE_MIGRATIONS
+    }
+}
+
+/// User records are only stored by [`SessionManagerStore`](crate::SessionManagerStore),
+/// which has always written envelopes.
+impl VersionedSnapshot for UserRecordSnapshot {
+    const KIND: SnapshotKind = SnapshotKind::UserRecord;
+
+    fn migrations() -> &'static [SnapshotMigration] {
+        &[]
+    }
+}
+
+impl VersionedSnapshot for GroupManagerSnapshot {
+    const KIND: SnapshotKind = SnapshotKind::GroupManager;
+
+    fn migrations() -> &'static [SnapshotMigration] {
+        LEGACY_MIGRATIONS
+    }
+}
+
+impl VersionedSnapshot for SenderKeyState {
+    const KIND: SnapshotKind = SnapshotKind::SenderKey;
+
+    fn migrations() -> &'static [SnapshotMigration] {
+        LEGACY_MIGRATIONS
+    }
+}
+
+pub fn encode_snapshot<T>(snapshot: &T) -> Result<String>
+where
+    T: VersionedSnapshot,
+{
+    let envelope = SnapshotEnvelope {
+        kind: T::KIND,
+        version: T::VERSION,
+        data: serde_json::to_value(snapshot)?,
+    };
+    Ok(serde_json::to_string(&envelope)?)
+}
+
+/// Parse a stored snapshot, migrating it forward to `T::VERSION`.
+///
+/// Input without an envelope is treated as version 0.
+pub fn decode_snapshot<T>(json: &str) -> Result<LoadedSnapshot<T>>
+where
+    T: VersionedSnapshot,
+{
+    let value: Value = serde_json::from_str(json)?;
+    let (stored_version, mut data) = if is_envelope(&value) {
+        let envelope: SnapshotEnvelope = serde_json::from_value(value)?;
+        if envelope.kind != T::KIND {
+            return Err(Error::Serialization(format!(
+                "expected {:?} snapshot, found {:?}",
+                T::KIND,
+                envelope.kind
+            )));
+        }
+        (envelope.version, envelope.data)
+    } else {
+        (0, value)
+    };
+
+    if stored_version > T::VERSION {
+        return Err(Error::Serialization(format!(
+            "{:?} snapshot version {stored_version} is newer than supported version {}",
+            T::KIND,
+            T::VERSION
+        )));
+    }
+
+    let mut version = stored_version;
+    let mut applied_migrations = Vec::new();
+    while version < T::VERSION {
+        let migration = T::migrations()
+            .iter()
+            .find(|migration| migration.from_version == version)
+            .ok_or_else(|| {
+                Error::Serialization(format!(
+                    "no migration for {:?} snapshot version {version}",
+                    T::KIND
+                ))
+            })?;
+        (migration.migrate)(&mut data)?;
+        applied_migrations.push(migration.name);
+        version += 1;
+    }
+
+    Ok(LoadedSnapshot {
+        snapshot: serde_json::from_value(data)?,
+        stored_version,
+        applied_migrations,
+    })
+}
+
+fn is_envelope(value: &Value) -> bool {
+    value.as_object().is_some_and(|object| {
+        object.len() == 3
+            && object.contains_key("kind")
+            && object.contains_key("version")
+            && object.contains_key("data")
+    })
+}
+
+fn wrap_unversioned_snapshot(data: &mut Value) -> Result<()> {
+    if data.is_object() {
+        Ok(())
+    } else {
+        Err(Error::Serialization(
+            "unversioned snapshot must be a JSON object".to_string(),
+        ))
+    }
+}
+
+fn as_object(value: &mut Value) -> Result<&mut Map<String, Value>> {
+    value
+        .as_object_mut()
+        .ok_or_else(|| Error::Serialization("snapshot field must be a JSON object".to_string()))
+}
+
+fn array_items<'a>(
+    object: &'a mut Map<String, Value>,
+    key: &str,
+) -> impl Iterator<Item = &'a mut Value> {
+    object
+        .get_mut(key)
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+}
+
+fn for_each_device(
+    data: &mut Value,
+    mut apply: impl FnMut(&mut Map<String, Value>) -> Result<()>,
+) -> Result<()> {
+    for user in array_items(as_object(data)?, "users") {
+        for device in array_items(as_object(user)?, "devices") {
+            apply(as_object(device)?)?;
+        }
+    }
+    Ok(())
+}
+
+fn for_each_manager_invite(
+    data: &mut Value,
+    mut migrate: impl FnMut(&mut Map<String, Value>) -> Result<()>,
+) -> Result<()> {
+    let manager = as_object(data)?;
+    if let Some(invite) = manager
+        .get_mut("local_invite")
+        .filter(|value| !value.is_null())
+    {
+        migrate(as_object(invite)?)?;
+    }
+    for invite in array_items(manager, "retired_local_invites") {
+        migrate(as_object(invite)?)?;
+    }
+    for_each_device(data, |device| match device.get_mut("public_invite") {
+        Some(invite) if !invite.is_null() => migrate(as_object(invite)?),
+        _ => Ok(()),
+    })
+}
+
+fn wrap_unversioned_manager_snapshot(data: &mut Value) -> Result<()> {
+    wrap_unversioned_snapshot(data)?;
+    for_each_manager_invite(data, digest_used_invite_responses)
+}
+
+fn wrap_unversioned_invite(data: &mut Value) -> Result<()> {
+    wrap_unversioned_snapshot(data)?;
+    digest_used_invite_responses(as_object(data)?)
+}
+
+/// Released invites listed the full contents of every used response; they
+/// become digests of unknown age, which the replay cache stamps on its next
+/// insert.
+fn digest_used_invite_responses(invite: &mut Map<String, Value>) -> Result<()> {
+    if let Some(contents) = invite.remove("used_response_contents") {
+        let contents: Vec<String> = serde_json::from_value(contents)?;
+        if !contents.is_empty() {
+            invite.insert(
+                "used_responses".to_string(),
+                serde_json::to_value(InviteReplayCache::from_contents(contents))?,
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,526 @@
+use crate::{
+    decode_snapshot, encode_snapshot, DevicePubkey, Error, Invite, OwnerPubkey, Result,
+    SeenMessageIdChanges, SessionManagerChanges, SessionManagerSettings, SessionManagerSnapshot,
+    SnapshotKind, SnapshotMigration, UserRecordSnapshot, VersionedSnapshot,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub const SESSION_MANAGER_STORAGE_PREFIX: &str = "v1/session-manager/";
+
+const META_KEY: &str = "meta";
+const LOCAL_INVITE_KEY: &str = "local-invite";
+const SEEN_MESSAGES_START_KEY: &str = "seen-messages-start";
+const SEEN_MESSAGE_CHUNK_PREFIX: &str = "seen-messages/";
+const SEEN_MESSAGE_CHUNK_LEN: u64 = 64;
+const RETIRED_INVITES_KEY: &str = "retired-local-invites";
+const SETTINGS_KEY: &str = "settings";
+const USER_KEY_PREFIX: &str = "user/";
+
+/// Key-value persistence used by [`SessionManagerStore`], mirroring the
+/// TypeScript `StorageAdapter`. Values are JSON strings.
+pub trait StorageAdapter {
+    fn get(&self, key: &str) -> Result<Option<String>>;
+    fn put(&mut self, key: &str, value: String) -> Result<()>;
+    fn del(&mut self, key: &str) -> Result<()>;
+    /// Keys starting with `prefix`, sorted.
+    fn list(&self, prefix: &str) -> Result<Vec<String>>;
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InMemoryStorageAdapter {
+    entries: BTreeMap<String, String>,
+}
+
+impl InMemoryStorageAdapter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl StorageAdapter for InMemoryStorageAdapter {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.entries.get(key).cloned())
+    }
+
+    fn put(&mut self, key: &str, value: String) -> Result<()> {
+        self.entries.insert(key.to_string(), value);
+        Ok(())
+    }
+
+    fn del(&mut self, key: &str) -> Result<()> {
+        self.entries.remove(key);
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        Ok(self
+            .entries
+            .range(prefix.to_string()..)
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+}
+
+/// Stores each key as one file under a root directory. Writes go to a
+/// `.tmp` file that is synced and renamed over the target, and the directory
+/// is synced after the rename, so a crash leaves either the old or the new
+/// value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileStorageAdapter {
+    root: PathBuf,
+}
+
+impl FileStorageAdapter {
+    pub fn new(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root).map_err(storage_error)?;
+        Ok(Self { root })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn path_for(&self, key: &str) -> PathBuf {
+        self.root.join(encode_file_name(key))
+    }
+
+    /// Make renames and removals in the root directory durable.
+    #[cfg(unix)]
+    fn sync_root(&self) -> Result<()> {
+        fs::File::open(&self.root)
+            .and_then(|dir| dir.sync_all())
+            .map_err(storage_error)
+    }
+
+    /// Directory handles cannot be synced here; the rename is still atomic.
+    #[cfg(not(unix))]
+    fn sync_root(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl StorageAdapter for FileStorageAdapter {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        match fs::read_to_string(self.path_for(key)) {
+            Ok(value) => Ok(Some(value)),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(storage_error(error)),
+        }
+    }
+
+    fn put(&mut self, key: &str, value: String) -> Result<()> {
+        let path = self.path_for(key);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path).map_err(storage_error)?;
+        file.write_all(value.as_bytes()).map_err(storage_error)?;
+        file.sync_all().map_err(storage_error)?;
+        drop(file);
+        fs::rename(&tmp_path, &path).map_err(storage_error)?;
+        self.sync_root()
+    }
+
+    fn del(&mut self, key: &str) -> Result<()> {
+        match fs::remove_file(self.path_for(key)) {
+            Ok(()) => self.sync_root(),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(error) => Err(storage_error(error)),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for entry in fs::read_dir(&self.root).map_err(storage_error)? {
+            let entry = entry.map_err(storage_error)?;
+            let file_name = entry.file_name();
+            let Some(key) = file_name
+                .to_str()
+                .filter(|name| !name.contains('.'))
+                .and_then(decode_file_name)
+            else {
+                continue;
+            };
+            if key.starts_with(prefix) {
+                keys.push(key);
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct StoredManagerMeta {
+    local_owner_pubkey: OwnerPubkey,
+    local_device_pubkey: DevicePubkey,
+}
+
+/// Seen message ids with sequence numbers `start..start + ids.len()`, all
+/// within one chunk of `SEEN_MESSAGE_CHUNK_LEN` sequence numbers.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct StoredSeenMessageChunk {
+    start: u64,
+    ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct StoredSeenMessagesStart {
+    first_seq: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+struct StoredRetiredInvites(Vec<Invite>);
+
+/// Store records have always been written in envelopes, so none of them
+/// has an unversioned form to migrate from.
+const STORE_RECORD_MIGRATIONS: &[SnapshotMigration] = &[];
+
+impl VersionedSnapshot for StoredManagerMeta {
+    const KIND: SnapshotKind = SnapshotKind::StoreMeta;
+
+    fn migrations() -> &'static [SnapshotMigration] {
+        STORE_RECORD_MIGRATIONS
+    }
+}
+
+impl VersionedSnapshot for StoredSeenMessageChunk {
+    const KIND: SnapshotKind = SnapshotKind::StoreSeenMessages;
+
+    fn migrations() -> &'static [SnapshotMigration] {
+        STORE_RECORD_MIGRATIONS
+    }
+}
+
+impl VersionedSnapshot for StoredSeenMessagesStart {
+    const KIND: SnapshotKind = SnapshotKind::StoreSeenMessagesStart;
+
+    fn migrations() -> &'static [SnapshotMigration] {
+        STORE_RECORD_MIGRATIONS
+    }
+}
+
+impl VersionedSnapshot for SessionManagerSettings {
+    const KIND: SnapshotKind = SnapshotKind::StoreSettings;
+
+    fn migrations() -> &'static [SnapshotMigration] {
+        STORE_RECORD_MIGRATIONS
+    }
+}
+
+impl VersionedSnapshot for StoredRetiredInvites {
+    const KIND: SnapshotKind = SnapshotKind::StoreRetiredInvites;
+
+    fn migrations() -> &'static [SnapshotMigration] {
+        STORE_RECORD_MIGRATIONS
+    }
+}
+
+/// Persists a [`SessionManager`](crate::SessionManager) as one record per
+/// user, so hosts can write only what [`SessionManager::take_changes`]
+/// reports instead of the whole snapshot.
+///
+/// [`SessionManager::take_changes`]: crate::SessionManager::take_changes
+#[derive(Debug, Clone)]
+pub struct SessionManagerStore<S> {
+    storage: S,
+    prefix: String,
+}
+
+impl<S: StorageAdapter> SessionManagerStore<S> {
+    pub fn new(storage: S) -> Self {
+        Self::with_prefix(storage, SESSION_MANAGER_STORAGE_PREFIX)
+    }
+
+    pub fn with_prefix(storage: S, prefix: impl Into<String>) -> Self {
+        Self {
+            storage,
+            prefix: prefix.into(),
+        }
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    pub fn into_storage(self) -> S {
+        self.storage
+    }
+
+    /// Write a full snapshot, deleting user records that are no longer in it.
+    pub fn save_snapshot(&mut self, snapshot: &SessionManagerSnapshot) -> Result<()> {
+        let meta = StoredManagerMeta {
+            local_owner_pubkey: snapshot.local_owner_pubkey,
+            local_device_pubkey: snapshot.local_device_pubkey,
+        };
+        self.storage
+            .put(&self.key(META_KEY), encode_snapshot(&meta)?)?;
+        match snapshot.local_invite.as_ref() {
+            Some(invite) => self.put_local_invite(invite)?,
+            None => self.storage.del(&self.key(LOCAL_INVITE_KEY))?,
+        }
+        self.put_retired_local_invites(&snapshot.retired_local_invites)?;
+        self.save_seen_message_ids(snapshot.seen_message_id_offset, &snapshot.seen_message_ids)?;
+        self.put_settings(&SessionManagerSettings {
+            retention_policy: snapshot.retention_policy,
+            session_limits: snapshot.session_limits,
+            payload_padding: snapshot.payload_padding,
+            decrypt_failure_threshold: snapshot.decrypt_failure_threshold,
+        })?;
+
+        let user_prefix = self.key(USER_KEY_PREFIX);
+        let mut stale_keys = self.storage.list(&user_prefix)?;
+        for user in &snapshot.users {
+            let key = self.user_key(user.owner_pubkey);
+            stale_keys.retain(|existing| *existing != key);
+            self.put_user(user)?;
+        }
+        for key in stale_keys {
+            self.storage.del(&key)?;
+        }
+        Ok(())
+    }
+
+    /// Apply incremental changes on top of a previously saved snapshot.
+    pub fn apply_changes(&mut self, changes: &SessionManagerChanges) -> Result<()> {
+        if let Some(invite) = changes.local_invite.as_ref() {
+            self.put_local_invite(invite)?;
+        } else if changes.local_invite_removed {
+            self.storage.del(&self.key(LOCAL_INVITE_KEY))?;
+        }
+        if let Some(retired_local_invites) = changes.retired_local_invites.as_ref() {
+            self.put_retired_local_invites(retired_local_invites)?;
+        }
+        if let Some(seen_message_ids) = changes.seen_message_ids.as_ref() {
+            self.apply_seen_message_ids(seen_message_ids)?;
+        }
+        if let Some(settings) = changes.settings.as_ref() {
+            self.put_settings(settings)?;
+        }
+        for user in &changes.upserted_users {
+            self.put_user(user)?;
+        }
+        for owner_pubkey in &changes.removed_users {
+            self.storage.del(&self.user_key(*owner_pubkey))?;
+        }
+        Ok(())
+    }
+
+    pub fn load_snapshot(&self) -> Result<Option<SessionManagerSnapshot>> {
+        let Some(meta) = self.storage.get(&self.key(META_KEY))? else {
+            return Ok(None);
+        };
+        let meta = decode_record::<StoredManagerMeta>(&meta)?;
+        let local_invite = self.get_record::<Invite>(&self.key(LOCAL_INVITE_KEY))?;
+        let StoredRetiredInvites(retired_local_invites) = self
+            .get_record(&self.key(RETIRED_INVITES_KEY))?
+            .unwrap_or_default();
+        let (seen_message_id_offset, seen_message_ids) = self.load_seen_message_ids()?;
+        let settings = self
+            .get_record::<SessionManagerSettings>(&self.key(SETTINGS_KEY))?
+            .unwrap_or_default();
+
+        let mut users = Vec::new();
+        for key in self.storage.list(&self.key(USER_KEY_PREFIX))? {
+            if let Some(user) = self.get_record::<UserRecordSnapshot>(&key)? {
+                users.push(user);
+            }
+        }
+        users.sort_by_key(|user| user.owner_pubkey);
+
+        Ok(Some(SessionManagerSnapshot {
+            local_owner_pubkey: meta.local_owner_pubkey,
+            local_device_pubkey: meta.local_device_pubkey,
+            local_invite,
+            retired_local_invites,
+            users,
+            seen_message_ids,
+            seen_message_id_offset,
+            retention_policy: settings.retention_policy,
+            session_limits: settings.session_limits,
+            payload_padding: settings.payload_padding,
+            decrypt_failure_threshold: settings.decrypt_failure_threshold,
+        }))
+    }
+
+    fn put_local_invite(&mut self, invite: &Invite) -> Result<()> {
+        self.storage
+            .put(&self.key(LOCAL_INVITE_KEY), encode_snapshot(invite)?)
+    }
+
+    fn put_retired_local_invites(&mut self, invites: &[Invite]) -> Result<()> {
+        if invites.is_empty() {
+            return self.storage.del(&self.key(RETIRED_INVITES_KEY));
+        }
+        self.storage.put(
+            &self.key(RETIRED_INVITES_KEY),
+            encode_snapshot(&StoredRetiredInvites(invites.to_vec()))?,
+        )
+    }
+
+    fn save_seen_message_ids(&mut self, first_seq: u64, ids: &[String]) -> Result<()> {
+        for key in self.storage.list(&self.key(SEEN_MESSAGE_CHUNK_PREFIX))? {
+            self.storage.del(&key)?;
+        }
+        self.apply_seen_message_ids(&SeenMessageIdChanges {
+            previous_first_seq: first_seq,
+            first_seq,
+            appended_from: first_seq,
+            appended: ids.to_vec(),
+        })
+    }
+
+    /// Append new ids to their chunks and delete chunks that only held
+    /// evicted ids. Only the chunks touched are written.
+    fn apply_seen_message_ids(&mut self, changes: &SeenMessageIdChanges) -> Result<()> {
+        if changes.first_seq != changes.previous_first_seq {
+            self.storage.put(
+                &self.key(SEEN_MESSAGES_START_KEY),
+                encode_snapshot(&StoredSeenMessagesStart {
+                    first_seq: changes.first_seq,
+                })?,
+            )?;
+        }
+        let first_kept_chunk = changes.first_seq / SEEN_MESSAGE_CHUNK_LEN;
+        for chunk in changes.previous_first_seq / SEEN_MESSAGE_CHUNK_LEN..first_kept_chunk {
+            self.storage.del(&self.seen_message_chunk_key(chunk))?;
+        }
+
+        let mut seq = changes.appended_from;
+        let mut remaining = changes.appended.as_slice();
+        while !remaining.is_empty() {
+            let chunk = seq / SEEN_MESSAGE_CHUNK_LEN;
+            let room = ((chunk + 1) * SEEN_MESSAGE_CHUNK_LEN - seq) as usize;
+            let (ids, rest) = remaining.split_at(room.min(remaining.len()));
+            let key = self.seen_message_chunk_key(chunk);
+            let mut stored = self
+                .get_record::<StoredSeenMessageChunk>(&key)?
+                .filter(|stored| stored.start <= seq)
+                .unwrap_or(StoredSeenMessageChunk {
+                    start: seq,
+                    ids: Vec::new(),
+                });
+            stored.ids.truncate((seq - stored.start) as usize);
+            if stored.start + stored.ids.len() as u64 != seq {
+                stored = StoredSeenMessageChunk {
+                    start: seq,
+                    ids: Vec::new(),
+                };
+            }
+            stored.ids.extend_from_slice(ids);
+            self.storage.put(&key, encode_snapshot(&stored)?)?;
+            seq += ids.len() as u64;
+            remaining = rest;
+        }
+        Ok(())
+    }
+
+    fn load_seen_message_ids(&self) -> Result<(u64, Vec<String>)> {
+        let StoredSeenMessagesStart { first_seq } = self
+            .get_record(&self.key(SEEN_MESSAGES_START_KEY))?
+            .unwrap_or_default();
+
+        let mut offset = None;
+        let mut ids = Vec::new();
+        for key in self.storage.list(&self.key(SEEN_MESSAGE_CHUNK_PREFIX))? {
+            let Some(stored) = self.get_record::<StoredSeenMessageChunk>(&key)? else {
+                continue;
+            };
+            for (seq, id) in (stored.start..).zip(stored.ids) {
+                if seq < first_seq {
+                    continue;
+                }
+                let next_seq = offset.unwrap_or(seq) + ids.len() as u64;
+                if seq != next_seq {
+                    offset = Some(seq);
+                    ids.clear();
+                }
+                offset.get_or_insert(seq);
+                ids.push(id);
+            }
+        }
+        Ok((offset.unwrap_or(first_seq), ids))
+    }
+
+    fn seen_message_chunk_key(&self, chunk: u64) -> String {
+        self.key(&format!("{SEEN_MESSAGE_CHUNK_PREFIX}{chunk:016x}"))
+    }
+
+    fn put_settings(&mut self, settings: &SessionManagerSettings) -> Result<()> {
+        self.storage
+            .put(&self.key(SETTINGS_KEY), encode_snapshot(settings)?)
+    }
+
+    fn put_user(&mut self, user: &UserRecordSnapshot) -> Result<()> {
+        self.storage
+            .put(&self.user_key(user.owner_pubkey), encode_snapshot(user)?)
+    }
+
+    fn get_record<T: VersionedSnapshot>(&self, key: &str) -> Result<Option<T>> {
+        self.storage
+            .get(key)?
+            .map(|value| decode_record(&value))
+            .transpose()
+    }
+
+    fn key(&self, suffix: &str) -> String {
+        format!("{}{suffix}", self.prefix)
+    }
+
+    fn user_key(&self, owner_pubkey: OwnerPubkey) -> String {
+        self.key(&format!("{USER_KEY_PREFIX}{}", owner_pubkey.to_hex()))
+    }
+}
+
+fn decode_record<T: VersionedSnapshot>(value: &str) -> Result<T> {
+    Ok(decode_snapshot(value)?.snapshot)
+}
+
+fn storage_error(error: std::io::Error) -> Error {
+    Error::Storage(error.to_string())
+}
+
+/// Percent-encode everything outside `[A-Za-z0-9_-]` so keys map to flat,
+/// portable file names.
+fn encode_file_name(key: &str) -> String {
+    let mut name = String::with_capacity(key.len());
+    for byte in key.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-' {
+            name.push(byte as char);
+        } else {
+            name.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    name
+}
+
+fn decode_file_name(name: &str) -> Option<String> {
+    let bytes = name.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = name.get(index + 1..index + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
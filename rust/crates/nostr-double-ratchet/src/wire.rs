@@ -1,9 +1,10 @@
 use crate::{
-    AuthorizedDevice, DevicePubkey, DeviceRoster, Error as CoreError,
-    GroupSenderKeyMessageEnvelope, Invite, InviteResponseEnvelope, MessageEnvelope, OwnerPubkey,
-    UnixSeconds,
+    AuthorizedDevice, DevicePubkey, DeviceRoster, DomainError, Error as CoreError,
+    GroupSenderKeyMessageEnvelope, Invite, InviteReplayCache, InviteResponseEnvelope,
+    MessageEnvelope, OwnerPubkey, ProofOfWork, SecretBytes, UnixSeconds, MAX_INVITE_POW_DIFFICULTY,
 };
 use base64::Engine;
+use bech32::{Bech32, Hrp};
 use nostr::{nips::nip44, Event, EventBuilder, Keys, Kind, Tag, Timestamp, UnsignedEvent};
 use thiserror::Error;
 
@@ -13,9 +14,17 @@ pub const INVITE_EVENT_KIND: u32 = 30078;
 pub const INVITE_RESPONSE_KIND: u32 = 1059;
 pub const ROSTER_EVENT_KIND: u32 = 30078;
 
-const ROSTER_D_TAG: &str = "double-ratchet/app-keys";
+pub const ROSTER_D_TAG: &str = "double-ratchet/app-keys";
 const ROSTER_VERSION: &str = "1";
 pub const INVITE_LIST_LABEL: &str = "double-ratchet/invites";
+pub const COMPACT_INVITE_HRP: &str = "ndrinvite";
+const COMPACT_INVITE_VERSION: u8 = 1;
+const COMPACT_INVITE_OWNER: u8 = 0;
+const COMPACT_INVITE_DEVICE_ID: u8 = 1;
+const COMPACT_INVITE_PURPOSE: u8 = 2;
+const COMPACT_INVITE_MAX_USES: u8 = 3;
+const COMPACT_INVITE_EXPIRES_AT: u8 = 4;
+const COMPACT_INVITE_POW_DIFFICULTY: u8 = 5;
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DecodedRosterEvent {
     pub owner_pubkey: OwnerPubkey,
@@ -63,7 +72,7 @@ impl From<Error> for CoreError {
 }
 
 pub fn message_event(envelope: &MessageEnvelope) -> Result<Event> {
-    let author_secret_key = secret_key_from_bytes(&envelope.signer_secret_key)?;
+    let author_secret_key = secret_key_from_bytes(envelope.signer_secret_key.expose_secret())?;
     let author_keys = Keys::new(author_secret_key);
     let derived_sender = DevicePubkey::from_bytes(author_keys.public_key().to_bytes());
     if derived_sender != envelope.sender {
@@ -100,7 +109,7 @@ pub fn parse_message_event(event: &Event) -> Result<MessageEnvelope> {
             .as_deref()
             .map(parse_device_pubkey)
             .transpose()?,
-        signer_secret_key: [0u8; 32],
+        signer_secret_key: SecretBytes::new([0u8; 32]),
         created_at: UnixSeconds(event.created_at.as_secs()),
         encrypted_header,
         ciphertext: event.content.clone(),
@@ -108,7 +117,7 @@ pub fn parse_message_event(event: &Event) -> Result<MessageEnvelope> {
 }
 
 pub fn group_sender_key_message_event(envelope: &GroupSenderKeyMessageEnvelope) -> Result<Event> {
-    let author_secret_key = secret_key_from_bytes(&envelope.signer_secret_key)?;
+    let author_secret_key = secret_key_from_bytes(envelope.signer_secret_key.expose_secret())?;
     let author_keys = Keys::new(author_secret_key);
     let derived_sender = DevicePubkey::from_bytes(author_keys.public_key().to_bytes());
     if derived_sender != envelope.sender_event_pubkey {
@@ -247,7 +256,7 @@ pub fn invite_url(invite: &Invite, root: &str) -> Result<String> {
     );
     data.insert(
         "sharedSecret".to_string(),
-        serde_json::Value::String(hex::encode(invite.shared_secret)),
+        serde_json::Value::String(hex::encode(invite.shared_secret.expose_secret())),
     );
     data.insert(
         "createdAt".to_string(),
@@ -281,6 +290,18 @@ pub fn invite_url(invite: &Invite, root: &str) -> Result<String> {
             serde_json::Value::Number(serde_json::Number::from(max_uses)),
         );
     }
+    if let Some(expires_at) = invite.expires_at {
+        data.insert(
+            "expiresAt".to_string(),
+            serde_json::Value::Number(serde_json::Number::from(expires_at.get())),
+        );
+    }
+    if let Some(pow_difficulty) = invite.pow_difficulty {
+        data.insert(
+            "powDifficulty".to_string(),
+            serde_json::Value::Number(serde_json::Number::from(pow_difficulty)),
+        );
+    }
 
     Ok(format!(
         "{root}#{}",
@@ -288,7 +309,19 @@ pub fn invite_url(invite: &Invite, root: &str) -> Result<String> {
     ))
 }
 
+/// Like [`invite_url`], but with an [`encode_compact_invite`] fragment short
+/// enough for a QR code.
+pub fn compact_invite_url(invite: &Invite, root: &str) -> Result<String> {
+    Ok(format!("{root}#{}", encode_compact_invite(invite)?))
+}
+
+/// Accepts both the JSON fragment of [`invite_url`] and the compact form of
+/// [`compact_invite_url`], with or without the URL around it.
 pub fn parse_invite_url(url: &str) -> Result<Invite> {
+    let fragment = url.split_once('#').map_or(url, |(_, hash)| hash);
+    if is_compact_invite(fragment.trim()) {
+        return parse_compact_invite(fragment);
+    }
     let hash = url
         .split('#')
         .nth(1)
@@ -322,12 +355,22 @@ pub fn parse_invite_url(url: &str) -> Result<Invite> {
     Ok(Invite {
         inviter_device_pubkey,
         inviter_ephemeral_public_key,
-        shared_secret,
+        shared_secret: SecretBytes::new(shared_secret),
         inviter_ephemeral_private_key: None,
         max_uses: data["maxUses"].as_u64().map(|value| value as usize),
         used_by: Vec::new(),
-        used_response_contents: Vec::new(),
+        used_responses: InviteReplayCache::default(),
         created_at: UnixSeconds(data["createdAt"].as_u64().unwrap_or(0)),
+        expires_at: data["expiresAt"].as_u64().map(UnixSeconds),
+        revoked_at: None,
+        pow_difficulty: data["powDifficulty"]
+            .as_u64()
+            .map(|value| {
+                u8::try_from(value)
+                    .map_err(|_| Error::InvalidEvent("invalid powDifficulty".to_string()))
+                    .and_then(checked_pow_difficulty)
+            })
+            .transpose()?,
         inviter_owner_pubkey,
         purpose: data["purpose"].as_str().map(ToString::to_string),
         inviter: public_key(inviter_device_pubkey)?,
@@ -338,22 +381,216 @@ pub fn parse_invite_url(url: &str) -> Result<Invite> {
     })
 }
 
+/// Bech32 encoding of the public part of an invite: a version byte, the
+/// inviter, ephemeral key, shared secret and creation time, then optional
+/// type-length-value fields. Unknown fields are skipped when parsing.
+pub fn encode_compact_invite(invite: &Invite) -> Result<String> {
+    let mut data = vec![COMPACT_INVITE_VERSION];
+    data.extend_from_slice(&invite.inviter_device_pubkey.to_bytes());
+    data.extend_from_slice(&invite.inviter_ephemeral_public_key.to_bytes());
+    data.extend_from_slice(invite.shared_secret.expose_secret());
+    data.extend_from_slice(&invite.created_at.get().to_be_bytes());
+
+    let owner = invite
+        .owner_public_key
+        .map(|pk| OwnerPubkey::from_bytes(pk.to_bytes()))
+        .or(invite.inviter_owner_pubkey);
+    if let Some(owner) = owner {
+        push_compact_field(&mut data, COMPACT_INVITE_OWNER, &owner.to_bytes())?;
+    }
+    if let Some(device_id) = invite.device_id.as_ref() {
+        push_compact_field(&mut data, COMPACT_INVITE_DEVICE_ID, device_id.as_bytes())?;
+    }
+    if let Some(purpose) = invite.purpose.as_ref() {
+        push_compact_field(&mut data, COMPACT_INVITE_PURPOSE, purpose.as_bytes())?;
+    }
+    if let Some(max_uses) = invite.max_uses {
+        push_compact_field(
+            &mut data,
+            COMPACT_INVITE_MAX_USES,
+            &(max_uses as u64).to_be_bytes(),
+        )?;
+    }
+    if let Some(expires_at) = invite.expires_at {
+        push_compact_field(
+            &mut data,
+            COMPACT_INVITE_EXPIRES_AT,
+            &expires_at.get().to_be_bytes(),
+        )?;
+    }
+    if let Some(pow_difficulty) = invite.pow_difficulty {
+        push_compact_field(&mut data, COMPACT_INVITE_POW_DIFFICULTY, &[pow_difficulty])?;
+    }
+
+    bech32::encode::<Bech32>(Hrp::parse_unchecked(COMPACT_INVITE_HRP), &data)
+        .map_err(|error| Error::InvalidEvent(format!("invalid compact invite: {error}")))
+}
+
+pub fn parse_compact_invite(input: &str) -> Result<Invite> {
+    let (hrp, data) = bech32::decode(input.trim())
+        .map_err(|error| Error::InvalidEvent(format!("invalid compact invite: {error}")))?;
+    if hrp != Hrp::parse_unchecked(COMPACT_INVITE_HRP) {
+        return Err(Error::InvalidEvent(format!(
+            "compact invite must use the {COMPACT_INVITE_HRP} prefix"
+        )));
+    }
+    let (&version, mut rest) = data
+        .split_first()
+        .ok_or_else(|| Error::InvalidEvent("empty compact invite".to_string()))?;
+    if version != COMPACT_INVITE_VERSION {
+        return Err(Error::InvalidEvent(format!(
+            "unsupported compact invite version {version}"
+        )));
+    }
+    let inviter_device_pubkey = compact_device_pubkey(take_compact_bytes(&mut rest, 32)?)?;
+    let inviter_ephemeral_public_key = compact_device_pubkey(take_compact_bytes(&mut rest, 32)?)?;
+    let shared_secret = compact_array(take_compact_bytes(&mut rest, 32)?)?;
+    let created_at = u64::from_be_bytes(compact_array(take_compact_bytes(&mut rest, 8)?)?);
+
+    let mut inviter_owner_pubkey = None;
+    let mut device_id = None;
+    let mut purpose = None;
+    let mut max_uses = None;
+    let mut expires_at = None;
+    let mut pow_difficulty = None;
+    while let Some((&field, tail)) = rest.split_first() {
+        rest = tail;
+        let length = usize::from(take_compact_bytes(&mut rest, 1)?[0]);
+        let value = take_compact_bytes(&mut rest, length)?;
+        match field {
+            COMPACT_INVITE_OWNER => {
+                let owner = compact_device_pubkey(value)?;
+                inviter_owner_pubkey = Some(OwnerPubkey::from_bytes(owner.to_bytes()));
+            }
+            COMPACT_INVITE_DEVICE_ID => device_id = Some(compact_string(value)?),
+            COMPACT_INVITE_PURPOSE => purpose = Some(compact_string(value)?),
+            COMPACT_INVITE_MAX_USES => {
+                max_uses = Some(u64::from_be_bytes(compact_array(value)?) as usize);
+            }
+            COMPACT_INVITE_EXPIRES_AT => {
+                expires_at = Some(UnixSeconds(u64::from_be_bytes(compact_array(value)?)));
+            }
+            COMPACT_INVITE_POW_DIFFICULTY => {
+                pow_difficulty = Some(checked_pow_difficulty(u8::from_be_bytes(compact_array(
+                    value,
+                )?))?);
+            }
+            _ => {}
+        }
+    }
+
+    Ok(Invite {
+        inviter_device_pubkey,
+        inviter_ephemeral_public_key,
+        shared_secret: SecretBytes::new(shared_secret),
+        inviter_ephemeral_private_key: None,
+        max_uses,
+        used_by: Vec::new(),
+        used_responses: InviteReplayCache::default(),
+        created_at: UnixSeconds(created_at),
+        expires_at,
+        revoked_at: None,
+        pow_difficulty,
+        inviter_owner_pubkey,
+        purpose,
+        inviter: public_key(inviter_device_pubkey)?,
+        device_id,
+        owner_public_key: inviter_owner_pubkey
+            .map(|owner| public_key(DevicePubkey::from_bytes(owner.to_bytes())))
+            .transpose()?,
+    })
+}
+
+fn is_compact_invite(value: &str) -> bool {
+    value
+        .get(..COMPACT_INVITE_HRP.len() + 1)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case(&format!("{COMPACT_INVITE_HRP}1")))
+}
+
+fn push_compact_field(data: &mut Vec<u8>, field: u8, value: &[u8]) -> Result<()> {
+    let length = u8::try_from(value.len())
+        .map_err(|_| Error::InvalidEvent("compact invite field too long".to_string()))?;
+    data.push(field);
+    data.push(length);
+    data.extend_from_slice(value);
+    Ok(())
+}
+
+fn take_compact_bytes<'a>(data: &mut &'a [u8], length: usize) -> Result<&'a [u8]> {
+    if data.len() < length {
+        return Err(Error::InvalidEvent("truncated compact invite".to_string()));
+    }
+    let (value, rest) = data.split_at(length);
+    *data = rest;
+    Ok(value)
+}
+
+fn compact_array<const N: usize>(value: &[u8]) -> Result<[u8; N]> {
+    value
+        .try_into()
+        .map_err(|_| Error::InvalidEvent("invalid compact invite field length".to_string()))
+}
+
+fn compact_device_pubkey(value: &[u8]) -> Result<DevicePubkey> {
+    Ok(DevicePubkey::from_bytes(
+        nostr::PublicKey::from_slice(value)?.to_bytes(),
+    ))
+}
+
+fn compact_string(value: &[u8]) -> Result<String> {
+    String::from_utf8(value.to_vec())
+        .map_err(|_| Error::InvalidEvent("invalid compact invite text".to_string()))
+}
+
 pub fn invite_unsigned_event(invite: &Invite) -> Result<UnsignedEvent> {
-    let inviter_device_pubkey = invite.inviter_device_pubkey;
-    let d_suffix = invite
-        .device_id
-        .clone()
-        .unwrap_or_else(|| inviter_device_pubkey.to_string());
-    let mut builder = EventBuilder::new(Kind::from(INVITE_EVENT_KIND as u16), "")
+    let builder = EventBuilder::new(Kind::from(INVITE_EVENT_KIND as u16), "")
         .tag(tag([
             "ephemeralKey",
             &invite.inviter_ephemeral_public_key.to_string(),
         ])?)
-        .tag(tag(["sharedSecret", &hex::encode(invite.shared_secret)])?)
-        .tag(tag(["d", &format!("double-ratchet/invites/{d_suffix}")])?)
+        .tag(tag([
+            "sharedSecret",
+            &hex::encode(invite.shared_secret.expose_secret()),
+        ])?)
+        .tag(tag(["d", &invite_d_tag(invite)])?)
         .tag(tag(["l", INVITE_LIST_LABEL])?)
         .custom_created_at(Timestamp::from(invite.created_at.get()));
+    finish_invite_event(invite, builder)
+}
+
+/// Tombstone replacing a published invite event. It keeps the invite's `d`
+/// tag so relays drop the original, and omits the shared secret.
+pub fn invite_revocation_unsigned_event(
+    invite: &Invite,
+    revoked_at: UnixSeconds,
+) -> Result<UnsignedEvent> {
+    if revoked_at <= invite.created_at {
+        return Err(Error::InvalidEvent(
+            "revocation must be newer than the invite".to_string(),
+        ));
+    }
+    let builder = EventBuilder::new(Kind::from(INVITE_EVENT_KIND as u16), "")
+        .tag(tag([
+            "ephemeralKey",
+            &invite.inviter_ephemeral_public_key.to_string(),
+        ])?)
+        .tag(tag(["revoked", "true"])?)
+        .tag(tag(["d", &invite_d_tag(invite)])?)
+        .tag(tag(["l", INVITE_LIST_LABEL])?)
+        .custom_created_at(Timestamp::from(revoked_at.get()));
+    finish_invite_event(invite, builder)
+}
+
+fn invite_d_tag(invite: &Invite) -> String {
+    let d_suffix = invite
+        .device_id
+        .clone()
+        .unwrap_or_else(|| invite.inviter_device_pubkey.to_string());
+    format!("double-ratchet/invites/{d_suffix}")
+}
 
+fn finish_invite_event(invite: &Invite, mut builder: EventBuilder) -> Result<UnsignedEvent> {
+    let inviter_device_pubkey = invite.inviter_device_pubkey;
     let owner = invite
         .owner_public_key
         .map(|pk| OwnerPubkey::from_bytes(pk.to_bytes()))
@@ -361,6 +598,12 @@ pub fn invite_unsigned_event(invite: &Invite) -> Result<UnsignedEvent> {
     if let Some(inviter_owner_pubkey) = owner {
         builder = builder.tag(tag(["ownerPublicKey", &inviter_owner_pubkey.to_string()])?);
     }
+    if let Some(expires_at) = invite.expires_at {
+        builder = builder.tag(tag(["expiration", &expires_at.get().to_string()])?);
+    }
+    if let Some(pow_difficulty) = invite.pow_difficulty {
+        builder = builder.tag(tag(["pow", &pow_difficulty.to_string()])?);
+    }
 
     Ok(builder.build(public_key(inviter_device_pubkey)?))
 }
@@ -380,6 +623,9 @@ pub fn parse_invite_event(event: &Event) -> Result<Invite> {
             "invite event author does not match inviter device".to_string(),
         ));
     }
+    let revoked_at = optional_tag_value(event, "revoked")
+        .filter(|value| value == "true")
+        .map(|_| UnixSeconds(event.created_at.as_secs()));
 
     Ok(Invite {
         inviter_device_pubkey,
@@ -387,12 +633,32 @@ pub fn parse_invite_event(event: &Event) -> Result<Invite> {
             event,
             "ephemeralKey",
         )?)?,
-        shared_secret: parse_hex_32(&required_tag_value(event, "sharedSecret")?)?,
+        shared_secret: SecretBytes::new(match revoked_at {
+            Some(_) => [0u8; 32],
+            None => parse_hex_32(&required_tag_value(event, "sharedSecret")?)?,
+        }),
         inviter_ephemeral_private_key: None,
         max_uses: None,
         used_by: Vec::new(),
-        used_response_contents: Vec::new(),
+        used_responses: InviteReplayCache::default(),
         created_at: UnixSeconds(event.created_at.as_secs()),
+        expires_at: optional_tag_value(event, "expiration")
+            .map(|value| {
+                value
+                    .parse::<u64>()
+                    .map(UnixSeconds)
+                    .map_err(|error| Error::InvalidEvent(error.to_string()))
+            })
+            .transpose()?,
+        revoked_at,
+        pow_difficulty: optional_tag_value(event, "pow")
+            .map(|value| {
+                value
+                    .parse::<u8>()
+                    .map_err(|error| Error::InvalidEvent(error.to_string()))
+                    .and_then(checked_pow_difficulty)
+            })
+            .transpose()?,
         inviter_owner_pubkey,
         purpose: None,
         inviter: event.pubkey,
@@ -404,7 +670,7 @@ pub fn parse_invite_event(event: &Event) -> Result<Invite> {
 }
 
 pub fn invite_response_event(envelope: &InviteResponseEnvelope) -> Result<Event> {
-    let author_secret_key = secret_key_from_bytes(&envelope.signer_secret_key)?;
+    let author_secret_key = secret_key_from_bytes(envelope.signer_secret_key.expose_secret())?;
     let author_keys = Keys::new(author_secret_key);
     let derived_sender = DevicePubkey::from_bytes(author_keys.public_key().to_bytes());
     if derived_sender != envelope.sender {
@@ -413,31 +679,107 @@ pub fn invite_response_event(envelope: &InviteResponseEnvelope) -> Result<Event>
         ));
     }
 
-    let unsigned = EventBuilder::new(
+    let mut builder = EventBuilder::new(
         Kind::from(INVITE_RESPONSE_KIND as u16),
         envelope.content.clone(),
     )
-    .tag(tag(["p", &envelope.recipient.to_string()])?)
-    .custom_created_at(Timestamp::from(envelope.created_at.get()))
-    .build(public_key(envelope.sender)?);
+    .tag(tag(["p", &envelope.recipient.to_string()])?);
+    if let Some(pow) = envelope.proof_of_work {
+        builder = builder.tag(tag([
+            "nonce",
+            &pow.nonce.to_string(),
+            &pow.difficulty.to_string(),
+        ])?);
+    }
+    let unsigned = builder
+        .custom_created_at(Timestamp::from(envelope.created_at.get()))
+        .build(public_key(envelope.sender)?);
 
     Ok(unsigned.sign_with_keys(&author_keys)?)
 }
 
+/// The committed NIP-13 difficulty is checked against the event id before
+/// the signature, so underworked spam is dropped at the cost of one hash.
 pub fn parse_invite_response_event(event: &Event) -> Result<InviteResponseEnvelope> {
+    parse_invite_response_event_with_difficulty(event, 0)
+}
+
+/// Parse an invite response to an invite demanding `required_difficulty`.
+/// The work is checked against the event id before the signature, so
+/// underworked spam is refused without a Schnorr verification.
+pub fn parse_invite_response_event_with_difficulty(
+    event: &Event,
+    required_difficulty: u8,
+) -> Result<InviteResponseEnvelope> {
     verify_event_kind(event, INVITE_RESPONSE_KIND)?;
+    if !event.verify_id() {
+        return Err(Error::InvalidEvent("invalid event id".to_string()));
+    }
+    let proof_of_work = parse_nonce_tag(event)?;
+    if proof_of_work.is_some_and(|pow| !event.id.check_pow(pow.difficulty)) {
+        return Err(Error::InvalidEvent(
+            "event id does not meet committed difficulty".to_string(),
+        ));
+    }
+    if required_difficulty > 0
+        && proof_of_work.is_none_or(|pow| pow.difficulty < required_difficulty)
+    {
+        return Err(CoreError::from(DomainError::InsufficientProofOfWork).into());
+    }
     event.verify()?;
-    let recipient = parse_device_pubkey(&required_tag_value(event, "p")?)?;
+    let recipient = invite_response_recipient(event)?;
 
     Ok(InviteResponseEnvelope {
         sender: DevicePubkey::from_bytes(event.pubkey.to_bytes()),
-        signer_secret_key: [0u8; 32],
+        signer_secret_key: SecretBytes::new([0u8; 32]),
         recipient,
         created_at: UnixSeconds(event.created_at.as_secs()),
         content: event.content.clone(),
+        proof_of_work,
+        event_id: Some(event.id.to_bytes()),
     })
 }
 
+/// Device the invite response is addressed to, read without verifying the
+/// event, so the invite's required difficulty can be looked up before
+/// parsing.
+pub(crate) fn invite_response_recipient(event: &Event) -> Result<DevicePubkey> {
+    parse_device_pubkey(&required_tag_value(event, "p")?)
+}
+
+/// Invites demanding more work than responders will mine are refused up
+/// front, so a send never stalls on them.
+fn checked_pow_difficulty(difficulty: u8) -> Result<u8> {
+    if difficulty > MAX_INVITE_POW_DIFFICULTY {
+        return Err(Error::InvalidEvent(format!(
+            "pow difficulty above {MAX_INVITE_POW_DIFFICULTY}"
+        )));
+    }
+    Ok(difficulty)
+}
+
+fn parse_nonce_tag(event: &Event) -> Result<Option<ProofOfWork>> {
+    let Some(values) = event
+        .tags
+        .iter()
+        .map(|tag| tag.as_slice())
+        .find(|values| values.first().map(String::as_str) == Some("nonce"))
+    else {
+        return Ok(None);
+    };
+    match values {
+        [_, nonce, difficulty] => Ok(Some(ProofOfWork {
+            nonce: nonce
+                .parse()
+                .map_err(|_| Error::InvalidEvent("invalid nonce".to_string()))?,
+            difficulty: difficulty
+                .parse()
+                .map_err(|_| Error::InvalidEvent("invalid nonce difficulty".to_string()))?,
+        })),
+        _ => Err(Error::InvalidEvent("invalid nonce tag".to_string())),
+    }
+}
+
 pub fn roster_unsigned_event(
     owner_pubkey: OwnerPubkey,
     roster: &DeviceRoster,
@@ -570,7 +912,7 @@ mod tests {
         let event = message_event(&MessageEnvelope {
             sender,
             recipient: Some(DevicePubkey::from_bytes([7u8; 32])),
-            signer_secret_key: signer_secret,
+            signer_secret_key: SecretBytes::new(signer_secret),
             created_at: UnixSeconds(10),
             encrypted_header: "header".to_string(),
             ciphertext: "ciphertext".to_string(),
@@ -595,7 +937,7 @@ mod tests {
         let event = group_sender_key_message_event(&GroupSenderKeyMessageEnvelope {
             group_id: "group-1".to_string(),
             sender_event_pubkey,
-            signer_secret_key: signer_secret,
+            signer_secret_key: SecretBytes::new(signer_secret),
             key_id: 7,
             message_number: 11,
             encrypted_header: None,
@@ -660,12 +1002,15 @@ mod tests {
         let invite = Invite {
             inviter_device_pubkey,
             inviter_ephemeral_public_key: DevicePubkey::from_bytes([9u8; 32]),
-            shared_secret: [7u8; 32],
-            inviter_ephemeral_private_key: Some([8u8; 32]),
+            shared_secret: SecretBytes::new([7u8; 32]),
+            inviter_ephemeral_private_key: Some(SecretBytes::new([8u8; 32])),
             max_uses: None,
             used_by: Vec::new(),
-            used_response_contents: Vec::new(),
+            used_responses: InviteReplayCache::default(),
             created_at: UnixSeconds(22),
+            expires_at: None,
+            revoked_at: None,
+            pow_difficulty: None,
             inviter_owner_pubkey: Some(owner_pubkey),
             purpose: None,
             inviter: inviter_device_pubkey.to_nostr().unwrap(),
@@ -732,6 +1077,95 @@ mod tests {
         assert_eq!(parsed.purpose.as_deref(), Some("private"));
     }
 
+    #[test]
+    fn invite_expiry_roundtrips_through_url_and_event() {
+        let keys = Keys::generate();
+        let mut invite = Invite::create_new(keys.public_key(), None, None).expect("invite");
+        invite.expires_at = Some(UnixSeconds(invite.created_at.get() + 3_600));
+
+        let url = invite_url(&invite, "https://chat.iris.to").unwrap();
+        assert_eq!(
+            parse_invite_url(&url).unwrap().expires_at,
+            invite.expires_at
+        );
+
+        let event = invite_unsigned_event(&invite)
+            .unwrap()
+            .sign_with_keys(&keys)
+            .unwrap();
+        assert_eq!(
+            optional_tag_value(&event, "expiration"),
+            Some((invite.created_at.get() + 3_600).to_string())
+        );
+        assert_eq!(
+            parse_invite_event(&event).unwrap().expires_at,
+            invite.expires_at
+        );
+    }
+
+    #[test]
+    fn compact_invite_roundtrips_and_is_shorter_than_url() {
+        let keys = Keys::generate();
+        let owner = Keys::generate().public_key();
+        let mut invite = Invite::create_new(keys.public_key(), Some("laptop".to_string()), Some(3))
+            .expect("invite");
+        invite.owner_public_key = Some(owner);
+        invite.purpose = Some("link".to_string());
+        invite.expires_at = Some(UnixSeconds(invite.created_at.get() + 3_600));
+        invite.pow_difficulty = Some(12);
+
+        let compact = encode_compact_invite(&invite).unwrap();
+        assert!(compact.starts_with("ndrinvite1"));
+        assert!(compact.bytes().all(|byte| byte.is_ascii_alphanumeric()));
+        let url = compact_invite_url(&invite, "https://chat.iris.to").unwrap();
+        assert!(url.len() < invite_url(&invite, "https://chat.iris.to").unwrap().len());
+
+        for parsed in [
+            parse_compact_invite(&compact).unwrap(),
+            parse_invite_url(&url).unwrap(),
+            parse_invite_url(&compact.to_uppercase()).unwrap(),
+        ] {
+            assert_eq!(parsed.inviter_device_pubkey, invite.inviter_device_pubkey);
+            assert_eq!(
+                parsed.inviter_ephemeral_public_key,
+                invite.inviter_ephemeral_public_key
+            );
+            assert_eq!(
+                parsed.shared_secret.expose_secret(),
+                invite.shared_secret.expose_secret()
+            );
+            assert_eq!(parsed.created_at, invite.created_at);
+            assert_eq!(parsed.owner_public_key, Some(owner));
+            assert_eq!(parsed.device_id.as_deref(), Some("laptop"));
+            assert_eq!(parsed.purpose.as_deref(), Some("link"));
+            assert_eq!(parsed.max_uses, Some(3));
+            assert_eq!(parsed.expires_at, invite.expires_at);
+            assert_eq!(parsed.pow_difficulty, Some(12));
+            assert!(parsed.inviter_ephemeral_private_key.is_none());
+        }
+    }
+
+    #[test]
+    fn compact_invite_rejects_corruption() {
+        let invite = Invite::create_new(Keys::generate().public_key(), None, None).unwrap();
+        let compact = encode_compact_invite(&invite).unwrap();
+
+        let mut corrupted = compact.clone().into_bytes();
+        let last = corrupted.len() - 1;
+        corrupted[last] = if corrupted[last] == b'q' { b'p' } else { b'q' };
+        let corrupted = String::from_utf8(corrupted).unwrap();
+        assert!(parse_compact_invite(&corrupted).is_err());
+        assert!(parse_invite_url(&format!("https://chat.iris.to#{corrupted}")).is_err());
+
+        let (_, mut data) = bech32::decode(&compact).unwrap();
+        let wrong_prefix = bech32::encode::<Bech32>(Hrp::parse_unchecked("npub"), &data).unwrap();
+        assert!(parse_compact_invite(&wrong_prefix).is_err());
+        data.truncate(64);
+        let truncated =
+            bech32::encode::<Bech32>(Hrp::parse_unchecked(COMPACT_INVITE_HRP), &data).unwrap();
+        assert!(parse_compact_invite(&truncated).is_err());
+    }
+
     #[test]
     fn invite_url_accepts_owner_public_key_alias() {
         let device = Keys::generate().public_key();
@@ -781,10 +1215,12 @@ mod tests {
 
         let event = invite_response_event(&InviteResponseEnvelope {
             sender,
-            signer_secret_key: sender_secret,
+            signer_secret_key: SecretBytes::new(sender_secret),
             recipient,
             created_at: UnixSeconds(25),
             content: "payload".to_string(),
+            proof_of_work: None,
+            event_id: None,
         })
         .unwrap();
 
@@ -812,12 +1248,15 @@ mod tests {
         let invite = Invite {
             inviter_device_pubkey,
             inviter_ephemeral_public_key: DevicePubkey::from_bytes([9u8; 32]),
-            shared_secret: [7u8; 32],
-            inviter_ephemeral_private_key: Some([8u8; 32]),
+            shared_secret: SecretBytes::new([7u8; 32]),
+            inviter_ephemeral_private_key: Some(SecretBytes::new([8u8; 32])),
             max_uses: None,
             used_by: Vec::new(),
-            used_response_contents: Vec::new(),
+            used_responses: InviteReplayCache::default(),
             created_at: UnixSeconds(22),
+            expires_at: None,
+            revoked_at: None,
+            pow_difficulty: None,
             inviter_owner_pubkey: Some(owner_pubkey),
             purpose: None,
             inviter: inviter_device_pubkey.to_nostr().unwrap(),
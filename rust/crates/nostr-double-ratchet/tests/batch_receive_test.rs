@@ -0,0 +1,138 @@
+mod support;
+
+use nostr_double_ratchet::{MessageEnvelope, Result};
+use support::{
+    checkpoint_session, connected_managers, context, direct_session_pair, manager_device,
+    mutate_text, payload_text, receive_message, send_text, SentMessage,
+};
+
+const BASE: u64 = 1_800_000_000;
+
+fn incoming(sent: &[&SentMessage]) -> Vec<MessageEnvelope> {
+    sent.iter().map(|sent| sent.incoming.clone()).collect()
+}
+
+#[test]
+fn batch_matches_sequential_receive_and_keeps_input_order() -> Result<()> {
+    let (_, _, mut alice, bob) = direct_session_pair(1, 2, BASE)?;
+    let sent = (0..5)
+        .map(|index| send_text(&mut alice, &mut context(1, BASE + 1), format!("m{index}")))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut sequential = bob.clone();
+    let mut ctx = context(2, BASE + 2);
+    for message in &sent {
+        receive_message(&mut sequential, &mut ctx, &message.incoming)?;
+    }
+
+    let mut batched = bob;
+    let shuffled = incoming(&[&sent[3], &sent[0], &sent[4], &sent[1], &sent[2]]);
+    let payloads = batched
+        .receive_batch(&mut context(2, BASE + 2), &shuffled)
+        .into_iter()
+        .map(|outcome| outcome.map(|outcome| payload_text(&outcome.payload)))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(payloads, vec!["m3", "m0", "m4", "m1", "m2"]);
+    assert_eq!(
+        checkpoint_session(&batched),
+        checkpoint_session(&sequential)
+    );
+    Ok(())
+}
+
+#[test]
+fn batch_spans_chains_and_rejects_bad_messages_individually() -> Result<()> {
+    let (_, _, mut alice, mut bob) = direct_session_pair(3, 4, BASE)?;
+    let first = send_text(&mut alice, &mut context(3, BASE + 1), "first")?;
+    receive_message(&mut bob, &mut context(4, BASE + 2), &first.incoming)?;
+    let reply = send_text(&mut bob, &mut context(5, BASE + 3), "reply")?;
+
+    let old_one = send_text(&mut alice, &mut context(6, BASE + 4), "old one")?;
+    let old_two = send_text(&mut alice, &mut context(7, BASE + 5), "old two")?;
+    receive_message(&mut alice, &mut context(8, BASE + 6), &reply.incoming)?;
+    let new_one = send_text(&mut alice, &mut context(9, BASE + 7), "new one")?;
+
+    let mut tampered = old_two.incoming.clone();
+    tampered.ciphertext = mutate_text(&tampered.ciphertext);
+    let batch = vec![
+        new_one.incoming.clone(),
+        tampered,
+        old_two.incoming.clone(),
+        old_one.incoming.clone(),
+        first.incoming.clone(),
+    ];
+    let results = bob.receive_batch(&mut context(10, BASE + 8), &batch);
+
+    let texts: Vec<Option<String>> = results
+        .iter()
+        .map(|result| {
+            result
+                .as_ref()
+                .ok()
+                .map(|outcome| payload_text(&outcome.payload))
+        })
+        .collect();
+    assert_eq!(
+        texts,
+        vec![
+            Some("new one".to_string()),
+            None,
+            Some("old two".to_string()),
+            Some("old one".to_string()),
+            None,
+        ]
+    );
+    assert!(checkpoint_session(&bob).skipped_keys.is_empty());
+
+    let after = send_text(&mut alice, &mut context(11, BASE + 9), "after")?;
+    let payload = receive_message(&mut bob, &mut context(12, BASE + 10), &after.incoming)?;
+    assert_eq!(payload_text(&payload), "after");
+    Ok(())
+}
+
+#[test]
+fn manager_batch_routes_envelopes_and_falls_back_for_unknown_senders() -> Result<()> {
+    let alice = manager_device(5, 51);
+    let bob = manager_device(6, 61);
+    let carol = manager_device(7, 71);
+    let (mut alice_manager, mut bob_manager) = connected_managers(&alice, &bob, BASE)?;
+
+    let mut envelopes = Vec::new();
+    for index in 0..4 {
+        let prepared = alice_manager.prepare_send(
+            &mut context(20 + index, BASE + 10),
+            bob.owner_pubkey,
+            format!("m{index}").into_bytes(),
+        )?;
+        envelopes.push(prepared.deliveries[0].envelope.clone());
+    }
+    envelopes.reverse();
+    let mut stranger = envelopes[0].clone();
+    stranger.sender = carol.device_pubkey;
+    envelopes.push(stranger);
+
+    let results = bob_manager.receive_batch(&mut context(31, BASE + 11), &envelopes);
+    let texts = results
+        .into_iter()
+        .map(|result| {
+            result.map(|received| {
+                received.map(|received| {
+                    assert_eq!(received.owner_pubkey, alice.owner_pubkey);
+                    payload_text(&received.payload)
+                })
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        texts,
+        vec![
+            Some("m3".to_string()),
+            Some("m2".to_string()),
+            Some("m1".to_string()),
+            Some("m0".to_string()),
+            None,
+        ]
+    );
+    assert!(bob_manager.has_pending_changes());
+    Ok(())
+}
@@ -0,0 +1,79 @@
+mod support;
+
+use nostr_double_ratchet::Result;
+use support::{
+    checkpoint_session, context, direct_session_pair, payload_text, receive_message,
+    restore_session, send_text,
+};
+
+const BASE: u64 = 1_800_000_000;
+
+#[test]
+fn backfill_on_one_chain_derives_conversation_keys_once() -> Result<()> {
+    let (_, _, mut alice, mut bob) = direct_session_pair(1, 2, BASE)?;
+    let sent = (0..50)
+        .map(|index| send_text(&mut alice, &mut context(1, BASE + 1), format!("m{index}")))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(alice.conversation_key_derivations(), 1);
+
+    receive_message(&mut bob, &mut context(2, BASE + 2), &sent[0].incoming)?;
+    let after_first = bob.conversation_key_derivations();
+    assert!(after_first > 0);
+    for (index, message) in sent.iter().enumerate().skip(1) {
+        let payload = receive_message(&mut bob, &mut context(3, BASE + 3), &message.incoming)?;
+        assert_eq!(payload_text(&payload), format!("m{index}"));
+    }
+    assert_eq!(bob.conversation_key_derivations(), after_first);
+    Ok(())
+}
+
+#[test]
+fn restored_session_rebuilds_cache_on_demand() -> Result<()> {
+    let (_, _, mut alice, mut bob) = direct_session_pair(3, 4, BASE)?;
+    let first = send_text(&mut alice, &mut context(4, BASE + 1), "first")?;
+    receive_message(&mut bob, &mut context(5, BASE + 2), &first.incoming)?;
+
+    let state = checkpoint_session(&bob);
+    assert!(!serde_json::to_string(&state)
+        .expect("state serializes")
+        .contains("conversation"));
+    let mut restored = restore_session(&state);
+    assert_eq!(restored.conversation_key_derivations(), 0);
+
+    let second = send_text(&mut alice, &mut context(6, BASE + 3), "second")?;
+    let third = send_text(&mut alice, &mut context(7, BASE + 4), "third")?;
+    receive_message(&mut restored, &mut context(8, BASE + 5), &second.incoming)?;
+    let derivations = restored.conversation_key_derivations();
+    receive_message(&mut restored, &mut context(9, BASE + 6), &third.incoming)?;
+    assert_eq!(restored.conversation_key_derivations(), derivations);
+    Ok(())
+}
+
+#[test]
+fn ping_pong_derivations_grow_with_ratchet_steps_only() -> Result<()> {
+    let (_, _, mut alice, mut bob) = direct_session_pair(5, 6, BASE)?;
+    let mut seed = 10;
+    for _ in 0..10 {
+        for _ in 0..5 {
+            let message = send_text(&mut alice, &mut context(seed, BASE + seed), "ping")?;
+            receive_message(
+                &mut bob,
+                &mut context(seed + 1, BASE + seed),
+                &message.incoming,
+            )?;
+            seed += 2;
+        }
+        let reply = send_text(&mut bob, &mut context(seed, BASE + seed), "pong")?;
+        receive_message(
+            &mut alice,
+            &mut context(seed + 1, BASE + seed),
+            &reply.incoming,
+        )?;
+        seed += 2;
+    }
+    // Ten round trips, each a ratchet step on both sides. Without the cache
+    // each of the fifty pings alone would cost a derivation on both sides.
+    assert!(bob.conversation_key_derivations() <= 30);
+    assert!(alice.conversation_key_derivations() <= 30);
+    Ok(())
+}
@@ -0,0 +1,301 @@
+mod support;
+
+use nostr_double_ratchet::{
+    BrokenSession, Delivery, DomainError, Error, Result, RetentionPolicy, SessionLimits,
+    SessionManager, DEFAULT_DECRYPT_FAILURE_THRESHOLD,
+};
+use support::{
+    connected_managers, context, manager_device, manager_device_snapshot,
+    manager_observe_invite_response, manager_receive_delivery, manager_user_snapshot, mutate_text,
+    ManagerDevice,
+};
+
+const BASE: u64 = 1_800_000_000;
+
+fn corrupted(delivery: &Delivery) -> Delivery {
+    let mut delivery = delivery.clone();
+    delivery.envelope.encrypted_header = mutate_text(&delivery.envelope.encrypted_header);
+    delivery
+}
+
+fn decrypt_failures(manager: &SessionManager, owner: &ManagerDevice) -> u32 {
+    let snapshot = manager.snapshot();
+    manager_device_snapshot(
+        manager_user_snapshot(&snapshot, owner.owner_pubkey),
+        owner.device_pubkey,
+    )
+    .decrypt_failures
+}
+
+#[test]
+fn repeated_header_failures_close_sessions_and_rehandshake() -> Result<()> {
+    let alice = manager_device(1, 11);
+    let bob = manager_device(2, 21);
+    let (mut alice_manager, mut bob_manager) = connected_managers(&alice, &bob, BASE)?;
+    assert_eq!(
+        bob_manager.decrypt_failure_threshold(),
+        Some(DEFAULT_DECRYPT_FAILURE_THRESHOLD)
+    );
+    bob_manager.set_decrypt_failure_threshold(Some(2));
+
+    let message = alice_manager.prepare_send(
+        &mut context(10, BASE + 10),
+        bob.owner_pubkey,
+        b"tampered".to_vec(),
+    )?;
+    let tampered = corrupted(&message.deliveries[0]);
+    for seed in [11, 12] {
+        assert!(matches!(
+            manager_receive_delivery(
+                &mut bob_manager,
+                &mut context(seed, BASE + 11),
+                alice.owner_pubkey,
+                &tampered,
+            ),
+            Err(Error::FailedToDecryptHeader)
+        ));
+    }
+    assert_eq!(decrypt_failures(&bob_manager, &alice), 2);
+    assert_eq!(
+        bob_manager.take_broken_sessions(),
+        vec![BrokenSession {
+            owner_pubkey: alice.owner_pubkey,
+            device_pubkey: alice.device_pubkey,
+            decrypt_failures: 2,
+        }]
+    );
+    assert!(bob_manager.take_broken_sessions().is_empty());
+
+    let reply = bob_manager.prepare_send(
+        &mut context(13, BASE + 12),
+        alice.owner_pubkey,
+        b"fresh start".to_vec(),
+    )?;
+    assert_eq!(reply.invite_responses.len(), 1);
+    manager_observe_invite_response(
+        &mut alice_manager,
+        &mut context(14, BASE + 13),
+        &reply.invite_responses[0],
+    )?;
+    let received = manager_receive_delivery(
+        &mut alice_manager,
+        &mut context(15, BASE + 14),
+        bob.owner_pubkey,
+        &reply.deliveries[0],
+    )?
+    .expect("reply over new session decrypts");
+    assert_eq!(received.payload, b"fresh start");
+    Ok(())
+}
+
+#[test]
+fn success_resets_count_and_duplicates_are_not_failures() -> Result<()> {
+    let alice = manager_device(3, 31);
+    let bob = manager_device(4, 41);
+    let (mut alice_manager, mut bob_manager) = connected_managers(&alice, &bob, BASE)?;
+    bob_manager.set_session_limits(SessionLimits {
+        max_skip_per_chain: 1,
+        ..SessionLimits::default()
+    });
+
+    let sent: Vec<_> = (0..3)
+        .map(|index| {
+            alice_manager.prepare_send(
+                &mut context(20 + index, BASE + 10),
+                bob.owner_pubkey,
+                format!("m{index}").into_bytes(),
+            )
+        })
+        .collect::<Result<_>>()?;
+    assert!(manager_receive_delivery(
+        &mut bob_manager,
+        &mut context(29, BASE + 11),
+        alice.owner_pubkey,
+        &corrupted(&sent[1].deliveries[0]),
+    )
+    .is_err());
+    assert_eq!(decrypt_failures(&bob_manager, &alice), 1);
+    for seed in 36..40 {
+        assert!(matches!(
+            manager_receive_delivery(
+                &mut bob_manager,
+                &mut context(seed, BASE + 11),
+                alice.owner_pubkey,
+                &sent[2].deliveries[0],
+            ),
+            Err(Error::Domain(DomainError::TooManySkippedMessages))
+        ));
+    }
+    assert_eq!(decrypt_failures(&bob_manager, &alice), 1);
+
+    manager_receive_delivery(
+        &mut bob_manager,
+        &mut context(31, BASE + 12),
+        alice.owner_pubkey,
+        &sent[0].deliveries[0],
+    )?
+    .expect("in-order message decrypts");
+    assert_eq!(decrypt_failures(&bob_manager, &alice), 0);
+
+    for seed in 32..36 {
+        assert!(manager_receive_delivery(
+            &mut bob_manager,
+            &mut context(seed, BASE + 13),
+            alice.owner_pubkey,
+            &sent[0].deliveries[0],
+        )
+        .is_err());
+    }
+    assert_eq!(decrypt_failures(&bob_manager, &alice), 0);
+    assert!(bob_manager.take_broken_sessions().is_empty());
+    Ok(())
+}
+
+#[test]
+fn disabled_threshold_never_closes_sessions() -> Result<()> {
+    let alice = manager_device(5, 51);
+    let bob = manager_device(6, 61);
+    let (mut alice_manager, mut bob_manager) = connected_managers(&alice, &bob, BASE)?;
+    bob_manager.set_decrypt_failure_threshold(None);
+
+    let message = alice_manager.prepare_send(
+        &mut context(40, BASE + 10),
+        bob.owner_pubkey,
+        b"later".to_vec(),
+    )?;
+    let tampered = corrupted(&message.deliveries[0]);
+    for seed in 41..46 {
+        assert!(manager_receive_delivery(
+            &mut bob_manager,
+            &mut context(seed, BASE + 11),
+            alice.owner_pubkey,
+            &tampered,
+        )
+        .is_err());
+    }
+    assert_eq!(decrypt_failures(&bob_manager, &alice), 5);
+    assert!(bob_manager.take_broken_sessions().is_empty());
+
+    let received = manager_receive_delivery(
+        &mut bob_manager,
+        &mut context(46, BASE + 12),
+        alice.owner_pubkey,
+        &message.deliveries[0],
+    )?
+    .expect("untampered message still decrypts");
+    assert_eq!(received.payload, b"later");
+    Ok(())
+}
+
+#[test]
+fn replayed_events_from_retired_ratchet_keys_leave_the_session_open() -> Result<()> {
+    let alice = manager_device(7, 71);
+    let bob = manager_device(8, 81);
+    let (mut alice_manager, mut bob_manager) = connected_managers(&alice, &bob, BASE)?;
+    bob_manager.set_decrypt_failure_threshold(Some(1));
+    bob_manager.set_retention_policy(RetentionPolicy {
+        seen_message_id_limit: 0,
+        ..RetentionPolicy::default()
+    });
+
+    let skipped = alice_manager.prepare_send(
+        &mut context(49, BASE + 10),
+        bob.owner_pubkey,
+        b"skipped".to_vec(),
+    )?;
+    let old = alice_manager.prepare_send(
+        &mut context(50, BASE + 10),
+        bob.owner_pubkey,
+        b"old".to_vec(),
+    )?;
+    manager_receive_delivery(
+        &mut bob_manager,
+        &mut context(51, BASE + 11),
+        alice.owner_pubkey,
+        &old.deliveries[0],
+    )?
+    .expect("old message decrypts");
+    let reply = bob_manager.prepare_send(
+        &mut context(52, BASE + 12),
+        alice.owner_pubkey,
+        b"reply".to_vec(),
+    )?;
+    manager_receive_delivery(
+        &mut alice_manager,
+        &mut context(53, BASE + 13),
+        bob.owner_pubkey,
+        &reply.deliveries[0],
+    )?
+    .expect("reply decrypts");
+    let current = alice_manager.prepare_send(
+        &mut context(54, BASE + 14),
+        bob.owner_pubkey,
+        b"current".to_vec(),
+    )?;
+    assert_ne!(
+        current.deliveries[0].envelope.sender,
+        old.deliveries[0].envelope.sender
+    );
+    manager_receive_delivery(
+        &mut bob_manager,
+        &mut context(55, BASE + 15),
+        alice.owner_pubkey,
+        &current.deliveries[0],
+    )?
+    .expect("current message decrypts");
+
+    let replays = [
+        old.deliveries[0].clone(),
+        corrupted(&old.deliveries[0]),
+        corrupted(&skipped.deliveries[0]),
+    ];
+    for (seed, replayed) in (56..).zip(replays) {
+        assert!(!matches!(
+            manager_receive_delivery(
+                &mut bob_manager,
+                &mut context(seed, BASE + 16),
+                alice.owner_pubkey,
+                &replayed,
+            ),
+            Ok(Some(_))
+        ));
+    }
+    assert_eq!(decrypt_failures(&bob_manager, &alice), 0);
+    assert!(bob_manager.take_broken_sessions().is_empty());
+
+    let next = alice_manager.prepare_send(
+        &mut context(60, BASE + 17),
+        bob.owner_pubkey,
+        b"still open".to_vec(),
+    )?;
+    assert!(next.invite_responses.is_empty());
+    let received = manager_receive_delivery(
+        &mut bob_manager,
+        &mut context(61, BASE + 18),
+        alice.owner_pubkey,
+        &next.deliveries[0],
+    )?
+    .expect("session is still open");
+    assert_eq!(received.payload, b"still open");
+    Ok(())
+}
+
+#[test]
+fn threshold_survives_snapshot_restore() -> Result<()> {
+    let alice = manager_device(9, 91);
+    let bob = manager_device(10, 101);
+    let (_, mut bob_manager) = connected_managers(&alice, &bob, BASE)?;
+    bob_manager.take_changes();
+
+    bob_manager.set_decrypt_failure_threshold(None);
+    let changes = bob_manager.take_changes();
+    assert_eq!(
+        changes
+            .settings
+            .map(|settings| settings.decrypt_failure_threshold),
+        Some(None)
+    );
+    let restored = SessionManager::from_snapshot(bob_manager.snapshot(), bob.secret_key)?;
+    assert_eq!(restored.decrypt_failure_threshold(), None);
+    Ok(())
+}
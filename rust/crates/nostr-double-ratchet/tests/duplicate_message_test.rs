@@ -0,0 +1,252 @@
+mod support;
+
+use nostr_double_ratchet::{
+    DomainError, Error, InMemoryStorageAdapter, Result, RetentionPolicy, SeenMessageIdChanges,
+    SessionManager, SessionManagerChanges, SessionManagerStore, StorageAdapter,
+    SESSION_MANAGER_STORAGE_PREFIX,
+};
+use support::{
+    connected_managers, context, direct_session_pair, manager_device, manager_receive_delivery,
+    payload_text, receive_message, send_text,
+};
+
+const BASE: u64 = 1_800_000_000;
+
+fn is_duplicate<T>(result: &Result<T>) -> bool {
+    matches!(result, Err(Error::Domain(DomainError::DuplicateMessage)))
+}
+
+fn is_outside_retention<T>(result: &Result<T>) -> bool {
+    matches!(
+        result,
+        Err(Error::Domain(DomainError::MessageOutsideRetention))
+    )
+}
+
+#[test]
+fn session_reports_replayed_message_numbers_as_duplicates() -> Result<()> {
+    let (_, _, mut alice, mut bob) = direct_session_pair(1, 2, BASE)?;
+    let first = send_text(&mut alice, &mut context(1, BASE + 1), "first")?;
+    let second = send_text(&mut alice, &mut context(1, BASE + 1), "second")?;
+
+    let mut ctx = context(2, BASE + 2);
+    receive_message(&mut bob, &mut ctx, &second.incoming)?;
+    assert!(is_duplicate(&receive_message(
+        &mut bob,
+        &mut ctx,
+        &second.incoming
+    )));
+
+    let payload = receive_message(&mut bob, &mut ctx, &first.incoming)?;
+    assert_eq!(payload_text(&payload), "first");
+    assert!(is_duplicate(&receive_message(
+        &mut bob,
+        &mut ctx,
+        &first.incoming
+    )));
+
+    let replays = bob.receive_batch(&mut ctx, &[first.incoming, second.incoming]);
+    assert!(replays.iter().all(is_duplicate));
+    Ok(())
+}
+
+#[test]
+fn manager_remembers_message_ids_across_restore() -> Result<()> {
+    let alice = manager_device(1, 11);
+    let bob = manager_device(2, 21);
+    let (mut alice_manager, mut bob_manager) = connected_managers(&alice, &bob, BASE)?;
+    bob_manager.take_changes();
+
+    let message = alice_manager.prepare_send(
+        &mut context(10, BASE + 10),
+        bob.owner_pubkey,
+        b"once".to_vec(),
+    )?;
+    manager_receive_delivery(
+        &mut bob_manager,
+        &mut context(11, BASE + 11),
+        alice.owner_pubkey,
+        &message.deliveries[0],
+    )?
+    .expect("message decrypts");
+    assert_eq!(bob_manager.snapshot().seen_message_ids.len(), 2);
+    let seen = bob_manager
+        .take_changes()
+        .seen_message_ids
+        .expect("new id is reported");
+    assert_eq!(seen.appended_from, 1);
+    assert_eq!(
+        seen.appended,
+        vec![message.deliveries[0].envelope.message_id()]
+    );
+
+    let mut store = SessionManagerStore::new(InMemoryStorageAdapter::new());
+    store.save_snapshot(&bob_manager.snapshot())?;
+    let mut restored = SessionManager::from_snapshot(
+        store.load_snapshot()?.expect("snapshot was saved"),
+        bob.secret_key,
+    )?;
+    let replay = manager_receive_delivery(
+        &mut restored,
+        &mut context(12, BASE + 12),
+        alice.owner_pubkey,
+        &message.deliveries[0],
+    );
+    assert!(is_duplicate(&replay));
+    assert_eq!(restored.snapshot().users[0].devices[0].decrypt_failures, 0);
+    Ok(())
+}
+
+#[test]
+fn seen_message_ids_are_bounded_and_catch_replays_from_retired_chains() -> Result<()> {
+    let alice = manager_device(1, 11);
+    let bob = manager_device(2, 21);
+    let (mut alice_manager, mut bob_manager) = connected_managers(&alice, &bob, BASE)?;
+    bob_manager.set_retention_policy(RetentionPolicy {
+        seen_message_id_limit: 2,
+        ..RetentionPolicy::default()
+    });
+
+    let old = alice_manager.prepare_send(
+        &mut context(10, BASE + 10),
+        bob.owner_pubkey,
+        b"old chain".to_vec(),
+    )?;
+    manager_receive_delivery(
+        &mut bob_manager,
+        &mut context(11, BASE + 11),
+        alice.owner_pubkey,
+        &old.deliveries[0],
+    )?;
+    let reply = bob_manager.prepare_send(
+        &mut context(12, BASE + 12),
+        alice.owner_pubkey,
+        b"reply".to_vec(),
+    )?;
+    manager_receive_delivery(
+        &mut alice_manager,
+        &mut context(13, BASE + 13),
+        bob.owner_pubkey,
+        &reply.deliveries[0],
+    )?;
+    let new = alice_manager.prepare_send(
+        &mut context(14, BASE + 14),
+        bob.owner_pubkey,
+        b"new chain".to_vec(),
+    )?;
+    manager_receive_delivery(
+        &mut bob_manager,
+        &mut context(15, BASE + 15),
+        alice.owner_pubkey,
+        &new.deliveries[0],
+    )?;
+    assert_eq!(bob_manager.snapshot().seen_message_ids.len(), 2);
+
+    let replay = |manager: &mut SessionManager| {
+        manager_receive_delivery(
+            manager,
+            &mut context(16, BASE + 16),
+            alice.owner_pubkey,
+            &old.deliveries[0],
+        )
+    };
+    assert!(is_duplicate(&replay(&mut bob_manager.clone())));
+
+    bob_manager.set_retention_policy(RetentionPolicy {
+        seen_message_id_limit: 0,
+        ..RetentionPolicy::default()
+    });
+    assert!(bob_manager.snapshot().seen_message_ids.is_empty());
+    assert!(!is_duplicate(&replay(&mut bob_manager)));
+    Ok(())
+}
+
+#[test]
+fn seen_ids_are_stored_in_chunks_and_evicted_incrementally() -> Result<()> {
+    let bob = manager_device(3, 31);
+    let mut snapshot = SessionManager::new(bob.owner_pubkey, bob.secret_key).snapshot();
+    snapshot.seen_message_id_offset = 60;
+    snapshot.seen_message_ids = (60..130).map(|seq| format!("id-{seq}")).collect();
+
+    let mut store = SessionManagerStore::new(InMemoryStorageAdapter::new());
+    store.save_snapshot(&snapshot)?;
+    let chunk_prefix = format!("{SESSION_MANAGER_STORAGE_PREFIX}seen-messages/");
+    assert_eq!(store.storage().list(&chunk_prefix)?.len(), 3);
+    assert_eq!(store.load_snapshot()?, Some(snapshot.clone()));
+
+    store.apply_changes(&SessionManagerChanges {
+        seen_message_ids: Some(SeenMessageIdChanges {
+            previous_first_seq: 60,
+            first_seq: 66,
+            appended_from: 130,
+            appended: vec!["id-130".to_string()],
+        }),
+        ..SessionManagerChanges::default()
+    })?;
+    assert_eq!(store.storage().list(&chunk_prefix)?.len(), 2);
+    let loaded = store.load_snapshot()?.expect("snapshot was saved");
+    assert_eq!(loaded.seen_message_id_offset, 66);
+    assert_eq!(
+        loaded.seen_message_ids,
+        (66..131).map(|seq| format!("id-{seq}")).collect::<Vec<_>>()
+    );
+
+    let restored = SessionManager::from_snapshot(loaded.clone(), bob.secret_key)?;
+    assert_eq!(
+        restored.snapshot().seen_message_ids,
+        loaded.seen_message_ids
+    );
+    Ok(())
+}
+
+#[test]
+fn replays_older_than_the_seen_ids_are_reported_outside_retention() -> Result<()> {
+    let alice = manager_device(4, 41);
+    let bob = manager_device(5, 51);
+    let (mut alice_manager, mut bob_manager) = connected_managers(&alice, &bob, BASE)?;
+    bob_manager.set_retention_policy(RetentionPolicy {
+        seen_message_id_limit: 1,
+        ..RetentionPolicy::default()
+    });
+
+    let mut deliveries = Vec::new();
+    for index in 0..2 {
+        let prepared = alice_manager.prepare_send(
+            &mut context(20 + index, BASE + 10),
+            bob.owner_pubkey,
+            format!("m{index}").into_bytes(),
+        )?;
+        manager_receive_delivery(
+            &mut bob_manager,
+            &mut context(30 + index, BASE + 11),
+            alice.owner_pubkey,
+            &prepared.deliveries[0],
+        )?
+        .expect("message decrypts");
+        deliveries.push(prepared.deliveries[0].clone());
+    }
+
+    let replay = |manager: &mut SessionManager, index: usize| {
+        manager_receive_delivery(
+            manager,
+            &mut context(40, BASE + 12),
+            alice.owner_pubkey,
+            &deliveries[index],
+        )
+    };
+    assert!(is_outside_retention(&replay(&mut bob_manager, 0)));
+    assert!(is_duplicate(&replay(&mut bob_manager, 1)));
+
+    let envelopes: Vec<_> = deliveries
+        .iter()
+        .map(|delivery| delivery.envelope.clone())
+        .collect();
+    let results = bob_manager.receive_batch(&mut context(41, BASE + 13), &envelopes);
+    assert!(is_outside_retention(&results[0]));
+    assert!(is_duplicate(&results[1]));
+    assert_eq!(
+        bob_manager.snapshot().users[0].devices[0].decrypt_failures,
+        0
+    );
+    Ok(())
+}
@@ -0,0 +1,256 @@
+mod support;
+
+use nostr::{EventBuilder, Keys, Kind, SecretKey, Tag, Timestamp};
+use nostr_double_ratchet::{
+    invite_response_event, invite_unsigned_event, invite_url, parse_invite_event,
+    parse_invite_response_event, parse_invite_response_event_with_difficulty, parse_invite_url,
+    wire, DomainError, Error, Invite, InviteResponseEnvelope, ProofOfWork, RelayGap, Result,
+    INVITE_RESPONSE_KIND, MAX_INVITE_POW_DIFFICULTY,
+};
+use support::{
+    context, invite_response_fixture, manager_device, manager_observe_invite_response,
+    manager_public_device_invite, public_invite_via_url, roster_for, session_manager,
+};
+
+const BASE: u64 = 1_800_000_000;
+const DIFFICULTY: u8 = 8;
+
+fn invite_with_difficulty() -> Result<(Invite, Invite)> {
+    let mut owned = invite_response_fixture(BASE, None)?.owned_invite;
+    owned.pow_difficulty = Some(DIFFICULTY);
+    let public = public_invite_via_url(&owned)?;
+    Ok((owned, public))
+}
+
+#[test]
+fn accepted_responses_carry_advertised_proof_of_work() -> Result<()> {
+    let fixture = invite_response_fixture(BASE, None)?;
+    let (mut owned, public) = invite_with_difficulty()?;
+    assert_eq!(public.pow_difficulty, Some(DIFFICULTY));
+
+    let (_, envelope) = public.accept_with_context(
+        &mut context(1, BASE + 1),
+        fixture.bob.device_pubkey,
+        fixture.bob.secret_key,
+    )?;
+    let event = invite_response_event(&envelope)?;
+    assert!(event.check_pow(DIFFICULTY));
+    let parsed = parse_invite_response_event(&event)?;
+    assert_eq!(parsed.proof_of_work, envelope.proof_of_work);
+    assert!(parsed.meets_difficulty(DIFFICULTY));
+    owned.process_response(&mut context(2, BASE + 2), &parsed, fixture.alice.secret_key)?;
+
+    let mut too_hard = owned.clone();
+    too_hard.pow_difficulty = Some(MAX_INVITE_POW_DIFFICULTY + 1);
+    assert!(too_hard
+        .accept_with_context(
+            &mut context(3, BASE + 3),
+            fixture.bob.device_pubkey,
+            fixture.bob.secret_key,
+        )
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn underworked_responses_are_rejected_before_decryption() -> Result<()> {
+    let fixture = invite_response_fixture(BASE, None)?;
+    let (mut owned, public) = invite_with_difficulty()?;
+    let (_, envelope) = public.accept_with_context(
+        &mut context(4, BASE + 1),
+        fixture.bob.device_pubkey,
+        fixture.bob.secret_key,
+    )?;
+
+    let garbage = InviteResponseEnvelope {
+        content: "not even ciphertext".to_string(),
+        proof_of_work: None,
+        ..envelope.clone()
+    };
+    assert!(matches!(
+        owned.process_response(
+            &mut context(5, BASE + 2),
+            &garbage,
+            fixture.alice.secret_key
+        ),
+        Err(Error::Domain(DomainError::InsufficientProofOfWork))
+    ));
+
+    let pow = envelope.proof_of_work.expect("response was mined");
+    let forged = (1..)
+        .map(|offset| InviteResponseEnvelope {
+            proof_of_work: Some(ProofOfWork {
+                nonce: pow.nonce.wrapping_add(offset),
+                ..pow
+            }),
+            ..envelope.clone()
+        })
+        .find(|candidate| {
+            invite_response_event(candidate).is_ok_and(|event| !event.check_pow(DIFFICULTY))
+        })
+        .expect("some nonce misses the target");
+    assert!(parse_invite_response_event(&invite_response_event(&forged)?).is_err());
+
+    let understated = InviteResponseEnvelope {
+        proof_of_work: Some(ProofOfWork {
+            difficulty: DIFFICULTY - 1,
+            ..pow
+        }),
+        ..envelope
+    };
+    assert!(matches!(
+        owned.process_response(
+            &mut context(6, BASE + 2),
+            &understated,
+            fixture.alice.secret_key
+        ),
+        Err(Error::Domain(DomainError::InsufficientProofOfWork))
+    ));
+    Ok(())
+}
+
+#[test]
+fn manager_invites_demand_configured_difficulty() -> Result<()> {
+    let alice = manager_device(1, 11);
+    let bob = manager_device(2, 21);
+    let mut bob_manager = session_manager(&bob);
+    assert!(bob_manager
+        .set_invite_pow_difficulty(Some(MAX_INVITE_POW_DIFFICULTY + 1))
+        .is_err());
+    bob_manager.set_invite_pow_difficulty(Some(DIFFICULTY))?;
+    let invite = bob_manager
+        .ensure_local_invite(&mut context(1, BASE))?
+        .clone();
+    let event = invite_unsigned_event(&invite)?.sign_with_keys(&bob.keys)?;
+    let published = parse_invite_event(&event)?;
+    assert_eq!(published.pow_difficulty, Some(DIFFICULTY));
+
+    let mut alice_manager = session_manager(&alice);
+    alice_manager.observe_peer_roster(bob.owner_pubkey, roster_for(&[&bob], 10));
+    alice_manager.observe_device_invite(bob.owner_pubkey, published)?;
+    let sent = alice_manager.prepare_send(
+        &mut context(2, BASE + 10),
+        bob.owner_pubkey,
+        b"worked for it".to_vec(),
+    )?;
+    let response = sent.invite_responses[0].clone();
+
+    let unmined = InviteResponseEnvelope {
+        proof_of_work: None,
+        ..response.clone()
+    };
+    assert!(matches!(
+        manager_observe_invite_response(&mut bob_manager, &mut context(3, BASE + 11), &unmined),
+        Err(Error::Domain(DomainError::InsufficientProofOfWork))
+    ));
+    manager_observe_invite_response(&mut bob_manager, &mut context(4, BASE + 11), &response)?
+        .expect("mined response is processed");
+    Ok(())
+}
+
+#[test]
+fn proof_of_work_is_checked_against_the_signed_event_id() -> Result<()> {
+    let fixture = invite_response_fixture(BASE, None)?;
+    let (mut owned, public) = invite_with_difficulty()?;
+    let (_, envelope) = public.accept_with_context(
+        &mut context(8, BASE + 1),
+        fixture.bob.device_pubkey,
+        fixture.bob.secret_key,
+    )?;
+
+    let signer = Keys::new(SecretKey::from_slice(
+        envelope.signer_secret_key.expose_secret(),
+    )?);
+    let event = EventBuilder::new(Kind::from(INVITE_RESPONSE_KIND as u16), &envelope.content)
+        .tags([
+            Tag::parse(["client", "other"]).expect("valid tag"),
+            Tag::parse(["p", &envelope.recipient.to_string()]).expect("valid tag"),
+        ])
+        .custom_created_at(Timestamp::from(envelope.created_at.get()))
+        .pow(DIFFICULTY)
+        .sign_with_keys(&signer)
+        .expect("event signs");
+    let parsed = parse_invite_response_event(&event)?;
+    assert!(parsed.meets_difficulty(DIFFICULTY));
+    owned.process_response(&mut context(9, BASE + 2), &parsed, fixture.alice.secret_key)?;
+    Ok(())
+}
+
+#[test]
+fn underworked_responses_are_refused_before_the_signature_is_checked() -> Result<()> {
+    let fixture = invite_response_fixture(BASE, None)?;
+    let (_, public) = invite_with_difficulty()?;
+    let (_, envelope) = public.accept_with_context(
+        &mut context(10, BASE + 1),
+        fixture.bob.device_pubkey,
+        fixture.bob.secret_key,
+    )?;
+    let mined = invite_response_event(&envelope)?;
+    assert!(
+        parse_invite_response_event_with_difficulty(&mined, DIFFICULTY)?
+            .meets_difficulty(DIFFICULTY)
+    );
+
+    let signer = Keys::new(SecretKey::from_slice(
+        envelope.signer_secret_key.expose_secret(),
+    )?);
+    let mut spam = EventBuilder::new(Kind::from(INVITE_RESPONSE_KIND as u16), &envelope.content)
+        .tag(Tag::parse(["p", &envelope.recipient.to_string()]).expect("valid tag"))
+        .custom_created_at(Timestamp::from(envelope.created_at.get()))
+        .sign_with_keys(&signer)
+        .expect("event signs");
+    assert!(!spam.check_pow(DIFFICULTY));
+    spam.sig = mined.sig;
+    assert!(spam.verify().is_err());
+    assert!(matches!(
+        parse_invite_response_event_with_difficulty(&spam, DIFFICULTY),
+        Err(wire::Error::Core(Error::Domain(
+            DomainError::InsufficientProofOfWork
+        )))
+    ));
+    assert!(matches!(
+        parse_invite_response_event_with_difficulty(&spam, 0),
+        Err(wire::Error::NostrEvent(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn invites_demanding_too_much_work_are_refused_when_parsed() -> Result<()> {
+    let bob = manager_device(2, 21);
+    let mut bob_manager = session_manager(&bob);
+    let invite = bob_manager
+        .ensure_local_invite(&mut context(1, BASE))?
+        .clone();
+    let mut too_hard = invite.clone();
+    too_hard.pow_difficulty = Some(MAX_INVITE_POW_DIFFICULTY + 1);
+    assert!(invite_url(&too_hard, "https://example.com")
+        .and_then(|url| parse_invite_url(&url))
+        .is_err());
+    let event = invite_unsigned_event(&too_hard)?.sign_with_keys(&bob.keys)?;
+    assert!(parse_invite_event(&event).is_err());
+
+    let alice = manager_device(1, 11);
+    let bob_laptop = manager_device(2, 22);
+    let mut alice_manager = session_manager(&alice);
+    alice_manager.observe_peer_roster(bob.owner_pubkey, roster_for(&[&bob, &bob_laptop], 10));
+    alice_manager.observe_device_invite(bob.owner_pubkey, public_invite_via_url(&invite)?)?;
+    let mut laptop_invite =
+        manager_public_device_invite(&mut session_manager(&bob_laptop), &bob_laptop, 2, BASE)?;
+    laptop_invite.pow_difficulty = Some(MAX_INVITE_POW_DIFFICULTY + 1);
+    alice_manager.observe_device_invite(bob.owner_pubkey, laptop_invite)?;
+    let sent = alice_manager.prepare_send(
+        &mut context(3, BASE + 10),
+        bob.owner_pubkey,
+        b"still delivered".to_vec(),
+    )?;
+    assert_eq!(sent.deliveries.len(), 1);
+    assert_eq!(
+        sent.relay_gaps,
+        vec![RelayGap::MissingDeviceInvite {
+            owner_pubkey: bob.owner_pubkey,
+            device_pubkey: bob_laptop.device_pubkey,
+        }]
+    );
+    Ok(())
+}
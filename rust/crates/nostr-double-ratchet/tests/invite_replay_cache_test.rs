@@ -0,0 +1,167 @@
+mod support;
+
+use nostr_double_ratchet::{
+    decode_snapshot, DomainError, Error, Invite, InviteResponseEnvelope, Result,
+    SessionManagerSnapshot, UnixSeconds, INVITE_REPLAY_WINDOW_SECS, MAX_INVITE_REPLAY_ENTRIES,
+    MAX_INVITE_RESPONSE_CLOCK_SKEW_SECS,
+};
+use support::{
+    context, invite_response_fixture, manager_device, session_manager, snapshot,
+    InviteResponseFixture,
+};
+
+const BASE: u64 = 1_700_500_000;
+
+fn accept_at(
+    fixture: &InviteResponseFixture,
+    seed: u64,
+    now: u64,
+) -> Result<InviteResponseEnvelope> {
+    let (_, envelope) = fixture.public_invite.accept_with_context(
+        &mut context(seed, now),
+        fixture.bob.device_pubkey,
+        fixture.bob.secret_key,
+    )?;
+    Ok(envelope)
+}
+
+fn process_at(
+    fixture: &mut InviteResponseFixture,
+    envelope: &InviteResponseEnvelope,
+    seed: u64,
+    now: u64,
+) -> Result<()> {
+    fixture
+        .owned_invite
+        .process_response(&mut context(seed, now), envelope, fixture.alice.secret_key)
+        .map(|_| ())
+}
+
+#[test]
+fn replay_cache_is_bounded_and_still_refuses_evicted_responses() -> Result<()> {
+    let mut fixture = invite_response_fixture(BASE, None)?;
+    let mut envelopes = Vec::new();
+    for index in 0..=MAX_INVITE_REPLAY_ENTRIES as u64 {
+        let envelope = accept_at(&fixture, 1_000 + index, BASE + 10 + index)?;
+        process_at(&mut fixture, &envelope, 5_000 + index, BASE + 10 + index)?;
+        envelopes.push(envelope);
+    }
+
+    assert_eq!(
+        fixture.owned_invite.used_responses.len(),
+        MAX_INVITE_REPLAY_ENTRIES
+    );
+    assert!(fixture.owned_invite.used_by.is_empty());
+    let before = snapshot(&fixture.owned_invite);
+    assert!(matches!(
+        process_at(&mut fixture, &envelopes[0], 9_000, BASE + 2_000),
+        Err(Error::Domain(DomainError::StaleInviteResponse))
+    ));
+    assert!(matches!(
+        process_at(&mut fixture, &envelopes[1], 9_001, BASE + 2_000),
+        Err(Error::Domain(DomainError::InviteAlreadyUsed))
+    ));
+    assert_eq!(snapshot(&fixture.owned_invite), before);
+
+    let fresh = accept_at(&fixture, 9_002, BASE + 2_000)?;
+    process_at(&mut fixture, &fresh, 9_003, BASE + 2_001)?;
+    Ok(())
+}
+
+#[test]
+fn responses_older_than_window_are_refused_unless_invite_expires() -> Result<()> {
+    let late = BASE + INVITE_REPLAY_WINDOW_SECS + 100;
+    let mut fixture = invite_response_fixture(BASE, None)?;
+    let old = accept_at(&fixture, 10, BASE + 10)?;
+    assert!(matches!(
+        process_at(&mut fixture, &old, 11, late),
+        Err(Error::Domain(DomainError::StaleInviteResponse))
+    ));
+
+    fixture.owned_invite.expires_at = Some(UnixSeconds(late + 1));
+    process_at(&mut fixture, &old, 12, late)?;
+    assert!(matches!(
+        process_at(&mut fixture, &old, 13, late),
+        Err(Error::Domain(DomainError::InviteAlreadyUsed))
+    ));
+    Ok(())
+}
+
+#[test]
+fn legacy_response_contents_still_block_replays() -> Result<()> {
+    let mut fixture = invite_response_fixture(BASE, None)?;
+    let envelope = fixture.response_envelope.clone();
+    let mut legacy: serde_json::Value = serde_json::from_str(&fixture.owned_invite.serialize()?)?;
+    legacy["used_response_contents"] = serde_json::json!([envelope.content]);
+    fixture.owned_invite = Invite::deserialize(&legacy.to_string())?;
+
+    assert!(fixture
+        .owned_invite
+        .used_responses
+        .contains(&envelope.content));
+    assert!(matches!(
+        process_at(&mut fixture, &envelope, 20, BASE + 20),
+        Err(Error::Domain(DomainError::InviteAlreadyUsed))
+    ));
+    Ok(())
+}
+
+#[test]
+fn future_dated_responses_cannot_push_the_floor_ahead() -> Result<()> {
+    let year = 365 * 24 * 60 * 60;
+    let mut fixture = invite_response_fixture(BASE, None)?;
+    let far_future = accept_at(&fixture, 30, BASE + year)?;
+    assert!(matches!(
+        process_at(&mut fixture, &far_future, 31, BASE + 10),
+        Err(Error::Domain(DomainError::StaleInviteResponse))
+    ));
+
+    let ahead = BASE + 10 + MAX_INVITE_RESPONSE_CLOCK_SKEW_SECS;
+    for index in 0..=MAX_INVITE_REPLAY_ENTRIES as u64 {
+        let envelope = accept_at(&fixture, 1_000 + index, ahead)?;
+        process_at(&mut fixture, &envelope, 5_000 + index, BASE + 10)?;
+    }
+    let genuine = accept_at(&fixture, 9_000, ahead + 1)?;
+    process_at(&mut fixture, &genuine, 9_001, ahead + 1)?;
+    Ok(())
+}
+
+#[test]
+fn evicting_legacy_entries_still_refuses_their_replays() -> Result<()> {
+    let mut fixture = invite_response_fixture(BASE, None)?;
+    let used = fixture.response_envelope.clone();
+    let mut legacy: serde_json::Value = serde_json::from_str(&fixture.owned_invite.serialize()?)?;
+    legacy["used_response_contents"] = serde_json::json!([used.content]);
+    fixture.owned_invite = Invite::deserialize(&legacy.to_string())?;
+
+    let now = BASE + 100;
+    for index in 0..MAX_INVITE_REPLAY_ENTRIES as u64 {
+        let envelope = accept_at(&fixture, 1_000 + index, now)?;
+        process_at(&mut fixture, &envelope, 5_000 + index, now)?;
+    }
+    assert!(!fixture.owned_invite.used_responses.contains(&used.content));
+    assert!(matches!(
+        process_at(&mut fixture, &used, 9_000, now + 1),
+        Err(Error::Domain(DomainError::StaleInviteResponse))
+    ));
+    Ok(())
+}
+
+#[test]
+fn manager_snapshots_migrate_plain_response_contents() -> Result<()> {
+    let bob = manager_device(2, 21);
+    let mut bob_manager = session_manager(&bob);
+    bob_manager.ensure_local_invite(&mut context(1, BASE))?;
+    let mut data = serde_json::to_value(bob_manager.snapshot())?;
+    data["local_invite"]["used_response_contents"] = serde_json::json!(["used response"]);
+    data["local_invite"]
+        .as_object_mut()
+        .expect("local invite")
+        .remove("used_responses");
+
+    let loaded = decode_snapshot::<SessionManagerSnapshot>(&data.to_string())?;
+    assert_eq!(loaded.applied_migrations, vec!["wrap-unversioned-snapshot"]);
+    let invite = loaded.snapshot.local_invite.expect("local invite kept");
+    assert!(invite.used_responses.contains("used response"));
+    Ok(())
+}
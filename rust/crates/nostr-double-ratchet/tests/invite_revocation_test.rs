@@ -0,0 +1,210 @@
+mod support;
+
+use nostr_double_ratchet::{
+    invite_revocation_unsigned_event, invite_unsigned_event, parse_invite_event, DomainError,
+    Error, InMemoryStorageAdapter, Invite, RelayGap, Result, RetentionPolicy, SessionManager,
+    SessionManagerStore, UnixSeconds,
+};
+use support::{
+    connected_managers, context, manager_device, manager_observe_invite_response,
+    manager_public_device_invite, roster_for, session_manager, ManagerDevice,
+};
+
+const BASE: u64 = 1_800_000_000;
+
+fn published(device: &ManagerDevice, invite: &Invite) -> Result<Invite> {
+    let event = invite_unsigned_event(invite)?.sign_with_keys(&device.keys)?;
+    Ok(parse_invite_event(&event)?)
+}
+
+fn tombstone(device: &ManagerDevice, invite: &Invite, revoked_at: u64) -> Result<Invite> {
+    let event = invite_revocation_unsigned_event(invite, UnixSeconds(revoked_at))?
+        .sign_with_keys(&device.keys)?;
+    Ok(parse_invite_event(&event)?)
+}
+
+#[test]
+fn revocation_event_replaces_invite_and_drops_shared_secret() -> Result<()> {
+    let bob = manager_device(2, 21);
+    let mut bob_manager = session_manager(&bob);
+    let invite = manager_public_device_invite(&mut bob_manager, &bob, 1, BASE)?;
+
+    assert!(invite_revocation_unsigned_event(&invite, UnixSeconds(BASE)).is_err());
+    let revoked = tombstone(&bob, &invite, BASE + 10)?;
+    assert_eq!(revoked.revoked_at, Some(UnixSeconds(BASE + 10)));
+    assert_eq!(
+        revoked.inviter_ephemeral_public_key,
+        invite.inviter_ephemeral_public_key
+    );
+    assert_eq!(revoked.shared_secret.expose_secret(), &[0u8; 32]);
+    assert!(published(&bob, &invite)?.revoked_at.is_none());
+    Ok(())
+}
+
+#[test]
+fn revoked_peer_invite_is_reported_as_relay_gap() -> Result<()> {
+    let alice = manager_device(1, 11);
+    let bob = manager_device(2, 21);
+    let mut bob_manager = session_manager(&bob);
+    let invite = manager_public_device_invite(&mut bob_manager, &bob, 1, BASE)?;
+
+    let mut alice_manager = session_manager(&alice);
+    alice_manager.observe_peer_roster(bob.owner_pubkey, roster_for(&[&bob], 10));
+    alice_manager.observe_device_invite(bob.owner_pubkey, tombstone(&bob, &invite, BASE + 10)?)?;
+    alice_manager.observe_device_invite(bob.owner_pubkey, published(&bob, &invite)?)?;
+
+    let blocked = alice_manager.prepare_send(
+        &mut context(2, BASE + 20),
+        bob.owner_pubkey,
+        b"blocked".to_vec(),
+    )?;
+    assert!(blocked.deliveries.is_empty());
+    assert_eq!(
+        blocked.relay_gaps,
+        vec![RelayGap::MissingDeviceInvite {
+            owner_pubkey: bob.owner_pubkey,
+            device_pubkey: bob.device_pubkey,
+        }]
+    );
+
+    let revoked = bob_manager
+        .revoke_local_invite(UnixSeconds(BASE + 30))?
+        .expect("local invite exists");
+    assert!(revoked.is_revoked());
+    let fresh = manager_public_device_invite(&mut bob_manager, &bob, 3, BASE + 40)?;
+    assert_ne!(
+        fresh.inviter_ephemeral_public_key,
+        invite.inviter_ephemeral_public_key
+    );
+    alice_manager.observe_device_invite(bob.owner_pubkey, published(&bob, &fresh)?)?;
+    let sent = alice_manager.prepare_send(
+        &mut context(4, BASE + 50),
+        bob.owner_pubkey,
+        b"fresh invite".to_vec(),
+    )?;
+    assert_eq!(sent.deliveries.len(), 1);
+    assert!(sent.relay_gaps.is_empty());
+    Ok(())
+}
+
+#[test]
+fn revocation_keeps_sessions_but_refuses_new_responses() -> Result<()> {
+    let alice = manager_device(1, 11);
+    let bob = manager_device(2, 21);
+    let carol = manager_device(3, 31);
+    let (mut alice_manager, mut bob_manager) = connected_managers(&alice, &bob, BASE)?;
+    let invite = bob_manager
+        .local_invite()
+        .cloned()
+        .expect("bob has a local invite");
+
+    let mut carol_manager = session_manager(&carol);
+    carol_manager.observe_peer_roster(bob.owner_pubkey, roster_for(&[&bob], 10));
+    carol_manager.observe_device_invite(bob.owner_pubkey, published(&bob, &invite)?)?;
+    let late = carol_manager.prepare_send(
+        &mut context(5, BASE + 10),
+        bob.owner_pubkey,
+        b"late".to_vec(),
+    )?;
+
+    let revoked = bob_manager
+        .revoke_local_invite(UnixSeconds(BASE + 20))?
+        .expect("local invite exists");
+    alice_manager.observe_device_invite(bob.owner_pubkey, tombstone(&bob, &revoked, BASE + 20)?)?;
+    let sent = alice_manager.prepare_send(
+        &mut context(6, BASE + 30),
+        bob.owner_pubkey,
+        b"still connected".to_vec(),
+    )?;
+    assert_eq!(sent.deliveries.len(), 1);
+    assert!(sent.invite_responses.is_empty());
+
+    let mut owned = revoked;
+    assert!(matches!(
+        owned.process_response(
+            &mut context(7, BASE + 31),
+            &late.invite_responses[0],
+            bob.secret_key,
+        ),
+        Err(Error::Domain(DomainError::InviteRevoked))
+    ));
+    assert!(manager_observe_invite_response(
+        &mut bob_manager,
+        &mut context(8, BASE + 31),
+        &late.invite_responses[0],
+    )?
+    .is_none());
+    Ok(())
+}
+
+#[test]
+fn revocation_is_persisted_by_incremental_store_writes() -> Result<()> {
+    let alice = manager_device(1, 11);
+    let bob = manager_device(2, 21);
+    let mut bob_manager = session_manager(&bob);
+    bob_manager.set_retention_policy(RetentionPolicy {
+        retired_invite_grace_secs: 100,
+        ..RetentionPolicy::default()
+    });
+    let retired = manager_public_device_invite(&mut bob_manager, &bob, 1, BASE)?;
+    bob_manager.rotate_local_invite(&mut context(2, BASE + 10), None)?;
+    let mut store = SessionManagerStore::new(InMemoryStorageAdapter::new());
+    store.save_snapshot(&bob_manager.snapshot())?;
+    bob_manager.take_changes();
+
+    let mut alice_manager = session_manager(&alice);
+    alice_manager.observe_peer_roster(bob.owner_pubkey, roster_for(&[&bob], 10));
+    alice_manager.observe_device_invite(bob.owner_pubkey, published(&bob, &retired)?)?;
+    let late = alice_manager.prepare_send(
+        &mut context(3, BASE + 20),
+        bob.owner_pubkey,
+        b"late".to_vec(),
+    )?;
+
+    bob_manager.revoke_local_invite(UnixSeconds(BASE + 20))?;
+    assert!(bob_manager.retired_local_invites().is_empty());
+    let changes = bob_manager.take_changes();
+    assert!(changes.local_invite_removed);
+    store.apply_changes(&changes)?;
+
+    let loaded = store.load_snapshot()?.expect("snapshot was saved");
+    assert!(loaded.local_invite.is_none());
+    assert!(loaded.retired_local_invites.is_empty());
+    let mut restored = SessionManager::from_snapshot(loaded, bob.secret_key)?;
+    assert!(manager_observe_invite_response(
+        &mut restored,
+        &mut context(4, BASE + 21),
+        &late.invite_responses[0],
+    )?
+    .is_none());
+    Ok(())
+}
+
+#[test]
+fn revoked_local_invite_leaves_no_secrets_in_snapshots() -> Result<()> {
+    let bob = manager_device(2, 21);
+    let mut bob_manager = session_manager(&bob);
+    let invite = bob_manager
+        .ensure_local_invite(&mut context(1, BASE))?
+        .clone();
+    let shared_secret = hex::encode(invite.shared_secret.expose_secret());
+    let ephemeral_key = hex::encode(
+        invite
+            .inviter_ephemeral_private_key
+            .as_ref()
+            .expect("local invite keeps its ephemeral key")
+            .expose_secret(),
+    );
+
+    let revoked = bob_manager
+        .revoke_local_invite(UnixSeconds(BASE + 10))?
+        .expect("local invite existed");
+    assert_eq!(revoked.shared_secret.expose_secret(), &[0u8; 32]);
+    assert!(revoked.inviter_ephemeral_private_key.is_none());
+
+    let snapshot = serde_json::to_string(&bob_manager.snapshot())?;
+    assert!(snapshot.contains("revoked_at"));
+    assert!(!snapshot.contains(&shared_secret));
+    assert!(!snapshot.contains(&ephemeral_key));
+    Ok(())
+}
@@ -0,0 +1,173 @@
+mod support;
+
+use nostr_double_ratchet::{
+    DomainError, Error, Invite, RelayGap, Result, RetentionPolicy, SessionManager, UnixSeconds,
+};
+use support::{
+    context, manager_device, manager_observe_invite_response, manager_public_device_invite,
+    manager_receive_delivery, payload_text, public_invite_via_url, roster_for, session_manager,
+    ManagerDevice,
+};
+
+const BASE: u64 = 1_800_000_000;
+
+fn peer_of(
+    local: &ManagerDevice,
+    inviter: &ManagerDevice,
+    invite: &Invite,
+) -> Result<SessionManager> {
+    let mut manager = session_manager(local);
+    manager.observe_peer_roster(inviter.owner_pubkey, roster_for(&[inviter], 10));
+    manager.observe_device_invite(inviter.owner_pubkey, public_invite_via_url(invite)?)?;
+    Ok(manager)
+}
+
+#[test]
+fn responses_to_expired_invites_are_rejected() -> Result<()> {
+    let alice = manager_device(1, 11);
+    let bob = manager_device(2, 21);
+    let carol = manager_device(3, 31);
+    let mut bob_manager = session_manager(&bob);
+    let invite = bob_manager
+        .rotate_local_invite(&mut context(1, BASE), Some(UnixSeconds(BASE + 100)))?
+        .clone();
+    assert_eq!(invite.expires_at, Some(UnixSeconds(BASE + 100)));
+    assert!(bob_manager.retired_local_invites().is_empty());
+
+    let mut alice_manager = peer_of(&alice, &bob, &invite)?;
+    let mut carol_manager = peer_of(&carol, &bob, &invite)?;
+    let sent = carol_manager.prepare_send(
+        &mut context(2, BASE + 50),
+        bob.owner_pubkey,
+        b"delayed".to_vec(),
+    )?;
+    let result = manager_observe_invite_response(
+        &mut bob_manager,
+        &mut context(3, BASE + 200),
+        &sent.invite_responses[0],
+    );
+    assert!(matches!(
+        result,
+        Err(Error::Domain(DomainError::InviteExpired))
+    ));
+
+    let too_late = alice_manager.prepare_send(
+        &mut context(5, BASE + 200),
+        bob.owner_pubkey,
+        b"too late".to_vec(),
+    )?;
+    assert!(too_late.invite_responses.is_empty());
+    assert_eq!(
+        too_late.relay_gaps,
+        vec![RelayGap::MissingDeviceInvite {
+            owner_pubkey: bob.owner_pubkey,
+            device_pubkey: bob.device_pubkey,
+        }]
+    );
+    assert!(matches!(
+        invite.accept_with_context(
+            &mut context(6, BASE + 200),
+            alice.device_pubkey,
+            alice.secret_key,
+        ),
+        Err(Error::Domain(DomainError::InviteExpired))
+    ));
+
+    let refreshed = bob_manager
+        .ensure_local_invite(&mut context(4, BASE + 200))?
+        .clone();
+    assert_ne!(
+        refreshed.inviter_ephemeral_public_key,
+        invite.inviter_ephemeral_public_key
+    );
+    assert_eq!(refreshed.expires_at, Some(UnixSeconds(BASE + 300)));
+    Ok(())
+}
+
+#[test]
+fn unbounded_grace_keeps_the_retired_invite_expiry() -> Result<()> {
+    let bob = manager_device(2, 21);
+    let mut bob_manager = session_manager(&bob);
+    bob_manager.set_retention_policy(RetentionPolicy {
+        retired_invite_grace_secs: u64::MAX,
+        ..RetentionPolicy::default()
+    });
+    bob_manager.rotate_local_invite(&mut context(1, BASE), Some(UnixSeconds(BASE + 100)))?;
+    bob_manager.rotate_local_invite(&mut context(2, BASE + 10), None)?;
+    assert_eq!(
+        bob_manager.retired_local_invites()[0].expires_at,
+        Some(UnixSeconds(BASE + 100))
+    );
+    Ok(())
+}
+
+#[test]
+fn rotated_invite_accepts_in_flight_responses_during_grace() -> Result<()> {
+    let alice = manager_device(1, 11);
+    let bob = manager_device(2, 21);
+    let carol = manager_device(3, 31);
+    let mut bob_manager = session_manager(&bob);
+    bob_manager.set_retention_policy(RetentionPolicy {
+        retired_invite_grace_secs: 100,
+        ..RetentionPolicy::default()
+    });
+    bob_manager.observe_peer_roster(alice.owner_pubkey, roster_for(&[&alice], 10));
+    let old_invite = manager_public_device_invite(&mut bob_manager, &bob, 1, BASE)?;
+    let mut alice_manager = peer_of(&alice, &bob, &old_invite)?;
+    let mut carol_manager = peer_of(&carol, &bob, &old_invite)?;
+
+    let new_invite = bob_manager
+        .rotate_local_invite(&mut context(2, BASE + 10), None)?
+        .clone();
+    assert_ne!(
+        new_invite.inviter_ephemeral_public_key,
+        old_invite.inviter_ephemeral_public_key
+    );
+    assert_eq!(
+        bob_manager.retired_local_invites()[0].expires_at,
+        Some(UnixSeconds(BASE + 110))
+    );
+    let changes = bob_manager.take_changes();
+    assert_eq!(
+        changes.retired_local_invites.map(|invites| invites.len()),
+        Some(1)
+    );
+
+    let sent = alice_manager.prepare_send(
+        &mut context(3, BASE + 20),
+        bob.owner_pubkey,
+        b"in flight".to_vec(),
+    )?;
+    let mut restored = SessionManager::from_snapshot(bob_manager.snapshot(), bob.secret_key)?;
+    manager_observe_invite_response(
+        &mut restored,
+        &mut context(4, BASE + 30),
+        &sent.invite_responses[0],
+    )?
+    .expect("retired invite still accepts responses");
+    let received = manager_receive_delivery(
+        &mut restored,
+        &mut context(5, BASE + 31),
+        alice.owner_pubkey,
+        &sent.deliveries[0],
+    )?
+    .expect("message decrypts");
+    assert_eq!(payload_text(&received.payload), "in flight");
+
+    let late = carol_manager.prepare_send(
+        &mut context(6, BASE + 200),
+        bob.owner_pubkey,
+        b"after grace".to_vec(),
+    )?;
+    assert!(matches!(
+        manager_observe_invite_response(
+            &mut restored,
+            &mut context(7, BASE + 200),
+            &late.invite_responses[0],
+        ),
+        Err(Error::Domain(DomainError::InviteExpired))
+    ));
+    restored.prune_stale(UnixSeconds(BASE + 200));
+    assert!(restored.retired_local_invites().is_empty());
+    Ok(())
+}
@@ -0,0 +1,200 @@
+mod support;
+
+use nostr_double_ratchet::{
+    decode_snapshot, encode_snapshot, pad_payload, unpad_payload, Error, InMemoryStorageAdapter,
+    PayloadPadding, Result, SessionManager, SessionManagerSnapshot, SessionManagerStore,
+    PADDING_BUCKETS,
+};
+use support::{
+    context, direct_session_pair, manager_device, manager_observe_invite_response,
+    manager_public_device_invite, manager_receive_delivery, payload_text, receive_message,
+    roster_for, send_text, session_manager,
+};
+
+const BASE: u64 = 1_800_000_000;
+
+#[test]
+fn payloads_pad_to_buckets_and_round_trip() -> Result<()> {
+    let padded = pad_payload(b"ok").expect("small payload pads");
+    assert_eq!(padded.len(), PADDING_BUCKETS[0]);
+    assert_eq!(unpad_payload(&padded)?, b"ok");
+
+    let medium = vec![7u8; 300];
+    let padded = pad_payload(&medium).expect("medium payload pads");
+    assert_eq!(padded.len(), PADDING_BUCKETS[1]);
+    assert_eq!(unpad_payload(&padded)?, medium);
+
+    assert!(pad_payload(&vec![0u8; 65_534]).is_none());
+    assert!(matches!(
+        unpad_payload(&[0, 9, 1]),
+        Err(Error::Decryption(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn sessions_pad_only_after_peer_advertises_support() -> Result<()> {
+    let (_, _, mut alice, mut bob) = direct_session_pair(1, 2, BASE)?;
+    alice.set_payload_padding(PayloadPadding::Bucketed);
+
+    let short = send_text(&mut alice, &mut context(1, BASE + 1), "a")?;
+    let long = send_text(&mut alice, &mut context(2, BASE + 2), "x".repeat(200))?;
+    assert_ne!(
+        short.incoming.ciphertext.len(),
+        long.incoming.ciphertext.len()
+    );
+    receive_message(&mut bob, &mut context(3, BASE + 3), &short.incoming)?;
+    receive_message(&mut bob, &mut context(4, BASE + 4), &long.incoming)?;
+    assert!(bob.state.peer_accepts_padding);
+
+    let reply = send_text(&mut bob, &mut context(5, BASE + 5), "plain reply")?;
+    receive_message(&mut alice, &mut context(6, BASE + 6), &reply.incoming)?;
+    assert!(!alice.state.peer_accepts_padding);
+    let still_plain = send_text(&mut alice, &mut context(7, BASE + 7), "a")?;
+    assert_eq!(
+        still_plain.incoming.ciphertext.len(),
+        short.incoming.ciphertext.len()
+    );
+
+    bob.set_payload_padding(PayloadPadding::Bucketed);
+    receive_message(&mut bob, &mut context(8, BASE + 8), &still_plain.incoming)?;
+    let padded_reply = send_text(&mut bob, &mut context(9, BASE + 9), "ok")?;
+    receive_message(
+        &mut alice,
+        &mut context(10, BASE + 10),
+        &padded_reply.incoming,
+    )?;
+    assert!(alice.state.peer_accepts_padding);
+
+    let short = send_text(&mut alice, &mut context(11, BASE + 11), "a")?;
+    let long = send_text(&mut alice, &mut context(12, BASE + 12), "x".repeat(200))?;
+    assert_eq!(
+        short.incoming.ciphertext.len(),
+        long.incoming.ciphertext.len()
+    );
+    assert_eq!(
+        payload_text(&receive_message(
+            &mut bob,
+            &mut context(13, BASE + 13),
+            &short.incoming
+        )?),
+        "a"
+    );
+    assert_eq!(
+        payload_text(&receive_message(
+            &mut bob,
+            &mut context(14, BASE + 14),
+            &long.incoming
+        )?),
+        "x".repeat(200)
+    );
+    Ok(())
+}
+
+#[test]
+fn late_messages_do_not_turn_padding_back_off() -> Result<()> {
+    let (_, _, mut alice, mut bob) = direct_session_pair(3, 4, BASE)?;
+    let old = send_text(&mut alice, &mut context(1, BASE + 1), "old")?;
+    alice.set_payload_padding(PayloadPadding::Bucketed);
+    let new = send_text(&mut alice, &mut context(2, BASE + 2), "new")?;
+
+    receive_message(&mut bob, &mut context(3, BASE + 3), &new.incoming)?;
+    assert!(bob.state.peer_accepts_padding);
+    assert_eq!(
+        payload_text(&receive_message(
+            &mut bob,
+            &mut context(4, BASE + 4),
+            &old.incoming
+        )?),
+        "old"
+    );
+    assert!(bob.state.peer_accepts_padding);
+    Ok(())
+}
+
+#[test]
+fn manager_padding_setting_applies_to_its_sessions() -> Result<()> {
+    let alice = manager_device(3, 31);
+    let bob = manager_device(4, 41);
+    let mut alice_manager = session_manager(&alice);
+    let mut bob_manager = session_manager(&bob);
+    alice_manager.set_payload_padding(PayloadPadding::Bucketed);
+    bob_manager.set_payload_padding(PayloadPadding::Bucketed);
+    assert_eq!(bob_manager.payload_padding(), PayloadPadding::Bucketed);
+    alice_manager.observe_peer_roster(bob.owner_pubkey, roster_for(&[&bob], 10));
+    bob_manager.observe_peer_roster(alice.owner_pubkey, roster_for(&[&alice], 10));
+    alice_manager.observe_device_invite(
+        bob.owner_pubkey,
+        manager_public_device_invite(&mut bob_manager, &bob, 3, BASE)?,
+    )?;
+
+    let first = alice_manager.prepare_send(
+        &mut context(20, BASE + 1),
+        bob.owner_pubkey,
+        b"hello".to_vec(),
+    )?;
+    manager_observe_invite_response(
+        &mut bob_manager,
+        &mut context(21, BASE + 2),
+        &first.invite_responses[0],
+    )?;
+    manager_receive_delivery(
+        &mut bob_manager,
+        &mut context(22, BASE + 3),
+        alice.owner_pubkey,
+        &first.deliveries[0],
+    )?
+    .expect("first message decrypts");
+
+    let short = bob_manager.prepare_send(
+        &mut context(23, BASE + 4),
+        alice.owner_pubkey,
+        b"a".to_vec(),
+    )?;
+    let long = bob_manager.prepare_send(
+        &mut context(24, BASE + 5),
+        alice.owner_pubkey,
+        vec![b'x'; 200],
+    )?;
+    assert_eq!(
+        short.deliveries[0].envelope.ciphertext.len(),
+        long.deliveries[0].envelope.ciphertext.len()
+    );
+    let received = manager_receive_delivery(
+        &mut alice_manager,
+        &mut context(25, BASE + 6),
+        bob.owner_pubkey,
+        &long.deliveries[0],
+    )?
+    .expect("padded reply decrypts");
+    assert_eq!(received.payload, vec![b'x'; 200]);
+    Ok(())
+}
+
+#[test]
+fn manager_padding_setting_survives_snapshot_and_store() -> Result<()> {
+    let alice = manager_device(5, 51);
+    let mut manager = session_manager(&alice);
+    let mut store = SessionManagerStore::new(InMemoryStorageAdapter::new());
+    store.save_snapshot(&manager.snapshot())?;
+    manager.take_changes();
+
+    manager.set_payload_padding(PayloadPadding::Bucketed);
+    assert!(manager.has_pending_changes());
+    let changes = manager.take_changes();
+    assert_eq!(
+        changes.settings.map(|settings| settings.payload_padding),
+        Some(PayloadPadding::Bucketed)
+    );
+    store.apply_changes(&changes)?;
+
+    let restored = SessionManager::from_snapshot(
+        store.load_snapshot()?.expect("snapshot was saved"),
+        alice.secret_key,
+    )?;
+    assert_eq!(restored.payload_padding(), PayloadPadding::Bucketed);
+
+    let loaded = decode_snapshot::<SessionManagerSnapshot>(&encode_snapshot(&manager.snapshot())?)?;
+    assert_eq!(loaded.snapshot.payload_padding, PayloadPadding::Bucketed);
+    Ok(())
+}
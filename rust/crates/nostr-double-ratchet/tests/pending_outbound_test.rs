@@ -0,0 +1,201 @@
+mod support;
+
+use nostr_double_ratchet::{
+    DevicePubkey, OutboundRetry, PendingOutbound, RelayGap, Result, RosterSnapshotDecision,
+    UnixSeconds, PENDING_OUTBOUND_MAX_AGE_SECS,
+};
+use support::{
+    context, manager_device, manager_observe_invite_response, manager_public_device_invite,
+    manager_receive_delivery, payload_text, prepared_targets, roster_for, session_manager,
+};
+
+#[test]
+fn queued_send_is_prepared_once_roster_and_invite_arrive() -> Result<()> {
+    let alice = manager_device(1, 11);
+    let bob = manager_device(2, 21);
+    let mut alice_manager = session_manager(&alice);
+    let mut bob_manager = session_manager(&bob);
+    let bob_invite = manager_public_device_invite(&mut bob_manager, &bob, 1, 1_800_000_000)?;
+
+    let mut queue = PendingOutbound::new();
+    let mut ctx = context(2, 1_800_000_010);
+    let prepared = alice_manager.prepare_send(&mut ctx, bob.owner_pubkey, b"queued".to_vec())?;
+    assert!(prepared.deliveries.is_empty());
+    assert!(queue.enqueue("msg-1", &prepared, UnixSeconds(1_800_000_010)));
+    assert_eq!(
+        queue.entries_for(bob.owner_pubkey)[0].relay_gaps,
+        vec![RelayGap::MissingRoster {
+            owner_pubkey: bob.owner_pubkey
+        }]
+    );
+
+    let mut ctx = context(3, 1_800_000_020);
+    let (decision, retry) = queue.observe_peer_roster(
+        &mut alice_manager,
+        &mut ctx,
+        bob.owner_pubkey,
+        roster_for(&[&bob], 1_800_000_000),
+    );
+    assert_eq!(decision, RosterSnapshotDecision::Advanced);
+    assert_eq!(retry, OutboundRetry::default());
+    assert_eq!(
+        queue.entries_for(bob.owner_pubkey)[0].relay_gaps,
+        vec![RelayGap::MissingDeviceInvite {
+            owner_pubkey: bob.owner_pubkey,
+            device_pubkey: bob.device_pubkey,
+        }]
+    );
+
+    let mut ctx = context(4, 1_800_000_030);
+    let resolved = queue
+        .observe_device_invite(&mut alice_manager, &mut ctx, bob.owner_pubkey, bob_invite)?
+        .resolved;
+    assert!(queue.is_empty());
+    assert_eq!(resolved.len(), 1);
+    assert_eq!(resolved[0].failure, None);
+    assert_eq!(resolved[0].id, "msg-1");
+    let prepared = &resolved[0].prepared[0];
+    assert_eq!(
+        prepared_targets(prepared),
+        vec![(bob.owner_pubkey, bob.device_pubkey)]
+    );
+    assert_eq!(prepared.invite_responses.len(), 1);
+
+    bob_manager.observe_peer_roster(alice.owner_pubkey, roster_for(&[&alice], 1_800_000_000));
+    let mut ctx = context(5, 1_800_000_040);
+    manager_observe_invite_response(&mut bob_manager, &mut ctx, &prepared.invite_responses[0])?;
+    let received = manager_receive_delivery(
+        &mut bob_manager,
+        &mut ctx,
+        alice.owner_pubkey,
+        &prepared.deliveries[0],
+    )?
+    .expect("queued delivery must decrypt");
+    assert_eq!(payload_text(&received.payload), "queued");
+    Ok(())
+}
+
+#[test]
+fn device_gap_is_dropped_when_roster_removes_the_device() -> Result<()> {
+    let alice = manager_device(3, 31);
+    let bob1 = manager_device(4, 41);
+    let bob2 = manager_device(4, 42);
+    let mut alice_manager = session_manager(&alice);
+    alice_manager.observe_peer_roster(
+        bob1.owner_pubkey,
+        roster_for(&[&bob1, &bob2], 1_800_000_000),
+    );
+
+    let mut queue = PendingOutbound::new();
+    let mut ctx = context(1, 1_800_000_010);
+    let prepared = alice_manager.prepare_send(&mut ctx, bob1.owner_pubkey, b"queued".to_vec())?;
+    assert!(queue.enqueue("msg-1", &prepared, UnixSeconds(1_800_000_010)));
+    assert_eq!(queue.entries_for(bob1.owner_pubkey)[0].relay_gaps.len(), 2);
+
+    let mut ctx = context(2, 1_800_000_020);
+    let (_, retry) = queue.observe_peer_roster(
+        &mut alice_manager,
+        &mut ctx,
+        bob1.owner_pubkey,
+        roster_for(&[&bob1], 1_800_000_020),
+    );
+    assert!(retry.resolved.is_empty());
+    assert_eq!(
+        queue.entries().next().unwrap().relay_gaps,
+        vec![RelayGap::MissingDeviceInvite {
+            owner_pubkey: bob1.owner_pubkey,
+            device_pubkey: bob1.device_pubkey,
+        }]
+    );
+
+    assert_eq!(queue.remove("msg-1").len(), 1);
+    assert!(queue.is_empty());
+    Ok(())
+}
+
+#[test]
+fn expired_entries_are_dropped_instead_of_retried() -> Result<()> {
+    let alice = manager_device(5, 51);
+    let bob = manager_device(6, 61);
+    let mut alice_manager = session_manager(&alice);
+    let mut bob_manager = session_manager(&bob);
+    let bob_invite = manager_public_device_invite(&mut bob_manager, &bob, 1, 1_800_000_000)?;
+    alice_manager.observe_peer_roster(bob.owner_pubkey, roster_for(&[&bob], 1_800_000_000));
+
+    let mut queue = PendingOutbound::new();
+    let mut ctx = context(2, 1_800_000_010);
+    let prepared = alice_manager.prepare_send(&mut ctx, bob.owner_pubkey, b"stale".to_vec())?;
+    assert!(queue.enqueue("msg-1", &prepared, UnixSeconds(1_800_000_010)));
+    assert!(queue
+        .prune_expired(UnixSeconds(1_800_000_010 + PENDING_OUTBOUND_MAX_AGE_SECS))
+        .is_empty());
+
+    let mut ctx = context(3, 1_800_000_011 + PENDING_OUTBOUND_MAX_AGE_SECS);
+    let retry =
+        queue.observe_device_invite(&mut alice_manager, &mut ctx, bob.owner_pubkey, bob_invite)?;
+    assert!(retry.resolved.is_empty());
+    assert_eq!(retry.expired.len(), 1);
+    assert_eq!(retry.expired[0].id, "msg-1");
+    assert!(queue.is_empty());
+    Ok(())
+}
+
+#[test]
+fn failed_gap_keeps_prepared_sends_and_stays_queued() -> Result<()> {
+    let alice = manager_device(7, 71);
+    let bob1 = manager_device(8, 81);
+    let bob2 = manager_device(8, 82);
+    let mut alice_manager = session_manager(&alice);
+    alice_manager.observe_peer_roster(
+        bob1.owner_pubkey,
+        roster_for(&[&bob1, &bob2], 1_800_000_000),
+    );
+
+    let mut queue = PendingOutbound::new();
+    let mut ctx = context(1, 1_800_000_010);
+    let prepared = alice_manager.prepare_send(&mut ctx, bob1.owner_pubkey, b"queued".to_vec())?;
+    assert!(queue.enqueue("msg-1", &prepared, UnixSeconds(1_800_000_010)));
+    let gaps = queue.entries_for(bob1.owner_pubkey)[0].relay_gaps.clone();
+    let device_of = |gap: &RelayGap| match gap {
+        RelayGap::MissingDeviceInvite { device_pubkey, .. } => *device_pubkey,
+        RelayGap::MissingRoster { .. } => unreachable!("roster is known"),
+    };
+    let (first, second) = (device_of(&gaps[0]), device_of(&gaps[1]));
+    let device = |pubkey| {
+        if pubkey == bob1.device_pubkey {
+            &bob1
+        } else {
+            &bob2
+        }
+    };
+
+    let good = manager_public_device_invite(
+        &mut session_manager(device(first)),
+        device(first),
+        2,
+        1_800_000_000,
+    )?;
+    let mut broken = manager_public_device_invite(
+        &mut session_manager(device(second)),
+        device(second),
+        3,
+        1_800_000_000,
+    )?;
+    broken.inviter_ephemeral_public_key = DevicePubkey::from_bytes([0xff; 32]);
+    alice_manager.observe_device_invite(bob1.owner_pubkey, good)?;
+    alice_manager.observe_device_invite(bob1.owner_pubkey, broken)?;
+
+    let mut ctx = context(4, 1_800_000_020);
+    let retry = queue.retry_owner(&mut alice_manager, &mut ctx, bob1.owner_pubkey);
+    assert_eq!(retry.resolved.len(), 1);
+    assert!(retry.resolved[0].failure.is_some());
+    assert_eq!(
+        prepared_targets(&retry.resolved[0].prepared[0]),
+        vec![(bob1.owner_pubkey, first)]
+    );
+    assert_eq!(
+        queue.entries_for(bob1.owner_pubkey)[0].relay_gaps,
+        vec![gaps[1].clone()]
+    );
+    Ok(())
+}
@@ -0,0 +1,103 @@
+mod support;
+
+use nostr_double_ratchet::{
+    ProtocolSubscriptionPlan, RelayGap, Result, INVITE_RESPONSE_SUBSCRIPTION_ID,
+    INVITE_SUBSCRIPTION_ID, MESSAGE_SUBSCRIPTION_ID, ROSTER_SUBSCRIPTION_ID,
+};
+use support::{
+    context, manager_device, manager_observe_invite_response, manager_public_device_invite,
+    roster_for, session_manager,
+};
+
+#[test]
+fn plan_tracks_rosters_invites_and_session_senders() -> Result<()> {
+    let alice = manager_device(1, 11);
+    let bob = manager_device(2, 21);
+    let mut alice_manager = session_manager(&alice);
+    let mut bob_manager = session_manager(&bob);
+    alice_manager.observe_peer_roster(bob.owner_pubkey, roster_for(&[&bob], 10));
+    bob_manager.observe_peer_roster(alice.owner_pubkey, roster_for(&[&alice], 10));
+    let invite = manager_public_device_invite(&mut bob_manager, &bob, 1, 1_800_000_000)?;
+    let invite_ephemeral = invite.inviter_ephemeral_public_key;
+    alice_manager.observe_device_invite(bob.owner_pubkey, invite)?;
+
+    let alice_plan = ProtocolSubscriptionPlan::from_session_manager(&alice_manager);
+    assert!(alice_plan.roster_authors.contains(&alice.owner_pubkey));
+    assert!(alice_plan.roster_authors.contains(&bob.owner_pubkey));
+    assert!(alice_plan.invite_authors.contains(&bob.device_pubkey));
+    assert!(!alice_plan.invite_authors.contains(&alice.device_pubkey));
+    assert!(alice_plan.message_authors.is_empty());
+
+    let mut ctx = context(2, 1_800_000_001);
+    let first = alice_manager.prepare_send(&mut ctx, bob.owner_pubkey, b"first".to_vec())?;
+    let mut ctx = context(3, 1_800_000_002);
+    manager_observe_invite_response(&mut bob_manager, &mut ctx, &first.invite_responses[0])?;
+
+    let bob_plan = ProtocolSubscriptionPlan::from_session_manager(&bob_manager);
+    assert!(bob_plan
+        .invite_response_recipients
+        .contains(&invite_ephemeral));
+    assert_eq!(
+        bob_plan.message_authors.iter().copied().collect::<Vec<_>>(),
+        bob_manager.message_author_pubkeys()
+    );
+    assert!(!bob_plan.message_authors.is_empty());
+
+    let filters = bob_plan.filters();
+    assert!(filters.contains_key(ROSTER_SUBSCRIPTION_ID));
+    assert!(filters.contains_key(INVITE_SUBSCRIPTION_ID));
+    assert!(filters.contains_key(INVITE_RESPONSE_SUBSCRIPTION_ID));
+    assert!(filters.contains_key(MESSAGE_SUBSCRIPTION_ID));
+    Ok(())
+}
+
+#[test]
+fn diff_only_replaces_changed_filters_and_backfills_new_authors() {
+    let alice = manager_device(4, 41);
+    let bob = manager_device(5, 51);
+    let carol = manager_device(6, 61);
+    let mut manager = session_manager(&alice);
+
+    let initial = ProtocolSubscriptionPlan::from_session_manager(&manager);
+    let startup = initial.diff(&ProtocolSubscriptionPlan::default(), 50);
+    assert_eq!(
+        startup
+            .added
+            .iter()
+            .map(|(subid, _)| subid.as_str())
+            .collect::<Vec<_>>(),
+        vec![ROSTER_SUBSCRIPTION_ID]
+    );
+    assert_eq!(startup.backfill.len(), 1);
+    assert!(initial.diff(&initial, 50).is_empty());
+
+    manager.observe_peer_roster(bob.owner_pubkey, roster_for(&[&bob], 10));
+    let with_bob = ProtocolSubscriptionPlan::from_session_manager(&manager).with_relay_gaps([
+        &RelayGap::MissingRoster {
+            owner_pubkey: carol.owner_pubkey,
+        },
+    ]);
+    let diff = with_bob.diff(&initial, 50);
+    let mut added = diff
+        .added
+        .iter()
+        .map(|(subid, _)| subid.as_str())
+        .collect::<Vec<_>>();
+    added.sort();
+    assert_eq!(added, vec![INVITE_SUBSCRIPTION_ID, ROSTER_SUBSCRIPTION_ID]);
+    assert!(diff.removed.is_empty());
+
+    let backfill = serde_json::to_value(&diff.backfill).unwrap();
+    let backfill = backfill.as_array().unwrap();
+    assert_eq!(backfill.len(), 2);
+    let roster_backfill_authors = backfill[0]["authors"].as_array().unwrap();
+    assert_eq!(roster_backfill_authors.len(), 2);
+    assert!(!roster_backfill_authors
+        .iter()
+        .any(|author| author.as_str() == Some(alice.owner_pubkey.to_hex().as_str())));
+    assert_eq!(backfill[0]["limit"], 50);
+
+    let reverted = initial.diff(&with_bob, 50);
+    assert_eq!(reverted.removed, vec![INVITE_SUBSCRIPTION_ID.to_string()]);
+    assert!(reverted.backfill.is_empty());
+}
@@ -0,0 +1,54 @@
+mod support;
+
+use nostr_double_ratchet::{
+    PreparedPublishBatch, Result, UnixSeconds, INVITE_RESPONSE_KIND, MESSAGE_EVENT_KIND,
+};
+use support::{context, manager_device, manager_public_device_invite, roster_for, session_manager};
+
+#[test]
+fn batch_signs_invite_responses_before_messages_and_tracks_acks() -> Result<()> {
+    let alice = manager_device(1, 11);
+    let bob = manager_device(2, 21);
+    let mut alice_manager = session_manager(&alice);
+    let mut bob_manager = session_manager(&bob);
+
+    let bob_invite = manager_public_device_invite(&mut bob_manager, &bob, 1, 1_800_000_000)?;
+    alice_manager.observe_peer_roster(bob.owner_pubkey, roster_for(&[&bob], 1_800_000_000));
+    alice_manager.observe_device_invite(bob.owner_pubkey, bob_invite)?;
+
+    let mut ctx = context(2, 1_800_000_010);
+    let prepared = alice_manager.prepare_send(&mut ctx, bob.owner_pubkey, b"hello".to_vec())?;
+    let mut batch = PreparedPublishBatch::from_prepared_sends(
+        "msg-1",
+        UnixSeconds(1_800_000_010),
+        [&prepared],
+    )?;
+
+    assert_eq!(
+        batch
+            .events
+            .iter()
+            .map(|entry| u32::from(entry.event.kind.as_u16()))
+            .collect::<Vec<_>>(),
+        vec![INVITE_RESPONSE_KIND, MESSAGE_EVENT_KIND]
+    );
+    assert_eq!(batch.pending_events().count(), 2);
+
+    let restored: PreparedPublishBatch =
+        serde_json::from_str(&serde_json::to_string(&batch).unwrap()).unwrap();
+    assert_eq!(restored, batch);
+
+    let first_id = batch.events[0].event.id;
+    assert!(batch.acknowledge(&first_id));
+    assert!(!batch.acknowledge(&first_id));
+    assert!(!batch.is_complete());
+    assert_eq!(
+        batch.pending_events().cloned().collect::<Vec<_>>(),
+        vec![batch.events[1].event.clone()]
+    );
+
+    batch.acknowledge_all();
+    assert!(batch.is_complete());
+    assert_eq!(batch.pending_events().count(), 0);
+    Ok(())
+}
@@ -0,0 +1,585 @@
+mod support;
+
+use nostr::Event;
+use nostr_double_ratchet::{
+    message_event, roster_unsigned_event, DevicePubkey, NdrRuntime, Result, RuntimeAction,
+    RuntimeInput, RuntimeSnapshot, TimerId, DEFAULT_DECRYPT_FAILURE_THRESHOLD,
+    INVITE_RESPONSE_KIND, MESSAGE_EVENT_KIND,
+};
+use support::{
+    context, manager_device, manager_public_device_invite, manager_user_snapshot, mutate_text,
+    payload_text, roster_for, session_manager, ManagerDevice,
+};
+
+fn runtime(device: &ManagerDevice) -> Result<NdrRuntime> {
+    NdrRuntime::new(device.owner_pubkey, device.secret_key)
+}
+
+fn roster_event(device: &ManagerDevice, created_at: u64) -> Result<Event> {
+    Ok(
+        roster_unsigned_event(device.owner_pubkey, &roster_for(&[device], created_at))?
+            .sign_with_keys(&device.owner_keys)?,
+    )
+}
+
+fn published(actions: &[RuntimeAction]) -> Vec<Event> {
+    actions
+        .iter()
+        .filter_map(|action| match action {
+            RuntimeAction::Publish(event) => Some(event.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn feed(
+    runtime: &mut NdrRuntime,
+    seed: u64,
+    now: u64,
+    events: impl IntoIterator<Item = Event>,
+) -> Result<Vec<RuntimeAction>> {
+    let mut actions = Vec::new();
+    for (index, event) in events.into_iter().enumerate() {
+        let mut ctx = context(seed + index as u64, now);
+        actions.extend(runtime.handle(&mut ctx, RuntimeInput::RelayEvent(event))?);
+    }
+    Ok(actions)
+}
+
+#[test]
+fn runtime_bootstraps_and_delivers_first_message() -> Result<()> {
+    let alice = manager_device(1, 11);
+    let bob = manager_device(2, 21);
+    let mut alice_runtime = runtime(&alice)?;
+    let mut bob_runtime = runtime(&bob)?;
+
+    let mut ctx = context(1, 1_800_000_000);
+    let bob_startup = bob_runtime.handle(&mut ctx, RuntimeInput::Startup)?;
+    let bob_invite = published(&bob_startup);
+    assert_eq!(bob_invite.len(), 1);
+    assert!(bob_startup.iter().any(|action| matches!(
+        action,
+        RuntimeAction::Subscribe { subid, .. } if subid == "ndr-runtime-invite-responses"
+    )));
+    assert!(matches!(
+        bob_startup.last(),
+        Some(RuntimeAction::Persist(_))
+    ));
+
+    feed(
+        &mut alice_runtime,
+        10,
+        1_800_000_010,
+        [roster_event(&bob, 1_800_000_000)?, bob_invite[0].clone()],
+    )?;
+
+    let mut ctx = context(20, 1_800_000_020);
+    let send_actions = alice_runtime.handle(
+        &mut ctx,
+        RuntimeInput::SendRumor {
+            recipient_owner: bob.owner_pubkey,
+            remote_payload: b"hello bob".to_vec(),
+            local_sibling_payload: None,
+            correlation_id: "msg-1".to_string(),
+        },
+    )?;
+    let outgoing = published(&send_actions);
+    assert_eq!(
+        outgoing
+            .iter()
+            .map(|event| u32::from(event.kind.as_u16()))
+            .collect::<Vec<_>>(),
+        vec![INVITE_RESPONSE_KIND, MESSAGE_EVENT_KIND]
+    );
+
+    let mut received = feed(
+        &mut bob_runtime,
+        30,
+        1_800_000_030,
+        [roster_event(&alice, 1_800_000_000)?],
+    )?;
+    received.extend(feed(&mut bob_runtime, 40, 1_800_000_040, outgoing.clone())?);
+    let delivered = received
+        .iter()
+        .filter_map(|action| match action {
+            RuntimeAction::DeliverDecrypted {
+                sender_owner,
+                sender_device,
+                payload,
+                outer_event_id,
+            } => Some((
+                *sender_owner,
+                *sender_device,
+                payload_text(payload),
+                outer_event_id.clone(),
+            )),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        delivered,
+        vec![(
+            alice.owner_pubkey,
+            Some(alice.device_pubkey),
+            "hello bob".to_string(),
+            Some(outgoing[1].id.to_hex()),
+        )]
+    );
+    assert!(received.iter().any(|action| matches!(
+        action,
+        RuntimeAction::Subscribe { subid, .. } if subid == "ndr-runtime-messages"
+    )));
+    Ok(())
+}
+
+#[test]
+fn runtime_requests_missing_roster_when_sending_to_unknown_owner() -> Result<()> {
+    let alice = manager_device(3, 31);
+    let bob = manager_device(4, 41);
+    let mut alice_runtime = runtime(&alice)?;
+
+    let mut ctx = context(1, 1_800_000_000);
+    let actions = alice_runtime.handle(
+        &mut ctx,
+        RuntimeInput::SendRumor {
+            recipient_owner: bob.owner_pubkey,
+            remote_payload: b"hello".to_vec(),
+            local_sibling_payload: None,
+            correlation_id: "msg-1".to_string(),
+        },
+    )?;
+
+    assert!(published(&actions).is_empty());
+    assert!(actions
+        .iter()
+        .any(|action| matches!(action, RuntimeAction::Fetch(filters) if filters.len() == 1)));
+    Ok(())
+}
+
+#[test]
+fn failed_publish_is_retried_with_identical_events() -> Result<()> {
+    let alice = manager_device(5, 51);
+    let bob = manager_device(6, 61);
+    let mut alice_runtime = runtime(&alice)?;
+    let mut bob_runtime = runtime(&bob)?;
+
+    let mut ctx = context(1, 1_800_000_000);
+    let bob_invite = published(&bob_runtime.handle(&mut ctx, RuntimeInput::Startup)?);
+    feed(
+        &mut alice_runtime,
+        10,
+        1_800_000_010,
+        [roster_event(&bob, 1_800_000_000)?, bob_invite[0].clone()],
+    )?;
+
+    let mut ctx = context(20, 1_800_000_020);
+    let first = published(&alice_runtime.handle(
+        &mut ctx,
+        RuntimeInput::SendRumor {
+            recipient_owner: bob.owner_pubkey,
+            remote_payload: b"retry me".to_vec(),
+            local_sibling_payload: None,
+            correlation_id: "msg-1".to_string(),
+        },
+    )?);
+    let before_retry = alice_runtime.snapshot();
+
+    let mut ctx = context(21, 1_800_000_021);
+    let failed = alice_runtime.handle(
+        &mut ctx,
+        RuntimeInput::PublishFinished {
+            correlation_id: "msg-1".to_string(),
+            success: false,
+        },
+    )?;
+    let timer = TimerId::PublishRetry {
+        correlation_id: "msg-1".to_string(),
+    };
+    assert_eq!(
+        failed,
+        vec![RuntimeAction::ScheduleTimer {
+            id: timer.clone(),
+            after_ms: nostr_double_ratchet::PUBLISH_RETRY_DELAY_MS,
+        }]
+    );
+
+    let mut ctx = context(22, 1_800_000_026);
+    let retried =
+        published(&alice_runtime.handle(&mut ctx, RuntimeInput::TimerFired(timer.clone()))?);
+    assert_eq!(retried, first);
+    assert_eq!(alice_runtime.snapshot(), before_retry);
+
+    let mut ctx = context(23, 1_800_000_027);
+    alice_runtime.handle(
+        &mut ctx,
+        RuntimeInput::PublishFinished {
+            correlation_id: "msg-1".to_string(),
+            success: true,
+        },
+    )?;
+    let mut ctx = context(24, 1_800_000_028);
+    assert!(alice_runtime
+        .handle(&mut ctx, RuntimeInput::TimerFired(timer))?
+        .is_empty());
+    Ok(())
+}
+
+#[test]
+fn publish_batch_survives_restart_and_resumes_unacknowledged_events() -> Result<()> {
+    let alice = manager_device(7, 71);
+    let bob = manager_device(8, 81);
+    let mut alice_runtime = runtime(&alice)?;
+    let mut bob_runtime = runtime(&bob)?;
+
+    let mut ctx = context(1, 1_800_000_000);
+    let bob_invite = published(&bob_runtime.handle(&mut ctx, RuntimeInput::Startup)?);
+    feed(
+        &mut alice_runtime,
+        10,
+        1_800_000_010,
+        [roster_event(&bob, 1_800_000_000)?, bob_invite[0].clone()],
+    )?;
+
+    let mut ctx = context(20, 1_800_000_020);
+    let send_actions = alice_runtime.handle(
+        &mut ctx,
+        RuntimeInput::SendRumor {
+            recipient_owner: bob.owner_pubkey,
+            remote_payload: b"survive restart".to_vec(),
+            local_sibling_payload: None,
+            correlation_id: "msg-1".to_string(),
+        },
+    )?;
+    let outgoing = published(&send_actions);
+    assert_eq!(outgoing.len(), 2);
+
+    let mut ctx = context(21, 1_800_000_021);
+    let ack_actions = alice_runtime.handle(
+        &mut ctx,
+        RuntimeInput::EventPublished {
+            correlation_id: "msg-1".to_string(),
+            event_id: outgoing[0].id,
+        },
+    )?;
+    let Some(RuntimeAction::Persist(persisted)) = ack_actions.last() else {
+        panic!("acknowledging an event must persist the batch");
+    };
+    let persisted: RuntimeSnapshot =
+        serde_json::from_str(&serde_json::to_string(persisted.as_ref()).unwrap()).unwrap();
+    assert_eq!(persisted.publish_batches.len(), 1);
+    assert!(persisted.publish_batches[0].events[0].acknowledged);
+    assert!(!persisted.publish_batches[0].events[1].acknowledged);
+
+    let mut restored = NdrRuntime::from_snapshot(persisted.clone(), alice.secret_key)?;
+    let mut ctx = context(30, 1_800_000_030);
+    let startup = restored.handle(&mut ctx, RuntimeInput::Startup)?;
+    let resumed = published(&startup)
+        .into_iter()
+        .filter(|event| event.kind.as_u16() == MESSAGE_EVENT_KIND as u16)
+        .collect::<Vec<_>>();
+    assert_eq!(resumed, vec![outgoing[1].clone()]);
+    assert_eq!(
+        manager_user_snapshot(&restored.snapshot().session_manager, bob.owner_pubkey),
+        manager_user_snapshot(&persisted.session_manager, bob.owner_pubkey)
+    );
+
+    let mut ctx = context(31, 1_800_000_031);
+    restored.handle(
+        &mut ctx,
+        RuntimeInput::EventPublished {
+            correlation_id: "msg-1".to_string(),
+            event_id: outgoing[1].id,
+        },
+    )?;
+    assert_eq!(restored.publish_batches().count(), 0);
+    assert!(restored.snapshot().publish_batches.is_empty());
+    Ok(())
+}
+
+#[test]
+fn send_waits_for_relay_gaps_and_publishes_when_they_close() -> Result<()> {
+    let alice = manager_device(9, 91);
+    let bob = manager_device(10, 101);
+    let mut alice_runtime = runtime(&alice)?;
+    let mut bob_runtime = runtime(&bob)?;
+
+    let mut ctx = context(1, 1_800_000_000);
+    let bob_invite = published(&bob_runtime.handle(&mut ctx, RuntimeInput::Startup)?);
+
+    let mut ctx = context(2, 1_800_000_005);
+    let send_actions = alice_runtime.handle(
+        &mut ctx,
+        RuntimeInput::SendRumor {
+            recipient_owner: bob.owner_pubkey,
+            remote_payload: b"before discovery".to_vec(),
+            local_sibling_payload: None,
+            correlation_id: "msg-1".to_string(),
+        },
+    )?;
+    assert!(published(&send_actions).is_empty());
+    assert_eq!(alice_runtime.pending_outbound().len(), 1);
+    let Some(RuntimeAction::Persist(persisted)) = send_actions.last() else {
+        panic!("queued send must persist");
+    };
+    assert_eq!(persisted.pending_outbound.len(), 1);
+
+    let mut alice_runtime =
+        NdrRuntime::from_snapshot(persisted.as_ref().clone(), alice.secret_key)?;
+    let roster_actions = feed(
+        &mut alice_runtime,
+        10,
+        1_800_000_010,
+        [roster_event(&bob, 1_800_000_000)?],
+    )?;
+    assert!(published(&roster_actions).is_empty());
+
+    let invite_actions = feed(&mut alice_runtime, 20, 1_800_000_020, bob_invite)?;
+    let outgoing = published(&invite_actions);
+    assert_eq!(outgoing.len(), 2);
+    assert!(alice_runtime.pending_outbound().is_empty());
+    assert_eq!(alice_runtime.publish_batches().count(), 1);
+
+    feed(
+        &mut bob_runtime,
+        30,
+        1_800_000_030,
+        [roster_event(&alice, 1_800_000_000)?],
+    )?;
+    let received = feed(&mut bob_runtime, 40, 1_800_000_040, outgoing)?;
+    assert!(received.iter().any(|action| matches!(
+        action,
+        RuntimeAction::DeliverDecrypted { payload, .. } if payload == b"before discovery"
+    )));
+    Ok(())
+}
+
+fn connected_runtimes(
+    alice: &ManagerDevice,
+    bob: &ManagerDevice,
+) -> Result<(NdrRuntime, NdrRuntime)> {
+    let mut alice_runtime = runtime(alice)?;
+    let mut bob_runtime = runtime(bob)?;
+
+    let bob_invite =
+        published(&bob_runtime.handle(&mut context(1, 1_800_000_000), RuntimeInput::Startup)?);
+    feed(
+        &mut alice_runtime,
+        10,
+        1_800_000_010,
+        [roster_event(bob, 1_800_000_000)?, bob_invite[0].clone()],
+    )?;
+    let outgoing = published(&alice_runtime.handle(
+        &mut context(20, 1_800_000_020),
+        RuntimeInput::SendRumor {
+            recipient_owner: bob.owner_pubkey,
+            remote_payload: b"hello bob".to_vec(),
+            local_sibling_payload: None,
+            correlation_id: "msg-1".to_string(),
+        },
+    )?);
+    feed(
+        &mut bob_runtime,
+        30,
+        1_800_000_030,
+        [roster_event(alice, 1_800_000_000)?],
+    )?;
+    feed(&mut bob_runtime, 40, 1_800_000_040, outgoing)?;
+
+    Ok((alice_runtime, bob_runtime))
+}
+
+#[test]
+fn refresh_request_is_answered_without_reaching_the_app() -> Result<()> {
+    let alice = manager_device(9, 91);
+    let bob = manager_device(10, 101);
+    let (mut alice_runtime, mut bob_runtime) = connected_runtimes(&alice, &bob)?;
+
+    let unadvertised = alice_runtime
+        .session_manager_mut()
+        .prepare_refresh(&mut context(41, 1_800_000_041), bob.owner_pubkey)?;
+    assert!(unadvertised.deliveries.is_empty());
+    let reply = published(&bob_runtime.handle(
+        &mut context(42, 1_800_000_042),
+        RuntimeInput::SendRumor {
+            recipient_owner: alice.owner_pubkey,
+            remote_payload: b"hello alice".to_vec(),
+            local_sibling_payload: None,
+            correlation_id: "reply-1".to_string(),
+        },
+    )?);
+    feed(&mut alice_runtime, 43, 1_800_000_043, reply)?;
+
+    let refresh = alice_runtime
+        .session_manager_mut()
+        .prepare_refresh(&mut context(50, 1_800_000_050), bob.owner_pubkey)?;
+    assert_eq!(refresh.deliveries.len(), 1);
+    let refresh_event = message_event(&refresh.deliveries[0].envelope)?;
+
+    let bob_actions = feed(&mut bob_runtime, 60, 1_800_000_060, [refresh_event])?;
+    assert!(!bob_actions
+        .iter()
+        .any(|action| matches!(action, RuntimeAction::DeliverDecrypted { .. })));
+    let key_update = published(&bob_actions);
+    assert_eq!(key_update.len(), 1);
+    assert_eq!(u32::from(key_update[0].kind.as_u16()), MESSAGE_EVENT_KIND);
+
+    let alice_actions = feed(&mut alice_runtime, 70, 1_800_000_070, key_update)?;
+    assert!(!alice_actions
+        .iter()
+        .any(|action| matches!(action, RuntimeAction::DeliverDecrypted { .. })));
+    assert!(published(&alice_actions).is_empty());
+    Ok(())
+}
+
+#[test]
+fn session_reset_is_reported_and_next_send_rehandshakes() -> Result<()> {
+    let alice = manager_device(11, 111);
+    let bob = manager_device(12, 121);
+    let (mut alice_runtime, mut bob_runtime) = connected_runtimes(&alice, &bob)?;
+
+    let reset = bob_runtime.session_manager_mut().prepare_reset(
+        &mut context(50, 1_800_000_050),
+        alice.owner_pubkey,
+        alice.device_pubkey,
+    )?;
+    assert_eq!(reset.deliveries.len(), 1);
+    let reset_event = message_event(&reset.deliveries[0].envelope)?;
+
+    let alice_actions = feed(&mut alice_runtime, 60, 1_800_000_060, [reset_event])?;
+    assert!(alice_actions.iter().any(|action| matches!(
+        action,
+        RuntimeAction::SessionReset { sender_owner, sender_device }
+            if *sender_owner == bob.owner_pubkey && *sender_device == bob.device_pubkey
+    )));
+    assert!(!alice_actions
+        .iter()
+        .any(|action| matches!(action, RuntimeAction::DeliverDecrypted { .. })));
+
+    let resend = published(&alice_runtime.handle(
+        &mut context(70, 1_800_000_070),
+        RuntimeInput::SendRumor {
+            recipient_owner: bob.owner_pubkey,
+            remote_payload: b"after reset".to_vec(),
+            local_sibling_payload: None,
+            correlation_id: "msg-2".to_string(),
+        },
+    )?);
+    assert!(resend
+        .iter()
+        .any(|event| u32::from(event.kind.as_u16()) == INVITE_RESPONSE_KIND));
+    let delivered = feed(&mut bob_runtime, 80, 1_800_000_080, resend)?;
+    assert!(delivered.iter().any(|action| matches!(
+        action,
+        RuntimeAction::DeliverDecrypted { payload, .. } if payload_text(payload) == "after reset"
+    )));
+    Ok(())
+}
+
+#[test]
+fn repeated_decrypt_failures_report_broken_session() -> Result<()> {
+    let alice = manager_device(13, 131);
+    let bob = manager_device(14, 141);
+    let (mut alice_runtime, mut bob_runtime) = connected_runtimes(&alice, &bob)?;
+
+    let prepared = alice_runtime.session_manager_mut().prepare_send(
+        &mut context(50, 1_800_000_050),
+        bob.owner_pubkey,
+        b"tampered".to_vec(),
+    )?;
+    let mut envelope = prepared.deliveries[0].envelope.clone();
+    envelope.encrypted_header = mutate_text(&envelope.encrypted_header);
+    let tampered = message_event(&envelope)?;
+
+    let actions = feed(
+        &mut bob_runtime,
+        60,
+        1_800_000_060,
+        std::iter::repeat_n(tampered, DEFAULT_DECRYPT_FAILURE_THRESHOLD as usize),
+    )?;
+    let broken: Vec<_> = actions
+        .iter()
+        .filter(|action| matches!(action, RuntimeAction::SessionBroken { .. }))
+        .collect();
+    assert_eq!(
+        broken,
+        vec![&RuntimeAction::SessionBroken {
+            sender_owner: alice.owner_pubkey,
+            sender_device: alice.device_pubkey,
+        }]
+    );
+    assert!(matches!(actions.last(), Some(RuntimeAction::Persist(_))));
+    Ok(())
+}
+
+#[test]
+fn roster_change_of_verified_owner_is_reported() -> Result<()> {
+    let alice = manager_device(15, 151);
+    let bob = manager_device(16, 161);
+    let bob_laptop = manager_device(16, 162);
+    let mut alice_runtime = runtime(&alice)?;
+    feed(
+        &mut alice_runtime,
+        10,
+        1_800_000_010,
+        [roster_event(&bob, 1_800_000_000)?],
+    )?;
+    let core = alice_runtime.session_manager_mut();
+    core.apply_local_roster(roster_for(&[&alice], 1_800_000_000));
+    core.mark_owner_verified(bob.owner_pubkey)?;
+
+    let updated = roster_unsigned_event(
+        bob.owner_pubkey,
+        &roster_for(&[&bob, &bob_laptop], 1_800_000_020),
+    )?
+    .sign_with_keys(&bob.owner_keys)?;
+    let actions = feed(&mut alice_runtime, 20, 1_800_000_020, [updated])?;
+    assert!(actions.contains(&RuntimeAction::VerifiedRosterChanged {
+        owner: bob.owner_pubkey,
+    }));
+    assert!(!alice_runtime
+        .session_manager()
+        .is_owner_verified(bob.owner_pubkey));
+    Ok(())
+}
+
+#[test]
+fn failed_sibling_copy_still_publishes_the_remote_send() -> Result<()> {
+    let alice = manager_device(17, 171);
+    let alice_laptop = manager_device(17, 172);
+    let bob = manager_device(18, 181);
+    let (mut alice_runtime, mut bob_runtime) = connected_runtimes(&alice, &bob)?;
+
+    let mut broken_invite =
+        manager_public_device_invite(&mut session_manager(&alice_laptop), &alice_laptop, 1, 1)?;
+    broken_invite.inviter_ephemeral_public_key = DevicePubkey::from_bytes([0xff; 32]);
+    let core = alice_runtime.session_manager_mut();
+    core.apply_local_roster(roster_for(&[&alice, &alice_laptop], 1_800_000_040));
+    core.observe_device_invite(alice.owner_pubkey, broken_invite)?;
+
+    let actions = alice_runtime.handle(
+        &mut context(50, 1_800_000_050),
+        RuntimeInput::SendRumor {
+            recipient_owner: bob.owner_pubkey,
+            remote_payload: b"still sent".to_vec(),
+            local_sibling_payload: Some(b"sibling copy".to_vec()),
+            correlation_id: "msg-2".to_string(),
+        },
+    )?;
+    assert!(actions.iter().any(|action| matches!(
+        action,
+        RuntimeAction::SendFailed { correlation_id, .. } if correlation_id == "msg-2"
+    )));
+    assert!(actions
+        .iter()
+        .any(|action| matches!(action, RuntimeAction::Persist(_))));
+    assert!(alice_runtime
+        .publish_batches()
+        .any(|batch| batch.correlation_id == "msg-2"));
+
+    let delivered = feed(&mut bob_runtime, 60, 1_800_000_060, published(&actions))?;
+    assert!(delivered.iter().any(|action| matches!(
+        action,
+        RuntimeAction::DeliverDecrypted { payload, .. } if payload_text(payload) == "still sent"
+    )));
+    Ok(())
+}
@@ -0,0 +1,114 @@
+mod support;
+
+use nostr_double_ratchet::{Result, SessionManager};
+use support::{manager_device, manager_user_snapshot, roster_for, session_manager, ManagerDevice};
+
+const BASE: u64 = 1_800_000_000;
+
+fn manager_with_rosters(
+    local: &ManagerDevice,
+    peer_devices: &[&ManagerDevice],
+    peer_roster_at: u64,
+) -> SessionManager {
+    let mut manager = session_manager(local);
+    manager.apply_local_roster(roster_for(&[local], BASE));
+    manager.observe_peer_roster(
+        peer_devices[0].owner_pubkey,
+        roster_for(peer_devices, peer_roster_at),
+    );
+    manager
+}
+
+#[test]
+fn both_sides_derive_the_same_safety_number() {
+    let alice = manager_device(1, 11);
+    let bob = manager_device(2, 21);
+    let bob_laptop = manager_device(2, 22);
+    let alice_manager = manager_with_rosters(&alice, &[&bob], BASE);
+    let mut bob_manager = manager_with_rosters(&bob, &[&alice], BASE);
+
+    assert!(session_manager(&alice)
+        .safety_number(bob.owner_pubkey)
+        .is_none());
+    let alice_view = alice_manager
+        .safety_number(bob.owner_pubkey)
+        .expect("both rosters known");
+    let bob_view = bob_manager
+        .safety_number(alice.owner_pubkey)
+        .expect("both rosters known");
+    assert_eq!(alice_view, bob_view);
+    assert_eq!(alice_view.digits().len(), 60);
+    assert!(alice_view.digits().chars().all(|c| c.is_ascii_digit()));
+    assert_eq!(alice_view.display().split(' ').count(), 12);
+    assert!(alice_view.matches_qr_payload(&bob_view.qr_payload()));
+
+    bob_manager.apply_local_roster(roster_for(&[&bob, &bob_laptop], BASE + 10));
+    let changed = bob_manager
+        .safety_number(alice.owner_pubkey)
+        .expect("both rosters known");
+    assert_ne!(changed.digits(), alice_view.digits());
+    assert!(!alice_view.matches_qr_payload(&changed.qr_payload()));
+}
+
+#[test]
+fn verification_lapses_and_notifies_when_roster_changes() -> Result<()> {
+    let alice = manager_device(1, 11);
+    let bob = manager_device(2, 21);
+    let bob_laptop = manager_device(2, 22);
+    let mut manager = manager_with_rosters(&alice, &[&bob], BASE);
+    let carol = manager_device(3, 31);
+    assert!(manager.mark_owner_verified(carol.owner_pubkey).is_err());
+
+    manager.mark_owner_verified(bob.owner_pubkey)?;
+    assert!(manager.is_owner_verified(bob.owner_pubkey));
+    let snapshot = manager.snapshot();
+    assert!(manager_user_snapshot(&snapshot, bob.owner_pubkey)
+        .verified_fingerprint
+        .is_some());
+    let mut restored = SessionManager::from_snapshot(snapshot, alice.secret_key)?;
+    assert!(restored.is_owner_verified(bob.owner_pubkey));
+
+    restored.observe_peer_roster(bob.owner_pubkey, roster_for(&[&bob], BASE));
+    assert!(restored.take_verified_roster_changes().is_empty());
+
+    restored.observe_peer_roster(bob.owner_pubkey, roster_for(&[&bob, &bob_laptop], BASE + 5));
+    assert!(!restored.is_owner_verified(bob.owner_pubkey));
+    assert_eq!(
+        restored.take_verified_roster_changes(),
+        vec![bob.owner_pubkey]
+    );
+    restored.observe_peer_roster(bob.owner_pubkey, roster_for(&[&bob_laptop], BASE + 6));
+    assert!(restored.take_verified_roster_changes().is_empty());
+
+    restored.mark_owner_verified(bob.owner_pubkey)?;
+    assert!(restored.is_owner_verified(bob.owner_pubkey));
+    restored.clear_owner_verification(bob.owner_pubkey);
+    assert!(!restored.is_owner_verified(bob.owner_pubkey));
+    Ok(())
+}
+
+#[test]
+fn local_roster_change_lapses_every_verification() -> Result<()> {
+    let alice = manager_device(1, 11);
+    let alice_laptop = manager_device(1, 12);
+    let bob = manager_device(2, 21);
+    let carol = manager_device(3, 31);
+    let mut manager = manager_with_rosters(&alice, &[&bob], BASE);
+    manager.observe_peer_roster(carol.owner_pubkey, roster_for(&[&carol], BASE));
+    manager.mark_owner_verified(bob.owner_pubkey)?;
+    manager.mark_owner_verified(carol.owner_pubkey)?;
+
+    manager.apply_local_roster(roster_for(&[&alice], BASE + 1));
+    assert!(manager.take_verified_roster_changes().is_empty());
+    assert!(manager.is_owner_verified(bob.owner_pubkey));
+
+    manager.apply_local_roster(roster_for(&[&alice, &alice_laptop], BASE + 2));
+    assert!(!manager.is_owner_verified(bob.owner_pubkey));
+    assert!(!manager.is_owner_verified(carol.owner_pubkey));
+    let mut changed = manager.take_verified_roster_changes();
+    changed.sort();
+    let mut expected = vec![bob.owner_pubkey, carol.owner_pubkey];
+    expected.sort();
+    assert_eq!(changed, expected);
+    Ok(())
+}
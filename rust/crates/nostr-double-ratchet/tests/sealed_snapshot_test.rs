@@ -0,0 +1,85 @@
+mod support;
+
+use nostr_double_ratchet::{
+    Error, GroupManagerSnapshot, Result, SealedSnapshot, SessionManagerSnapshot, SnapshotKeySource,
+    SnapshotKind, SnapshotStorageKey,
+};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use support::{context, manager_device, session_manager};
+
+fn manager_snapshot() -> Result<(SessionManagerSnapshot, [u8; 32])> {
+    let alice = manager_device(1, 11);
+    let mut manager = session_manager(&alice);
+    manager.ensure_local_invite(&mut context(1, 1_800_000_000))?;
+    Ok((manager.snapshot(), alice.secret_key))
+}
+
+#[test]
+fn sealed_session_snapshot_opens_with_device_secret_key() -> Result<()> {
+    let (snapshot, device_secret) = manager_snapshot()?;
+    let key = SnapshotStorageKey::from_device_secret(&device_secret);
+    let mut rng = StdRng::seed_from_u64(7);
+    let sealed = SealedSnapshot::seal(&snapshot, snapshot.local_device_pubkey, &key, &mut rng)?;
+
+    assert_eq!(sealed.header.kind, SnapshotKind::SessionManager);
+    assert_eq!(
+        sealed.header.local_device_pubkey,
+        snapshot.local_device_pubkey
+    );
+    assert_eq!(sealed.header.key_source, SnapshotKeySource::DeviceSecret);
+    let stored = serde_json::to_string(&sealed)?;
+    assert!(!stored.contains("inviter_ephemeral_private_key"));
+
+    let restored: SealedSnapshot = serde_json::from_str(&stored)?;
+    let reopened_key = SnapshotStorageKey::from_device_secret(&device_secret);
+    let loaded = restored.open::<SessionManagerSnapshot>(&reopened_key)?;
+    assert_eq!(loaded.snapshot, snapshot);
+    assert!(loaded.applied_migrations.is_empty());
+    Ok(())
+}
+
+#[test]
+fn sealed_group_snapshot_opens_with_app_key() -> Result<()> {
+    let alice = manager_device(2, 21);
+    let groups = GroupManagerSnapshot {
+        local_owner_pubkey: alice.owner_pubkey,
+        groups: Vec::new(),
+        sender_keys: Vec::new(),
+    };
+    let key = SnapshotStorageKey::from_bytes([5u8; 32]);
+    let mut rng = StdRng::seed_from_u64(8);
+    let sealed = SealedSnapshot::seal(&groups, alice.device_pubkey, &key, &mut rng)?;
+    assert_eq!(sealed.header.key_source, SnapshotKeySource::AppProvided);
+
+    let loaded = sealed.open::<GroupManagerSnapshot>(&key)?;
+    assert_eq!(loaded.snapshot, groups);
+    assert!(matches!(
+        sealed.open::<SessionManagerSnapshot>(&key),
+        Err(Error::Serialization(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn wrong_key_or_tampered_header_fails_to_open() -> Result<()> {
+    let (snapshot, _) = manager_snapshot()?;
+    let key = SnapshotStorageKey::from_bytes([6u8; 32]);
+    let mut rng = StdRng::seed_from_u64(9);
+    let sealed = SealedSnapshot::seal(&snapshot, snapshot.local_device_pubkey, &key, &mut rng)?;
+
+    assert!(matches!(
+        sealed.open::<SessionManagerSnapshot>(&SnapshotStorageKey::from_bytes([7u8; 32])),
+        Err(Error::Decryption(_))
+    ));
+
+    let mut tampered = sealed.clone();
+    tampered.header.local_device_pubkey = manager_device(3, 31).device_pubkey;
+    assert!(matches!(
+        tampered.open::<SessionManagerSnapshot>(&key),
+        Err(Error::Decryption(_))
+    ));
+
+    assert!(!format!("{key:?}").contains("6, 6"));
+    Ok(())
+}
@@ -0,0 +1,62 @@
+mod support;
+
+use nostr_double_ratchet::{Result, SecretBytes, SenderKeyState};
+use support::{
+    checkpoint_session, direct_session_pair, manager_device, manager_public_device_invite,
+    session_manager,
+};
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[test]
+fn debug_output_redacts_session_invite_and_sender_key_secrets() -> Result<()> {
+    let (_, _, session, _) = direct_session_pair(1, 2, 1_800_000_000)?;
+    let state = checkpoint_session(&session);
+    let rendered = format!("{state:?}");
+    assert!(rendered.contains("[REDACTED]"));
+    assert!(!rendered.contains(&format!("{:?}", state.root_key.expose_secret())));
+    assert!(!rendered.contains(&format!(
+        "{:?}",
+        state.our_next_nostr_key.private_key.expose_secret()
+    )));
+
+    let bob = manager_device(3, 31);
+    let mut manager = session_manager(&bob);
+    manager.ensure_local_invite(&mut support::context(2, 1_800_000_000))?;
+    let invite = manager.local_invite().cloned().expect("local invite");
+    let rendered = format!("{invite:?}");
+    assert!(rendered.contains("[REDACTED]"));
+    assert!(!rendered.contains(&format!("{:?}", invite.shared_secret.expose_secret())));
+
+    let sender_key = SenderKeyState::new(7, [9u8; 32], 0);
+    assert!(!format!("{sender_key:?}").contains("9, 9"));
+    assert_eq!(format!("{:?}", SecretBytes::new([1u8; 32])), "[REDACTED]");
+    Ok(())
+}
+
+#[test]
+fn wrapped_secrets_keep_hex_snapshot_format() -> Result<()> {
+    let (_, _, session, _) = direct_session_pair(4, 5, 1_800_000_000)?;
+    let state = checkpoint_session(&session);
+    let json: serde_json::Value = serde_json::to_value(&state)?;
+    assert_eq!(json["root_key"], hex(state.root_key.expose_secret()));
+    assert_eq!(
+        json["our_next_nostr_key"]["private_key"],
+        hex(state.our_next_nostr_key.private_key.expose_secret())
+    );
+
+    let restored: nostr_double_ratchet::SessionState = serde_json::from_value(json)?;
+    assert_eq!(restored, state);
+
+    let bob = manager_device(6, 61);
+    let mut bob_manager = session_manager(&bob);
+    let invite = manager_public_device_invite(&mut bob_manager, &bob, 3, 1_800_000_000)?;
+    let json = serde_json::to_value(&invite)?;
+    assert_eq!(
+        json["shared_secret"],
+        hex(invite.shared_secret.expose_secret())
+    );
+    Ok(())
+}
@@ -0,0 +1,116 @@
+mod support;
+
+use nostr_double_ratchet::{DomainError, Error, Result, Session, SessionLimits, MAX_SKIP};
+use support::{
+    checkpoint_session, context, direct_session_pair, payload_text, receive_message, send_text,
+    SentMessage,
+};
+
+const BASE: u64 = 1_800_000_000;
+
+fn send_many(session: &mut Session, seed: u64, count: usize) -> Result<Vec<SentMessage>> {
+    (0..count)
+        .map(|index| send_text(session, &mut context(seed, BASE + 1), format!("m{index}")))
+        .collect()
+}
+
+#[test]
+fn defaults_match_max_skip() {
+    let limits = SessionLimits::default();
+    assert_eq!(limits.max_skip_per_chain, MAX_SKIP);
+    assert_eq!(limits.max_stored_skipped_keys, MAX_SKIP);
+    assert_eq!(limits.max_skipped_senders, None);
+}
+
+#[test]
+fn max_skip_per_chain_is_configurable() -> Result<()> {
+    let (_, _, mut alice, mut bob) = direct_session_pair(1, 2, BASE)?;
+    let sent = send_many(&mut alice, 1, 8)?;
+
+    let mut strict = bob.clone();
+    strict.set_limits(SessionLimits {
+        max_skip_per_chain: 5,
+        ..SessionLimits::default()
+    });
+    assert!(matches!(
+        receive_message(&mut strict, &mut context(2, BASE + 2), &sent[7].incoming),
+        Err(Error::Domain(DomainError::TooManySkippedMessages))
+    ));
+
+    bob.set_limits(SessionLimits {
+        max_skip_per_chain: 7,
+        ..SessionLimits::default()
+    });
+    let payload = receive_message(&mut bob, &mut context(3, BASE + 2), &sent[7].incoming)?;
+    assert_eq!(payload_text(&payload), "m7");
+    Ok(())
+}
+
+#[test]
+fn stored_skipped_keys_are_capped_oldest_first() -> Result<()> {
+    let (_, _, mut alice, mut bob) = direct_session_pair(3, 4, BASE)?;
+    bob.set_limits(SessionLimits {
+        max_stored_skipped_keys: 2,
+        ..SessionLimits::default()
+    });
+    let sent = send_many(&mut alice, 4, 5)?;
+    receive_message(&mut bob, &mut context(5, BASE + 2), &sent[4].incoming)?;
+
+    let state = checkpoint_session(&bob);
+    let stored: Vec<u32> = state
+        .skipped_keys
+        .values()
+        .flat_map(|entry| entry.message_keys.keys().copied())
+        .collect();
+    assert_eq!(stored, vec![2, 3]);
+
+    assert!(receive_message(
+        &mut bob.clone(),
+        &mut context(6, BASE + 3),
+        &sent[0].incoming
+    )
+    .is_err());
+    let payload = receive_message(&mut bob, &mut context(7, BASE + 3), &sent[3].incoming)?;
+    assert_eq!(payload_text(&payload), "m3");
+    Ok(())
+}
+
+#[test]
+fn skipped_sender_chains_are_capped() -> Result<()> {
+    let (_, _, mut alice, mut bob) = direct_session_pair(5, 6, BASE)?;
+    bob.set_limits(SessionLimits {
+        max_skipped_senders: Some(1),
+        ..SessionLimits::default()
+    });
+
+    let first_chain = send_many(&mut alice, 8, 2)?;
+    receive_message(
+        &mut bob,
+        &mut context(9, BASE + 2),
+        &first_chain[1].incoming,
+    )?;
+    let reply = send_text(&mut bob, &mut context(10, BASE + 3), "reply")?;
+    receive_message(&mut alice, &mut context(11, BASE + 4), &reply.incoming)?;
+
+    let second_chain = send_many(&mut alice, 12, 2)?;
+    receive_message(
+        &mut bob,
+        &mut context(13, BASE + 5),
+        &second_chain[1].incoming,
+    )?;
+
+    assert_eq!(checkpoint_session(&bob).skipped_keys.len(), 1);
+    assert!(receive_message(
+        &mut bob.clone(),
+        &mut context(14, BASE + 6),
+        &first_chain[0].incoming
+    )
+    .is_err());
+    let payload = receive_message(
+        &mut bob,
+        &mut context(15, BASE + 6),
+        &second_chain[0].incoming,
+    )?;
+    assert_eq!(payload_text(&payload), "m0");
+    Ok(())
+}
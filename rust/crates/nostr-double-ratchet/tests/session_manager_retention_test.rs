@@ -0,0 +1,195 @@
+mod support;
+
+use nostr_double_ratchet::{
+    InMemoryStorageAdapter, PrunedSessions, Result, RetentionPolicy, SessionLimits, SessionManager,
+    SessionManagerSettings, SessionManagerStore, UnixSeconds, DEFAULT_STALE_DEVICE_GRACE_SECS,
+};
+use support::{
+    checkpoint_session, context, direct_session_pair, manager_device, manager_device_snapshot,
+    manager_observe_invite_response, manager_public_device_invite, manager_receive_delivery,
+    manager_user_snapshot, payload_text, roster_for, session_manager,
+};
+
+#[test]
+fn stale_devices_survive_until_grace_period_ends() -> Result<()> {
+    let alice = manager_device(1, 11);
+    let bob = manager_device(2, 21);
+    let mut manager = session_manager(&alice);
+    manager.observe_peer_roster(bob.owner_pubkey, roster_for(&[&bob], 100));
+    manager.observe_peer_roster(bob.owner_pubkey, roster_for(&[], 200));
+    let mut immediate = manager.clone();
+    assert_eq!(
+        immediate.prune_stale(UnixSeconds(200)).removed_devices,
+        vec![(bob.owner_pubkey, bob.device_pubkey)]
+    );
+
+    manager.set_retention_policy(RetentionPolicy::recommended());
+    let report = manager.prune_stale(UnixSeconds(200 + DEFAULT_STALE_DEVICE_GRACE_SECS - 1));
+    assert!(report.removed_devices.is_empty());
+    manager_device_snapshot(
+        manager_user_snapshot(&manager.snapshot(), bob.owner_pubkey),
+        bob.device_pubkey,
+    );
+
+    let report = manager.prune_stale(UnixSeconds(200 + DEFAULT_STALE_DEVICE_GRACE_SECS));
+    assert_eq!(
+        report.removed_devices,
+        vec![(bob.owner_pubkey, bob.device_pubkey)]
+    );
+    assert!(manager_user_snapshot(&manager.snapshot(), bob.owner_pubkey)
+        .devices
+        .is_empty());
+    Ok(())
+}
+
+#[test]
+fn idle_inactive_sessions_are_dropped_but_active_session_kept() -> Result<()> {
+    let alice = manager_device(3, 31);
+    let bob = manager_device(4, 41);
+    let mut manager = session_manager(&alice);
+    manager.set_retention_policy(RetentionPolicy {
+        inactive_session_max_idle_secs: Some(1_000),
+        ..RetentionPolicy::default()
+    });
+
+    let (_, _, first, _) = direct_session_pair(3, 4, 1_800_000_000)?;
+    let (_, _, second, _) = direct_session_pair(5, 6, 1_800_000_000)?;
+    manager.import_session_state(
+        bob.owner_pubkey,
+        bob.device_pubkey,
+        checkpoint_session(&first),
+        UnixSeconds(5_000),
+    );
+    manager.import_session_state(
+        bob.owner_pubkey,
+        bob.device_pubkey,
+        checkpoint_session(&second),
+        UnixSeconds(5_000),
+    );
+
+    assert!(manager
+        .prune_stale(UnixSeconds(5_999))
+        .removed_sessions
+        .is_empty());
+
+    let report = manager.prune_stale(UnixSeconds(6_000));
+    assert_eq!(
+        report.removed_sessions,
+        vec![PrunedSessions {
+            owner_pubkey: bob.owner_pubkey,
+            device_pubkey: bob.device_pubkey,
+            inactive_sessions: 1,
+        }]
+    );
+    let snapshot = manager.snapshot();
+    let record = manager_device_snapshot(
+        manager_user_snapshot(&snapshot, bob.owner_pubkey),
+        bob.device_pubkey,
+    );
+    assert!(record.active_session.is_some());
+    assert!(record.inactive_sessions.is_empty());
+    Ok(())
+}
+
+#[test]
+fn skipped_keys_are_capped_oldest_first() -> Result<()> {
+    let alice = manager_device(5, 51);
+    let bob = manager_device(6, 61);
+    let mut alice_manager = session_manager(&alice);
+    let mut bob_manager = session_manager(&bob);
+    alice_manager.observe_peer_roster(bob.owner_pubkey, roster_for(&[&bob], 10));
+    bob_manager.observe_peer_roster(alice.owner_pubkey, roster_for(&[&alice], 10));
+    alice_manager.observe_device_invite(
+        bob.owner_pubkey,
+        manager_public_device_invite(&mut bob_manager, &bob, 5, 1_800_000_000)?,
+    )?;
+
+    let mut sent = Vec::new();
+    for index in 0..5u64 {
+        let mut ctx = context(10 + index, 1_800_000_001 + index);
+        sent.push(alice_manager.prepare_send(
+            &mut ctx,
+            bob.owner_pubkey,
+            format!("message {index}").into_bytes(),
+        )?);
+    }
+    let mut ctx = context(20, 1_800_000_010);
+    manager_observe_invite_response(&mut bob_manager, &mut ctx, &sent[0].invite_responses[0])?;
+    let mut ctx = context(21, 1_800_000_011);
+    let received = manager_receive_delivery(
+        &mut bob_manager,
+        &mut ctx,
+        alice.owner_pubkey,
+        &sent[4].deliveries[0],
+    )?
+    .expect("latest message must decrypt");
+    assert_eq!(payload_text(&received.payload), "message 4");
+
+    bob_manager.set_session_limits(SessionLimits {
+        max_skipped_keys_per_device: Some(2),
+        ..SessionLimits::default()
+    });
+    let report = bob_manager.prune_stale(UnixSeconds(1_800_000_012));
+    assert_eq!(
+        report
+            .removed_skipped_keys
+            .iter()
+            .map(|entry| (entry.device_pubkey, entry.message_number))
+            .collect::<Vec<_>>(),
+        vec![(alice.device_pubkey, 0), (alice.device_pubkey, 1)]
+    );
+
+    let mut ctx = context(22, 1_800_000_013);
+    assert!(manager_receive_delivery(
+        &mut bob_manager,
+        &mut ctx,
+        alice.owner_pubkey,
+        &sent[0].deliveries[0],
+    )
+    .map_or(true, |received| received.is_none()));
+    let mut ctx = context(23, 1_800_000_014);
+    let received = manager_receive_delivery(
+        &mut bob_manager,
+        &mut ctx,
+        alice.owner_pubkey,
+        &sent[2].deliveries[0],
+    )?
+    .expect("retained skipped key must decrypt");
+    assert_eq!(payload_text(&received.payload), "message 2");
+    Ok(())
+}
+
+#[test]
+fn retention_settings_survive_snapshot_and_store() -> Result<()> {
+    let alice = manager_device(7, 71);
+    let mut manager = session_manager(&alice);
+    let policy = RetentionPolicy::recommended();
+    let limits = SessionLimits {
+        max_skipped_keys_per_device: Some(64),
+        ..SessionLimits::default()
+    };
+    let mut store = SessionManagerStore::new(InMemoryStorageAdapter::default());
+    store.save_snapshot(&manager.snapshot())?;
+
+    manager.set_retention_policy(policy);
+    manager.set_session_limits(limits);
+    let changes = manager.take_changes();
+    assert_eq!(
+        changes.settings,
+        Some(SessionManagerSettings {
+            retention_policy: policy,
+            session_limits: limits,
+            ..SessionManagerSettings::default()
+        })
+    );
+    store.apply_changes(&changes)?;
+    assert!(manager.take_changes().settings.is_none());
+
+    let restored = SessionManager::from_snapshot(
+        store.load_snapshot()?.expect("snapshot was saved"),
+        alice.secret_key,
+    )?;
+    assert_eq!(restored.retention_policy(), policy);
+    assert_eq!(restored.session_limits(), limits);
+    Ok(())
+}
@@ -0,0 +1,129 @@
+mod support;
+
+use nostr_double_ratchet::wire as codec;
+use nostr_double_ratchet::{Delivery, MessageEnvelope, Result, SessionManager};
+use support::{
+    context, manager_device, manager_observe_invite_response, manager_public_device_invite,
+    payload_text, restore_manager, roster_for, session_manager, ManagerDevice,
+};
+
+fn incoming(delivery: &Delivery) -> Result<MessageEnvelope> {
+    let event = codec::message_event(&delivery.envelope)?;
+    Ok(codec::parse_message_event(&event)?)
+}
+
+fn bootstrapped_pair(
+    alice: &ManagerDevice,
+    bob: &ManagerDevice,
+    seed: u64,
+) -> Result<(SessionManager, SessionManager, Delivery)> {
+    let mut alice_manager = session_manager(alice);
+    let mut bob_manager = session_manager(bob);
+    alice_manager.observe_peer_roster(bob.owner_pubkey, roster_for(&[bob], 10));
+    bob_manager.observe_peer_roster(alice.owner_pubkey, roster_for(&[alice], 10));
+    alice_manager.observe_device_invite(
+        bob.owner_pubkey,
+        manager_public_device_invite(&mut bob_manager, bob, seed, 1_800_000_000)?,
+    )?;
+
+    let mut ctx = context(seed + 1, 1_800_000_001);
+    let first = alice_manager.prepare_send(&mut ctx, bob.owner_pubkey, b"first".to_vec())?;
+    let mut ctx = context(seed + 2, 1_800_000_002);
+    manager_observe_invite_response(&mut bob_manager, &mut ctx, &first.invite_responses[0])?;
+    Ok((alice_manager, bob_manager, first.deliveries[0].clone()))
+}
+
+#[test]
+fn receive_any_routes_by_sender_without_owner_hint() -> Result<()> {
+    let alice = manager_device(1, 11);
+    let bob = manager_device(2, 21);
+    let (_, mut bob_manager, first) = bootstrapped_pair(&alice, &bob, 1)?;
+
+    let envelope = incoming(&first)?;
+    assert!(bob_manager
+        .message_author_pubkeys()
+        .contains(&envelope.sender));
+
+    let mut ctx = context(10, 1_800_000_010);
+    let received = bob_manager
+        .receive_any(&mut ctx, &envelope)?
+        .expect("indexed sender must route to alice");
+    assert_eq!(received.owner_pubkey, alice.owner_pubkey);
+    assert_eq!(received.device_pubkey, alice.device_pubkey);
+    assert_eq!(payload_text(&received.payload), "first");
+    Ok(())
+}
+
+#[test]
+fn receive_any_ignores_unknown_senders() -> Result<()> {
+    let alice = manager_device(3, 31);
+    let bob = manager_device(4, 41);
+    let carol = manager_device(5, 51);
+    let (_, mut bob_manager, _) = bootstrapped_pair(&alice, &bob, 3)?;
+    let (_, _, carol_delivery) = bootstrapped_pair(&carol, &manager_device(6, 61), 5)?;
+
+    let before = bob_manager.snapshot();
+    let mut ctx = context(10, 1_800_000_010);
+    assert!(bob_manager
+        .receive_any(&mut ctx, &incoming(&carol_delivery)?)?
+        .is_none());
+    assert_eq!(bob_manager.snapshot(), before);
+    Ok(())
+}
+
+#[test]
+fn watched_authors_follow_ratchet_steps_and_skipped_keys() -> Result<()> {
+    let alice = manager_device(7, 71);
+    let bob = manager_device(8, 81);
+    let (mut alice_manager, mut bob_manager, first) = bootstrapped_pair(&alice, &bob, 7)?;
+
+    let mut ctx = context(10, 1_800_000_010);
+    bob_manager.receive_any(&mut ctx, &incoming(&first)?)?;
+    let mut ctx = context(11, 1_800_000_011);
+    let reply = bob_manager.prepare_send(&mut ctx, alice.owner_pubkey, b"reply".to_vec())?;
+    let mut ctx = context(12, 1_800_000_012);
+    alice_manager.receive_any(&mut ctx, &incoming(&reply.deliveries[0])?)?;
+
+    let mut ctx = context(13, 1_800_000_013);
+    let skipped = alice_manager.prepare_send(&mut ctx, bob.owner_pubkey, b"skipped".to_vec())?;
+    let mut ctx = context(14, 1_800_000_014);
+    let delivered = alice_manager.prepare_send(&mut ctx, bob.owner_pubkey, b"next".to_vec())?;
+
+    let skipped_envelope = incoming(&skipped.deliveries[0])?;
+    let mut ctx = context(15, 1_800_000_015);
+    let received = bob_manager
+        .receive_any(&mut ctx, &incoming(&delivered.deliveries[0])?)?
+        .expect("second message must decrypt");
+    assert_eq!(payload_text(&received.payload), "next");
+    assert!(bob_manager
+        .message_author_pubkeys()
+        .contains(&skipped_envelope.sender));
+
+    let mut restored = restore_manager(&bob_manager.snapshot(), bob.secret_key)?;
+    assert_eq!(
+        restored.message_author_pubkeys(),
+        bob_manager.message_author_pubkeys()
+    );
+    let mut ctx = context(16, 1_800_000_016);
+    let late = restored
+        .receive_any(&mut ctx, &skipped_envelope)?
+        .expect("skipped key sender must stay routable after restore");
+    assert_eq!(payload_text(&late.payload), "skipped");
+    Ok(())
+}
+
+#[test]
+fn deleting_a_user_drops_its_senders_from_the_index() -> Result<()> {
+    let alice = manager_device(9, 91);
+    let bob = manager_device(10, 101);
+    let (_, mut bob_manager, first) = bootstrapped_pair(&alice, &bob, 9)?;
+    let envelope = incoming(&first)?;
+
+    bob_manager.delete_user(alice.owner_pubkey);
+    assert!(!bob_manager
+        .message_author_pubkeys()
+        .contains(&envelope.sender));
+    let mut ctx = context(10, 1_800_000_010);
+    assert!(bob_manager.receive_any(&mut ctx, &envelope)?.is_none());
+    Ok(())
+}
@@ -0,0 +1,172 @@
+mod support;
+
+use nostr_double_ratchet::{
+    Error, FileStorageAdapter, InMemoryStorageAdapter, Result, SessionManager, SessionManagerStore,
+    SnapshotEnvelope, SnapshotKind, StorageAdapter, SESSION_MANAGER_STORAGE_PREFIX,
+    SNAPSHOT_VERSION,
+};
+use support::{
+    context, manager_device, manager_public_device_invite, restore_manager, roster_for,
+    session_manager, ManagerDevice,
+};
+
+fn manager_with_peers(
+    alice: &ManagerDevice,
+    bob: &ManagerDevice,
+    carol: &ManagerDevice,
+) -> Result<SessionManager> {
+    let mut manager = session_manager(alice);
+    manager.ensure_local_invite(&mut context(1, 1_800_000_000))?;
+    for (seed, peer) in [(2, bob), (3, carol)] {
+        let mut peer_manager = session_manager(peer);
+        manager.observe_peer_roster(peer.owner_pubkey, roster_for(&[peer], 10));
+        manager.observe_device_invite(
+            peer.owner_pubkey,
+            manager_public_device_invite(&mut peer_manager, peer, seed, 1_800_000_000)?,
+        )?;
+    }
+    Ok(manager)
+}
+
+#[test]
+fn changes_report_only_touched_users() -> Result<()> {
+    let alice = manager_device(1, 11);
+    let bob = manager_device(2, 21);
+    let carol = manager_device(3, 31);
+    let mut manager = manager_with_peers(&alice, &bob, &carol)?;
+    assert!(manager.has_pending_changes());
+
+    let mut store = SessionManagerStore::new(InMemoryStorageAdapter::new());
+    store.save_snapshot(&manager.snapshot())?;
+    manager.take_changes();
+    assert!(!manager.has_pending_changes());
+
+    manager.prepare_send(
+        &mut context(4, 1_800_000_001),
+        bob.owner_pubkey,
+        b"hi".to_vec(),
+    )?;
+    let changes = manager.take_changes();
+    let upserted: Vec<_> = changes
+        .upserted_users
+        .iter()
+        .map(|user| user.owner_pubkey)
+        .collect();
+    assert!(upserted.contains(&bob.owner_pubkey));
+    assert!(!upserted.contains(&carol.owner_pubkey));
+    assert!(changes.local_invite.is_none());
+    assert!(changes.removed_users.is_empty());
+
+    store.apply_changes(&changes)?;
+    assert_eq!(store.load_snapshot()?, Some(manager.snapshot()));
+
+    manager.delete_user(carol.owner_pubkey);
+    let changes = manager.take_changes();
+    assert_eq!(changes.removed_users, vec![carol.owner_pubkey]);
+    assert!(changes.upserted_users.is_empty());
+    store.apply_changes(&changes)?;
+    assert_eq!(store.load_snapshot()?, Some(manager.snapshot()));
+    assert_eq!(
+        store
+            .storage()
+            .list(&format!("{SESSION_MANAGER_STORAGE_PREFIX}user/"))?
+            .len(),
+        manager.snapshot().users.len()
+    );
+    Ok(())
+}
+
+#[test]
+fn file_storage_round_trips_manager_and_leaves_no_temp_files() -> Result<()> {
+    let alice = manager_device(4, 41);
+    let bob = manager_device(5, 51);
+    let carol = manager_device(6, 61);
+    let mut manager = manager_with_peers(&alice, &bob, &carol)?;
+    let dir = tempfile::tempdir().expect("tempdir");
+
+    let mut store = SessionManagerStore::new(FileStorageAdapter::new(dir.path())?);
+    assert_eq!(store.load_snapshot()?, None);
+    store.save_snapshot(&manager.snapshot())?;
+    manager.take_changes();
+
+    manager.prepare_send(
+        &mut context(5, 1_800_000_001),
+        carol.owner_pubkey,
+        b"hello".to_vec(),
+    )?;
+    store.apply_changes(&manager.take_changes())?;
+
+    let reopened = SessionManagerStore::new(FileStorageAdapter::new(dir.path())?);
+    let loaded = reopened.load_snapshot()?.expect("stored snapshot");
+    assert_eq!(loaded, manager.snapshot());
+    restore_manager(&loaded, alice.secret_key)?;
+
+    let leftovers = std::fs::read_dir(dir.path())
+        .expect("read dir")
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().ends_with(".tmp"))
+        .count();
+    assert_eq!(leftovers, 0);
+    Ok(())
+}
+
+#[test]
+fn memory_and_file_adapters_list_keys_by_prefix() -> Result<()> {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let mut adapters: Vec<Box<dyn StorageAdapter>> = vec![
+        Box::new(InMemoryStorageAdapter::new()),
+        Box::new(FileStorageAdapter::new(dir.path())?),
+    ];
+    for adapter in &mut adapters {
+        adapter.put("a/2", "two".to_string())?;
+        adapter.put("a/1", "one".to_string())?;
+        adapter.put("b/1.json", "other".to_string())?;
+        adapter.put("a/1", "uno".to_string())?;
+
+        assert_eq!(adapter.list("a/")?, vec!["a/1", "a/2"]);
+        assert_eq!(adapter.get("a/1")?.as_deref(), Some("uno"));
+        assert_eq!(adapter.get("b/1.json")?.as_deref(), Some("other"));
+
+        adapter.del("a/1")?;
+        adapter.del("missing")?;
+        assert_eq!(adapter.get("a/1")?, None);
+        assert_eq!(adapter.list("")?, vec!["a/2", "b/1.json"]);
+    }
+    Ok(())
+}
+
+#[test]
+fn every_record_is_stored_in_a_versioned_envelope() -> Result<()> {
+    let alice = manager_device(7, 71);
+    let bob = manager_device(8, 81);
+    let carol = manager_device(9, 91);
+    let manager = manager_with_peers(&alice, &bob, &carol)?;
+    let mut store = SessionManagerStore::new(InMemoryStorageAdapter::new());
+    store.save_snapshot(&manager.snapshot())?;
+
+    let keys = store.storage().list(SESSION_MANAGER_STORAGE_PREFIX)?;
+    assert!(!keys.is_empty());
+    let user_key = format!(
+        "{SESSION_MANAGER_STORAGE_PREFIX}user/{}",
+        bob.owner_pubkey.to_hex()
+    );
+    for key in &keys {
+        let value = store.storage().get(key)?.expect("listed key");
+        let envelope: SnapshotEnvelope = serde_json::from_str(&value)?;
+        assert_eq!(envelope.version, SNAPSHOT_VERSION, "{key}");
+        if *key == user_key {
+            assert_eq!(envelope.kind, SnapshotKind::UserRecord);
+        }
+    }
+
+    let value = store.storage().get(&user_key)?.expect("bob record");
+    let mut envelope: SnapshotEnvelope = serde_json::from_str(&value)?;
+    envelope.version = SNAPSHOT_VERSION + 1;
+    let mut storage = store.into_storage();
+    storage.put(&user_key, serde_json::to_string(&envelope)?)?;
+    assert!(matches!(
+        SessionManagerStore::new(storage).load_snapshot(),
+        Err(Error::Serialization(_))
+    ));
+    Ok(())
+}
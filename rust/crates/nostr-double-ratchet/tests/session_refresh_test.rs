@@ -0,0 +1,132 @@
+mod support;
+
+use nostr_double_ratchet::{DomainError, Error, Result, SessionControl, UnixSeconds};
+use support::{
+    context, direct_session_pair, manager_device, manager_observe_invite_response,
+    manager_public_device_invite, manager_receive_delivery, payload_text, receive_message,
+    roster_for, send_text, session_manager,
+};
+
+const BASE: u64 = 1_800_000_000;
+
+#[test]
+fn refresh_round_trip_moves_one_way_sender_to_fresh_chain() -> Result<()> {
+    let (_, _, mut alice, mut bob) = direct_session_pair(1, 2, BASE)?;
+    let first = send_text(&mut alice, &mut context(1, BASE + 1), "first")?;
+    receive_message(&mut bob, &mut context(2, BASE + 2), &first.incoming)?;
+    assert!(matches!(
+        alice.plan_refresh(UnixSeconds(BASE + 2)),
+        Err(Error::Domain(DomainError::ControlNotSupported))
+    ));
+    let ack = send_text(&mut bob, &mut context(7, BASE + 2), "ack")?;
+    receive_message(&mut alice, &mut context(8, BASE + 2), &ack.incoming)?;
+    assert!(alice.peer_accepts_control());
+    let second = send_text(&mut alice, &mut context(9, BASE + 2), "second")?;
+    receive_message(&mut bob, &mut context(10, BASE + 2), &second.incoming)?;
+    let sending_key_before = alice.state.our_current_nostr_key.clone();
+
+    let refresh = alice.apply_send(alice.plan_refresh(UnixSeconds(BASE + 3))?);
+    let plan = bob.plan_receive(&mut context(3, BASE + 4), &refresh.envelope)?;
+    let outcome = bob.apply_receive(plan);
+    assert_eq!(outcome.control, Some(SessionControl::RefreshRequest));
+    assert!(outcome.payload.is_empty());
+
+    let key_update = bob.apply_send(bob.plan_key_update(UnixSeconds(BASE + 5))?);
+    let plan = alice.plan_receive(&mut context(4, BASE + 6), &key_update.envelope)?;
+    assert_eq!(
+        alice.apply_receive(plan).control,
+        Some(SessionControl::KeyUpdate)
+    );
+    assert_ne!(alice.state.our_current_nostr_key, sending_key_before);
+
+    let healed = send_text(&mut alice, &mut context(5, BASE + 7), "after refresh")?;
+    assert_ne!(
+        Some(healed.incoming.sender),
+        sending_key_before.map(|key| key.public_key)
+    );
+    let payload = receive_message(&mut bob, &mut context(6, BASE + 8), &healed.incoming)?;
+    assert_eq!(payload_text(&payload), "after refresh");
+    Ok(())
+}
+
+#[test]
+fn manager_refresh_is_reported_as_control_and_answered() -> Result<()> {
+    let alice = manager_device(3, 31);
+    let bob = manager_device(4, 41);
+    let mut alice_manager = session_manager(&alice);
+    let mut bob_manager = session_manager(&bob);
+    alice_manager.observe_peer_roster(bob.owner_pubkey, roster_for(&[&bob], 10));
+    bob_manager.observe_peer_roster(alice.owner_pubkey, roster_for(&[&alice], 10));
+    alice_manager.observe_device_invite(
+        bob.owner_pubkey,
+        manager_public_device_invite(&mut bob_manager, &bob, 3, BASE)?,
+    )?;
+
+    let first = alice_manager.prepare_send(
+        &mut context(10, BASE + 1),
+        bob.owner_pubkey,
+        b"hello".to_vec(),
+    )?;
+    manager_observe_invite_response(
+        &mut bob_manager,
+        &mut context(11, BASE + 2),
+        &first.invite_responses[0],
+    )?;
+    manager_receive_delivery(
+        &mut bob_manager,
+        &mut context(12, BASE + 3),
+        alice.owner_pubkey,
+        &first.deliveries[0],
+    )?
+    .expect("first message decrypts");
+    assert!(alice_manager
+        .prepare_refresh(&mut context(18, BASE + 3), bob.owner_pubkey)?
+        .deliveries
+        .is_empty());
+    let reply = bob_manager.prepare_send(
+        &mut context(19, BASE + 3),
+        alice.owner_pubkey,
+        b"hi".to_vec(),
+    )?;
+    manager_receive_delivery(
+        &mut alice_manager,
+        &mut context(20, BASE + 3),
+        bob.owner_pubkey,
+        &reply.deliveries[0],
+    )?
+    .expect("reply decrypts");
+
+    let refresh = alice_manager.prepare_refresh(&mut context(13, BASE + 4), bob.owner_pubkey)?;
+    assert_eq!(refresh.deliveries.len(), 1);
+    assert!(refresh.invite_responses.is_empty());
+    let received = manager_receive_delivery(
+        &mut bob_manager,
+        &mut context(14, BASE + 5),
+        alice.owner_pubkey,
+        &refresh.deliveries[0],
+    )?
+    .expect("refresh decrypts");
+    assert_eq!(received.control, Some(SessionControl::RefreshRequest));
+    assert!(received.payload.is_empty());
+
+    let key_update = bob_manager.prepare_key_update(
+        &mut context(15, BASE + 6),
+        alice.owner_pubkey,
+        alice.device_pubkey,
+    )?;
+    let received = manager_receive_delivery(
+        &mut alice_manager,
+        &mut context(16, BASE + 7),
+        bob.owner_pubkey,
+        &key_update.deliveries[0],
+    )?
+    .expect("key update decrypts");
+    assert_eq!(received.control, Some(SessionControl::KeyUpdate));
+
+    let unknown = manager_device(5, 51);
+    assert!(alice_manager
+        .prepare_refresh(&mut context(17, BASE + 8), unknown.owner_pubkey)?
+        .deliveries
+        .is_empty());
+    Ok(())
+}
@@ -0,0 +1,159 @@
+mod support;
+
+use nostr_double_ratchet::{DomainError, Error, Result, SessionControl, UnixSeconds};
+use support::{
+    checkpoint_session, context, direct_session_pair, manager_device,
+    manager_observe_invite_response, manager_public_device_invite, manager_receive_delivery,
+    payload_text, receive_message, restore_session, roster_for, send_text, session_manager,
+};
+
+const BASE: u64 = 1_800_000_000;
+
+#[test]
+fn reset_closes_receiving_session_but_keeps_in_flight_messages() -> Result<()> {
+    let (_, _, mut alice, mut bob) = direct_session_pair(1, 2, BASE)?;
+    assert!(alice.plan_reset(UnixSeconds(BASE)).is_err());
+    let hello = send_text(&mut alice, &mut context(4, BASE), "hello")?;
+    receive_message(&mut bob, &mut context(5, BASE), &hello.incoming)?;
+    let in_flight = send_text(&mut bob, &mut context(1, BASE + 1), "in flight")?;
+
+    let reset = bob.apply_send(bob.plan_reset(UnixSeconds(BASE + 2))?);
+    bob.close();
+    assert!(bob.is_closed());
+    assert!(!bob.can_send());
+
+    let plan = alice.plan_receive(&mut context(2, BASE + 3), &reset.envelope)?;
+    let outcome = alice.apply_receive(plan);
+    assert_eq!(outcome.control, Some(SessionControl::Reset));
+    assert!(outcome.payload.is_empty());
+    assert!(alice.is_closed());
+    assert!(!alice.can_send());
+
+    let mut restored = restore_session(&checkpoint_session(&alice));
+    assert!(restored.is_closed());
+    let payload = receive_message(
+        &mut restored,
+        &mut context(3, BASE + 4),
+        &in_flight.incoming,
+    )?;
+    assert_eq!(payload_text(&payload), "in flight");
+    Ok(())
+}
+
+#[test]
+fn manager_reset_rehandshakes_on_next_send() -> Result<()> {
+    let alice = manager_device(3, 31);
+    let bob = manager_device(4, 41);
+    let mut alice_manager = session_manager(&alice);
+    let mut bob_manager = session_manager(&bob);
+    alice_manager.observe_peer_roster(bob.owner_pubkey, roster_for(&[&bob], 10));
+    bob_manager.observe_peer_roster(alice.owner_pubkey, roster_for(&[&alice], 10));
+    alice_manager.observe_device_invite(
+        bob.owner_pubkey,
+        manager_public_device_invite(&mut bob_manager, &bob, 3, BASE)?,
+    )?;
+    bob_manager.observe_device_invite(
+        alice.owner_pubkey,
+        manager_public_device_invite(&mut alice_manager, &alice, 4, BASE)?,
+    )?;
+
+    let first = alice_manager.prepare_send(
+        &mut context(10, BASE + 1),
+        bob.owner_pubkey,
+        b"hello".to_vec(),
+    )?;
+    manager_observe_invite_response(
+        &mut bob_manager,
+        &mut context(11, BASE + 2),
+        &first.invite_responses[0],
+    )?;
+    manager_receive_delivery(
+        &mut bob_manager,
+        &mut context(12, BASE + 3),
+        alice.owner_pubkey,
+        &first.deliveries[0],
+    )?
+    .expect("first message decrypts");
+
+    assert!(matches!(
+        alice_manager.prepare_reset(
+            &mut context(20, BASE + 3),
+            bob.owner_pubkey,
+            bob.device_pubkey,
+        ),
+        Err(Error::Domain(DomainError::ControlNotSupported))
+    ));
+    let unknown = manager_device(5, 51);
+    assert!(matches!(
+        alice_manager.prepare_reset(
+            &mut context(21, BASE + 3),
+            unknown.owner_pubkey,
+            unknown.device_pubkey,
+        ),
+        Err(Error::Domain(DomainError::SessionNotReady))
+    ));
+    let still_open = alice_manager.prepare_send(
+        &mut context(22, BASE + 3),
+        bob.owner_pubkey,
+        b"still open".to_vec(),
+    )?;
+    assert!(still_open.invite_responses.is_empty());
+    manager_receive_delivery(
+        &mut bob_manager,
+        &mut context(23, BASE + 3),
+        alice.owner_pubkey,
+        &still_open.deliveries[0],
+    )?
+    .expect("session was not closed");
+
+    let reset = bob_manager.prepare_reset(
+        &mut context(13, BASE + 4),
+        alice.owner_pubkey,
+        alice.device_pubkey,
+    )?;
+    assert_eq!(reset.deliveries.len(), 1);
+    let received = manager_receive_delivery(
+        &mut alice_manager,
+        &mut context(14, BASE + 5),
+        bob.owner_pubkey,
+        &reset.deliveries[0],
+    )?
+    .expect("reset decrypts");
+    assert_eq!(received.control, Some(SessionControl::Reset));
+
+    let reply = alice_manager.prepare_send(
+        &mut context(15, BASE + 6),
+        bob.owner_pubkey,
+        b"fresh start".to_vec(),
+    )?;
+    assert_eq!(reply.invite_responses.len(), 1);
+    manager_observe_invite_response(
+        &mut bob_manager,
+        &mut context(16, BASE + 7),
+        &reply.invite_responses[0],
+    )?;
+    let received = manager_receive_delivery(
+        &mut bob_manager,
+        &mut context(17, BASE + 8),
+        alice.owner_pubkey,
+        &reply.deliveries[0],
+    )?
+    .expect("reply over new session decrypts");
+    assert_eq!(received.payload, b"fresh start");
+
+    let next = bob_manager.prepare_send(
+        &mut context(18, BASE + 9),
+        alice.owner_pubkey,
+        b"back again".to_vec(),
+    )?;
+    assert!(next.invite_responses.is_empty());
+    let received = manager_receive_delivery(
+        &mut alice_manager,
+        &mut context(19, BASE + 10),
+        bob.owner_pubkey,
+        &next.deliveries[0],
+    )?
+    .expect("bob answers on the new session");
+    assert_eq!(received.payload, b"back again");
+    Ok(())
+}
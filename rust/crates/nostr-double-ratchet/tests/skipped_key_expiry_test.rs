@@ -0,0 +1,100 @@
+mod support;
+
+use nostr_double_ratchet::{Result, UnixSeconds, DEFAULT_SKIPPED_KEY_MAX_AGE_SECS};
+use support::{
+    checkpoint_session, context, direct_session_pair, payload_text, receive_message,
+    restore_session, send_text,
+};
+
+const BASE: u64 = 1_800_000_000;
+
+#[test]
+fn skipped_keys_record_derivation_time() -> Result<()> {
+    let (_, _, mut alice, mut bob) = direct_session_pair(1, 2, BASE)?;
+    let lost = send_text(&mut alice, &mut context(1, BASE + 1), "lost")?;
+    let kept = send_text(&mut alice, &mut context(2, BASE + 2), "kept")?;
+    assert_eq!(
+        payload_text(&receive_message(
+            &mut bob,
+            &mut context(3, BASE + 10),
+            &kept.incoming
+        )?),
+        "kept"
+    );
+
+    let state = checkpoint_session(&bob);
+    let entry = state
+        .skipped_keys
+        .values()
+        .next()
+        .expect("skipped key entry");
+    assert_eq!(entry.message_keys.len(), 1);
+    assert_eq!(
+        entry.derived_at.values().copied().collect::<Vec<_>>(),
+        vec![UnixSeconds(BASE + 10)]
+    );
+
+    let mut restored = restore_session(&state);
+    assert_eq!(
+        payload_text(&receive_message(
+            &mut restored,
+            &mut context(4, BASE + 20),
+            &lost.incoming
+        )?),
+        "lost"
+    );
+    assert!(checkpoint_session(&restored).skipped_keys.is_empty());
+    Ok(())
+}
+
+#[test]
+fn skipped_keys_older_than_window_are_discarded_on_receive() -> Result<()> {
+    let (_, _, mut alice, mut bob) = direct_session_pair(3, 4, BASE)?;
+    bob.set_skipped_key_max_age_secs(Some(60));
+    let lost = send_text(&mut alice, &mut context(5, BASE + 1), "lost")?;
+    let first = send_text(&mut alice, &mut context(6, BASE + 2), "first")?;
+    let second = send_text(&mut alice, &mut context(7, BASE + 3), "second")?;
+
+    receive_message(&mut bob, &mut context(8, BASE + 10), &first.incoming)?;
+    assert!(!checkpoint_session(&bob).skipped_keys.is_empty());
+
+    let mut within_window = bob.clone();
+    assert_eq!(
+        payload_text(&receive_message(
+            &mut within_window,
+            &mut context(9, BASE + 70),
+            &lost.incoming
+        )?),
+        "lost"
+    );
+
+    receive_message(&mut bob, &mut context(10, BASE + 71), &second.incoming)?;
+    assert!(checkpoint_session(&bob).skipped_keys.is_empty());
+    assert!(receive_message(&mut bob, &mut context(11, BASE + 72), &lost.incoming).is_err());
+    Ok(())
+}
+
+#[test]
+fn window_is_opt_in_and_can_be_disabled() -> Result<()> {
+    let (_, _, mut alice, mut bob) = direct_session_pair(5, 6, BASE)?;
+    assert_eq!(bob.skipped_key_max_age_secs(), None);
+    bob.set_skipped_key_max_age_secs(Some(DEFAULT_SKIPPED_KEY_MAX_AGE_SECS));
+    let lost = send_text(&mut alice, &mut context(12, BASE + 1), "lost")?;
+    let kept = send_text(&mut alice, &mut context(13, BASE + 2), "kept")?;
+    receive_message(&mut bob, &mut context(14, BASE + 10), &kept.incoming)?;
+
+    let late = BASE + 10 + DEFAULT_SKIPPED_KEY_MAX_AGE_SECS + 1;
+    let mut expiring = bob.clone();
+    assert!(receive_message(&mut expiring, &mut context(15, late), &lost.incoming).is_err());
+
+    bob.set_skipped_key_max_age_secs(None);
+    assert_eq!(
+        payload_text(&receive_message(
+            &mut bob,
+            &mut context(16, late),
+            &lost.incoming
+        )?),
+        "lost"
+    );
+    Ok(())
+}
@@ -0,0 +1,122 @@
+mod support;
+
+use nostr_double_ratchet::{
+    decode_snapshot, encode_snapshot, Error, GroupManagerSnapshot, Result, RetentionPolicy,
+    SenderKeyState, SessionManagerSnapshot, SessionState, SnapshotEnvelope, SnapshotKind,
+    VersionedSnapshot, SNAPSHOT_VERSION,
+};
+use serde_json::Value;
+use support::{
+    checkpoint_session, context, direct_session_pair, manager_device, manager_public_device_invite,
+    roster_for, session_manager,
+};
+
+fn populated_manager_snapshot() -> Result<SessionManagerSnapshot> {
+    let alice = manager_device(1, 11);
+    let bob = manager_device(2, 21);
+    let mut alice_manager = session_manager(&alice);
+    let mut bob_manager = session_manager(&bob);
+    alice_manager.observe_peer_roster(bob.owner_pubkey, roster_for(&[&bob], 10));
+    alice_manager.observe_device_invite(
+        bob.owner_pubkey,
+        manager_public_device_invite(&mut bob_manager, &bob, 1, 1_800_000_000)?,
+    )?;
+    let mut ctx = context(2, 1_800_000_001);
+    alice_manager.prepare_send(&mut ctx, bob.owner_pubkey, b"hello".to_vec())?;
+    Ok(alice_manager.snapshot())
+}
+
+#[test]
+fn current_snapshots_round_trip_without_migrations() -> Result<()> {
+    let snapshot = populated_manager_snapshot()?;
+    let stored = encode_snapshot(&snapshot)?;
+    let envelope: SnapshotEnvelope = serde_json::from_str(&stored)?;
+    assert_eq!(envelope.kind, SnapshotKind::SessionManager);
+    assert_eq!(envelope.version, SNAPSHOT_VERSION);
+
+    let loaded = decode_snapshot::<SessionManagerSnapshot>(&stored)?;
+    assert_eq!(loaded.snapshot, snapshot);
+    assert_eq!(loaded.stored_version, SNAPSHOT_VERSION);
+    assert!(loaded.applied_migrations.is_empty());
+
+    let sender_key = SenderKeyState::new(7, [9u8; 32], 3);
+    let loaded = decode_snapshot::<SenderKeyState>(&encode_snapshot(&sender_key)?)?;
+    assert_eq!(loaded.snapshot, sender_key);
+    assert_eq!(loaded.stored_version, SNAPSHOT_VERSION);
+
+    let groups = GroupManagerSnapshot {
+        local_owner_pubkey: manager_device(3, 31).owner_pubkey,
+        groups: Vec::new(),
+        sender_keys: Vec::new(),
+    };
+    let loaded = decode_snapshot::<GroupManagerSnapshot>(&encode_snapshot(&groups)?)?;
+    assert_eq!(loaded.snapshot, groups);
+    Ok(())
+}
+
+#[test]
+fn unversioned_snapshots_are_migrated_and_reported() -> Result<()> {
+    let snapshot = populated_manager_snapshot()?;
+    let loaded = decode_snapshot::<SessionManagerSnapshot>(&serde_json::to_string(&snapshot)?)?;
+    assert_eq!(loaded.snapshot, snapshot);
+    assert_eq!(loaded.stored_version, 0);
+    assert_eq!(loaded.applied_migrations, vec!["wrap-unversioned-snapshot"]);
+
+    let (_, _, session, _) = direct_session_pair(4, 5, 1_800_000_000)?;
+    let state = checkpoint_session(&session);
+    let loaded = decode_snapshot::<SessionState>(&serde_json::to_string(&state)?)?;
+    assert_eq!(loaded.snapshot, state);
+    assert_eq!(loaded.applied_migrations, vec!["wrap-unversioned-snapshot"]);
+    Ok(())
+}
+
+#[test]
+fn newer_or_mismatched_snapshots_are_rejected() -> Result<()> {
+    let snapshot = populated_manager_snapshot()?;
+    let mut envelope: SnapshotEnvelope = serde_json::from_str(&encode_snapshot(&snapshot)?)?;
+
+    envelope.version = SessionManagerSnapshot::VERSION + 1;
+    let future = serde_json::to_string(&envelope)?;
+    assert!(matches!(
+        decode_snapshot::<SessionManagerSnapshot>(&future),
+        Err(Error::Serialization(_))
+    ));
+
+    envelope.version = SessionManagerSnapshot::VERSION;
+    let stored = serde_json::to_string(&envelope)?;
+    assert!(matches!(
+        decode_snapshot::<GroupManagerSnapshot>(&stored),
+        Err(Error::Serialization(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn released_manager_snapshots_default_later_fields() -> Result<()> {
+    let snapshot = populated_manager_snapshot()?;
+    let mut data = serde_json::to_value(&snapshot)?;
+    let manager = data.as_object_mut().expect("snapshot is an object");
+    manager.remove("retention_policy");
+    manager.remove("session_limits");
+    manager.remove("payload_padding");
+    for user in manager["users"].as_array_mut().expect("users") {
+        for device in user["devices"].as_array_mut().expect("devices") {
+            let device = device.as_object_mut().expect("device");
+            device.remove("decrypt_failures");
+            let active = device["active_session"]
+                .as_object_mut()
+                .expect("send created an active session");
+            active.remove("peer_accepts_padding");
+            active.remove("closed");
+        }
+    }
+
+    let loaded = decode_snapshot::<SessionManagerSnapshot>(&data.to_string())?;
+    assert_eq!(loaded.stored_version, 0);
+    assert_eq!(loaded.snapshot.retention_policy, RetentionPolicy::default());
+    assert_eq!(loaded.snapshot, snapshot);
+
+    let reencoded: Value = serde_json::from_str(&encode_snapshot(&loaded.snapshot)?)?;
+    assert_eq!(reencoded["version"], SNAPSHOT_VERSION);
+    Ok(())
+}
@@ -269,6 +269,46 @@ pub fn received_payloads(received: &[ReceivedMessage]) -> Vec<String> {
         .collect()
 }
 
+/// Managers for `alice` and `bob` with a two-way session, bootstrapped by
+/// one message from alice.
+pub fn connected_managers(
+    alice: &ManagerDevice,
+    bob: &ManagerDevice,
+    base_secs: u64,
+) -> Result<(SessionManager, SessionManager)> {
+    let mut alice_manager = session_manager(alice);
+    let mut bob_manager = session_manager(bob);
+    alice_manager.observe_peer_roster(bob.owner_pubkey, roster_for(&[bob], 10));
+    bob_manager.observe_peer_roster(alice.owner_pubkey, roster_for(&[alice], 10));
+    alice_manager.observe_device_invite(
+        bob.owner_pubkey,
+        manager_public_device_invite(&mut bob_manager, bob, 1, base_secs)?,
+    )?;
+    bob_manager.observe_device_invite(
+        alice.owner_pubkey,
+        manager_public_device_invite(&mut alice_manager, alice, 2, base_secs)?,
+    )?;
+
+    let first = alice_manager.prepare_send(
+        &mut context(3, base_secs + 1),
+        bob.owner_pubkey,
+        b"hello".to_vec(),
+    )?;
+    manager_observe_invite_response(
+        &mut bob_manager,
+        &mut context(4, base_secs + 2),
+        &first.invite_responses[0],
+    )?;
+    manager_receive_delivery(
+        &mut bob_manager,
+        &mut context(5, base_secs + 3),
+        alice.owner_pubkey,
+        &first.deliveries[0],
+    )?
+    .expect("first message decrypts");
+    Ok((alice_manager, bob_manager))
+}
+
 pub fn direct_session_pair(
     alice_fill: u8,
     bob_fill: u8,
@@ -519,9 +559,10 @@ fn decrypt_outer_response(
 ) -> Result<TestInviteResponseInnerEvent> {
     let inviter_ephemeral_private_key = invite
         .inviter_ephemeral_private_key
+        .as_ref()
         .expect("owned invite must have ephemeral private key");
     let decrypted = nip44::decrypt(
-        &SecretKey::from_slice(&inviter_ephemeral_private_key).unwrap(),
+        &SecretKey::from_slice(inviter_ephemeral_private_key.expose_secret()).unwrap(),
         &nostr_pubkey(response.sender),
         &response.content,
     )?;
@@ -535,17 +576,19 @@ fn reencrypt_outer_response(
     plaintext: String,
 ) -> Result<InviteResponseEnvelope> {
     let content = nip44::encrypt(
-        &SecretKey::from_slice(&response.signer_secret_key).unwrap(),
+        &SecretKey::from_slice(response.signer_secret_key.expose_secret()).unwrap(),
         &nostr_pubkey(response.recipient),
         plaintext,
         Version::V2,
     )?;
     Ok(InviteResponseEnvelope {
         sender: response.sender,
-        signer_secret_key: response.signer_secret_key,
+        signer_secret_key: response.signer_secret_key.clone(),
         recipient: response.recipient,
         created_at: response.created_at,
         content,
+        proof_of_work: None,
+        event_id: None,
     })
 }
 
@@ -560,7 +603,7 @@ fn build_invite_payload_ciphertext(
         payload_json,
         Version::V2,
     )?;
-    let conversation_key = nip44::v2::ConversationKey::new(invite.shared_secret);
+    let conversation_key = nip44::v2::ConversationKey::new(*invite.shared_secret.expose_secret());
     let encrypted_bytes = nip44::v2::encrypt_to_bytes(&conversation_key, dh_encrypted.as_bytes())?;
     Ok(base64::engine::general_purpose::STANDARD.encode(encrypted_bytes))
 }